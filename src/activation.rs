@@ -0,0 +1,250 @@
+// Activation of environments in (interactive) shells.
+//
+// Activating an environment means prepending its executable directories to the PATH, setting the
+// variables conda sets on activation (CONDA_PREFIX, CONDA_DEFAULT_ENV), and running the activation
+// scripts packages install into `etc/conda/activate.d`, in the syntax of the users' shell.
+
+use anyhow::{bail, Result};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use crate::defaults::CONDA_BIN_DIRNAME;
+
+/// The shells viva can generate activation code for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShellType {
+    Bash,
+    Zsh,
+    Fish,
+    PowerShell,
+}
+
+impl FromStr for ShellType {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.to_lowercase().as_str() {
+            "bash" | "sh" => Ok(ShellType::Bash),
+            "zsh" => Ok(ShellType::Zsh),
+            "fish" => Ok(ShellType::Fish),
+            "powershell" | "pwsh" => Ok(ShellType::PowerShell),
+            _ => bail!("Unsupported shell: {} (supported: bash, zsh, fish, powershell)", s),
+        }
+    }
+}
+
+impl ShellType {
+    /// The shell of the current user (from `$SHELL`), or the platform default.
+    pub fn detect() -> ShellType {
+        let shell = std::env::var_os("SHELL").map(PathBuf::from);
+        let name = shell
+            .as_ref()
+            .and_then(|shell| shell.file_stem())
+            .map(|name| name.to_string_lossy().to_string());
+        match name.and_then(|name| ShellType::from_str(&name).ok()) {
+            Some(shell_type) => shell_type,
+            None if cfg!(windows) => ShellType::PowerShell,
+            None => ShellType::Bash,
+        }
+    }
+
+    /// The name of the executable of this shell.
+    pub fn executable(&self) -> &'static str {
+        match self {
+            ShellType::Bash => "bash",
+            ShellType::Zsh => "zsh",
+            ShellType::Fish => "fish",
+            ShellType::PowerShell if cfg!(windows) => "powershell",
+            ShellType::PowerShell => "pwsh",
+        }
+    }
+
+    /// The extension of activation scripts (in `etc/conda/activate.d`) for this shell.
+    fn script_extension(&self) -> &'static str {
+        match self {
+            ShellType::Bash | ShellType::Zsh => "sh",
+            ShellType::Fish => "fish",
+            ShellType::PowerShell => "ps1",
+        }
+    }
+}
+
+/// Everything that needs to change in a shell to activate an environment.
+#[derive(Debug, Clone, Default)]
+pub struct Activation {
+    /// Directories to prepend to the `PATH`, in order of precedence.
+    pub path_prepend: Vec<PathBuf>,
+    /// Environment variables to set.
+    pub env_vars: BTreeMap<String, String>,
+    /// Activation scripts of the installed packages (`etc/conda/activate.d`).
+    pub scripts: Vec<PathBuf>,
+}
+
+impl Activation {
+    /// The activation of the environment in the provided prefix (with an optional base prefix,
+    /// whose executables come after the ones of the environment).
+    pub fn create(env_id: &str, prefix: &Path, base_prefix: Option<&Path>) -> Activation {
+        let mut path_prepend = prefix_bin_dirs(prefix);
+        if let Some(base_prefix) = base_prefix {
+            path_prepend.extend(prefix_bin_dirs(base_prefix));
+        }
+
+        let mut env_vars = BTreeMap::new();
+        env_vars.insert(
+            String::from("CONDA_PREFIX"),
+            prefix.to_string_lossy().to_string(),
+        );
+        env_vars.insert(String::from("CONDA_DEFAULT_ENV"), String::from(env_id));
+        env_vars.insert(String::from("VIVA_ENV"), String::from(env_id));
+
+        let mut scripts = match std::fs::read_dir(prefix.join("etc").join("conda").join("activate.d")) {
+            Ok(entries) => entries
+                .flatten()
+                .map(|entry| entry.path())
+                .filter(|path| path.is_file())
+                .collect(),
+            Err(_) => vec![],
+        };
+        scripts.sort();
+
+        Activation {
+            path_prepend,
+            env_vars,
+            scripts,
+        }
+    }
+
+    /// The value of the `PATH` with the environment activated, based on the provided one.
+    pub fn activated_path(&self, current_path: Option<&std::ffi::OsStr>) -> Result<std::ffi::OsString> {
+        let mut paths = self.path_prepend.clone();
+        if let Some(current_path) = current_path {
+            paths.extend(std::env::split_paths(current_path));
+        }
+        Ok(std::env::join_paths(paths)?)
+    }
+
+    /// Shell code that activates the environment in the current shell, when evaluated.
+    pub fn render(&self, shell: ShellType) -> String {
+        let separator = if cfg!(windows) { ";" } else { ":" };
+        let path = self
+            .path_prepend
+            .iter()
+            .map(|p| p.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+        let scripts = self
+            .scripts
+            .iter()
+            .filter(|script| {
+                script.extension().and_then(|e| e.to_str()) == Some(shell.script_extension())
+            })
+            .map(|script| script.to_string_lossy().to_string())
+            .collect::<Vec<_>>();
+
+        let mut lines = vec![];
+        match shell {
+            ShellType::Bash | ShellType::Zsh => {
+                lines.push(format!("export PATH={}{}\"$PATH\"", posix_quote(&path.join(separator)), separator));
+                for (key, value) in &self.env_vars {
+                    lines.push(format!("export {}={}", key, posix_quote(value)));
+                }
+                for script in scripts {
+                    lines.push(format!(". {}", posix_quote(&script)));
+                }
+            }
+            ShellType::Fish => {
+                let path = path.iter().map(|p| fish_quote(p)).collect::<Vec<_>>();
+                lines.push(format!("set -gx PATH {} $PATH", path.join(" ")));
+                for (key, value) in &self.env_vars {
+                    lines.push(format!("set -gx {} {}", key, fish_quote(value)));
+                }
+                for script in scripts {
+                    lines.push(format!("source {}", fish_quote(&script)));
+                }
+            }
+            ShellType::PowerShell => {
+                lines.push(format!(
+                    "$Env:PATH = {} + $Env:PATH",
+                    powershell_quote(&format!("{}{}", path.join(separator), separator))
+                ));
+                for (key, value) in &self.env_vars {
+                    lines.push(format!("$Env:{} = {}", key, powershell_quote(value)));
+                }
+                for script in scripts {
+                    lines.push(format!(". {}", powershell_quote(&script)));
+                }
+            }
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+
+    /// Shell code for the init file of an interactive shell: it loads the users' own init file
+    /// first (which might change the `PATH`), then activates the environment, and prefixes the
+    /// prompt with the id of the environment.
+    pub fn render_interactive_init(&self, shell: ShellType, env_id: &str) -> String {
+        let prompt_prefix = format!("({}) ", env_id);
+        let mut lines = vec![];
+        match shell {
+            ShellType::Bash => {
+                lines.push(String::from("[ -f \"$HOME/.bashrc\" ] && . \"$HOME/.bashrc\""));
+                lines.push(self.render(shell));
+                lines.push(format!("PS1={}\"$PS1\"", posix_quote(&prompt_prefix)));
+            }
+            ShellType::Zsh => {
+                // the init file is found via ZDOTDIR, which points to a temporary directory
+                lines.push(String::from("ZDOTDIR=\"${VIVA_ORIG_ZDOTDIR:-$HOME}\""));
+                lines.push(String::from("unset VIVA_ORIG_ZDOTDIR"));
+                lines.push(String::from("[ -f \"$ZDOTDIR/.zshrc\" ] && . \"$ZDOTDIR/.zshrc\""));
+                lines.push(self.render(shell));
+                lines.push(format!("PROMPT={}\"$PROMPT\"", posix_quote(&prompt_prefix)));
+            }
+            ShellType::Fish => {
+                lines.push(self.render(shell));
+                lines.push(String::from("functions -c fish_prompt _viva_original_prompt"));
+                lines.push(format!(
+                    "function fish_prompt; echo -n {}; _viva_original_prompt; end",
+                    fish_quote(&prompt_prefix)
+                ));
+            }
+            ShellType::PowerShell => {
+                lines.push(self.render(shell));
+                lines.push(String::from("$function:_viva_original_prompt = $function:prompt"));
+                lines.push(format!(
+                    "function global:prompt {{ {} + (& $function:_viva_original_prompt) }}",
+                    powershell_quote(&prompt_prefix)
+                ));
+            }
+        }
+        lines.push(String::new());
+        lines.join("\n")
+    }
+}
+
+/// The directories with executables in a (conda) prefix, in the order conda puts them on the PATH.
+pub(crate) fn prefix_bin_dirs(prefix: &Path) -> Vec<PathBuf> {
+    if cfg!(windows) {
+        vec![
+            prefix.to_path_buf(),
+            prefix.join("Library").join("mingw-w64").join("bin"),
+            prefix.join("Library").join("usr").join("bin"),
+            prefix.join("Library").join("bin"),
+            prefix.join(CONDA_BIN_DIRNAME),
+            prefix.join("bin"),
+        ]
+    } else {
+        vec![prefix.join(CONDA_BIN_DIRNAME)]
+    }
+}
+
+fn posix_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "'\\''"))
+}
+
+fn fish_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn powershell_quote(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
@@ -0,0 +1,394 @@
+// Credentials for private channels.
+//
+// Credentials are looked up (in this order) in environment variables (`VIVA_AUTH_<HOST>`), the
+// credentials stored with `viva auth login`, the `channel_auth` config key, and the users' `.netrc`
+// file. Except for environment variables, they are registered for a host (`repo.example.com`), or a
+// url prefix (`https://repo.example.com/channels/private`), and the most specific match wins.
+
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use once_cell::sync::OnceCell;
+use rattler_conda_types::{Channel, ChannelConfig};
+use reqwest::header::HeaderValue;
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::Write;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::config::VivaConfig;
+
+static CREDENTIALS_FILE: OnceCell<PathBuf> = OnceCell::new();
+
+/// Set the file credentials are stored in by `viva auth login` (only the first call has an
+/// effect). Without one, stored credentials are not used.
+pub(crate) fn set_credentials_file(credentials_file: PathBuf) {
+    let _ = CREDENTIALS_FILE.set(credentials_file);
+}
+
+/// Credentials for a channel, in their string form: `token:<token>` (a conda token, which is added
+/// to urls as `/t/<token>/`), `bearer:<token>` (sent as `Authorization: Bearer` header), or
+/// `basic:<username>:<password>`.
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum ChannelCredentials {
+    CondaToken(String),
+    Bearer(String),
+    Basic {
+        username: String,
+        password: Option<String>,
+    },
+}
+
+impl ChannelCredentials {
+    /// A description of the credentials, without the secret parts.
+    pub fn redacted(&self) -> String {
+        match self {
+            ChannelCredentials::CondaToken(_) => String::from("token:***"),
+            ChannelCredentials::Bearer(_) => String::from("bearer:***"),
+            ChannelCredentials::Basic { username, .. } => format!("basic:{}:***", username),
+        }
+    }
+
+    /// The value of the `Authorization` header, if these credentials are sent as header.
+    fn authorization_header(&self) -> Option<String> {
+        match self {
+            ChannelCredentials::CondaToken(_) => None,
+            ChannelCredentials::Bearer(token) => Some(format!("Bearer {}", token)),
+            ChannelCredentials::Basic { username, password } => {
+                let credentials = format!("{}:{}", username, password.as_deref().unwrap_or(""));
+                Some(format!("Basic {}", BASE64.encode(credentials)))
+            }
+        }
+    }
+}
+
+impl FromStr for ChannelCredentials {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (kind, value) = s.split_once(':').ok_or_else(|| {
+            anyhow!("Invalid channel credentials (must be 'token:<token>', 'bearer:<token>', or 'basic:<username>:<password>')")
+        })?;
+        let credentials = match kind {
+            "token" if !value.is_empty() => ChannelCredentials::CondaToken(String::from(value)),
+            "bearer" if !value.is_empty() => ChannelCredentials::Bearer(String::from(value)),
+            "basic" => {
+                let (username, password) = match value.split_once(':') {
+                    Some((username, password)) => (username, Some(String::from(password))),
+                    None => (value, None),
+                };
+                if username.is_empty() {
+                    bail!("Invalid channel credentials: no username");
+                }
+                ChannelCredentials::Basic {
+                    username: String::from(username),
+                    password,
+                }
+            }
+            _ => bail!(
+                "Invalid channel credentials (must be 'token:<token>', 'bearer:<token>', or 'basic:<username>:<password>')"
+            ),
+        };
+        Ok(credentials)
+    }
+}
+
+impl TryFrom<String> for ChannelCredentials {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        ChannelCredentials::from_str(&value)
+    }
+}
+
+impl From<ChannelCredentials> for String {
+    fn from(credentials: ChannelCredentials) -> Self {
+        match credentials {
+            ChannelCredentials::CondaToken(token) => format!("token:{}", token),
+            ChannelCredentials::Bearer(token) => format!("bearer:{}", token),
+            ChannelCredentials::Basic { username, password } => match password {
+                Some(password) => format!("basic:{}:{}", username, password),
+                None => format!("basic:{}", username),
+            },
+        }
+    }
+}
+
+// credentials should not end up in logs by accident
+impl fmt::Debug for ChannelCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.redacted())
+    }
+}
+
+/// The key credentials are registered under: a url prefix (without trailing slash), or a host.
+///
+/// Channel names (like `my-org`, without a `.`) are resolved to their url first.
+pub fn credentials_key(channel: &str, channel_config: &ChannelConfig) -> String {
+    let channel = channel.trim().trim_end_matches('/');
+    if channel.contains("://") {
+        return String::from(channel);
+    }
+    if !channel.contains('.') {
+        if let Ok(resolved) = Channel::from_str(channel, channel_config) {
+            return resolved.base_url.as_str().trim_end_matches('/').to_string();
+        }
+    }
+    channel.to_lowercase()
+}
+
+/// The name of the environment variable that can contain credentials for a host.
+pub fn credentials_env_var(host: &str) -> String {
+    let host = host
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() {
+            true => c.to_ascii_uppercase(),
+            false => '_',
+        })
+        .collect::<String>();
+    format!("VIVA_AUTH_{}", host)
+}
+
+/// Read the credentials stored with `viva auth login` (key -> credentials).
+pub fn read_stored_credentials() -> Result<BTreeMap<String, ChannelCredentials>> {
+    let credentials_file = match CREDENTIALS_FILE.get() {
+        Some(credentials_file) if credentials_file.is_file() => credentials_file,
+        _ => return Ok(BTreeMap::new()),
+    };
+    let content = std::fs::read_to_string(credentials_file)?;
+    serde_json::from_str(&content)
+        .map_err(|e| anyhow!("Invalid credentials file '{}': {}", credentials_file.display(), e))
+}
+
+fn write_stored_credentials(credentials: &BTreeMap<String, ChannelCredentials>) -> Result<()> {
+    let credentials_file = CREDENTIALS_FILE
+        .get()
+        .ok_or_else(|| anyhow!("No credentials file configured"))?;
+    if let Some(parent) = credentials_file.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // only readable by the user
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(credentials_file)?;
+    // the mode only applies to new files, existing ones might have been created by something else
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(serde_json::to_string_pretty(credentials)?.as_bytes())?;
+    Ok(())
+}
+
+/// A url for messages and logs, without a conda token (`/t/<token>/`) or user info.
+pub(crate) fn display_url(url: &Url) -> String {
+    let mut url = url.clone();
+    let _ = url.set_username("");
+    let _ = url.set_password(None);
+    if let Some(rest) = url.path().strip_prefix("/t/") {
+        let path = rest.split_once('/').map(|(_, path)| format!("/{}", path)).unwrap_or_default();
+        url.set_path(&path);
+    }
+    url.to_string()
+}
+
+/// Store credentials for a channel (url prefix or host), replacing existing ones.
+pub fn store_credentials(
+    channel: &str,
+    channel_config: &ChannelConfig,
+    credentials: ChannelCredentials,
+) -> Result<String> {
+    let key = credentials_key(channel, channel_config);
+    let mut stored = read_stored_credentials()?;
+    stored.insert(key.clone(), credentials);
+    write_stored_credentials(&stored)?;
+    Ok(key)
+}
+
+/// Remove the stored credentials of a channel, returns whether there were any.
+pub fn remove_credentials(channel: &str, channel_config: &ChannelConfig) -> Result<bool> {
+    let mut stored = read_stored_credentials()?;
+    if stored.remove(&credentials_key(channel, channel_config)).is_none() {
+        return Ok(false);
+    }
+    write_stored_credentials(&stored)?;
+    Ok(true)
+}
+
+/// The path of the users' netrc file (`NETRC`, or `.netrc`/`_netrc` in the home directory).
+fn netrc_path() -> Option<PathBuf> {
+    if let Some(netrc) = std::env::var_os("NETRC") {
+        return Some(PathBuf::from(netrc));
+    }
+    let home = dirs::home_dir()?;
+    match cfg!(windows) {
+        true => Some(home.join("_netrc")),
+        false => Some(home.join(".netrc")),
+    }
+}
+
+/// Parse the `machine`/`login`/`password` entries of a netrc file (host -> credentials). The
+/// `default` entry is ignored, its credentials would be sent to every host without any.
+pub(crate) fn parse_netrc(content: &str) -> BTreeMap<String, ChannelCredentials> {
+    let mut entries = BTreeMap::new();
+    let mut machine: Option<String> = None;
+    let mut login: Option<String> = None;
+    let mut password: Option<String> = None;
+
+    let mut finish = |machine: &mut Option<String>, login: &mut Option<String>, password: &mut Option<String>| {
+        if let (Some(host), Some(username)) = (machine.take(), login.take()) {
+            entries.entry(host).or_insert(ChannelCredentials::Basic {
+                username,
+                password: password.take(),
+            });
+        }
+        *login = None;
+        *password = None;
+    };
+
+    let mut tokens = content.split_whitespace();
+    while let Some(token) = tokens.next() {
+        match token {
+            "machine" => {
+                finish(&mut machine, &mut login, &mut password);
+                machine = tokens.next().map(|host| host.to_lowercase());
+            }
+            "default" => {
+                finish(&mut machine, &mut login, &mut password);
+                machine = None;
+            }
+            "login" => login = tokens.next().map(String::from),
+            "password" => password = tokens.next().map(String::from),
+            _ => {}
+        }
+    }
+    finish(&mut machine, &mut login, &mut password);
+    entries
+}
+
+/// The most specific entry matching a url: the longest matching url prefix, or else the host.
+fn best_match<'a>(
+    entries: &'a BTreeMap<String, ChannelCredentials>,
+    url: &Url,
+) -> Option<&'a ChannelCredentials> {
+    let url_str = url.as_str();
+    let prefix_match = entries
+        .iter()
+        .filter(|(key, _)| key.contains("://"))
+        .filter(|(key, _)| {
+            url_str.starts_with(key.as_str())
+                && matches!(url_str[key.len()..].chars().next(), None | Some('/'))
+        })
+        .max_by_key(|(key, _)| key.len())
+        .map(|(_, credentials)| credentials);
+    prefix_match.or_else(|| {
+        url.host_str()
+            .and_then(|host| entries.get(&host.to_lowercase()))
+    })
+}
+
+/// The credentials for all channels, used to authenticate repodata and package downloads.
+#[derive(Debug, Default)]
+pub(crate) struct ChannelAuth {
+    stored: BTreeMap<String, ChannelCredentials>,
+    configured: BTreeMap<String, ChannelCredentials>,
+    netrc: BTreeMap<String, ChannelCredentials>,
+}
+
+impl ChannelAuth {
+    pub(crate) fn load(config: &VivaConfig) -> Result<ChannelAuth> {
+        let netrc = match netrc_path() {
+            Some(path) if path.is_file() => parse_netrc(&std::fs::read_to_string(path)?),
+            _ => BTreeMap::new(),
+        };
+        let channel_config = config.channel_config()?;
+        Ok(ChannelAuth {
+            stored: read_stored_credentials()?,
+            configured: config
+                .channel_auth
+                .iter()
+                .map(|(key, credentials)| (credentials_key(key, &channel_config), credentials.clone()))
+                .collect(),
+            netrc,
+        })
+    }
+
+    /// The credentials to use for a url, if any.
+    pub(crate) fn credentials_for(&self, url: &Url) -> Result<Option<ChannelCredentials>> {
+        let host = match url.host_str() {
+            Some(host) => host.to_lowercase(),
+            None => return Ok(None),
+        };
+        if let Ok(value) = std::env::var(credentials_env_var(&host)) {
+            return ChannelCredentials::from_str(&value)
+                .map(Some)
+                .map_err(|e| anyhow!("{} (in '{}')", e, credentials_env_var(&host)));
+        }
+        let credentials = best_match(&self.stored, url)
+            .or_else(|| best_match(&self.configured, url))
+            .or_else(|| self.netrc.get(&host))
+            .cloned();
+        Ok(credentials)
+    }
+
+    /// The url to download a file from, and the `Authorization` header to send with the request
+    /// (if any): conda tokens are inserted into the url, other credentials are sent as header.
+    pub(crate) fn authenticate(&self, url: Url) -> Result<(Url, Option<HeaderValue>)> {
+        let credentials = match self.credentials_for(&url)? {
+            Some(credentials) => credentials,
+            None => return Ok((url, None)),
+        };
+        if let ChannelCredentials::CondaToken(token) = &credentials {
+            if url.path().starts_with("/t/") {
+                return Ok((url, None));
+            }
+            let mut url = url;
+            let path = format!("/t/{}{}", token, url.path());
+            url.set_path(&path);
+            return Ok((url, None));
+        }
+
+        let header = credentials
+            .authorization_header()
+            .expect("non-token credentials are sent as header");
+        let mut header_value = HeaderValue::from_str(&header)?;
+        header_value.set_sensitive(true);
+        Ok((url, Some(header_value)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_netrc_ignores_default() {
+        let netrc = "machine repo.example.com login alice password secret\ndefault login anonymous password guest\n";
+        let entries = parse_netrc(netrc);
+        assert_eq!(entries.keys().collect::<Vec<_>>(), vec!["repo.example.com"]);
+
+        let auth = ChannelAuth {
+            netrc: entries,
+            ..Default::default()
+        };
+        let (url, header) = auth
+            .authenticate(Url::parse("https://other.example.com/channel/noarch/repodata.json").unwrap())
+            .unwrap();
+        assert_eq!(url.as_str(), "https://other.example.com/channel/noarch/repodata.json");
+        assert!(header.is_none());
+        let (_, header) = auth
+            .authenticate(Url::parse("https://repo.example.com/channel/noarch/repodata.json").unwrap())
+            .unwrap();
+        assert_eq!(header.unwrap(), format!("Basic {}", BASE64.encode("alice:secret")).as_str());
+    }
+}
@@ -0,0 +1,230 @@
+// Checking that the packages of environment specs are available on their channels, for the target
+// platforms, so a missing package is reported as such (e.g. "not found on channel conda-forge for
+// win-64") before users run into a solver error.
+//
+// The check only uses repodata: every channel subdirectory (e.g. `conda-forge/win-64`) is fetched
+// at most once per check, from the repodata cache if it is up to date, no matter how many
+// environments use it. Requests to the same channel are spaced out, so checking many platforms
+// doesn't hammer a channel server.
+
+use anyhow::Result;
+use futures::StreamExt;
+use rattler_conda_types::{MatchSpec, Platform};
+use rattler_repodata_gateway::sparse::SparseRepoData;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::config::VivaConfig;
+use crate::models::environment::VivaEnvSpec;
+use crate::rattler::commands::create::fetch_channel_repodata;
+use crate::throttle::RequestThrottle;
+
+/// The minimum time between two repodata requests to the same channel.
+const CHANNEL_REQUEST_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The number of channel subdirectories fetched concurrently.
+const REPODATA_CONCURRENCY: usize = 8;
+
+/// Why a package spec can't be satisfied by the channels of an environment.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Unavailable {
+    /// No package with the name exists.
+    NotFound,
+    /// Packages with the name exist, but none matches the version (or build) constraint.
+    NoMatchingVersion,
+}
+
+/// A package spec of an environment that can't be satisfied for one of its target platforms.
+#[derive(Debug, Clone)]
+pub(crate) struct MissingPackage {
+    pub env_id: String,
+    pub pkg_spec: String,
+    pub platform: String,
+    pub channels: Vec<String>,
+    pub reason: Unavailable,
+}
+
+impl fmt::Display for MissingPackage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let channels = match self.channels.len() {
+            1 => format!("channel {}", self.channels[0]),
+            _ => format!("channels {}", self.channels.join(", ")),
+        };
+        match self.reason {
+            Unavailable::NotFound => write!(f, "package '{}' not found on {} for {}", self.pkg_spec, channels, self.platform),
+            Unavailable::NoMatchingVersion => write!(
+                f,
+                "no version of '{}' on {} for {} matches the spec",
+                self.pkg_spec, channels, self.platform
+            ),
+        }
+    }
+}
+
+/// The result of an availability check.
+#[derive(Debug, Default)]
+pub(crate) struct AvailabilityReport {
+    pub missing: Vec<MissingPackage>,
+    /// Channel subdirectories whose repodata could not be fetched (`<channel>/<subdir>`, error), the
+    /// specs of environments that use them were not checked for the affected platforms.
+    pub unchecked: BTreeSet<(String, String)>,
+}
+
+/// The platforms to check an environment for: the provided ones, or the platform of the spec.
+fn target_platforms(env_spec: &VivaEnvSpec, platforms: &[String]) -> Vec<String> {
+    match platforms.is_empty() {
+        true => vec![env_spec.get_platform_name()],
+        false => platforms.to_vec(),
+    }
+}
+
+/// The subdirectories packages for a platform can come from.
+fn subdirs_for(platform: &str) -> Vec<String> {
+    match platform == Platform::NoArch.as_str() {
+        true => vec![String::from(platform)],
+        false => vec![String::from(platform), Platform::NoArch.to_string()],
+    }
+}
+
+/// Check whether the package specs of environments are available on their channels, for the
+/// provided platforms (or the platform of each environment, if none are provided).
+pub(crate) async fn check_availability(
+    envs: &[(String, VivaEnvSpec)],
+    platforms: &[String],
+    config: &VivaConfig,
+) -> Result<AvailabilityReport> {
+    for platform in platforms {
+        Platform::from_str(platform)?;
+    }
+
+    let mut subdirs: BTreeSet<(String, String)> = BTreeSet::new();
+    for (_, env_spec) in envs {
+        for platform in target_platforms(env_spec, platforms) {
+            for channel in &env_spec.channels {
+                for subdir in subdirs_for(&platform) {
+                    subdirs.insert((channel.clone(), subdir));
+                }
+            }
+        }
+    }
+
+    let throttle = RequestThrottle::new(CHANNEL_REQUEST_INTERVAL);
+    let fetched = futures::stream::iter(subdirs)
+        .map(|(channel, subdir)| {
+            let throttle = &throttle;
+            async move {
+                throttle.wait(&channel).await;
+                let result = match Platform::from_str(&subdir) {
+                    Ok(platform) => fetch_channel_repodata(&channel, platform, config).await,
+                    Err(e) => Err(e.into()),
+                };
+                ((channel, subdir), result)
+            }
+        })
+        .buffer_unordered(REPODATA_CONCURRENCY)
+        .collect::<Vec<_>>()
+        .await;
+
+    let mut report = AvailabilityReport::default();
+    let mut repodata: HashMap<(String, String), SparseRepoData> = HashMap::new();
+    for (key, result) in fetched {
+        match result {
+            Ok(sparse) => {
+                repodata.insert(key, sparse);
+            }
+            Err(e) => {
+                report
+                    .unchecked
+                    .insert((format!("{}/{}", key.0, key.1), format!("{:#}", e)));
+            }
+        }
+    }
+
+    for (env_id, env_spec) in envs.iter().filter(|(_, env_spec)| !env_spec.channels.is_empty()) {
+        for platform in target_platforms(env_spec, platforms) {
+            let sources = env_spec
+                .channels
+                .iter()
+                .flat_map(|channel| {
+                    subdirs_for(&platform)
+                        .into_iter()
+                        .map(move |subdir| (channel.clone(), subdir))
+                })
+                .map(|key| repodata.get(&key))
+                .collect::<Option<Vec<&SparseRepoData>>>();
+            // without the repodata of all channels, a package might be reported missing wrongly
+            let sources = match sources {
+                Some(sources) => sources,
+                None => continue,
+            };
+            for pkg_spec in &env_spec.pkg_specs {
+                let match_spec = match MatchSpec::from_str(pkg_spec) {
+                    Ok(match_spec) => match_spec,
+                    Err(_) => continue,
+                };
+                let name = match &match_spec.name {
+                    // virtual packages are provided by the system, not by channels
+                    Some(name) if !name.starts_with("__") => name,
+                    _ => continue,
+                };
+                let mut found = false;
+                let mut matched = false;
+                for sparse in &sources {
+                    let records = sparse.load_records(name).unwrap_or_default();
+                    found = found || !records.is_empty();
+                    if records.iter().any(|record| match_spec.matches(&record.package_record)) {
+                        matched = true;
+                        break;
+                    }
+                }
+                if !matched {
+                    report.missing.push(MissingPackage {
+                        env_id: env_id.clone(),
+                        pkg_spec: pkg_spec.clone(),
+                        platform: platform.clone(),
+                        channels: env_spec.channels.clone(),
+                        reason: match found {
+                            true => Unavailable::NoMatchingVersion,
+                            false => Unavailable::NotFound,
+                        },
+                    });
+                }
+            }
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_missing_package_display() {
+        let mut missing = MissingPackage {
+            env_id: String::from("data"),
+            pkg_spec: String::from("pywin32"),
+            platform: String::from("linux-64"),
+            channels: vec![String::from("conda-forge")],
+            reason: Unavailable::NotFound,
+        };
+        assert_eq!(
+            missing.to_string(),
+            "package 'pywin32' not found on channel conda-forge for linux-64"
+        );
+        missing.channels.push(String::from("bioconda"));
+        missing.reason = Unavailable::NoMatchingVersion;
+        assert_eq!(
+            missing.to_string(),
+            "no version of 'pywin32' on channels conda-forge, bioconda for linux-64 matches the spec"
+        );
+    }
+
+    #[test]
+    fn test_subdirs_for() {
+        assert_eq!(subdirs_for("win-64"), vec!["win-64", "noarch"]);
+        assert_eq!(subdirs_for("noarch"), vec!["noarch"]);
+    }
+}
@@ -0,0 +1,203 @@
+// Self-extracting app bundles.
+//
+// A bundle is a copy of the viva executable, with a payload appended to it. The payload contains
+// the spec of a single app and (optionally) a tarball of the package cache entries needed to create
+// its environment. When a bundle is started, it registers the app, creates its environment and
+// runs it, passing along all command-line arguments.
+//
+// Layout of the appended data:
+//
+//   [cache tarball][manifest json][cache tarball length: u64 LE][manifest length: u64 LE][magic]
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use tracing::debug;
+
+use crate::context::VivaContext;
+use crate::models::app::{AppEnvPlacementStrategy, VivaAppSpec};
+use crate::models::environment::EnvCheckStrategy;
+use crate::config::VivaConfig;
+use crate::rattler::commands::create::{find_installed_packages, packages_dir};
+
+const BUNDLE_MAGIC: &[u8; 8] = b"VIVABNDL";
+const TRAILER_LEN: u64 = 8 + 8 + 8;
+
+/// The manifest of an app bundle.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct AppBundleManifest {
+    pub app_id: String,
+    pub spec: VivaAppSpec,
+}
+
+/// Create a bundle for a registered app.
+///
+/// If `include_cache` is set, the app environment needs to be synced, and the package cache
+/// entries of all of its packages are included, so the bundle can be installed without network access.
+pub async fn create_bundle(
+    context: &VivaContext,
+    app_id: &str,
+    output: &PathBuf,
+    include_cache: bool,
+) -> Result<()> {
+    let app = context.get_app(app_id).await?;
+    let mut spec = app.spec.clone();
+    spec.env_id = None;
+    let manifest = AppBundleManifest {
+        app_id: String::from(app_id),
+        spec,
+    };
+
+    let cache_tarball: Vec<u8> = match include_cache {
+        true => {
+            let env = context.get_env(&context.get_app_env_id(app_id).await?).await?;
+            create_cache_tarball(env.get_env_path()).await?
+        }
+        false => vec![],
+    };
+
+    let current_exe = std::env::current_exe().context("Could not determine path of viva executable")?;
+    let exe_len = match read_trailer(&current_exe)? {
+        // don't nest bundles, if we are running from one
+        Some((offset, _, _)) => offset,
+        None => std::fs::metadata(&current_exe)?.len(),
+    };
+
+    let manifest_data = serde_json::to_vec(&manifest)?;
+
+    let mut exe = File::open(&current_exe)?;
+    let mut out = File::create(output)?;
+    std::io::copy(&mut (&mut exe).take(exe_len), &mut out)?;
+    out.write_all(&cache_tarball)?;
+    out.write_all(&manifest_data)?;
+    out.write_all(&(cache_tarball.len() as u64).to_le_bytes())?;
+    out.write_all(&(manifest_data.len() as u64).to_le_bytes())?;
+    out.write_all(BUNDLE_MAGIC)?;
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(output, std::fs::Permissions::from_mode(0o755))?;
+    }
+
+    Ok(())
+}
+
+/// Tar up the extracted package cache directories of all packages installed in a prefix.
+async fn create_cache_tarball(env_path: &Path) -> Result<Vec<u8>> {
+    let installed = find_installed_packages(env_path, 100).await?;
+    if installed.is_empty() {
+        bail!("Environment is not synced, can't include package cache: {}", env_path.display());
+    }
+
+    let mut builder = tar::Builder::new(Vec::new());
+    for record in installed {
+        let package_dir = match record.extracted_package_dir {
+            Some(dir) if dir.is_dir() => dir,
+            _ => {
+                debug!("No cache entry for package, skipping: {}", record.repodata_record.package_record.name);
+                continue;
+            }
+        };
+        let name = package_dir.file_name().ok_or(anyhow!("Invalid cache entry"))?.to_owned();
+        builder.append_dir_all(name, &package_dir)?;
+    }
+    Ok(builder.into_inner()?)
+}
+
+/// Read the trailer of an executable, returns the offset of the payload and the lengths of its parts.
+fn read_trailer(exe: &Path) -> Result<Option<(u64, u64, u64)>> {
+    let mut file = File::open(exe)?;
+    let file_len = file.metadata()?.len();
+    if file_len < TRAILER_LEN {
+        return Ok(None);
+    }
+    file.seek(SeekFrom::End(-(TRAILER_LEN as i64)))?;
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    file.read_exact(&mut trailer)?;
+    if &trailer[16..] != BUNDLE_MAGIC {
+        return Ok(None);
+    }
+    let cache_len = u64::from_le_bytes(trailer[0..8].try_into()?);
+    let manifest_len = u64::from_le_bytes(trailer[8..16].try_into()?);
+    let payload_len = cache_len + manifest_len;
+    if payload_len + TRAILER_LEN > file_len {
+        bail!("Invalid bundle: {}", exe.display());
+    }
+    Ok(Some((file_len - TRAILER_LEN - payload_len, cache_len, manifest_len)))
+}
+
+/// Check whether the current executable is an app bundle, and return its manifest if it is.
+pub fn read_bundle_manifest() -> Result<Option<AppBundleManifest>> {
+    let current_exe = std::env::current_exe()?;
+    let (offset, cache_len, manifest_len) = match read_trailer(&current_exe)? {
+        Some(trailer) => trailer,
+        None => return Ok(None),
+    };
+    let mut file = File::open(&current_exe)?;
+    file.seek(SeekFrom::Start(offset + cache_len))?;
+    let mut manifest_data = vec![0u8; manifest_len as usize];
+    file.read_exact(&mut manifest_data)?;
+    Ok(Some(serde_json::from_slice(&manifest_data)?))
+}
+
+/// Unpack the package cache entries of the current bundle (if it has any) into the package cache.
+fn unpack_bundle_cache(config: &VivaConfig) -> Result<()> {
+    let current_exe = std::env::current_exe()?;
+    let (offset, cache_len, _) = match read_trailer(&current_exe)? {
+        Some(trailer) => trailer,
+        None => return Ok(()),
+    };
+    if cache_len == 0 {
+        return Ok(());
+    }
+    let pkgs_dir = packages_dir(config)?;
+    std::fs::create_dir_all(&pkgs_dir)?;
+
+    let mut file = File::open(&current_exe)?;
+    file.seek(SeekFrom::Start(offset))?;
+    let mut archive = tar::Archive::new(file.take(cache_len));
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let path = entry.path()?.into_owned();
+        let top_level = path.components().next().map(|c| pkgs_dir.join(c));
+        // never overwrite existing cache entries
+        if let Some(top_level) = top_level {
+            if top_level.exists() && path.components().count() == 1 {
+                continue;
+            }
+        }
+        entry.unpack_in(&pkgs_dir)?;
+    }
+    Ok(())
+}
+
+/// Install (if necessary) and run the app contained in the current bundle, returns the exit status
+/// of the app.
+pub async fn run_bundle(
+    context: &mut VivaContext,
+    manifest: AppBundleManifest,
+    args: Vec<String>,
+) -> Result<ExitStatus> {
+    if !context.list_apps().await.contains_key(&manifest.app_id) {
+        unpack_bundle_cache(context.get_config())?;
+        context
+            .add_app(&manifest.app_id, manifest.spec.clone(), "default", AppEnvPlacementStrategy::AppId)
+            .await?;
+    }
+
+    let env_id = String::from(context.get_app(&manifest.app_id).await?.get_env_id());
+    if !context.has_env(&env_id).await {
+        context.add_env(&env_id, Some(manifest.spec.env_spec.clone()), None).await?;
+    } else {
+        context.merge_env_specs(&env_id, &manifest.spec.env_spec, true, false).await?;
+    }
+    let env = context.get_env_mut(&env_id).await?;
+
+    let mut cmd = manifest.spec.get_full_cmd();
+    cmd.extend(args);
+    env.run_command_in_env(&cmd, EnvCheckStrategy::Auto).await
+}
@@ -0,0 +1,459 @@
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::{debug, warn};
+
+use crate::config::{parse_size, VivaConfig};
+use crate::defaults::PACKAGE_LAST_USED_DIRNAME;
+use crate::locks::{CacheLock, PackageCacheLock};
+use crate::rattler::commands::create::{default_cache_dir, packages_dir};
+
+/// A single entry (extracted package or package archive) in the package cache.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheEntry {
+    pub path: PathBuf,
+    pub size: u64,
+    pub modified: SystemTime,
+    /// When the package was last installed into an environment (or `modified`, if that is later,
+    /// or viva never recorded a use).
+    pub last_used: SystemTime,
+}
+
+/// The result of pruning the package cache.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct PruneResult {
+    pub removed: Vec<CacheEntry>,
+    pub freed_bytes: u64,
+}
+
+/// The size of the cached data of a single channel.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct ChannelCacheInfo {
+    pub packages: usize,
+    pub packages_size: u64,
+    pub repodata_files: usize,
+    pub repodata_size: u64,
+}
+
+/// An overview of the package and repodata cache.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct CacheInfo {
+    pub location: PathBuf,
+    pub packages_dir: PathBuf,
+    pub repodata_dir: PathBuf,
+    pub packages_size: u64,
+    pub repodata_size: u64,
+    pub total_size: u64,
+    /// Sizes per channel url, entries whose channel can't be determined (e.g. package archives that
+    /// were never extracted) are listed under `<unknown>`.
+    pub channels: BTreeMap<String, ChannelCacheInfo>,
+}
+
+const UNKNOWN_CHANNEL: &str = "<unknown>";
+
+/// The directory containing cached (and extracted) package artifacts.
+pub fn packages_cache_dir(config: &VivaConfig) -> Result<PathBuf> {
+    packages_dir(config)
+}
+
+/// The directory containing cached repodata (one file per channel subdir, plus metadata).
+pub fn repodata_cache_dir() -> Result<PathBuf> {
+    Ok(default_cache_dir()?.join("repodata"))
+}
+
+/// Returns the total size of a file or directory, without following symlinks.
+pub(crate) fn path_size(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    match fs::read_dir(path) {
+        Ok(entries) => entries
+            .flatten()
+            .map(|entry| path_size(&entry.path()))
+            .sum(),
+        Err(_) => 0,
+    }
+}
+
+/// List all entries of the package cache.
+pub fn list_cache_entries(config: &VivaConfig) -> Result<Vec<CacheEntry>> {
+    list_entries(&packages_cache_dir(config)?)
+}
+
+/// List all files of the repodata cache.
+pub fn list_repodata_entries() -> Result<Vec<CacheEntry>> {
+    list_entries(&repodata_cache_dir()?)
+}
+
+fn list_entries(dir: &Path) -> Result<Vec<CacheEntry>> {
+    let mut entries = vec![];
+    if !dir.is_dir() {
+        return Ok(entries);
+    }
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        // lock files and other metadata, not artifacts
+        if path.extension().map(|e| e == "lock").unwrap_or(false)
+            || entry.file_name() == PACKAGE_LAST_USED_DIRNAME
+        {
+            continue;
+        }
+        let modified = entry.metadata()?.modified()?;
+        entries.push(CacheEntry {
+            size: path_size(&path),
+            last_used: last_used(&path, modified),
+            path,
+            modified,
+        });
+    }
+    Ok(entries)
+}
+
+/// The file that records when a package directory was last used (see [`record_package_use`]).
+fn last_used_marker(package_dir: &Path) -> Option<PathBuf> {
+    let name = package_dir.file_name()?;
+    Some(package_dir.parent()?.join(PACKAGE_LAST_USED_DIRNAME).join(name))
+}
+
+/// Record that a package directory of the package cache was used (to install the package into an
+/// environment), so it is pruned by when it was last used, not when it was extracted. Errors are
+/// only logged.
+pub(crate) fn record_package_use(package_dir: &Path) {
+    let marker = match last_used_marker(package_dir) {
+        Some(marker) => marker,
+        None => return,
+    };
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let result = marker
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .and_then(|_| fs::write(&marker, now.to_string()));
+    if let Err(e) = result {
+        debug!("Could not record use of cache entry '{}': {}", package_dir.display(), e);
+    }
+}
+
+/// When a cache entry was last used: the time recorded by [`record_package_use`], or when it was
+/// modified, whichever is later.
+fn last_used(path: &Path, modified: SystemTime) -> SystemTime {
+    last_used_marker(path)
+        .and_then(|marker| fs::read_to_string(marker).ok())
+        .and_then(|content| content.trim().parse::<u64>().ok())
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs))
+        .map_or(modified, |used| used.max(modified))
+}
+
+/// Whether a cache entry is a (package or repodata) download that is in progress, or was left
+/// behind by a process that was killed.
+fn is_download(path: &Path) -> bool {
+    path.extension().map(|e| e == "download").unwrap_or(false)
+}
+
+/// Remove cache entries according to the retention policy in the config.
+///
+/// Leftover downloads are removed first (downloads in progress are locked), then entries that
+/// were not used in `cache_max_age_days`, after that the least recently used entries are removed
+/// until the cache is smaller than `cache_max_size`. Environments
+/// that were created from removed entries are not affected, since their files are hard-linked.
+pub fn prune_cache(config: &VivaConfig) -> Result<PruneResult> {
+    let max_size = match &config.cache_max_size {
+        Some(max_size) => Some(parse_size(max_size)?),
+        None => None,
+    };
+    let max_age = config
+        .cache_max_age_days
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+
+    let (mut to_remove, mut entries): (Vec<CacheEntry>, Vec<CacheEntry>) = list_cache_entries(config)?
+        .into_iter()
+        .partition(|entry| is_download(&entry.path));
+    to_remove.extend(list_repodata_entries()?.into_iter().filter(|entry| is_download(&entry.path)));
+    entries.sort_by_key(|entry| entry.last_used);
+
+    let now = SystemTime::now();
+    let mut keep: Vec<CacheEntry> = vec![];
+    for entry in entries {
+        let age = now.duration_since(entry.last_used).unwrap_or_default();
+        match max_age {
+            Some(max_age) if age > max_age => to_remove.push(entry),
+            _ => keep.push(entry),
+        }
+    }
+
+    if let Some(max_size) = max_size {
+        let mut total: u64 = keep.iter().map(|entry| entry.size).sum();
+        // entries are sorted least recently used first
+        let mut remaining = keep.into_iter();
+        while total > max_size {
+            match remaining.next() {
+                Some(entry) => {
+                    total -= entry.size;
+                    to_remove.push(entry);
+                }
+                None => break,
+            }
+        }
+    }
+
+    Ok(remove_entries(to_remove))
+}
+
+/// The channel an extracted package was downloaded from (from its `info/repodata_record.json`).
+fn package_channel(entry: &CacheEntry) -> Option<String> {
+    let record = fs::read_to_string(entry.path.join("info").join("repodata_record.json")).ok()?;
+    let record: serde_json::Value = serde_json::from_str(&record).ok()?;
+    record
+        .get("channel")
+        .and_then(|channel| channel.as_str())
+        .map(|channel| channel.trim_end_matches('/').to_string())
+}
+
+/// The channel a cached repodata file belongs to, from the url in its `.info.json` file (the url
+/// of the `repodata.json` of a channel subdir).
+fn repodata_channel(entry: &CacheEntry) -> Option<String> {
+    let file_name = entry.path.file_name()?.to_string_lossy().to_string();
+    let stem = file_name.split('.').next()?;
+    let info_path = entry.path.with_file_name(format!("{}.info.json", stem));
+    let info = fs::read_to_string(info_path).ok()?;
+    let info: serde_json::Value = serde_json::from_str(&info).ok()?;
+    let url = info.get("url").and_then(|url| url.as_str())?;
+    let subdir_url = url.trim_end_matches('/').rsplit_once('/')?.0;
+    Some(subdir_url.rsplit_once('/')?.0.to_string())
+}
+
+/// Whether a file of the repodata cache is the metadata (`<name>.info.json`) of a repodata file.
+fn is_repodata_info(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().ends_with(".info.json"))
+        .unwrap_or(false)
+}
+
+/// Collect the location and size (in total and per channel) of the package and repodata cache.
+pub fn cache_info(config: &VivaConfig) -> Result<CacheInfo> {
+    let mut info = CacheInfo {
+        location: default_cache_dir()?,
+        packages_dir: packages_cache_dir(config)?,
+        repodata_dir: repodata_cache_dir()?,
+        packages_size: 0,
+        repodata_size: 0,
+        total_size: 0,
+        channels: BTreeMap::new(),
+    };
+
+    for entry in list_cache_entries(config)? {
+        let channel = package_channel(&entry).unwrap_or_else(|| String::from(UNKNOWN_CHANNEL));
+        let channel_info = info.channels.entry(channel).or_default();
+        channel_info.packages += 1;
+        channel_info.packages_size += entry.size;
+        info.packages_size += entry.size;
+    }
+    for entry in list_repodata_entries()? {
+        let channel = repodata_channel(&entry).unwrap_or_else(|| String::from(UNKNOWN_CHANNEL));
+        let channel_info = info.channels.entry(channel).or_default();
+        // the metadata next to a repodata file is part of it
+        if !is_repodata_info(&entry.path) {
+            channel_info.repodata_files += 1;
+        }
+        channel_info.repodata_size += entry.size;
+        info.repodata_size += entry.size;
+    }
+    info.total_size = info.packages_size + info.repodata_size;
+    Ok(info)
+}
+
+/// Remove entries from the package and repodata cache: those that were not used in the provided
+/// number of days (and leftover downloads), or all of them.
+///
+/// Unlike [`prune_cache`], this doesn't depend on the configured retention policy.
+pub fn clean_cache(config: &VivaConfig, older_than_days: Option<u64>) -> Result<PruneResult> {
+    let max_age = older_than_days.map(|days| Duration::from_secs(days * 24 * 60 * 60));
+    let now = SystemTime::now();
+
+    let mut entries = list_cache_entries(config)?;
+    entries.extend(list_repodata_entries()?);
+    let to_remove = entries
+        .into_iter()
+        .filter(|entry| match max_age {
+            Some(max_age) => {
+                is_download(&entry.path) || now.duration_since(entry.last_used).unwrap_or_default() > max_age
+            }
+            None => true,
+        })
+        .collect();
+    Ok(remove_entries(to_remove))
+}
+
+/// The lock that is held while the repodata of a cached file (or its metadata) is fetched.
+fn repodata_lock_path(path: &Path) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let stem = file_name.split('.').next().unwrap_or_default();
+    path.with_file_name(format!("{}.json.lock", stem))
+}
+
+/// Remove cache entries that no other process (or task) uses: entries are locked while they are
+/// removed, package directories (and their downloads) with their package lock, repodata files with
+/// the lock that is held while they are fetched.
+fn remove_entries(to_remove: Vec<CacheEntry>) -> PruneResult {
+    let repodata_dir = repodata_cache_dir().ok();
+    let mut result = PruneResult::default();
+    for entry in to_remove {
+        let locked = match entry.path.parent() == repodata_dir.as_deref() {
+            true => CacheLock::try_acquire(&repodata_lock_path(&entry.path))
+                .map(|lock| lock.map(|lock| (None, Some(lock)))),
+            false => match is_download(&entry.path) {
+                true => PackageCacheLock::try_acquire_exclusive(&entry.path.with_extension("")),
+                false => PackageCacheLock::try_acquire_exclusive(&entry.path),
+            }
+                .map(|lock| lock.map(|lock| (Some(lock), None))),
+        };
+        let _locks = match locked {
+            Ok(Some(locks)) => locks,
+            Ok(None) => {
+                debug!("Not removing cache entry that is in use: {}", entry.path.display());
+                continue;
+            }
+            Err(e) => {
+                warn!("Could not lock cache entry '{}': {}", entry.path.display(), e);
+                continue;
+            }
+        };
+        let removed = match entry.path.is_dir() {
+            true => fs::remove_dir_all(&entry.path),
+            false => fs::remove_file(&entry.path),
+        };
+        match removed {
+            Ok(_) => {
+                debug!("Removed cache entry: {}", entry.path.display());
+                if let Some(marker) = last_used_marker(&entry.path).filter(|marker| marker.exists()) {
+                    let _ = fs::remove_file(marker);
+                }
+                result.freed_bytes += entry.size;
+                result.removed.push(entry);
+            }
+            Err(e) => {
+                warn!("Could not remove cache entry '{}': {}", entry.path.display(), e);
+            }
+        }
+    }
+    result
+}
+
+/// Prune the package cache if a retention policy is configured, errors are only logged.
+pub(crate) async fn prune_cache_opportunistically(config: &VivaConfig) {
+    if config.cache_max_size.is_none() && config.cache_max_age_days.is_none() {
+        return;
+    }
+    // other tools might not expect packages to disappear from a shared cache
+    if config.share_pkgs_dir {
+        return;
+    }
+    let config = config.clone();
+    let result = tokio::task::spawn_blocking(move || prune_cache(&config))
+        .await
+        .map_err(|e| anyhow!(e))
+        .and_then(|r| r);
+    match result {
+        Ok(result) => debug!("Pruned package cache, freed {} bytes", result.freed_bytes),
+        Err(e) => warn!("Could not prune package cache: {}", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_repodata_lock_path() {
+        let dir = Path::new("/cache/repodata");
+        let lock_path = dir.join("https___conda_anaconda_org_conda_linux_64_.json.lock");
+        assert_eq!(
+            repodata_lock_path(&dir.join("https___conda_anaconda_org_conda_linux_64_.json")),
+            lock_path
+        );
+        assert_eq!(
+            repodata_lock_path(&dir.join("https___conda_anaconda_org_conda_linux_64_.info.json")),
+            lock_path
+        );
+        assert!(is_repodata_info(&dir.join("https___conda_anaconda_org_conda_linux_64_.info.json")));
+        assert!(!is_repodata_info(&dir.join("https___conda_anaconda_org_conda_linux_64_.json")));
+    }
+
+    #[test]
+    fn test_remove_entries_skips_locked() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_dir = dir.path().join("pkg-1.0-0");
+        fs::create_dir_all(&package_dir).unwrap();
+        let entry = |path: &Path| CacheEntry {
+            path: path.to_path_buf(),
+            size: 0,
+            modified: SystemTime::now(),
+            last_used: SystemTime::now(),
+        };
+
+        let lock = PackageCacheLock::try_acquire_exclusive(&package_dir).unwrap().unwrap();
+        let result = remove_entries(vec![entry(&package_dir)]);
+        assert!(result.removed.is_empty());
+        assert!(package_dir.is_dir());
+
+        drop(lock);
+        let result = remove_entries(vec![entry(&package_dir)]);
+        assert_eq!(result.removed.len(), 1);
+        assert!(!package_dir.exists());
+    }
+
+    #[test]
+    fn test_remove_entries_skips_downloads_in_progress() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_dir = dir.path().join("pkg-1.0-0");
+        let download_dir = dir.path().join("pkg-1.0-0.download");
+        fs::create_dir_all(&download_dir).unwrap();
+        assert!(is_download(&download_dir));
+        assert!(!is_download(&package_dir));
+        let entry = CacheEntry {
+            path: download_dir.clone(),
+            size: 0,
+            modified: SystemTime::now(),
+            last_used: SystemTime::now(),
+        };
+
+        // the package lock is held while the package is downloaded
+        let lock = PackageCacheLock::try_acquire_exclusive(&package_dir).unwrap().unwrap();
+        assert!(remove_entries(vec![entry.clone()]).removed.is_empty());
+        assert!(download_dir.is_dir());
+
+        drop(lock);
+        assert_eq!(remove_entries(vec![entry]).removed.len(), 1);
+        assert!(!download_dir.exists());
+    }
+
+    #[test]
+    fn test_last_used() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_dir = dir.path().join("pkg-1.0-0");
+        fs::create_dir_all(&package_dir).unwrap();
+        let extracted = SystemTime::now() - Duration::from_secs(60 * 24 * 60 * 60);
+        assert_eq!(last_used(&package_dir, extracted), extracted);
+
+        record_package_use(&package_dir);
+        let used = last_used(&package_dir, extracted);
+        assert!(used > extracted);
+        assert!(SystemTime::now().duration_since(used).unwrap() < Duration::from_secs(60));
+
+        let entries = list_entries(dir.path()).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].path, package_dir);
+        remove_entries(entries);
+        assert!(!dir.path().join(PACKAGE_LAST_USED_DIRNAME).join("pkg-1.0-0").exists());
+    }
+}
@@ -1,19 +1,29 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use ::viva::*;
-use anyhow::{bail, Result};
-use clap::builder::OsStr;
-use clap::{arg, Arg, ArgAction, Command};
+use anyhow::{bail, Context, Result};
+use clap::builder::{OsStr, PossibleValue, PossibleValuesParser};
+use prettytable::{format, row, Table};
+use clap::parser::ValueSource;
+use clap::{arg, Arg, ArgAction, ArgMatches, Command};
 use config::{Config, Environment, FileFormat};
 
-use serde::{Deserialize, Serialize};
 
 
 use std::fs;
 use std::path::{PathBuf};
-use tracing::debug;
+use std::process::ExitStatus;
+use tracing::{debug, warn};
 // use tracing_subscriber::{util::SubscriberInitExt};
-use viva::models::app::{AppEnvPlacementStrategy, DefaultAppCollection, VivaAppSpec};
-use viva::models::environment::DefaultEnvCollection;
+use viva::models::app::{read_app_manifest, AppEnvPlacementStrategy, AppUninstallReport, DefaultAppCollection, VivaAppSpec};
+use viva::models::environment::{DefaultEnvCollection, EnvCheckStrategy, EnvironmentCollection, PlannedAction, SyncPlan, VivaEnv, validate_env_var_name};
+use viva::models::profile::ProvisioningProfile;
+use viva::models::project::ProjectEnvCollection;
+use viva::models::remote::{parse_interval, RemoteCollectionConfig, RemoteEnvCollection, DEFAULT_REFRESH_INTERVAL};
+use viva::models::requirements::import_requirements;
+use viva::models::system::SystemEnvCollection;
+use viva::models::matrix::{expand_matrix, lock_matrix_entry, MatrixAxis};
+use viva::models::workspace::WorkspaceEnvCollection;
+use viva::listing::{ListFormat, ListOptions};
 
 // fn handle_result<T>(result: Result<T, anyhow::Error>) -> T {
 //     if let Err(e) = result {
@@ -27,13 +37,41 @@ use viva::models::environment::DefaultEnvCollection;
 //     }
 // }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct VivaConfig {
-    pub default_channels: Vec<String>,
-}
-
 fn create_command(viva_config: &VivaConfig) -> Command {
-    let verbose_arg = arg!(-v --verbose "Log verbose");
+    let verbose_arg = arg!(-v --verbose "Log verbose (same as '--log-level info,viva=debug')").global(true);
+    let log_level_arg = Arg::new("log-level")
+        .long("log-level")
+        .global(true)
+        .help("What to log to the console ('RUST_LOG' syntax, e.g. 'warn,viva=debug'), overrides the 'log_level' config value.");
+    let log_file_level_arg = Arg::new("log-file-level")
+        .long("log-file-level")
+        .global(true)
+        .help("Also log to a (rotating) file in the log dir ('RUST_LOG' syntax, e.g. 'info,rattler=trace'), overrides the 'log_file_level' config value.");
+    let yes_arg = arg!(-y --yes "Don't ask for confirmation (e.g. for large downloads)").global(true);
+    let limit_rate_arg = Arg::new("limit-rate")
+        .long("limit-rate")
+        .global(true)
+        .help("Limit the aggregate package download bandwidth, per second (e.g. '2MB').");
+    let offline_arg = arg!(--offline "Don't access the network when creating environments, only use cached repodata and packages").global(true);
+    let strict_arg = arg!(--strict "Fail on any collection problem (invalid specs, shadowed ids, unknown fields)").global(true);
+    let clobber_arg = arg!(--clobber "Let packages overwrite files that belong to other packages of an environment (the default)")
+        .global(true)
+        .conflicts_with_all(["skip-conflicts", "fail-on-conflicts"]);
+    let skip_conflicts_arg = arg!(--"skip-conflicts" "Don't link files of packages that belong to other packages of an environment")
+        .global(true)
+        .conflicts_with("fail-on-conflicts");
+    let fail_on_conflicts_arg = arg!(--"fail-on-conflicts" "Don't link packages that contain files that belong to other packages of an environment").global(true);
+    let progress_arg = Arg::new("progress")
+        .long("progress")
+        .global(true)
+        .value_parser(["bars", "json"])
+        .default_value("bars")
+        .help("How to report progress: progress bars, or newline-delimited json events (on stderr, or the '--progress-file').");
+    let progress_file_arg = Arg::new("progress-file")
+        .long("progress-file")
+        .global(true)
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("Write json progress events to this file (e.g. a named pipe) instead of stderr.");
     let default_channels = viva_config
         .default_channels
         .iter()
@@ -68,6 +106,16 @@ fn create_command(viva_config: &VivaConfig) -> Command {
         .action(ArgAction::Append)
         .help("The required package specs.");
 
+    let pip_specs_arg = Arg::new("pip_specs")
+        .long("pip-spec")
+        .action(ArgAction::Append)
+        .help("Python packages to install with pip (from PyPI), after the conda packages.");
+
+    let env_vars_arg = Arg::new("env_vars")
+        .long("env-var")
+        .action(ArgAction::Append)
+        .help("An environment variable ('KEY=VALUE') for processes that run in the environment.");
+
     let app_name = Arg::new("app")
         .help("The name to register the application.")
         .required(true);
@@ -81,6 +129,7 @@ fn create_command(viva_config: &VivaConfig) -> Command {
     let app_args = Arg::new("cmd_args")
         .long("arg")
         .action(ArgAction::Append)
+        .allow_hyphen_values(true)
         .help("The (optional) arguments for the command to run.")
         .default_values(Vec::<OsStr>::new());
 
@@ -98,6 +147,34 @@ fn create_command(viva_config: &VivaConfig) -> Command {
         .help("Install all required packages locally, now.")
         ;
 
+    let max_size_arg = Arg::new("max_size")
+        .long("max-size")
+        .help("A size budget for the environment (e.g. '500MB'), checked before installing.");
+
+    let platform_arg = Arg::new("platform")
+        .long("platform")
+        .help("The platform to solve the environment for (e.g. 'linux-64', 'osx-arm64', 'win-64', 'noarch'), instead of the current one.");
+
+    let base_prefix_arg = Arg::new("base_prefix")
+        .long("base-prefix")
+        .value_parser(clap::value_parser!(PathBuf))
+        .help("A (read-only) conda prefix this environment extends, only missing packages will be installed.");
+
+    let plan_arg = Arg::new("dry-run")
+        .long("dry-run")
+        .action(ArgAction::SetTrue)
+        .help("Only show which packages would be downloaded, installed and removed (nothing is registered, installed or run).");
+
+    let check_arg = Arg::new("check")
+        .long("check")
+        .value_parser(PossibleValuesParser::new([
+            PossibleValue::new("auto"),
+            PossibleValue::new("force").alias("always"),
+            PossibleValue::new("skip"),
+        ]))
+        .default_value("auto")
+        .help("How to check environments before they are used: sync them if their spec is not satisfied ('auto'), always solve their spec again ('force'), or don't touch them at all ('skip', fails if they don't exist).");
+
     let register_env_subcommand = Command::new("register-env")
         .about(
             "Register an environment, and optionally create it locally.",
@@ -105,16 +182,46 @@ fn create_command(viva_config: &VivaConfig) -> Command {
         .arg(environment_arg.clone())
         .arg(channels_arg.clone())
         .arg(pks_specs_arg.clone())
+        .arg(pip_specs_arg.clone())
+        .arg(env_vars_arg)
+        .arg(base_prefix_arg)
+        .arg(max_size_arg)
+        .arg(platform_arg)
+        .arg(
+            Arg::new("from_file")
+                .long("from-file")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Read the environment spec from a file (viva's own format, or a conda 'environment.yml'), merged with the other spec arguments."),
+        )
         .arg(replace_arg)
-        .arg(env_sync);
+        .arg(env_sync)
+        .arg(plan_arg.clone());
 
     let delete_env_subcommand = Command::new("delete-env")
         .about("Delete an environment.")
         .arg(environment_arg.clone());
 
+    let delete_app_subcommand = Command::new("delete-app")
+        .about("Delete an app (its spec and shims).")
+        .arg(Arg::new("app").required(true).help("The id of the app."))
+        .arg(
+            Arg::new("delete-env")
+                .action(ArgAction::SetTrue)
+                .long("delete-env")
+                .help("Also delete the environment of the app, if it was created for it (not a shared one like 'default'), and no other app uses it."),
+        );
+
     let sync_env_subcommand = Command::new("sync")
         .about("Make sure all environment packages from a specs' environment are installed locally.")
-        .arg(environments_arg.clone());
+        .arg(environments_arg.clone())
+        .arg(
+            Arg::new("gha")
+                .long("gha")
+                .action(ArgAction::SetTrue)
+                .help("Output GitHub Actions workflow commands (log groups, error annotations), and set the 'env-path' and 'cache-key' step outputs."),
+        )
+        .arg(plan_arg.clone().conflicts_with("gha"))
+        .arg(check_arg.clone());
 
     let register_app_subcommand = Command::new("register-app")
         .about("Register an app, and optionally install all the required packages locally.")
@@ -122,41 +229,929 @@ fn create_command(viva_config: &VivaConfig) -> Command {
         .arg(channels_arg.clone())
         .arg(pks_specs_arg.clone())
         .arg(executable_arg)
-        .arg(app_args)
-        .arg(app_sync);
+        .arg(app_args.clone())
+        .arg(app_sync)
+        .arg(
+            Arg::new("expose")
+                .action(ArgAction::SetTrue)
+                .long("expose")
+                .help("Install the packages of the app, and create a shim for it in the shim directory (to run it by name, like any other executable)."),
+        );
+
 
+    // everything after '--' is passed to the child verbatim (including further '--', flags, and
+    // empty arguments)
+    let cmd_arg = Arg::new("cmd")
+        .required(true)
+        .num_args(1..)
+        .last(true)
+        .allow_hyphen_values(true)
+        .help("The command to run, and its arguments (after '--').");
 
-    let cmd_arg = Arg::new("cmd").required(true).help("The command to run.");
-    let cmd_args = Arg::new("cmd_args").action(ArgAction::Append).help("The (optional) arguments for the command to run.").default_values(Vec::<OsStr>::new());
+    let run_environment_arg = Arg::new("env-id")
+        .short('e')
+        .long("env")
+        .help("The id of the environment to use (defaults to the env of a project spec, the 'default_env' config value, or 'default').");
+
+    let also_env_arg = Arg::new("also-env")
+        .long("also-env")
+        .action(ArgAction::Append)
+        .help("Additional (already registered) environments whose executables should be available, in order of precedence after '--env'.");
 
     let run_subcommand = Command::new("run")
         .about("Start an executable contained in an environment, create the environment if it doesn't exist.")
+        .arg(run_environment_arg)
+        .arg(also_env_arg)
+        .arg(
+            Arg::new("shell")
+                .long("shell")
+                .action(ArgAction::SetTrue)
+                .help("Run the command through the platform shell ('sh -c', or 'cmd /C' on Windows), to be able to use pipes, redirects, etc."),
+        )
+        .arg(
+            Arg::new("persist")
+                .long("persist")
+                .action(ArgAction::SetTrue)
+                .help("Add the channels and specs to the environment permanently, instead of only using them for this run."),
+        )
+        .arg(
+            Arg::new("ephemeral")
+                .long("ephemeral")
+                .action(ArgAction::SetTrue)
+                .conflicts_with_all(["env-id", "persist"])
+                .help("Run the command in a temporary environment that only contains the provided specs, and is deleted afterwards."),
+        )
+        .arg(
+            Arg::new("keep-ephemeral")
+                .long("keep-ephemeral")
+                .action(ArgAction::SetTrue)
+                .requires("ephemeral")
+                .help("Keep the temporary environment (keyed by its spec), so later runs with the same specs can use it."),
+        )
+        .arg(channels_arg.clone())
+        .arg(pks_specs_arg.clone())
+        .arg(pip_specs_arg)
+        .arg(plan_arg)
+        .arg(check_arg.clone())
+        .arg(cmd_arg.clone());
+
+    let remote_run_subcommand = Command::new("run")
+        .about("Run a command in an environment on a remote host (via ssh), creating viva and the environment there if necessary.")
+        .arg(
+            Arg::new("host")
+                .long("host")
+                .required(true)
+                .help("The remote host, in the form accepted by ssh (e.g. 'user@host')."),
+        )
         .arg(environment_arg.clone())
         .arg(channels_arg.clone())
         .arg(pks_specs_arg.clone())
-        .arg(cmd_arg)
-        .arg(cmd_args);
+        .arg(cmd_arg);
+
+    let remote_subcommand = Command::new("remote")
+        .about("Manage and use environments on remote hosts.")
+        .subcommand_required(true)
+        .subcommand(remote_run_subcommand);
+
+    let app_from_env_subcommand = Command::new("from-env")
+        .about("Register an app that uses an executable which is already installed in an existing environment.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment that contains the executable."))
+        .arg(Arg::new("executable").required(true).help("The name of the executable."))
+        .arg(
+            Arg::new("app-id")
+                .short('a')
+                .long("app-id")
+                .required(false)
+                .help("The name to register the application (defaults to the executable name)."),
+        )
+        .arg(app_args.clone());
+
+    let app_register_subcommand = Command::new("register")
+        .about("Register multiple apps at once, from a manifest file.")
+        .arg(
+            Arg::new("manifest")
+                .short('m')
+                .long("manifest")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The path to the (json or yaml) app manifest file."),
+        );
+
+    let app_bundle_subcommand = Command::new("bundle")
+        .about("Create a self-extracting launcher for an app, that installs and runs it when started.")
+        .arg(Arg::new("app").required(true).help("The id of the app to bundle."))
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The path of the launcher to create."),
+        )
+        .arg(
+            Arg::new("include-cache")
+                .action(ArgAction::SetTrue)
+                .long("include-cache")
+                .help("Include all required packages, so the app can be installed without network access (the app environment needs to be synced)."),
+        );
+
+    let app_outdated_subcommand = Command::new("outdated")
+        .about("Check registered apps for newer package versions (using fresh repodata), and optionally update them.")
+        .arg(
+            Arg::new("apps")
+                .action(ArgAction::Append)
+                .required(false)
+                .help("The ids of the apps to check (defaults to all)."),
+        )
+        .arg(
+            Arg::new("apply")
+                .action(ArgAction::SetTrue)
+                .long("apply")
+                .help("Update the environments of all outdated apps."),
+        );
+
+    let app_rename_subcommand = Command::new("rename")
+        .about("Rename an app (and its shims, and its environment, if it is named after the app and not shared).")
+        .arg(Arg::new("app").required(true).help("The id of the app."))
+        .arg(Arg::new("new-id").required(true).help("The new id of the app."));
+
+    let app_fix_subcommand = Command::new("fix")
+        .about("Re-point an app to another environment (e.g. if its environment was deleted).")
+        .arg(Arg::new("app").required(true).help("The id of the app."))
+        .arg(
+            Arg::new("env-id")
+                .long("env")
+                .required(true)
+                .help("The id of the environment the app should use."),
+        );
+
+    let app_shim_subcommand = Command::new("shim")
+        .about("Create (or update) shims for apps, in the configured shim directory.")
+        .arg(
+            Arg::new("apps")
+                .action(ArgAction::Append)
+                .required(true)
+                .help("The ids of the apps."),
+        )
+        .arg(
+            Arg::new("force")
+                .action(ArgAction::SetTrue)
+                .long("force")
+                .help("Overwrite existing files not created by viva, and allow shims that shadow protected executables."),
+        );
+
+    let app_install_subcommand = Command::new("install")
+        .about("Install apps: sync their environments, and create shims for them in the shim directory.")
+        .arg(
+            Arg::new("apps")
+                .action(ArgAction::Append)
+                .required(true)
+                .help("The ids of the apps."),
+        )
+        .arg(
+            Arg::new("force")
+                .action(ArgAction::SetTrue)
+                .long("force")
+                .help("Overwrite existing files not created by viva, and allow shims that shadow protected executables."),
+        );
+
+    let app_unshim_subcommand = Command::new("unshim")
+        .about("Remove the shims viva created for an app.")
+        .arg(Arg::new("app").required(true).help("The id of the app."));
+
+    let app_doctor_subcommand = Command::new("doctor")
+        .about("Check whether an app can be launched (environment solvable, executable installed, usable env vars), without creating its environment.")
+        .arg(Arg::new("app").required(true).help("The id of the app."));
+
+    let app_uninstall_subcommand = Command::new("uninstall")
+        .about("Remove an app, including its spec and shims.")
+        .arg(Arg::new("app").required(true).help("The id of the app."))
+        .arg(
+            Arg::new("purge-env")
+                .action(ArgAction::SetTrue)
+                .long("purge-env")
+                .help("Also remove the environment of the app, if it was created for it (not a shared one like 'default'), and no other app uses it."),
+        );
+
+    let app_run_subcommand = Command::new("run")
+        .about("Run a registered app (sync its environment first, if necessary).")
+        .arg(Arg::new("app").required(true).help("The id of the app."))
+        .arg(check_arg)
+        .arg(
+            Arg::new("args")
+                .num_args(1..)
+                .last(true)
+                .allow_hyphen_values(true)
+                .help("Additional arguments for the app (after '--'), appended to the ones of the app spec."),
+        );
+
+    let app_shims_subcommand = Command::new("shims")
+        .about("List all shims viva created.");
+
+    let app_subcommand = Command::new("app")
+        .about("Manage registered apps.")
+        .subcommand_required(true)
+        .subcommand(app_from_env_subcommand)
+        .subcommand(app_register_subcommand)
+        .subcommand(app_bundle_subcommand)
+        .subcommand(app_outdated_subcommand)
+        .subcommand(app_fix_subcommand)
+        .subcommand(app_rename_subcommand)
+        .subcommand(app_install_subcommand)
+        .subcommand(app_shim_subcommand)
+        .subcommand(app_unshim_subcommand)
+        .subcommand(app_uninstall_subcommand)
+        .subcommand(app_shims_subcommand)
+        .subcommand(app_run_subcommand)
+        .subcommand(app_doctor_subcommand);
+
+    let provision_subcommand = Command::new("provision")
+        .about("Apply a provisioning profile, registering and syncing all the environments and apps it lists.")
+        .arg(
+            Arg::new("profile")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The path to the (json or yaml) provisioning profile."),
+        )
+        .arg(
+            Arg::new("diff")
+                .action(ArgAction::SetTrue)
+                .long("diff")
+                .help("Only print the actions that would be taken, don't apply anything."),
+        )
+        .arg(
+            Arg::new("prune")
+                .action(ArgAction::SetTrue)
+                .long("prune")
+                .help("Remove environments and apps (and their shims) of the 'default' collections that are not part of the profile."),
+        );
+
+    let status_subcommand = Command::new("status")
+        .about("Print a (json) report of how the current state deviates from a profile or collection.")
+        .arg(
+            Arg::new("against")
+                .long("against")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The path to a provisioning profile, or a collection config directory."),
+        );
+
+    let relocate_subcommand = Command::new("relocate")
+        .about("Move the prefix of an environment to a new location, without re-installing its packages.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment to move."))
+        .arg(
+            Arg::new("new-path")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The new location of the environment prefix."),
+        );
+
+    let snapshot_create_subcommand = Command::new("create")
+        .about("Create a (hard-link) snapshot of the prefix of an environment, e.g. before a risky update.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("name")
+                .long("name")
+                .help("The name of the snapshot (defaults to the current timestamp)."),
+        );
+
+    let snapshot_restore_subcommand = Command::new("restore")
+        .about("Restore the prefix of an environment from a snapshot.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(Arg::new("name").help("The name of the snapshot (defaults to the latest one)."));
+
+    let snapshot_list_subcommand = Command::new("list")
+        .about("List the snapshots of an environment.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."));
+
+    let snapshot_delete_subcommand = Command::new("delete")
+        .about("Delete a snapshot of an environment.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(Arg::new("name").required(true).help("The name of the snapshot."));
+
+    let snapshot_subcommand = Command::new("snapshot")
+        .about("Manage snapshots of environment prefixes.")
+        .subcommand_required(true)
+        .subcommand(snapshot_create_subcommand)
+        .subcommand(snapshot_restore_subcommand)
+        .subcommand(snapshot_list_subcommand)
+        .subcommand(snapshot_delete_subcommand);
+
+    let cache_prune_subcommand = Command::new("prune")
+        .about("Remove leftover downloads, and package cache entries according to the configured retention policy ('cache_max_size', 'cache_max_age_days').");
+
+    let cache_key_subcommand = Command::new("key")
+        .about("Print a key for caching the prefix of an environment (e.g. in CI), derived from its spec, lockfile, and the platform.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."));
+
+    let cache_info_subcommand = Command::new("info")
+        .about("Show the location and size of the package and repodata cache, per channel.")
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .value_parser(["table", "json", "yaml"])
+                .default_value("table")
+                .help("The output format."),
+        );
+
+    let cache_clean_subcommand = Command::new("clean")
+        .about("Remove entries from the package and repodata cache.")
+        .arg(
+            Arg::new("older-than")
+                .long("older-than")
+                .value_parser(clap::value_parser!(u64))
+                .value_name("DAYS")
+                .help("Only remove entries that were not used in this number of days."),
+        )
+        .arg(
+            Arg::new("all")
+                .long("all")
+                .action(ArgAction::SetTrue)
+                .help("Remove all entries."),
+        )
+        .group(clap::ArgGroup::new("selection").args(["older-than", "all"]).required(true));
+
+    let cache_subcommand = Command::new("cache")
+        .about("Manage the package cache.")
+        .subcommand_required(true)
+        .subcommand(cache_info_subcommand)
+        .subcommand(cache_clean_subcommand)
+        .subcommand(cache_prune_subcommand)
+        .subcommand(cache_key_subcommand);
+
+    let explain_sync_subcommand = Command::new("sync")
+        .about("Explain why an environment is (or is not) considered synced.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."));
+
+    let explain_subcommand = Command::new("explain")
+        .about("Explain viva's decisions.")
+        .subcommand_required(true)
+        .subcommand(explain_sync_subcommand);
+
+    let env_info_subcommand = Command::new("info")
+        .about("Show details about an environment, including where each channel and package spec came from.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."));
+
+    let env_inspect_subcommand = Command::new("inspect")
+        .about("List the packages that are installed in an environment.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .value_parser(["table", "json", "yaml"])
+                .default_value("table")
+                .help("The output format."),
+        );
+
+    let env_history_subcommand = Command::new("history")
+        .about("List the revisions of an environment (recorded by every sync), with the package changes of each.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .value_parser(["table", "json"])
+                .default_value("table")
+                .help("The output format."),
+        );
+
+    let env_rollback_subcommand = Command::new("rollback")
+        .about("Restore the spec and the exact packages of a previous revision of an environment.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("revision")
+                .long("revision")
+                .short('r')
+                .required(true)
+                .value_parser(clap::value_parser!(u32))
+                .help("The revision to restore (see 'env history')."),
+        );
+
+    let env_undo_subcommand = Command::new("undo")
+        .about("Restore the previous version of an environment spec (the environment itself is changed on the next sync).")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."));
+
+    let env_export_subcommand = Command::new("export")
+        .about("Export an environment as a conda-compatible 'environment.yml' file.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The file to write to (defaults to printing to stdout)."),
+        )
+        .arg(
+            Arg::new("spec-only")
+                .action(ArgAction::SetTrue)
+                .long("spec-only")
+                .help("Export the package specs of the environment, instead of the exact installed packages."),
+        );
+
+    let env_rename_subcommand = Command::new("rename")
+        .about("Rename an environment, keeping its installed packages (apps that use it are updated).")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(Arg::new("new-id").required(true).help("The new id of the environment."));
+
+    let env_clone_subcommand = Command::new("clone")
+        .about("Register a copy of an environment under a new id, e.g. to try out changes without touching a working environment.")
+        .arg(Arg::new("source").required(true).help("The id of the environment to copy."))
+        .arg(Arg::new("target").required(true).help("The id of the new environment."))
+        .arg(
+            Arg::new("collection")
+                .long("collection")
+                .help("The collection to register the new environment in (defaults to the one of the copied environment)."),
+        )
+        .arg(
+            Arg::new("link")
+                .long("link")
+                .action(ArgAction::SetTrue)
+                .help("Install the exact packages of the copied environment right away, linked from the package cache (instead of with the next sync, which might resolve newer versions)."),
+        );
+
+    let env_freeze_subcommand = Command::new("freeze")
+        .about("Print the exact packages of an environment as an explicit lock, or generate a Docker build context that reproduces it.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("docker")
+                .long("docker")
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Write a Dockerfile (and the files it needs) to this directory, instead of printing the lock."),
+        )
+        .arg(
+            Arg::new("base")
+                .long("base")
+                .value_parser(["micromamba", "viva"])
+                .default_value("micromamba")
+                .requires("docker")
+                .help("Install the packages in the image with micromamba (from the explicit lock), or with (a copy of this) viva."),
+        )
+        .arg(
+            Arg::new("image")
+                .long("image")
+                .requires("docker")
+                .help("The base image (defaults to a micromamba image, or debian for the viva base)."),
+        )
+        .arg(
+            Arg::new("platform")
+                .long("platform")
+                .help("The platform of the packages (defaults to the one of the environment, or 'linux-64' for Docker images)."),
+        );
+
+    let env_update_subcommand = Command::new("update")
+        .about("Update an environment to the newest packages that satisfy its spec (using fresh repodata), only changed packages are re-installed.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("dry-run")
+                .action(ArgAction::SetTrue)
+                .long("dry-run")
+                .help("Only list the packages that would be updated."),
+        );
+
+    let env_remove_pkg_subcommand = Command::new("remove-pkg")
+        .about("Remove package specs from an environment, the packages are uninstalled with the next sync.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("pkg_specs")
+                .required(true)
+                .num_args(1..)
+                .help("The package specs to remove (as they appear in the environment spec)."),
+        )
+        .arg(
+            Arg::new("sync")
+                .action(ArgAction::SetTrue)
+                .short('S')
+                .long("sync")
+                .help("Sync the environment now, to uninstall the packages right away."),
+        );
+
+    let env_activate_subcommand = Command::new("activate")
+        .about("Print shell code that activates an environment in the current shell (e.g. 'eval \"$(viva env activate default)\"').")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("shell")
+                .long("shell")
+                .value_parser(["bash", "zsh", "fish", "powershell"])
+                .help("The shell to generate code for (defaults to the current shell)."),
+        );
+
+    let env_subcommand = Command::new("env")
+        .about("Manage registered environments.")
+        .subcommand_required(true)
+        .subcommand(env_info_subcommand)
+        .subcommand(env_inspect_subcommand)
+        .subcommand(env_history_subcommand)
+        .subcommand(env_rollback_subcommand)
+        .subcommand(env_undo_subcommand)
+        .subcommand(env_export_subcommand)
+        .subcommand(env_freeze_subcommand)
+        .subcommand(env_clone_subcommand)
+        .subcommand(env_rename_subcommand)
+        .subcommand(env_update_subcommand)
+        .subcommand(env_remove_pkg_subcommand)
+        .subcommand(env_activate_subcommand);
+
+    let matrix_subcommand = Command::new("matrix")
+        .about("Expand a template spec into one concrete spec per combination of package versions (e.g. for CI test matrices).")
+        .arg(
+            Arg::new("spec")
+                .required(true)
+                .help("The template: the id of an environment, or the path of a spec file."),
+        )
+        .arg(
+            Arg::new("vary")
+                .long("vary")
+                .required(true)
+                .action(ArgAction::Append)
+                .value_name("PACKAGE=VERSION,...")
+                .help("A package and the versions to use for it (can be repeated, all combinations are created)."),
+        )
+        .arg(
+            Arg::new("output")
+                .long("output")
+                .value_parser(["table", "json", "yaml"])
+                .default_value("table")
+                .help("The output format."),
+        )
+        .arg(
+            Arg::new("lock")
+                .long("lock")
+                .value_name("DIR")
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("Solve each spec (for the platform of the spec, or the ones of '--platform'), and write its lockfile into this directory."),
+        )
+        .arg(
+            Arg::new("platform")
+                .long("platform")
+                .action(ArgAction::Append)
+                .requires("lock")
+                .help("Lock the specs for this platform instead of the current one (can be repeated, e.g. to lock for all deployment targets in CI)."),
+        );
+
+    let workspace_list_subcommand = Command::new("list")
+        .about("List all workspaces, and their environments.");
+
+    let workspace_sync_subcommand = Command::new("sync")
+        .about("Sync all environments of a workspace.")
+        .arg(Arg::new("workspace").required(true).help("The id of the workspace."));
+
+    let workspace_subcommand = Command::new("workspace")
+        .about("Manage workspaces (groups of environments with shared channels and pins).")
+        .subcommand_required(true)
+        .subcommand(workspace_list_subcommand)
+        .subcommand(workspace_sync_subcommand);
+
+    let collection_add_subcommand = Command::new("add")
+        .about("Add a remote collection (an index file served over http(s), or a git repository), its environments are registered as '<collection>/<env>'.")
+        .arg(Arg::new("collection").required(true).help("The id of the collection."))
+        .arg(
+            Arg::new("url")
+                .long("url")
+                .required(true)
+                .help("The url of the index file (e.g. 'https://example.com/envs/index.yaml'), or of the git repository (e.g. 'git+https://example.com/team/envs', or one that ends with '.git')."),
+        )
+        .arg(
+            Arg::new("refresh-interval")
+                .long("refresh-interval")
+                .help("How old the cached specs can get before they are refreshed (e.g. '30m', '12h', '7d', default: '1d')."),
+        );
+
+    let collection_list_subcommand = Command::new("list")
+        .about("List the remote collections, and when they were refreshed.");
+
+    let collection_refresh_subcommand = Command::new("refresh")
+        .about("Fetch the specs of remote collections, even if the cached ones are up to date.")
+        .arg(
+            Arg::new("collection")
+                .num_args(0..)
+                .help("The ids of the collections (default: all)."),
+        );
+
+    let collection_remove_subcommand = Command::new("remove")
+        .about("Remove a remote collection, and its cached specs (the prefixes of its environments are kept).")
+        .arg(Arg::new("collection").required(true).help("The id of the collection."));
+
+    let collection_subcommand = Command::new("collection")
+        .about("Manage remote environment collections.")
+        .subcommand_required(true)
+        .subcommand(collection_add_subcommand)
+        .subcommand(collection_list_subcommand)
+        .subcommand(collection_refresh_subcommand)
+        .subcommand(collection_remove_subcommand);
+
+    let auth_login_subcommand = Command::new("login")
+        .about("Store credentials for a private channel (a channel name or url prefix, or a host).")
+        .arg(Arg::new("channel").required(true).help("The channel (e.g. 'my-org', 'https://repo.example.com/channels/private', or 'repo.example.com')."))
+        .arg(
+            Arg::new("token")
+                .long("token")
+                .help("A conda token (added to urls as '/t/<token>/', e.g. for anaconda.org)."),
+        )
+        .arg(
+            Arg::new("bearer")
+                .long("bearer")
+                .help("A token that is sent as 'Authorization: Bearer' header."),
+        )
+        .arg(
+            Arg::new("username")
+                .long("username")
+                .help("A username for basic auth (the password is asked for, if not provided)."),
+        )
+        .arg(
+            Arg::new("password")
+                .long("password")
+                .requires("username")
+                .help("The password for basic auth."),
+        )
+        .group(clap::ArgGroup::new("credentials").args(["token", "bearer", "username"]).required(true));
+
+    let auth_logout_subcommand = Command::new("logout")
+        .about("Remove the stored credentials of a channel.")
+        .arg(Arg::new("channel").required(true).help("The channel, as used with 'auth login'."));
+
+    let auth_list_subcommand = Command::new("list")
+        .about("List the stored channel credentials (without secrets).");
+
+    let auth_subcommand = Command::new("auth")
+        .about("Manage credentials for private channels.")
+        .subcommand_required(true)
+        .subcommand(auth_login_subcommand)
+        .subcommand(auth_logout_subcommand)
+        .subcommand(auth_list_subcommand);
+
+    let trust_list_subcommand = Command::new("list")
+        .about("List the keys that are pinned for signed collections.");
+
+    let trust_pin_subcommand = Command::new("pin")
+        .about("Pin the (minisign) public key of a collection, before it is used for the first time.")
+        .arg(Arg::new("collection").required(true).help("The id of the collection."))
+        .arg(Arg::new("public-key").required(true).help("The public key (the base64 line of a minisign public key file)."));
+
+    let trust_forget_subcommand = Command::new("forget")
+        .about("Forget the pinned key of a collection (e.g. after it was rotated), the next key it provides is trusted on first use.")
+        .arg(Arg::new("collection").required(true).help("The id of the collection."));
+
+    let trust_subcommand = Command::new("trust")
+        .about("Manage the keys signed collections are verified with.")
+        .subcommand_required(true)
+        .subcommand(trust_list_subcommand)
+        .subcommand(trust_pin_subcommand)
+        .subcommand(trust_forget_subcommand);
+
+    let shell_subcommand = Command::new("shell")
+        .about("Start an interactive shell with an environment activated (sync it first, if necessary).")
+        .arg(environment_arg.clone())
+        .arg(
+            Arg::new("shell")
+                .long("shell")
+                .value_parser(["bash", "zsh", "fish", "powershell"])
+                .help("The shell to start (defaults to the current shell)."),
+        );
+
+    let solve_subcommand = Command::new("solve")
+        .about("Solve an environment (without installing anything), and list the resulting packages.")
+        .arg(Arg::new("env-id").required(true).help("The id of the environment."))
+        .arg(
+            Arg::new("explain")
+                .long("explain")
+                .value_name("PACKAGE")
+                .help("Explain why the chosen version of this package was picked."),
+        )
+        .arg(
+            Arg::new("platform")
+                .long("platform")
+                .help("Solve for this platform (e.g. 'linux-64', 'osx-arm64', 'win-64'), instead of the one of the environment."),
+        );
+
+    let import_reqs_subcommand = Command::new("import-reqs")
+        .about("Add the requirements of a pip requirements file to an environment, as conda packages where possible.")
+        .arg(
+            Arg::new("requirements")
+                .required(true)
+                .value_parser(clap::value_parser!(PathBuf))
+                .help("The path of the requirements file (files it includes with '-r' are read as well)."),
+        )
+        .arg(
+            Arg::new("env-id")
+                .short('e')
+                .long("env")
+                .required(true)
+                .help("The id of the environment (created if it doesn't exist)."),
+        )
+        .arg(
+            Arg::new("pip-fallback")
+                .long("pip-fallback")
+                .action(ArgAction::SetTrue)
+                .help("Add requirements that can't be installed as conda packages (urls, environment markers, ...) as pip specs, instead of failing."),
+        )
+        .arg(channels_arg.clone())
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Only print the resulting spec, don't change the environment."),
+        );
+
+    let doctor_subcommand = Command::new("doctor")
+        .about("Check collections for invalid specs and other problems.")
+        .arg(
+            Arg::new("check-channels")
+                .long("check-channels")
+                .action(ArgAction::SetTrue)
+                .help("Also check that the packages of all environments are available on their channels (uses cached repodata where possible)."),
+        )
+        .arg(
+            Arg::new("platform")
+                .long("platform")
+                .action(ArgAction::Append)
+                .requires("check-channels")
+                .help("Check package availability for this platform instead of the one of each environment (can be repeated, e.g. 'win-64')."),
+        );
+
+    let gc_subcommand = Command::new("gc")
+        .about("Delete environment directories that don't belong to any registered environment or app anymore.")
+        .arg(
+            Arg::new("dry-run")
+                .long("dry-run")
+                .action(ArgAction::SetTrue)
+                .help("Only list the orphaned directories."),
+        );
+
+    let support_bundle_subcommand = Command::new("support-bundle")
+        .about("Collect config and spec files, recent logs, 'doctor' output and platform details into a zip file (with credentials redacted), to attach to bug reports.")
+        .arg(
+            Arg::new("output")
+                .short('o')
+                .long("output")
+                .value_parser(clap::value_parser!(PathBuf))
+                .default_value("viva-support-bundle.zip")
+                .help("The path of the zip file to create."),
+        );
+
+    let daemon_start_subcommand = Command::new("start")
+        .about("Start the daemon in the background.")
+        .arg(
+            Arg::new("foreground")
+                .long("foreground")
+                .action(ArgAction::SetTrue)
+                .help("Run the daemon in the current process, until it is stopped."),
+        );
+
+    let daemon_stop_subcommand = Command::new("stop")
+        .about("Stop the running daemon.");
+
+    let daemon_status_subcommand = Command::new("status")
+        .about("Show whether the daemon is running.");
+
+    let daemon_subcommand = Command::new("daemon")
+        .about("Manage the (optional) background process that serves launch plans for 'app run', to start apps faster.")
+        .subcommand_required(true)
+        .subcommand(daemon_start_subcommand)
+        .subcommand(daemon_stop_subcommand)
+        .subcommand(daemon_status_subcommand);
 
-    let list_envs_subcommand = Command::new("list-envs").about("List all registered environments.");
+    let completions_subcommand = Command::new("completions")
+        .about("Print the completion script for a shell (completes subcommands, and environment and app ids, with their status).")
+        .arg(
+            Arg::new("shell")
+                .required(true)
+                .value_parser(["bash", "zsh", "fish"])
+                .help("The shell to print the completion script for."),
+        )
+        .arg(
+            Arg::new("candidates")
+                .long("candidates")
+                .value_parser(["envs", "apps"])
+                .hide(true)
+                .help("Print the ids of all environments or apps instead (used by the completion scripts)."),
+        );
+
+    let list_envs_subcommand = Command::new("list-envs")
+        .about("List all registered environments.")
+        .args(list_args());
 
-    let list_apps_subcommand = Command::new("list-apps").about("List all registered apps.");
+    let list_apps_subcommand = Command::new("list-apps")
+        .about("List all registered apps.")
+        .args(list_args());
 
     let app = Command::new("viva")
         .version("0.0.4")
         .author("Markus Binsteiner")
         .about("A tool to manage environments and run commands in them.")
         .arg(verbose_arg)
+        .arg(log_level_arg)
+        .arg(log_file_level_arg)
+        .arg(yes_arg)
+        .arg(limit_rate_arg)
+        .arg(offline_arg)
+        .arg(strict_arg)
+        .arg(clobber_arg)
+        .arg(skip_conflicts_arg)
+        .arg(fail_on_conflicts_arg)
+        .arg(progress_arg)
+        .arg(progress_file_arg)
         .subcommand(list_envs_subcommand)
         .subcommand(register_env_subcommand)
         .subcommand(delete_env_subcommand)
         .subcommand(sync_env_subcommand)
         .subcommand(list_apps_subcommand)
         .subcommand(register_app_subcommand)
+        .subcommand(delete_app_subcommand)
+        .subcommand(app_subcommand)
+        .subcommand(provision_subcommand)
+        .subcommand(status_subcommand)
+        .subcommand(remote_subcommand)
+        .subcommand(relocate_subcommand)
+        .subcommand(snapshot_subcommand)
+        .subcommand(cache_subcommand)
+        .subcommand(explain_subcommand)
+        .subcommand(doctor_subcommand)
+        .subcommand(gc_subcommand)
+        .subcommand(support_bundle_subcommand)
+        .subcommand(daemon_subcommand)
+        .subcommand(solve_subcommand)
+        .subcommand(import_reqs_subcommand)
+        .subcommand(shell_subcommand)
+        .subcommand(workspace_subcommand)
+        .subcommand(collection_subcommand)
+        .subcommand(trust_subcommand)
+        .subcommand(auth_subcommand)
+        .subcommand(matrix_subcommand)
+        .subcommand(env_subcommand)
+        .subcommand(completions_subcommand)
         .subcommand(run_subcommand);
 
     app
 }
 
+/// Tell the user to add the shim directory to the PATH, if it's not on it yet.
+fn warn_if_shim_dir_not_on_path(context: &VivaContext) {
+    if !context.is_shim_dir_on_path() {
+        eprintln!(
+            "The shim directory is not on your PATH, add it to start apps by name: {}",
+            context.get_shim_dir().display()
+        );
+    }
+}
+
+/// The arguments of listings (filter, sort order, pages, layout).
+fn list_args() -> Vec<Arg> {
+    vec![
+        Arg::new("filter")
+            .long("filter")
+            .short('f')
+            .value_name("[COLUMN=]PATTERN")
+            .help("Only list entries that contain the text, or match the glob (e.g. 'py*', or 'status=Invalid' to only match a column)."),
+        Arg::new("sort")
+            .long("sort")
+            .value_name("COLUMN")
+            .help("The column to sort by (defaults to the name)."),
+        Arg::new("reverse")
+            .long("reverse")
+            .action(ArgAction::SetTrue)
+            .help("Reverse the sort order."),
+        Arg::new("page-size")
+            .long("page-size")
+            .value_parser(clap::value_parser!(usize))
+            .help("The number of entries per page (all entries are listed if not set)."),
+        Arg::new("page")
+            .long("page")
+            .value_parser(clap::value_parser!(usize))
+            .default_value("1")
+            .requires("page-size")
+            .help("The page to list."),
+        Arg::new("compact")
+            .long("compact")
+            .action(ArgAction::SetTrue)
+            .conflicts_with("wide")
+            .help("List each entry on a single line."),
+        Arg::new("wide")
+            .long("wide")
+            .action(ArgAction::SetTrue)
+            .help("List each value of an entry on its own line, with separators between entries (the default)."),
+        Arg::new("output")
+            .long("output")
+            .value_parser(["table", "csv", "markdown"])
+            .default_value("table")
+            .help("The output format ('csv' for spreadsheets, 'markdown' for docs)."),
+    ]
+}
+
+fn list_options(matches: &ArgMatches) -> Result<ListOptions> {
+    Ok(ListOptions {
+        filter: matches.get_one::<String>("filter").cloned(),
+        sort_by: matches.get_one::<String>("sort").cloned(),
+        reverse: matches.get_flag("reverse"),
+        page_size: matches.get_one::<usize>("page-size").copied(),
+        page: matches.get_one::<usize>("page").copied().unwrap_or(1),
+        compact: matches.get_flag("compact"),
+        format: match matches.get_one::<String>("output") {
+            Some(format) => format.parse()?,
+            None => ListFormat::Table,
+        },
+    })
+}
+
 async fn get_config(config_file: &PathBuf) -> Result<Config> {
     let config = Config::builder()
         .add_source(
@@ -167,6 +1162,60 @@ async fn get_config(config_file: &PathBuf) -> Result<Config> {
     Ok(config)
 }
 
+/// Launch the app of an 'app run' command with a plan from the daemon, if one is running.
+///
+/// Returns `None` if the app needs to be launched the usual way (no daemon, the daemon can't
+/// provide a plan, or the environment is supposed to be checked).
+#[cfg(feature = "daemon")]
+async fn launch_app_via_daemon(context: &VivaContext, matches: &ArgMatches) -> Result<Option<ExitStatus>> {
+    let run_matches = match matches.subcommand() {
+        Some(("app", app_matches)) => match app_matches.subcommand() {
+            Some(("run", run_matches)) => run_matches,
+            _ => return Ok(None),
+        },
+        _ => return Ok(None),
+    };
+    // the daemon only knows whether an environment was synced, not how to solve it again
+    if check_strategy(run_matches)? == EnvCheckStrategy::Force {
+        return Ok(None);
+    }
+    let app_id = run_matches
+        .get_one::<String>("app")
+        .expect("No app id provided.");
+    let plan = match daemon::request_launch_plan(context, app_id) {
+        Some(plan) => plan,
+        None => return Ok(None),
+    };
+    debug!("launching app '{}' with plan from daemon", app_id);
+    let args = match run_matches.get_many::<String>("args") {
+        Some(args) => args.cloned().collect::<Vec<String>>(),
+        None => vec![],
+    };
+    Ok(Some(plan.run(&args).await?))
+}
+
+/// The script to run for 'run --shell'.
+///
+/// A single argument is used verbatim (so it can contain pipes, redirects, etc.), multiple arguments
+/// are quoted, so each of them reaches the command unchanged.
+fn shell_script(cmd: &[String]) -> String {
+    match cmd {
+        [script] => script.clone(),
+        #[cfg(unix)]
+        _ => cmd
+            .iter()
+            .map(|arg| remote::shell_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" "),
+        #[cfg(windows)]
+        _ => cmd
+            .iter()
+            .map(|arg| remote::cmd_quote(arg))
+            .collect::<Vec<_>>()
+            .join(" "),
+    }
+}
+
 fn extract_env_spec(matches: &clap::ArgMatches) -> Result<VivaEnvSpec> {
     let channels = match matches.get_many::<String>("channels") {
         Some(channels) => channels.map(|s| s.to_string()).collect::<Vec<String>>(),
@@ -176,121 +1225,1067 @@ fn extract_env_spec(matches: &clap::ArgMatches) -> Result<VivaEnvSpec> {
         Some(pkg_specs) => pkg_specs.map(|s| s.to_string()).collect::<Vec<String>>(),
         None => vec![],
     };
-    let env_spec = VivaEnvSpec {
+    let pip_specs = match matches.try_get_many::<String>("pip_specs").ok().flatten() {
+        Some(pip_specs) => pip_specs.map(|s| s.to_string()).collect::<Vec<String>>(),
+        None => vec![],
+    };
+    let mut env_vars = BTreeMap::new();
+    if let Some(values) = matches.try_get_many::<String>("env_vars").ok().flatten() {
+        for value in values {
+            match value.split_once('=') {
+                Some((key, value)) if !key.is_empty() => {
+                    validate_env_var_name(key)?;
+                    env_vars.insert(key.to_string(), value.to_string());
+                }
+                _ => bail!("Invalid environment variable '{}', expected 'KEY=VALUE'.", value),
+            }
+        }
+    }
+    let base_prefix = matches
+        .try_get_one::<PathBuf>("base_prefix")
+        .ok()
+        .flatten()
+        .cloned();
+    let max_size = matches
+        .try_get_one::<String>("max_size")
+        .ok()
+        .flatten()
+        .cloned();
+    let platform = matches
+        .try_get_one::<String>("platform")
+        .ok()
+        .flatten()
+        .cloned();
+    let mut env_spec = VivaEnvSpec {
         channels,
         pkg_specs,
+        pip_specs,
+        env_vars,
+        base_prefix,
+        max_size,
+        platform,
+        ..VivaEnvSpec::new()
     };
+    let invocation = std::env::args().skip(1).collect::<Vec<String>>().join(" ");
+    env_spec.record_provenance(&format!("command line: viva {}", invocation));
     Ok(env_spec)
 }
 
-#[tokio::main]
-async fn main() -> Result<()> {
-    let mut context = viva::VivaContext::init();
-
-    let config_file = context.project_dirs.config_dir().join("viva.yaml");
-
-    if !config_file.exists() {
-        let parent = config_file
-            .parent()
-            .expect("Could not get parent dir of config file.");
-        fs::create_dir_all(parent)?;
-        let default_config = "---\ndefault_channels:\n  - conda-forge\n";
-        fs::write(&config_file, default_config)?;
+/// The environment check strategy of a subcommand with a '--check' argument.
+fn check_strategy(matches: &ArgMatches) -> Result<EnvCheckStrategy> {
+    match matches.get_one::<String>("check") {
+        Some(strategy) => strategy.parse::<EnvCheckStrategy>(),
+        None => Ok(EnvCheckStrategy::Auto),
     }
+}
 
-    let config_data = get_config(&config_file).await?;
-    let viva_config: VivaConfig = config_data.try_deserialize()?;
-
-    let app = create_command(&viva_config);
-    let matches = app.get_matches();
-
-    // let env_base_path = context.project_dirs.data_dir().join("envs");
-    let config_path = PathBuf::from(context.project_dirs.config_dir());
-
-    let env_collection =
-        Box::new(DefaultEnvCollection::create(config_path.clone()).await?);
-    context
-        .add_env_collection("default", env_collection)
-        .await?;
+/// Run a command in an environment, with the executables of the additional environments available.
+///
+/// All environments must have been checked (and synced) already.
+async fn run_in_envs(
+    context: &VivaContext,
+    env: &VivaEnv,
+    also_env_ids: &[String],
+    cmd: &[String],
+    shell: bool,
+) -> Result<ExitStatus> {
+    let mut also_envs = vec![];
+    for id in also_env_ids {
+        also_envs.push(context.get_env(id).await?);
+    }
+    if shell {
+        env.run_shell_in_layered_envs(&also_envs, &shell_script(cmd)).await
+    } else {
+        env.run_command_in_layered_envs(&also_envs, cmd).await
+    }
+}
 
-    let placement_strategy = AppEnvPlacementStrategy::CollectionId;
+async fn handle_env_command(context: &mut VivaContext, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("info", info_matches)) => {
+            debug!("running 'env info' subcommand");
+            let env_id = info_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            context.merge_all_apps().await?;
+            context.check_envs_sync_status().await?;
+            let env = context.get_env(env_id).await?;
 
-    let app_collection = Box::new(DefaultAppCollection::create(config_path).await?);
-    context.add_app_collection("default", app_collection, Some(placement_strategy)).await?;
+            println!("id:          {}", env.id);
+            println!("collection:  {}", env.collection_id);
+            println!("path:        {}", env.get_env_path().display());
+            println!("status:      {}", env.sync_status.to_string());
+            println!("spec hash:   {}", env.spec.content_hash());
+            if let Some(base_prefix) = &env.spec.base_prefix {
+                println!("base prefix: {}", base_prefix.display());
+            }
+            if let Some(max_size) = &env.spec.max_size {
+                println!("max size:    {}", max_size);
+            }
+            if let Some(platform) = &env.spec.platform {
+                println!("platform:    {}", platform);
+            }
 
-    match matches.subcommand() {
-        Some(("register-env", apply_matches)) => {
-            debug!("running 'apply' subcommand");
-            let env_name = apply_matches
+            let provenance = &env.spec.provenance;
+            let unknown = String::from("unknown");
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.set_titles(row!["type", "entry", "source"]);
+            for channel in &env.spec.channels {
+                let source = provenance.channels.get(channel).unwrap_or(&unknown);
+                table.add_row(row!["channel", channel, source]);
+            }
+            for pkg_spec in &env.spec.pkg_specs {
+                let source = provenance.pkg_specs.get(pkg_spec).unwrap_or(&unknown);
+                table.add_row(row!["package", pkg_spec, source]);
+            }
+            println!();
+            table.printstd();
+        }
+        Some(("inspect", inspect_matches)) => {
+            debug!("running 'env inspect' subcommand");
+            let env_id = inspect_matches
                 .get_one::<String>("env-id")
-                .map(|s| s.to_string())
-                .expect("No environment name provided.");
-            let viva_env_spec = extract_env_spec(apply_matches)?;
+                .expect("No environment id provided.");
+            context.merge_all_apps().await?;
+            let env = context.get_env(env_id).await?;
+            let packages = env.installed_packages().await?;
 
-            match context.has_env(&env_name).await {
-                true => {
-                    let replace = apply_matches.get_flag("replace");
-                    if replace {
-                        context.remove_env(&env_name).await?;
-                        debug!("environment {} already registered", env_name);
-                    } else {
-                        bail!("environment {} already registered", env_name);
+            match inspect_matches.get_one::<String>("output").map(|s| s.as_str()) {
+                Some("json") => println!("{}", serde_json::to_string_pretty(&packages)?),
+                Some("yaml") => print!("{}", serde_yaml::to_string(&packages)?),
+                _ => {
+                    if packages.is_empty() {
+                        println!("No packages installed in environment '{}'.", env_id);
+                        return Ok(());
                     }
-                    // context.get_env(&env_name).await?
-                }
-                false => {
-                    // this actually writes an empty spec config file
-                    context.add_env(&env_name, None, None).await?;
+                    let mut table = Table::new();
+                    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                    table.set_titles(row!["name", "version", "build", "channel", "size"]);
+                    for pkg in &packages {
+                        table.add_row(row![
+                            pkg.name,
+                            pkg.version,
+                            pkg.build,
+                            pkg.channel,
+                            indicatif::HumanBytes(pkg.size)
+                        ]);
+                    }
+                    table.printstd();
+                    println!(
+                        "\n{} packages, {}",
+                        packages.len(),
+                        indicatif::HumanBytes(packages.iter().map(|pkg| pkg.size).sum())
+                    );
                 }
-            };
-
-            context
-                .merge_env_specs(&env_name, &viva_env_spec, true, true)
-                .await?;
-
-            let sync = apply_matches.get_flag("sync");
-            if sync {
-                let env = context.get_env_mut(&env_name).await?;
-                env.sync().await?;
-                println!("Registered and applied environment: {}", env_name);
-            } else {
-                // let env = context.get_env(&env_name).await?;
-                println!("Registered environment: {}", env_name);
             }
-
-
         }
-        Some(("delete-env", delete_matches)) => {
-            debug!("running 'delete' subcommand");
-            let env_name = delete_matches
+        Some(("history", history_matches)) => {
+            debug!("running 'env history' subcommand");
+            let env_id = history_matches
                 .get_one::<String>("env-id")
-                .map(|s| s.to_string())
-                .expect("No environment name provided.");
-            context.remove_env(&env_name).await?;
-            println!("Deleted environment: {}", env_name);
-        }
-        Some(("list-envs", _list_matches)) => {
-            debug!("running 'run' subcommand");
-            context.check_envs_sync_status().await?;
-            context.pretty_print_envs().await;
-        }
-        Some(("sync", _sync_matches)) => {
-            debug!("running 'sync-envs' subcommand");
-            let env_names = match _sync_matches.get_many::<String>("env-id") {
-                Some(env_names) => env_names.map(|s| s.to_string()).collect::<HashSet<String>>(),
-                None => HashSet::new(),
-            };
-
+                .expect("No environment id provided.");
+            context.merge_all_apps().await?;
+            let env = context.get_env(env_id).await?;
+            let revisions = history::read_revisions(env.get_env_path()).await?;
 
-            context.sync_envs(&env_names).await?;
+            if history_matches.get_one::<String>("output").map(|s| s.as_str()) == Some("json") {
+                println!("{}", serde_json::to_string_pretty(&revisions)?);
+                return Ok(());
+            }
+            if revisions.is_empty() {
+                println!("No revisions recorded for environment '{}'.", env_id);
+                return Ok(());
+            }
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.set_titles(row!["revision", "recorded", "operation", "packages", "changes"]);
+            let mut previous: Option<&history::Revision> = None;
+            for revision in &revisions {
+                table.add_row(row![
+                    revision.revision,
+                    format_age(revision.timestamp),
+                    revision.operation,
+                    revision.packages.len(),
+                    revision_changes(previous, revision).join("\n")
+                ]);
+                previous = Some(revision);
+            }
+            table.printstd();
         }
-        Some(("list-apps", _app_matches)) => {
-            debug!("running 'run' subcommand");
+        Some(("rollback", rollback_matches)) => {
+            debug!("running 'env rollback' subcommand");
+            let env_id = rollback_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            let revision = *rollback_matches
+                .get_one::<u32>("revision")
+                .expect("No revision provided.");
             context.merge_all_apps().await?;
-            context.check_envs_sync_status().await?;
-            context.pretty_print_apps().await;
+            context.rollback_env(env_id, revision).await?;
+            println!("Rolled back environment '{}' to revision {}.", env_id, revision);
         }
-        Some(("register-app", set_app_matches)) => {
+        Some(("undo", undo_matches)) => {
+            debug!("running 'env undo' subcommand");
+            let env_id = undo_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            match context.undo_env(env_id).await? {
+                Some(env_spec) => {
+                    println!("Restored previous spec of environment '{}':", env_id);
+                    println!("  channels:  {}", env_spec.channels.join(", "));
+                    println!("  pkg_specs: {}", env_spec.pkg_specs.join(", "));
+                }
+                None => println!("Removed environment '{}' (it did not exist before the last change).", env_id),
+            }
+        }
+        Some(("update", update_matches)) => {
+            debug!("running 'env update' subcommand");
+            let env_id = update_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            context.merge_all_apps().await?;
+            let outdated = context.get_env(env_id).await?.find_outdated_packages().await?;
+            if outdated.is_empty() {
+                println!("Environment '{}' is up to date.", env_id);
+                return Ok(());
+            }
+
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.set_titles(row!["package", "installed", "available"]);
+            for pkg in &outdated {
+                table.add_row(row![pkg.name, pkg.installed_version, pkg.available_version]);
+            }
+            table.printstd();
+            if !update_matches.get_flag("dry-run") {
+                context.get_env_mut(env_id).await?.update().await?;
+            }
+        }
+        Some(("remove-pkg", remove_matches)) => {
+            debug!("running 'env remove-pkg' subcommand");
+            let env_id = remove_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            let pkg_specs = remove_matches
+                .get_many::<String>("pkg_specs")
+                .expect("No package specs provided.")
+                .map(|s| s.to_string())
+                .collect::<Vec<String>>();
+            let removed = context.remove_env_pkg_specs(env_id, &pkg_specs).await?;
+            for pkg_spec in pkg_specs.iter().filter(|s| !removed.contains(s)) {
+                eprintln!("Package spec '{}' is not part of the spec of environment '{}'", pkg_spec, env_id);
+            }
+            if removed.is_empty() {
+                return Ok(());
+            }
+            println!("Removed from environment '{}': {}", env_id, removed.join(", "));
+            if remove_matches.get_flag("sync") {
+                // specs of apps in the environment must not be pruned
+                context.merge_all_apps().await?;
+                context.get_env_mut(env_id).await?.sync(EnvCheckStrategy::Auto).await?;
+            } else {
+                println!("The packages will be uninstalled with the next sync.");
+            }
+        }
+        Some(("activate", activate_matches)) => {
+            debug!("running 'env activate' subcommand");
+            let env_id = activate_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            let shell = match activate_matches.get_one::<String>("shell") {
+                Some(shell) => shell.parse::<activation::ShellType>()?,
+                None => activation::ShellType::detect(),
+            };
+            context.merge_all_apps().await?;
+            let env = context.get_env_mut(env_id).await?;
+            env.sync(EnvCheckStrategy::Auto).await?;
+            print!("{}", env.activation().render(shell));
+        }
+        Some(("export", export_matches)) => {
+            debug!("running 'env export' subcommand");
+            let env_id = export_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            context.merge_all_apps().await?;
+            let env = context.get_env(env_id).await?;
+            let conda_env = env
+                .export_conda_env(!export_matches.get_flag("spec-only"))
+                .await?;
+            let conda_env_yaml = serde_yaml::to_string(&conda_env)?;
+            match export_matches.get_one::<PathBuf>("output") {
+                Some(output) => {
+                    tokio::fs::write(output, conda_env_yaml).await?;
+                    println!("Exported environment '{}' to: {}", env_id, output.display());
+                }
+                None => print!("{}", conda_env_yaml),
+            }
+        }
+        Some(("rename", rename_matches)) => {
+            debug!("running 'env rename' subcommand");
+            let env_id = rename_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            let new_env_id = rename_matches
+                .get_one::<String>("new-id")
+                .expect("No new environment id provided.");
+            context.merge_all_apps().await?;
+            context.rename_env(env_id, new_env_id).await?;
+            println!("Renamed environment '{}' to '{}'.", env_id, new_env_id);
+        }
+        Some(("clone", clone_matches)) => {
+            debug!("running 'env clone' subcommand");
+            let source = clone_matches
+                .get_one::<String>("source")
+                .expect("No source environment id provided.");
+            let target = clone_matches
+                .get_one::<String>("target")
+                .expect("No target environment id provided.");
+            let link = clone_matches.get_flag("link");
+            context.merge_all_apps().await?;
+            context
+                .clone_env(source, target, clone_matches.get_one::<String>("collection").map(|s| s.as_str()), link)
+                .await?;
+            match link {
+                true => println!("Cloned environment '{}' to '{}' (with its installed packages).", source, target),
+                false => println!("Cloned environment '{}' to '{}', it will be created with the next sync.", source, target),
+            }
+        }
+        Some(("freeze", freeze_matches)) => {
+            debug!("running 'env freeze' subcommand");
+            let env_id = freeze_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            context.merge_all_apps().await?;
+            context.check_envs_sync_status().await?;
+            let env = context.get_env(env_id).await?;
+            let platform = freeze_matches.get_one::<String>("platform");
+            match freeze_matches.get_one::<PathBuf>("docker") {
+                Some(output_dir) => {
+                    let base = freeze_matches
+                        .get_one::<String>("base")
+                        .map(|s| s.as_str())
+                        .unwrap_or("micromamba")
+                        .parse::<docker::DockerBase>()?;
+                    let platform = platform.map(|s| s.as_str()).unwrap_or("linux-64");
+                    let written = docker::write_docker_context(
+                        env,
+                        base,
+                        platform,
+                        freeze_matches.get_one::<String>("image").map(|s| s.as_str()),
+                        output_dir,
+                        context.get_config(),
+                    )
+                    .await?;
+                    for path in &written {
+                        println!("Wrote: {}", path.display());
+                    }
+                    println!(
+                        "\nBuild the image with: docker build -t {} {}",
+                        env_id.replace('/', "-").to_lowercase(),
+                        output_dir.display()
+                    );
+                }
+                None => {
+                    let platform = platform.cloned().unwrap_or_else(|| env.spec.get_platform_name());
+                    let packages = docker::frozen_packages(env, &platform, context.get_config()).await?;
+                    print!("{}", docker::render_explicit_lock(env_id, &platform, &packages));
+                }
+            }
+        }
+        _ => {
+            println!("No env subcommand provided, use the '--help' flag to get more information.)");
+        }
+    }
+    Ok(())
+}
+
+async fn handle_app_command(context: &mut VivaContext, matches: &ArgMatches) -> Result<()> {
+    match matches.subcommand() {
+        Some(("from-env", from_env_matches)) => {
+            debug!("running 'app from-env' subcommand");
+            let env_id = from_env_matches
+                .get_one::<String>("env-id")
+                .map(|s| s.to_string())
+                .expect("No environment id provided.");
+            let executable = from_env_matches
+                .get_one::<String>("executable")
+                .map(|s| s.to_string())
+                .expect("No executable provided.");
+            let app_id = match from_env_matches.get_one::<String>("app-id") {
+                Some(app_id) => app_id.to_string(),
+                None => executable.clone(),
+            };
+            let args = match from_env_matches.get_many::<String>("cmd_args") {
+                Some(cmd_args) => cmd_args.map(|s| s.to_string()).collect::<Vec<String>>(),
+                None => vec![],
+            };
+
+            context
+                .add_app_from_env(&app_id, &env_id, &executable, args, "default")
+                .await?;
+            println!("Registered app '{}' using environment: {}", app_id, env_id);
+        }
+        Some(("register", register_matches)) => {
+            debug!("running 'app register' subcommand");
+            let manifest_file = register_matches
+                .get_one::<PathBuf>("manifest")
+                .expect("No manifest file provided.");
+
+            let manifest = read_app_manifest(manifest_file).await?;
+            let mut apps: BTreeMap<String, (VivaAppSpec, AppEnvPlacementStrategy)> = BTreeMap::new();
+            for (app_id, entry) in manifest {
+                let placement_strategy = entry.get_placement_strategy()?;
+                apps.insert(app_id, (entry.spec, placement_strategy));
+            }
+
+            let added = context.add_apps(apps, "default").await?;
+            for app_id in added {
+                println!("Registered app: {}", app_id);
+            }
+        }
+        Some(("bundle", bundle_matches)) => {
+            debug!("running 'app bundle' subcommand");
+            let app_id = bundle_matches
+                .get_one::<String>("app")
+                .expect("No app provided.");
+            let output = bundle_matches
+                .get_one::<PathBuf>("output")
+                .expect("No output path provided.");
+            let include_cache = bundle_matches.get_flag("include-cache");
+            bundle::create_bundle(context, app_id, output, include_cache).await?;
+            println!("Created launcher for app '{}': {}", app_id, output.display());
+        }
+        Some(("outdated", outdated_matches)) => {
+            debug!("running 'app outdated' subcommand");
+            let app_ids = match outdated_matches.get_many::<String>("apps") {
+                Some(app_ids) => app_ids.map(|s| s.to_string()).collect::<Vec<String>>(),
+                None => vec![],
+            };
+            let outdated = context.find_outdated_apps(&app_ids).await?;
+
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.set_titles(row!["app", "package", "installed", "available"]);
+            let mut outdated_app_ids: Vec<String> = vec![];
+            for (app_id, packages) in &outdated {
+                if !packages.is_empty() {
+                    outdated_app_ids.push(app_id.clone());
+                }
+                for pkg in packages {
+                    table.add_row(row![app_id, pkg.name, pkg.installed_version, pkg.available_version]);
+                }
+            }
+
+            if outdated_app_ids.is_empty() {
+                println!("All apps are up to date.");
+            } else {
+                table.printstd();
+                if outdated_matches.get_flag("apply") {
+                    context.update_apps(&outdated_app_ids).await?;
+                    println!("Updated apps: {}", outdated_app_ids.join(", "));
+                }
+            }
+        }
+        Some(("fix", fix_matches)) => {
+            debug!("running 'app fix' subcommand");
+            let app_id = fix_matches
+                .get_one::<String>("app")
+                .expect("No app id provided.");
+            let env_id = fix_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            context.fix_app(app_id, env_id).await?;
+            println!("App '{}' now uses environment: {}", app_id, env_id);
+        }
+        Some(("rename", rename_matches)) => {
+            debug!("running 'app rename' subcommand");
+            let app_id = rename_matches
+                .get_one::<String>("app")
+                .expect("No app id provided.");
+            let new_app_id = rename_matches
+                .get_one::<String>("new-id")
+                .expect("No new app id provided.");
+            context.merge_all_apps().await?;
+            context.rename_app(app_id, new_app_id).await?;
+            println!(
+                "Renamed app '{}' to '{}', it uses environment: {}",
+                app_id,
+                new_app_id,
+                context.get_app_env_id(new_app_id).await?
+            );
+        }
+        Some(("shim", shim_matches)) => {
+            debug!("running 'app shim' subcommand");
+            let force = shim_matches.get_flag("force");
+            for app_id in shim_matches
+                .get_many::<String>("apps")
+                .expect("No app ids provided.")
+            {
+                let shim_path = context.install_app_shim(app_id, force).await?;
+                println!("Created shim for app '{}': {}", app_id, shim_path.display());
+            }
+            warn_if_shim_dir_not_on_path(context);
+        }
+        Some(("install", install_matches)) => {
+            debug!("running 'app install' subcommand");
+            let force = install_matches.get_flag("force");
+            for app_id in install_matches
+                .get_many::<String>("apps")
+                .expect("No app ids provided.")
+            {
+                let shim_path = context.install_app(app_id, force).await?;
+                println!("Installed app '{}': {}", app_id, shim_path.display());
+            }
+            warn_if_shim_dir_not_on_path(context);
+        }
+        Some(("unshim", unshim_matches)) => {
+            debug!("running 'app unshim' subcommand");
+            let app_id = unshim_matches
+                .get_one::<String>("app")
+                .expect("No app id provided.");
+            let removed = context.remove_app_shims(app_id).await?;
+            if removed.is_empty() {
+                println!("No shims found for app: {}", app_id);
+            }
+            for shim_path in removed {
+                println!("Removed shim: {}", shim_path.display());
+            }
+        }
+        Some(("run", run_matches)) => {
+            debug!("running 'app run' subcommand");
+            let app_id = run_matches
+                .get_one::<String>("app")
+                .expect("No app id provided.");
+            context.merge_all_apps().await?;
+            let env_id = context.get_app_env_id(app_id).await?;
+            let app_spec = context.get_app(app_id).await?.spec.clone();
+            let mut cmd = app_spec.get_full_cmd();
+            if let Some(args) = run_matches.get_many::<String>("args") {
+                cmd.extend(args.cloned());
+            }
+
+            let check_strategy = check_strategy(run_matches)?;
+            let env = context.get_env_mut(&env_id).await?;
+            env.sync(check_strategy)
+                .await
+                .with_context(|| format!("Environment of app '{}' is not ready", app_id))?;
+
+            let runtime_vars = app_spec.get_runtime_vars(env.get_env_path())?;
+            let status = env.run_app_command_in_env(&cmd, &runtime_vars).await?;
+            if !status.success() {
+                std::process::exit(status.code().unwrap_or(1));
+            }
+        }
+        Some(("doctor", doctor_matches)) => {
+            debug!("running 'app doctor' subcommand");
+            let app_id = doctor_matches
+                .get_one::<String>("app")
+                .expect("No app id provided.");
+            context.merge_all_apps().await?;
+            let checks = doctor::diagnose_app(context, app_id).await?;
+            for check in &checks {
+                println!("{}", check);
+            }
+            if checks.iter().any(|check| check.status == doctor::CheckStatus::Error) {
+                bail!("App '{}' has problems that need to be fixed.", app_id);
+            }
+        }
+        Some(("uninstall", uninstall_matches)) => {
+            debug!("running 'app uninstall' subcommand");
+            let app_id = uninstall_matches
+                .get_one::<String>("app")
+                .expect("No app id provided.");
+            let report = context
+                .uninstall_app(app_id, uninstall_matches.get_flag("purge-env"))
+                .await?;
+            print_uninstall_report(&report);
+        }
+        Some(("shims", _shims_matches)) => {
+            debug!("running 'app shims' subcommand");
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+            table.set_titles(row!["shim", "app"]);
+            for (shim_name, app_id) in context.list_shims().await? {
+                table.add_row(row![shim_name, app_id]);
+            }
+            table.printstd();
+            println!("\nShim directory: {}", context.get_shim_dir().display());
+        }
+        _ => {
+            println!("No app subcommand provided, use the '--help' flag to get more information.)");
+        }
+    }
+    Ok(())
+}
+
+/// How long ago a timestamp (seconds since the unix epoch) was, for humans.
+fn format_age(timestamp: u64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default();
+    match now.saturating_sub(timestamp) {
+        age if age < 60 => String::from("just now"),
+        age if age < 60 * 60 => format!("{} min ago", age / 60),
+        age if age < 60 * 60 * 24 => format!("{} h ago", age / (60 * 60)),
+        age => format!("{} days ago", age / (60 * 60 * 24)),
+    }
+}
+
+/// The package changes of a revision, compared to the previous one (all packages of the first one).
+fn revision_changes(previous: Option<&history::Revision>, revision: &history::Revision) -> Vec<String> {
+    let versions = |revision: &history::Revision| {
+        revision
+            .packages
+            .iter()
+            .map(|record| {
+                let package = &record.package_record;
+                (package.name.clone(), format!("{} ({})", package.version, package.build))
+            })
+            .collect::<BTreeMap<String, String>>()
+    };
+    let new = versions(revision);
+    let old = match previous {
+        Some(previous) => versions(previous),
+        None => return vec![format!("{} packages installed", new.len())],
+    };
+    let mut changes = vec![];
+    for (name, version) in &new {
+        match old.get(name) {
+            None => changes.push(format!("+ {} {}", name, version)),
+            Some(old_version) if old_version != version => {
+                changes.push(format!("~ {} {} -> {}", name, old_version, version))
+            }
+            Some(_) => {}
+        }
+    }
+    for (name, version) in &old {
+        if !new.contains_key(name) {
+            changes.push(format!("- {} {}", name, version));
+        }
+    }
+    if changes.is_empty() {
+        changes.push(String::from("no package changes"));
+    }
+    changes
+}
+
+/// Print what a sync would change in the prefix of an environment.
+fn print_sync_plan(env_id: &str, plan: Option<&SyncPlan>) {
+    let plan = match plan {
+        Some(plan) => plan,
+        None => {
+            println!("Environment '{}' is synced, nothing to do.", env_id);
+            return;
+        }
+    };
+    let source = match plan.from_lockfile {
+        true => "from lockfile",
+        false => "solved",
+    };
+    println!("Environment '{}' ({}, {}): {}", plan.env_id, plan.platform, source, plan.env_path.display());
+    if plan.operations.is_empty() {
+        println!("  No package changes.");
+    } else {
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(row!["action", "package", "installed", "new", "channel", "download"]);
+        let none = String::new();
+        for operation in &plan.operations {
+            let download = match operation.download_bytes {
+                Some(bytes) => indicatif::HumanBytes(bytes).to_string(),
+                None => none.clone(),
+            };
+            table.add_row(row![
+                operation.action,
+                operation.name,
+                operation.installed.as_ref().unwrap_or(&none),
+                operation.version.as_ref().unwrap_or(&none),
+                operation.channel.as_ref().unwrap_or(&none),
+                download
+            ]);
+        }
+        table.printstd();
+    }
+    if !plan.pip_specs.is_empty() {
+        println!("  pip install: {}", plan.pip_specs.join(" "));
+    }
+    let removed = plan
+        .operations
+        .iter()
+        .filter(|operation| operation.action == PlannedAction::Remove)
+        .count();
+    println!(
+        "  {} to install, {} to remove, {} to download ({} package(s))\n",
+        plan.estimate.install_count,
+        removed,
+        indicatif::HumanBytes(plan.estimate.download_bytes),
+        plan.estimate.download_count
+    );
+}
+
+fn print_uninstall_report(report: &AppUninstallReport) {
+    println!(
+        "Removed app spec: {} (collection '{}')",
+        report.app_id, report.app_collection_id
+    );
+    for shim_path in &report.removed_shims {
+        println!("Removed shim: {}", shim_path.display());
+    }
+    if let Some((env_id, env_path)) = &report.removed_env {
+        println!("Removed environment '{}': {}", env_id, env_path.display());
+    }
+    for snapshot in &report.removed_snapshots {
+        println!("Removed snapshot: {}", snapshot);
+    }
+    if let Some(reason) = &report.kept_env_reason {
+        println!("Kept environment ({})", reason);
+    }
+}
+
+/// Sync environments, with GitHub Actions workflow commands around the output of each of them, and
+/// their prefix paths and cache keys as step outputs.
+async fn sync_envs_gha(
+    context: &mut VivaContext,
+    env_names: HashSet<String>,
+    check_strategy: EnvCheckStrategy,
+) -> Result<()> {
+    let mut env_ids = match env_names.is_empty() {
+        true => context.get_env_ids().await,
+        false => env_names.into_iter().collect::<Vec<String>>(),
+    };
+    env_ids.sort();
+    let mut failed = vec![];
+    for env_id in &env_ids {
+        // before syncing, which might write a lockfile, so the key is the one a cache restore step
+        // that runs before viva computed
+        let cache_key = match context.get_env(env_id).await {
+            Ok(env) => Some(env.cache_key().await),
+            Err(_) => None,
+        };
+        gha::start_group(&format!("Sync environment: {}", env_id));
+        let result = context.sync_envs(&HashSet::from([env_id.clone()]), check_strategy).await;
+        gha::end_group();
+        if let Err(e) = result {
+            gha::error(&format!("Failed to sync environment '{}'", env_id), &format!("{:#}", e));
+            failed.push(env_id.clone());
+            continue;
+        }
+        let env = context.get_env(env_id).await?;
+        let env_path = env.get_env_path().to_string_lossy().to_string();
+        let cache_key = match cache_key {
+            Some(cache_key) => cache_key,
+            None => env.cache_key().await,
+        };
+        let name = gha::output_name(env_id);
+        gha::set_output(&format!("env-path-{}", name), &env_path)?;
+        gha::set_output(&format!("cache-key-{}", name), &cache_key)?;
+        if env_ids.len() == 1 {
+            gha::set_output("env-path", &env_path)?;
+            gha::set_output("cache-key", &cache_key)?;
+        }
+    }
+    if !failed.is_empty() {
+        bail!("Failed to sync environments: {}", failed.join(", "));
+    }
+    Ok(())
+}
+
+async fn load_default_collections(context: &mut VivaContext) -> Result<()> {
+    // let env_base_path = context.project_dirs.data_dir().join("envs");
+    let config_path = PathBuf::from(context.project_dirs.config_dir());
+
+    // the environments of the project viva runs in take precedence over the ones of the user
+    let project_envs_file = std::env::current_dir()
+        .ok()
+        .and_then(|current_dir| context.find_project_envs_file(&current_dir));
+    if let Some(envs_file) = project_envs_file {
+        // a broken project file shouldn't make viva unusable everywhere below it
+        match ProjectEnvCollection::create(envs_file.clone()).await {
+            Ok(project_collection) => {
+                context.add_env_collection("project", Box::new(project_collection)).await?;
+            }
+            Err(e) => warn!("Ignoring project environments file '{}': {:#}", envs_file.display(), e),
+        }
+    }
+
+    let env_collection =
+        Box::new(DefaultEnvCollection::create(config_path.clone()).await?);
+    context
+        .add_env_collection("default", env_collection)
+        .await?;
+
+    let data_path = PathBuf::from(context.project_dirs.data_dir());
+    for (workspace_id, workspace_file) in WorkspaceEnvCollection::find_workspaces(&config_path) {
+        let workspace_collection =
+            Box::new(WorkspaceEnvCollection::create(&workspace_id, workspace_file, &data_path).await?);
+        context
+            .add_env_collection(&format!("workspace:{}", workspace_id), workspace_collection)
+            .await?;
+    }
+
+    for (collection_id, remote) in RemoteEnvCollection::read_registry(&config_path).await? {
+        let remote_collection =
+            Box::new(RemoteEnvCollection::create(&collection_id, remote, &data_path, context.get_config()).await?);
+        context
+            .add_env_collection(&format!("remote:{}", collection_id), remote_collection)
+            .await?;
+    }
+
+    // collections added by provisioning profiles
+    let profile_collections = context.read_profile_collections().await?;
+    for (collection_id, collection_path) in &profile_collections {
+        match DefaultEnvCollection::create(collection_path.clone()).await {
+            Ok(env_collection) => context.add_env_collection(collection_id, Box::new(env_collection)).await?,
+            Err(e) => warn!("Ignoring collection '{}' ({}): {:#}", collection_id, collection_path.display(), e),
+        }
+    }
+
+    // registered after the collections of the user, so user environments shadow system ones
+    let system_envs_dir = SystemEnvCollection::envs_dir(context.get_config());
+    if system_envs_dir.is_dir() {
+        let system_collection = Box::new(SystemEnvCollection::create(system_envs_dir).await?);
+        context.add_env_collection("system", system_collection).await?;
+    }
+
+    let placement_strategy = match &context.get_config().app_env_placement {
+        Some(placement) => AppEnvPlacementStrategy::from_str(placement)?,
+        None => AppEnvPlacementStrategy::CollectionId,
+    };
+
+    let app_collection = Box::new(DefaultAppCollection::create(config_path).await?);
+    context.add_app_collection("default", app_collection, Some(placement_strategy)).await?;
+    for (collection_id, collection_path) in profile_collections {
+        match DefaultAppCollection::create(collection_path.clone()).await {
+            Ok(app_collection) => context.add_app_collection(&collection_id, Box::new(app_collection), None).await?,
+            Err(e) => warn!("Ignoring collection '{}' ({}): {:#}", collection_id, collection_path.display(), e),
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let mut context = viva::VivaContext::init();
+
+    let config_file = context.project_dirs.config_dir().join("viva.yaml");
+
+    if !config_file.exists() {
+        let parent = config_file
+            .parent()
+            .expect("Could not get parent dir of config file.");
+        fs::create_dir_all(parent)?;
+        let default_config = "---\ndefault_channels:\n  - conda-forge\n";
+        fs::write(&config_file, default_config)?;
+    }
+
+    let config_data = get_config(&config_file).await?;
+    let viva_config: VivaConfig = config_data.try_deserialize()?;
+
+    let app = create_command(&viva_config);
+    context.set_config(viva_config.clone());
+
+    let default_log_dir = context.project_dirs.data_dir().join("logs");
+
+    // if this executable is an app bundle, all arguments are passed through to the app
+    if let Some(manifest) = bundle::read_bundle_manifest()? {
+        logging::init_logging(&logging::LogSettings::from_config(&viva_config, &default_log_dir)?)?;
+        load_default_collections(&mut context).await?;
+        let args = std::env::args().skip(1).collect::<Vec<String>>();
+        let status = bundle::run_bundle(&mut context, manifest, args).await?;
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    let matches = app.get_matches();
+
+    let mut config = viva_config;
+    if matches.get_flag("yes") {
+        config.assume_yes = true;
+    }
+    if let Some(limit_rate) = matches.get_one::<String>("limit-rate") {
+        config.limit_rate = Some(limit_rate.clone());
+    }
+    if matches.get_flag("offline") {
+        config.offline = true;
+    }
+    if matches.get_flag("strict") {
+        config.strict = true;
+    }
+    if matches.get_flag("clobber") {
+        config.path_conflicts = PathConflictPolicy::Clobber;
+    }
+    if matches.get_flag("skip-conflicts") {
+        config.path_conflicts = PathConflictPolicy::Skip;
+    }
+    if matches.get_flag("fail-on-conflicts") {
+        config.path_conflicts = PathConflictPolicy::Error;
+    }
+    if matches.get_flag("verbose") {
+        config.log_level = Some(String::from("info,viva=debug"));
+    }
+    if let Some(log_level) = matches.get_one::<String>("log-level") {
+        config.log_level = Some(log_level.clone());
+    }
+    if let Some(log_file_level) = matches.get_one::<String>("log-file-level") {
+        config.log_file_level = Some(log_file_level.clone());
+    }
+    logging::init_logging(&logging::LogSettings::from_config(&config, &default_log_dir)?)?;
+    let progress_file = matches.get_one::<PathBuf>("progress-file");
+    if matches.get_one::<String>("progress").map(|s| s.as_str()) == Some("json") || progress_file.is_some() {
+        progress::enable_json_progress(progress_file.map(|p| p.as_path()))?;
+    }
+    context.set_config(config);
+
+    // apps can be launched with a plan from the daemon, without loading any collections
+    #[cfg(feature = "daemon")]
+    if let Some(status) = launch_app_via_daemon(&context, &matches).await? {
+        std::process::exit(status.code().unwrap_or(1));
+    }
+
+    // before anything is loaded, in case a spec file was only partially written
+    context.recover_interrupted_operations().await?;
+    load_default_collections(&mut context).await?;
+
+    match matches.subcommand() {
+        Some(("register-env", apply_matches)) => {
+            debug!("running 'apply' subcommand");
+            let env_name = apply_matches
+                .get_one::<String>("env-id")
+                .map(|s| s.to_string())
+                .expect("No environment name provided.");
+            let mut viva_env_spec = extract_env_spec(apply_matches)?;
+            if let Some(spec_file) = apply_matches.get_one::<PathBuf>("from_file") {
+                let mut file_env_spec = VivaEnvSpec::read_env_spec(spec_file).await?;
+                // channels from the file take precedence over the default ones
+                file_env_spec.append_spec(viva_env_spec);
+                viva_env_spec = file_env_spec;
+            }
+
+            if apply_matches.get_flag("dry-run") {
+                if context.has_env(&env_name).await && !apply_matches.get_flag("replace") {
+                    bail!("environment {} already registered", env_name);
+                }
+                let mut env = context.preview_env(&env_name, &viva_env_spec).await?;
+                if apply_matches.get_flag("replace") {
+                    env.spec = viva_env_spec.clone();
+                    env.check_and_update_sync_status();
+                }
+                print_sync_plan(&env_name, env.plan_sync().await?.as_ref());
+                return Ok(());
+            }
+
+            match context.has_env(&env_name).await {
+                true => {
+                    let replace = apply_matches.get_flag("replace");
+                    if replace {
+                        context.remove_env(&env_name).await?;
+                        debug!("environment {} already registered", env_name);
+                    } else {
+                        bail!("environment {} already registered", env_name);
+                    }
+                    // context.get_env(&env_name).await?
+                }
+                false => {
+                    context.add_env(&env_name, Some(viva_env_spec.clone()), None).await?;
+                }
+            };
+
+            context
+                .merge_env_specs(&env_name, &viva_env_spec, true, true)
+                .await?;
+
+            let sync = apply_matches.get_flag("sync");
+            if sync {
+                let env = context.get_env_mut(&env_name).await?;
+                env.sync(EnvCheckStrategy::Auto).await?;
+                println!("Registered and applied environment: {}", env_name);
+            } else {
+                // let env = context.get_env(&env_name).await?;
+                println!("Registered environment: {}", env_name);
+            }
+
+
+        }
+        Some(("delete-env", delete_matches)) => {
+            debug!("running 'delete' subcommand");
+            let env_name = delete_matches
+                .get_one::<String>("env-id")
+                .map(|s| s.to_string())
+                .expect("No environment name provided.");
+            context.remove_env(&env_name).await?;
+            println!("Deleted environment: {}", env_name);
+        }
+        Some(("delete-app", delete_matches)) => {
+            debug!("running 'delete-app' subcommand");
+            let app_id = delete_matches
+                .get_one::<String>("app")
+                .expect("No app id provided.");
+            let report = context
+                .uninstall_app(app_id, delete_matches.get_flag("delete-env"))
+                .await?;
+            print_uninstall_report(&report);
+        }
+        Some(("gc", gc_matches)) => {
+            debug!("running 'gc' subcommand");
+            let report = context.garbage_collect(gc_matches.get_flag("dry-run")).await?;
+            if report.orphaned.is_empty() {
+                println!("No orphaned environment directories found.");
+            } else if report.deleted {
+                for orphan in &report.orphaned {
+                    println!("Deleted '{}': {}", orphan.env_id, orphan.path.display());
+                }
+                println!("Reclaimed {}.", indicatif::HumanBytes(report.reclaimed_bytes));
+            } else {
+                let mut table = Table::new();
+                table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                table.set_titles(row!["environment", "size", "path"]);
+                for orphan in &report.orphaned {
+                    table.add_row(row![orphan.env_id, indicatif::HumanBytes(orphan.size), orphan.path.display()]);
+                }
+                table.printstd();
+                let total: u64 = report.orphaned.iter().map(|orphan| orphan.size).sum();
+                println!("\nNothing deleted ({} could be reclaimed).", indicatif::HumanBytes(total));
+            }
+        }
+        Some(("list-envs", list_matches)) => {
+            debug!("running 'list-envs' subcommand");
+            context.check_envs_sync_status().await?;
+            context.pretty_print_envs(&list_options(list_matches)?).await?;
+        }
+        Some(("sync", _sync_matches)) => {
+            debug!("running 'sync-envs' subcommand");
+            let env_names = match _sync_matches.get_many::<String>("env-id") {
+                Some(env_names) => env_names.map(|s| s.to_string()).collect::<HashSet<String>>(),
+                None => HashSet::new(),
+            };
+
+            if _sync_matches.get_flag("dry-run") {
+                let mut env_ids = env_names.into_iter().collect::<Vec<String>>();
+                if env_ids.is_empty() {
+                    env_ids = context.get_env_ids().await;
+                }
+                env_ids.sort();
+                for env_id in env_ids {
+                    let env = context.get_env_mut(&env_id).await?;
+                    print_sync_plan(&env_id, env.plan_sync().await?.as_ref());
+                }
+                return Ok(());
+            }
+
+            let check_strategy = check_strategy(_sync_matches)?;
+            match _sync_matches.get_flag("gha") {
+                true => sync_envs_gha(&mut context, env_names, check_strategy).await?,
+                false => context.sync_envs(&env_names, check_strategy).await?,
+            }
+        }
+        Some(("list-apps", list_matches)) => {
+            debug!("running 'list-apps' subcommand");
+            context.merge_all_apps().await?;
+            context.check_envs_sync_status().await?;
+            context.pretty_print_apps(&list_options(list_matches)?).await?;
+        }
+        Some(("register-app", set_app_matches)) => {
             debug!("running 'set-app' subcommand");
             let app_id = set_app_matches
                 .get_one::<String>("app")
@@ -320,6 +2315,10 @@ async fn main() -> Result<()> {
                 executable: exe,
                 args,
                 env_spec: viva_env_spec,
+                env_id: None,
+                shim_prefix: None,
+                runtime_stacks: vec![],
+                runtime_vars: BTreeMap::new(),
             };
 
             println!("set-app: {}", app_id);
@@ -329,19 +2328,909 @@ async fn main() -> Result<()> {
             let placement_strategy = AppEnvPlacementStrategy::CollectionId;
 
             context.add_app(&app_id, app_spec, col_id, placement_strategy).await?;
+            if set_app_matches.get_flag("expose") {
+                let shim_path = context.install_app(&app_id, false).await?;
+                println!("Installed app '{}': {}", app_id, shim_path.display());
+                warn_if_shim_dir_not_on_path(&context);
+            }
 
-
+        }
+        Some(("app", app_matches)) => {
+            handle_app_command(&mut context, app_matches).await?;
+        }
+        Some(("env", env_matches)) => {
+            handle_env_command(&mut context, env_matches).await?;
+        }
+        Some(("provision", provision_matches)) => {
+            debug!("running 'provision' subcommand");
+            let profile_file = provision_matches
+                .get_one::<PathBuf>("profile")
+                .expect("No profile provided.");
+            let profile = ProvisioningProfile::read(profile_file).await?;
+            let prune = provision_matches.get_flag("prune");
+            let actions = match provision_matches.get_flag("diff") {
+                true => context.plan_profile(&profile, prune).await?,
+                false => context.apply_profile(&profile, prune).await?,
+            };
+            match actions.len() {
+                0 => println!("Nothing to do, machine is already provisioned."),
+                _ => {
+                    for action in actions {
+                        println!("{}", action);
+                    }
+                }
+            }
+        }
+        Some(("status", status_matches)) => {
+            debug!("running 'status' subcommand");
+            let against = status_matches
+                .get_one::<PathBuf>("against")
+                .expect("No profile or collection provided.");
+            let profile = ProvisioningProfile::read_or_collect(against).await?;
+            let report = context.drift_report(&profile).await?;
+            println!("{}", serde_json::to_string_pretty(&report)?);
+            if !report.in_sync {
+                std::process::exit(1);
+            }
+        }
+        Some(("completions", completions_matches)) => {
+            debug!("running 'completions' subcommand");
+            let shell = completions_matches
+                .get_one::<String>("shell")
+                .expect("No shell provided.")
+                .parse::<completions::CompletionShell>()?;
+            match completions_matches.get_one::<String>("candidates").map(|s| s.as_str()) {
+                Some("envs") => {
+                    context.check_envs_sync_status().await?;
+                    let candidates = completions::env_candidates(context.list_envs().await);
+                    print!("{}", completions::render_candidates(shell, &candidates));
+                }
+                Some("apps") => {
+                    context.check_envs_sync_status().await?;
+                    let candidates = completions::app_candidates(context.list_apps().await, context.list_envs().await);
+                    print!("{}", completions::render_candidates(shell, &candidates));
+                }
+                _ => print!("{}", completions::render_script(shell, &create_command(context.get_config()))),
+            }
         }
         Some(("run", run_matches)) => {
             debug!("running 'run' subcommand");
-            let _env_name = run_matches
-                .get_one::<String>("env")
+            let mut viva_env_spec = extract_env_spec(run_matches)?;
+            let cmd = run_matches
+                .get_many::<String>("cmd")
+                .expect("No command provided.")
                 .map(|s| s.to_string())
+                .collect::<Vec<String>>();
+
+            let also_env_ids = match run_matches.get_many::<String>("also-env") {
+                Some(env_ids) => env_ids.map(|s| s.to_string()).collect::<Vec<String>>(),
+                None => vec![],
+            };
+
+            if run_matches.get_flag("ephemeral") {
+                if viva_env_spec.pkg_specs.is_empty() {
+                    bail!("No package specs provided for the ephemeral environment (use '--spec').");
+                }
+                let keep = run_matches.get_flag("keep-ephemeral");
+                let mut env = context.create_ephemeral_env(viva_env_spec, keep).await?;
+                if run_matches.get_flag("dry-run") {
+                    let plan = env.plan_sync().await?;
+                    print_sync_plan(&env.id, plan.as_ref());
+                    for id in &also_env_ids {
+                        let also_env = context.get_env_mut(id).await?;
+                        print_sync_plan(id, also_env.plan_sync().await?.as_ref());
+                    }
+                    println!("Would run: {}", cmd.join(" "));
+                    return Ok(());
+                }
+
+                let check_strategy = check_strategy(run_matches)?;
+                for id in &also_env_ids {
+                    context.get_env_mut(id).await?.sync(check_strategy).await?;
+                }
+                let result = match env.sync(check_strategy).await {
+                    Ok(_) => run_in_envs(&context, &env, &also_env_ids, &cmd, run_matches.get_flag("shell")).await,
+                    Err(e) => Err(e),
+                };
+                if !keep {
+                    context.remove_ephemeral_env(&env).await?;
+                }
+                let status = result?;
+                if !status.success() {
+                    std::process::exit(status.code().unwrap_or(1));
+                }
+                return Ok(());
+            }
+
+            let mut project_env_spec = None;
+            let env_id = match run_matches.get_one::<String>("env-id") {
+                Some(env_id) => env_id.to_string(),
+                None => {
+                    let default_env = context.resolve_default_env().await?;
+                    eprintln!("Using environment '{}' ({})", default_env.env_id, default_env.source);
+                    if let Some(mut env_spec) = default_env.env_spec {
+                        env_spec.record_provenance(&default_env.source);
+                        project_env_spec = Some(env_spec);
+                    }
+                    default_env.env_id
+                }
+            };
+            if run_matches.get_flag("dry-run") {
+                if context.has_env(&env_id).await
+                    && run_matches.value_source("channels") != Some(ValueSource::CommandLine)
+                {
+                    viva_env_spec.channels.clear();
+                }
+                if let Some(project_env_spec) = project_env_spec {
+                    viva_env_spec.append_spec(project_env_spec);
+                }
+                let mut env = context.preview_env(&env_id, &viva_env_spec).await?;
+                print_sync_plan(&env_id, env.plan_sync().await?.as_ref());
+                for id in &also_env_ids {
+                    let also_env = context.get_env_mut(id).await?;
+                    print_sync_plan(id, also_env.plan_sync().await?.as_ref());
+                }
+                println!("Would run: {}", cmd.join(" "));
+                return Ok(());
+            }
+
+            // the spec of a project is part of the project environment, so it's always persisted
+            if let Some(project_env_spec) = &project_env_spec {
+                context
+                    .merge_env_specs(&env_id, project_env_spec, true, true)
+                    .await?;
+            }
+
+            // extra specs for an existing environment are only used for this run (in a temporary
+            // overlay environment), unless they are persisted explicitly
+            let mut overlay = None;
+            if context.has_env(&env_id).await {
+                if run_matches.value_source("channels") != Some(ValueSource::CommandLine) {
+                    viva_env_spec.channels.clear();
+                }
+                let env = context.get_env(&env_id).await?;
+                if !viva_env_spec.is_satisfied_by(&env.spec)
+                    && !run_matches.get_flag("persist")
+                    && !context.confirm_persist_specs(&env.id, &viva_env_spec)?
+                {
+                    overlay = Some(context.create_overlay_env(&env_id, &viva_env_spec).await?);
+                }
+            }
+            if overlay.is_none() {
+                context
+                    .merge_env_specs(&env_id, &viva_env_spec, true, true)
+                    .await?;
+            }
+
+            // the overlay is removed however the run ends, so nothing in between may return early
+            let result = async {
+                let check_strategy = check_strategy(run_matches)?;
+                for id in std::iter::once(&env_id).chain(also_env_ids.iter()) {
+                    if overlay.is_none() || id != &env_id {
+                        context.get_env_mut(id).await?.sync(check_strategy).await?;
+                    }
+                }
+
+                match &mut overlay {
+                    Some(overlay_env) => {
+                        eprintln!("Using a temporary overlay of environment '{}' for the extra specs", env_id);
+                        overlay_env.sync(check_strategy).await?;
+                        run_in_envs(&context, overlay_env, &also_env_ids, &cmd, run_matches.get_flag("shell")).await
+                    }
+                    None => {
+                        let env = context.get_env(&env_id).await?;
+                        run_in_envs(&context, env, &also_env_ids, &cmd, run_matches.get_flag("shell")).await
+                    }
+                }
+            }
+            .await;
+            if let Some(overlay_env) = &overlay {
+                context.remove_overlay_env(overlay_env).await?;
+            }
+            let status = result?;
+            if !status.success() {
+                std::process::exit(status.code().unwrap_or(1));
+            }
+        }
+        Some(("cache", cache_matches)) => match cache_matches.subcommand() {
+            Some(("info", info_matches)) => {
+                debug!("running 'cache info' subcommand");
+                let info = cache::cache_info(context.get_config())?;
+                match info_matches.get_one::<String>("output").map(|s| s.as_str()) {
+                    Some("json") => println!("{}", serde_json::to_string_pretty(&info)?),
+                    Some("yaml") => print!("{}", serde_yaml::to_string(&info)?),
+                    _ => {
+                        println!("Location: {}", info.location.display());
+                        println!(
+                            "Total size: {} (packages: {}, repodata: {})",
+                            indicatif::HumanBytes(info.total_size),
+                            indicatif::HumanBytes(info.packages_size),
+                            indicatif::HumanBytes(info.repodata_size)
+                        );
+                        if !info.channels.is_empty() {
+                            println!();
+                            let mut table = Table::new();
+                            table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                            table.set_titles(row!["channel", "packages", "packages size", "repodata size"]);
+                            for (channel, channel_info) in &info.channels {
+                                table.add_row(row![
+                                    channel,
+                                    channel_info.packages,
+                                    indicatif::HumanBytes(channel_info.packages_size),
+                                    indicatif::HumanBytes(channel_info.repodata_size)
+                                ]);
+                            }
+                            table.printstd();
+                        }
+                    }
+                }
+            }
+            Some(("clean", clean_matches)) => {
+                debug!("running 'cache clean' subcommand");
+                let older_than = clean_matches.get_one::<u64>("older-than").copied();
+                let result = cache::clean_cache(context.get_config(), older_than)?;
+                println!(
+                    "Removed {} cache entries, freed {}.",
+                    result.removed.len(),
+                    indicatif::HumanBytes(result.freed_bytes)
+                );
+            }
+            Some(("prune", _prune_matches)) => {
+                debug!("running 'cache prune' subcommand");
+                let config = context.get_config();
+                // without a retention policy, only leftover downloads are removed
+                if config.cache_max_size.is_none() && config.cache_max_age_days.is_none() {
+                    println!("No cache retention policy configured, only removing leftover downloads.");
+                }
+                let result = cache::prune_cache(config)?;
+                println!(
+                    "Removed {} cache entries, freed {}.",
+                    result.removed.len(),
+                    indicatif::HumanBytes(result.freed_bytes)
+                );
+            }
+            Some(("key", key_matches)) => {
+                debug!("running 'cache key' subcommand");
+                let env_id = key_matches
+                    .get_one::<String>("env-id")
+                    .expect("No environment name provided.");
+                context.merge_all_apps().await?;
+                println!("{}", context.get_env(env_id).await?.cache_key().await);
+            }
+            _ => {
+                println!("No cache subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        Some(("shell", shell_matches)) => {
+            debug!("running 'shell' subcommand");
+            let env_id = shell_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            let shell = match shell_matches.get_one::<String>("shell") {
+                Some(shell) => shell.parse::<activation::ShellType>()?,
+                None => activation::ShellType::detect(),
+            };
+            context.merge_all_apps().await?;
+            let env = context.get_env_mut(env_id).await?;
+            env.sync(EnvCheckStrategy::Auto).await?;
+            let exit_code = env.run_interactive_shell(shell).await?;
+            if exit_code != 0 {
+                std::process::exit(exit_code);
+            }
+        }
+        Some(("import-reqs", import_matches)) => {
+            debug!("running 'import-reqs' subcommand");
+            let requirements_file = import_matches
+                .get_one::<PathBuf>("requirements")
+                .expect("No requirements file provided.");
+            let env_id = import_matches
+                .get_one::<String>("env-id")
+                .expect("No environment id provided.");
+            let import = import_requirements(requirements_file).await?;
+            for note in &import.notes {
+                eprintln!("Note: {}", note);
+            }
+            if !import.pip_specs.is_empty() && !import_matches.get_flag("pip-fallback") {
+                let pip_only = import
+                    .pip_specs
+                    .iter()
+                    .map(|(requirement, reason)| format!("  {} ({})", requirement, reason))
+                    .collect::<Vec<String>>();
+                bail!(
+                    "Some requirements can only be installed with pip (use '--pip-fallback' to add them as pip specs):\n{}",
+                    pip_only.join("\n")
+                );
+            }
+
+            let mut env_spec = VivaEnvSpec::new();
+            if !context.has_env(env_id).await
+                || import_matches.value_source("channels") == Some(ValueSource::CommandLine)
+            {
+                env_spec.channels = import_matches
+                    .get_many::<String>("channels")
+                    .map(|channels| channels.cloned().collect())
+                    .unwrap_or_default();
+            }
+            env_spec.pkg_specs = import.pkg_specs;
+            env_spec.pip_specs = import.pip_specs.into_iter().map(|(requirement, _)| requirement).collect();
+            env_spec.record_provenance(&requirements_file.display().to_string());
+
+            if import_matches.get_flag("dry-run") {
+                print!("{}", serde_yaml::to_string(&env_spec.normalized())?);
+                return Ok(());
+            }
+            context.merge_env_specs(env_id, &env_spec, true, true).await?;
+            println!(
+                "Added {} package specs and {} pip specs to environment '{}' (run 'viva sync {}' to install them).",
+                env_spec.pkg_specs.len(),
+                env_spec.pip_specs.len(),
+                env_id,
+                env_id
+            );
+        }
+        Some(("solve", solve_matches)) => {
+            debug!("running 'solve' subcommand");
+            let env_id = solve_matches
+                .get_one::<String>("env-id")
                 .expect("No environment name provided.");
-            let _viva_env_spec = extract_env_spec(run_matches)?;
+            context.merge_all_apps().await?;
+            let env = context.get_env(env_id).await?;
+            let platform = solve_matches.get_one::<String>("platform").map(|s| s.as_str());
+            match solve_matches.get_one::<String>("explain") {
+                Some(package_name) => {
+                    let explanation = env.explain_package(package_name, platform).await?;
+                    println!(
+                        "{} {} {} (from: {})",
+                        explanation.name, explanation.version, explanation.build, explanation.channel
+                    );
+                    println!("\nRequired by:");
+                    for chain in &explanation.chains {
+                        println!("  {}", chain.join("\n    -> "));
+                    }
+                    if explanation.rejected_newer.is_empty() {
+                        println!("\nThis is the newest available version.");
+                    } else {
+                        println!("\nNewer versions that were not chosen:");
+                        for candidate in &explanation.rejected_newer {
+                            let reason = match candidate.excluded_by.is_empty() {
+                                true => "excluded indirectly (e.g. by its own dependencies)".to_string(),
+                                false => format!("excluded by: {}", candidate.excluded_by.join(", ")),
+                            };
+                            println!("  {} {} ({}): {}", candidate.version, candidate.build, candidate.channel, reason);
+                        }
+                    }
+                }
+                None => {
+                    let mut table = Table::new();
+                    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                    table.set_titles(row!["package", "version", "build", "channel"]);
+                    let packages = env.solve_packages(platform).await?;
+                    for pkg in &packages {
+                        table.add_row(row![pkg.name, pkg.version, pkg.build, pkg.channel]);
+                    }
+                    table.printstd();
+                    for (executable, package) in env.find_protected_executables_in_solution(platform).await? {
+                        eprintln!(
+                            "Warning: package '{}' provides an executable that shadows the protected system executable '{}'.",
+                            package, executable
+                        );
+                    }
+                }
+            }
+        }
+        Some(("doctor", doctor_matches)) => {
+            debug!("running 'doctor' subcommand");
+            let mut diagnostics = doctor::diagnose(&context).await;
+            if doctor_matches.get_flag("check-channels") {
+                let platforms = doctor_matches
+                    .get_many::<String>("platform")
+                    .map(|platforms| platforms.cloned().collect::<Vec<_>>())
+                    .unwrap_or_default();
+                diagnostics.extend(doctor::diagnose_availability(&context, &platforms).await?);
+            }
+            if diagnostics.is_empty() {
+                println!("No problems found.");
+            }
+            for diagnostic in &diagnostics {
+                println!("{}", diagnostic);
+            }
+            if diagnostics.iter().any(|d| d.severity == doctor::Severity::Error) {
+                std::process::exit(1);
+            }
+        }
+        Some(("support-bundle", support_bundle_matches)) => {
+            debug!("running 'support-bundle' subcommand");
+            let output = support_bundle_matches
+                .get_one::<PathBuf>("output")
+                .expect("No output path provided.");
+            let log_dir = logging::LogSettings::from_config(context.get_config(), &default_log_dir)?.log_dir;
+            let entries = support::create_support_bundle(&context, &log_dir, output).await?;
+            println!("Created support bundle: {}", output.display());
+            for entry in entries {
+                println!("  {}", entry);
+            }
+            println!("Credentials and the path of your home directory were redacted, please check the contents before sharing it anyway.");
+        }
+        Some(("explain", explain_matches)) => match explain_matches.subcommand() {
+            Some(("sync", explain_sync_matches)) => {
+                debug!("running 'explain sync' subcommand");
+                let env_id = explain_sync_matches
+                    .get_one::<String>("env-id")
+                    .expect("No environment name provided.");
+                let explanation = context.get_env(env_id).await?.explain_sync();
+                println!(
+                    "Environment '{}': {}",
+                    env_id,
+                    explanation.sync_status.to_string()
+                );
+                let reasons = explanation.reasons();
+                if reasons.is_empty() {
+                    println!("  all package specs and channels are present in the environment");
+                }
+                for reason in reasons {
+                    println!("  - {}", reason);
+                }
+            }
+            _ => {
+                println!("No explain subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        Some(("relocate", relocate_matches)) => {
+            debug!("running 'relocate' subcommand");
+            let env_id = relocate_matches
+                .get_one::<String>("env-id")
+                .expect("No environment name provided.");
+            let new_path = relocate_matches
+                .get_one::<PathBuf>("new-path")
+                .expect("No path provided.");
+            let rewritten = context.relocate_env(env_id, new_path).await?;
+            println!(
+                "Moved environment '{}' to: {} ({} files updated)",
+                env_id,
+                context.get_env(env_id).await?.get_env_path().display(),
+                rewritten
+            );
+        }
+        Some(("matrix", matrix_matches)) => {
+            debug!("running 'matrix' subcommand");
+            let template = matrix_matches
+                .get_one::<String>("spec")
+                .expect("No template spec provided.");
+            let axes = matrix_matches
+                .get_many::<String>("vary")
+                .expect("No matrix axis provided.")
+                .map(|axis| axis.parse::<MatrixAxis>())
+                .collect::<Result<Vec<MatrixAxis>>>()?;
+
+            let template_path = PathBuf::from(template);
+            let (name, template_spec) = match template_path.is_file() {
+                true => {
+                    let name = template_path
+                        .file_stem()
+                        .map(|stem| stem.to_string_lossy().to_string())
+                        .unwrap_or_else(|| String::from("matrix"));
+                    (name, VivaEnvSpec::read_env_spec(&template_path).await?)
+                }
+                false => (
+                    template.replace('/', "-"),
+                    context.get_env(template).await?.spec.clone(),
+                ),
+            };
+
+            let mut entries = expand_matrix(&name, &template_spec, &axes);
+            if let Some(lockfile_dir) = matrix_matches.get_one::<PathBuf>("lock") {
+                let platforms = match matrix_matches.get_many::<String>("platform") {
+                    Some(platforms) => platforms.cloned().collect::<Vec<String>>(),
+                    None => vec![],
+                };
+                for entry in entries.iter_mut() {
+                    lock_matrix_entry(entry, lockfile_dir, &platforms, context.get_config()).await?;
+                }
+            }
 
-            println!("run");
+            match matrix_matches.get_one::<String>("output").map(|s| s.as_str()) {
+                Some("json") => println!("{}", serde_json::to_string_pretty(&entries)?),
+                Some("yaml") => print!("{}", serde_yaml::to_string(&entries)?),
+                _ => {
+                    let mut table = Table::new();
+                    table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                    table.set_titles(row!["name", "package specs", "spec hash", "lockfile"]);
+                    for entry in &entries {
+                        let lockfile = entry
+                            .lockfile
+                            .as_ref()
+                            .map(|path| path.display().to_string())
+                            .unwrap_or_default();
+                        table.add_row(row![entry.name, entry.spec.pkg_specs.join("\n"), entry.spec_hash, lockfile]);
+                    }
+                    table.printstd();
+                }
+            }
         }
+        Some(("auth", auth_matches)) => match auth_matches.subcommand() {
+            Some(("login", login_matches)) => {
+                debug!("running 'auth login' subcommand");
+                let channel = login_matches
+                    .get_one::<String>("channel")
+                    .expect("No channel provided.");
+                let credentials = if let Some(token) = login_matches.get_one::<String>("token") {
+                    auth::ChannelCredentials::CondaToken(token.clone())
+                } else if let Some(token) = login_matches.get_one::<String>("bearer") {
+                    auth::ChannelCredentials::Bearer(token.clone())
+                } else {
+                    let username = login_matches
+                        .get_one::<String>("username")
+                        .expect("No username provided.");
+                    let password = match login_matches.get_one::<String>("password") {
+                        Some(password) => password.clone(),
+                        None => {
+                            if !console::user_attended() {
+                                bail!("No password provided, use the '--password' option in non-interactive sessions.");
+                            }
+                            eprint!("Password for '{}': ", username);
+                            console::Term::stderr().read_secure_line()?
+                        }
+                    };
+                    auth::ChannelCredentials::Basic {
+                        username: username.clone(),
+                        password: Some(password),
+                    }
+                };
+                let channel_config = context.get_config().channel_config()?;
+                let key = auth::store_credentials(channel, &channel_config, credentials)?;
+                println!("Stored credentials for: {}", key);
+            }
+            Some(("logout", logout_matches)) => {
+                debug!("running 'auth logout' subcommand");
+                let channel = logout_matches
+                    .get_one::<String>("channel")
+                    .expect("No channel provided.");
+                let channel_config = context.get_config().channel_config()?;
+                let key = auth::credentials_key(channel, &channel_config);
+                match auth::remove_credentials(channel, &channel_config)? {
+                    true => println!("Removed credentials for: {}", key),
+                    false => println!("No credentials stored for: {}", key),
+                }
+            }
+            Some(("list", _list_matches)) => {
+                debug!("running 'auth list' subcommand");
+                let mut table = Table::new();
+                table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                table.set_titles(row!["channel", "credentials", "source"]);
+                for (key, credentials) in auth::read_stored_credentials()? {
+                    table.add_row(row![key, credentials.redacted(), "auth login"]);
+                }
+                for (key, credentials) in &context.get_config().channel_auth {
+                    table.add_row(row![key, credentials.redacted(), "config"]);
+                }
+                table.printstd();
+            }
+            _ => {
+                println!("No auth subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        #[cfg(not(feature = "daemon"))]
+        Some(("daemon", _daemon_matches)) => {
+            bail!("This build of viva does not include the daemon (cargo feature 'daemon').");
+        }
+        #[cfg(feature = "daemon")]
+        Some(("daemon", daemon_matches)) => match daemon_matches.subcommand() {
+            Some(("start", start_matches)) => {
+                debug!("running 'daemon start' subcommand");
+                if start_matches.get_flag("foreground") {
+                    daemon::run_daemon(&mut context).await?;
+                } else {
+                    if let Some(info) = daemon::read_daemon_info(&context) {
+                        bail!("Daemon is already running (pid {}): {}", info.pid, info.endpoint);
+                    }
+                    let mut command = std::process::Command::new(std::env::current_exe()?);
+                    command
+                        .args(["daemon", "start", "--foreground"])
+                        .stdin(std::process::Stdio::null())
+                        .stdout(std::process::Stdio::null())
+                        .stderr(std::process::Stdio::null());
+                    // detach from the terminal, so the daemon survives the shell it was started from
+                    #[cfg(unix)]
+                    {
+                        use std::os::unix::process::CommandExt;
+                        command.process_group(0);
+                    }
+                    let child = command.spawn()?;
+                    println!("Started daemon (pid {}): {}", child.id(), daemon::daemon_endpoint(&context));
+                }
+            }
+            Some(("stop", _stop_matches)) => {
+                debug!("running 'daemon stop' subcommand");
+                match daemon::send_request(&context, &daemon::DaemonRequest::Shutdown, 5000)? {
+                    Some(_) => println!("Stopped daemon."),
+                    None => println!("Daemon is not running."),
+                }
+            }
+            Some(("status", _status_matches)) => {
+                debug!("running 'daemon status' subcommand");
+                match daemon::send_request(&context, &daemon::DaemonRequest::Ping, 1000)? {
+                    Some(daemon::DaemonResponse::Pong { pid, version, cached_plans }) => {
+                        println!("Daemon is running (pid {}, version {}): {}", pid, version, daemon::daemon_endpoint(&context));
+                        println!("Cached launch plans: {}", cached_plans);
+                    }
+                    Some(response) => bail!("Unexpected response from daemon: {:?}", response),
+                    None => println!("Daemon is not running."),
+                }
+            }
+            _ => {
+                println!("No daemon subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        Some(("trust", trust_matches)) => match trust_matches.subcommand() {
+            Some(("list", _list_matches)) => {
+                debug!("running 'trust list' subcommand");
+                let trust_store = context.get_trust_store().await?;
+                let mut table = Table::new();
+                table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                table.set_titles(row!["collection", "key id", "public key"]);
+                for (collection_id, pinned) in trust_store.get_pinned_keys() {
+                    table.add_row(row![collection_id, pinned.key_id, pinned.public_key]);
+                }
+                table.printstd();
+            }
+            Some(("pin", pin_matches)) => {
+                debug!("running 'trust pin' subcommand");
+                let collection_id = pin_matches
+                    .get_one::<String>("collection")
+                    .expect("No collection id provided.");
+                let public_key = pin_matches
+                    .get_one::<String>("public-key")
+                    .expect("No public key provided.");
+                let mut trust_store = context.get_trust_store().await?;
+                let pinned = trust_store.pin(collection_id, public_key).await?;
+                println!("Pinned key '{}' for collection '{}'.", pinned.key_id, collection_id);
+            }
+            Some(("forget", forget_matches)) => {
+                debug!("running 'trust forget' subcommand");
+                let collection_id = forget_matches
+                    .get_one::<String>("collection")
+                    .expect("No collection id provided.");
+                let mut trust_store = context.get_trust_store().await?;
+                match trust_store.forget(collection_id).await? {
+                    true => println!("Forgot the pinned key of collection '{}'.", collection_id),
+                    false => println!("No key pinned for collection '{}'.", collection_id),
+                }
+            }
+            _ => {
+                println!("No trust subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        Some(("collection", collection_matches)) => match collection_matches.subcommand() {
+            Some(("add", add_matches)) => {
+                debug!("running 'collection add' subcommand");
+                let collection_id = add_matches
+                    .get_one::<String>("collection")
+                    .expect("No collection id provided.");
+                let url = add_matches.get_one::<String>("url").expect("No url provided.");
+                let refresh_interval = add_matches.get_one::<String>("refresh-interval").cloned();
+                if let Some(refresh_interval) = &refresh_interval {
+                    parse_interval(refresh_interval)?;
+                }
+                let config_path = PathBuf::from(context.project_dirs.config_dir());
+                let mut remotes = RemoteEnvCollection::read_registry(&config_path).await?;
+                if remotes.contains_key(collection_id) {
+                    bail!("A remote collection with id '{}' exists already.", collection_id);
+                }
+                let remote = RemoteCollectionConfig {
+                    url: url.clone(),
+                    refresh_interval,
+                };
+                // fetch it right away, so a wrong url (or signature) is noticed before it's added
+                let mut collection =
+                    RemoteEnvCollection::create(collection_id, remote.clone(), context.project_dirs.data_dir(), context.get_config())
+                        .await?;
+                collection.refresh().await?;
+                remotes.insert(collection_id.clone(), remote);
+                RemoteEnvCollection::write_registry(&config_path, &remotes).await?;
+                let env_ids = collection.get_env_ids().await;
+                println!(
+                    "Added collection '{}' with {} environment(s): {}",
+                    collection_id,
+                    env_ids.len(),
+                    env_ids.join(", ")
+                );
+            }
+            Some(("list", _list_matches)) => {
+                debug!("running 'collection list' subcommand");
+                let config_path = PathBuf::from(context.project_dirs.config_dir());
+                let mut table = Table::new();
+                table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                table.set_titles(row!["collection", "url", "refresh interval", "refreshed", "environments"]);
+                for (collection_id, remote) in RemoteEnvCollection::read_registry(&config_path).await? {
+                    let registered_id = format!("remote:{}", collection_id);
+                    let env_ids = context
+                        .list_envs()
+                        .await
+                        .values()
+                        .filter(|env| env.collection_id == registered_id)
+                        .map(|env| env.id.clone())
+                        .collect::<Vec<String>>();
+                    let refreshed = RemoteEnvCollection::refreshed_at(context.project_dirs.data_dir(), &collection_id)
+                        .and_then(|refreshed| refreshed.duration_since(std::time::UNIX_EPOCH).ok())
+                        .map(|refreshed| format_age(refreshed.as_secs()))
+                        .unwrap_or_else(|| String::from("never"));
+                    table.add_row(row![
+                        collection_id,
+                        remote.url,
+                        remote.refresh_interval.as_deref().unwrap_or(DEFAULT_REFRESH_INTERVAL),
+                        refreshed,
+                        env_ids.join("\n")
+                    ]);
+                }
+                table.printstd();
+            }
+            Some(("refresh", refresh_matches)) => {
+                debug!("running 'collection refresh' subcommand");
+                let config_path = PathBuf::from(context.project_dirs.config_dir());
+                let mut remotes = RemoteEnvCollection::read_registry(&config_path).await?;
+                if let Some(collection_ids) = refresh_matches.get_many::<String>("collection") {
+                    let collection_ids = collection_ids.collect::<Vec<&String>>();
+                    for collection_id in &collection_ids {
+                        if !remotes.contains_key(*collection_id) {
+                            bail!("No remote collection found with id: {}", collection_id);
+                        }
+                    }
+                    remotes.retain(|collection_id, _| collection_ids.contains(&collection_id));
+                }
+                let mut failed = false;
+                for (collection_id, remote) in remotes {
+                    let result = async {
+                        let mut collection =
+                            RemoteEnvCollection::create(&collection_id, remote, context.project_dirs.data_dir(), context.get_config())
+                                .await?;
+                        collection.refresh().await?;
+                        Ok::<usize, anyhow::Error>(collection.get_env_ids().await.len())
+                    }
+                    .await;
+                    match result {
+                        Ok(env_count) => println!("Refreshed collection '{}': {} environment(s)", collection_id, env_count),
+                        Err(e) => {
+                            eprintln!("Failed to refresh collection '{}': {:#}", collection_id, e);
+                            failed = true;
+                        }
+                    }
+                }
+                if failed {
+                    std::process::exit(1);
+                }
+            }
+            Some(("remove", remove_matches)) => {
+                debug!("running 'collection remove' subcommand");
+                let collection_id = remove_matches
+                    .get_one::<String>("collection")
+                    .expect("No collection id provided.");
+                let config_path = PathBuf::from(context.project_dirs.config_dir());
+                let mut remotes = RemoteEnvCollection::read_registry(&config_path).await?;
+                if remotes.remove(collection_id).is_none() {
+                    bail!("No remote collection found with id: {}", collection_id);
+                }
+                RemoteEnvCollection::write_registry(&config_path, &remotes).await?;
+                let cache_dir = RemoteEnvCollection::cache_dir(context.project_dirs.data_dir(), collection_id);
+                if cache_dir.exists() {
+                    fs::remove_dir_all(&cache_dir)?;
+                }
+                println!("Removed collection '{}'.", collection_id);
+            }
+            _ => {
+                println!("No collection subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        Some(("workspace", workspace_matches)) => match workspace_matches.subcommand() {
+            Some(("list", _list_matches)) => {
+                debug!("running 'workspace list' subcommand");
+                let config_path = PathBuf::from(context.project_dirs.config_dir());
+                let mut table = Table::new();
+                table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+                table.set_titles(row!["workspace", "channels", "pins", "environments"]);
+                for (workspace_id, workspace_file) in WorkspaceEnvCollection::find_workspaces(&config_path) {
+                    let collection =
+                        WorkspaceEnvCollection::create(&workspace_id, workspace_file, context.project_dirs.data_dir())
+                            .await?;
+                    let workspace = collection.get_workspace();
+                    table.add_row(row![
+                        workspace_id,
+                        workspace.channels.join("\n"),
+                        workspace.pins.join("\n"),
+                        collection.get_env_ids().await.join("\n")
+                    ]);
+                }
+                table.printstd();
+            }
+            Some(("sync", sync_matches)) => {
+                debug!("running 'workspace sync' subcommand");
+                let workspace_id = sync_matches
+                    .get_one::<String>("workspace")
+                    .expect("No workspace provided.");
+                let config_path = PathBuf::from(context.project_dirs.config_dir());
+                let workspace_file = match WorkspaceEnvCollection::find_workspaces(&config_path).remove(workspace_id) {
+                    Some(workspace_file) => workspace_file,
+                    None => bail!("No workspace found with id: {}", workspace_id),
+                };
+                let collection =
+                    WorkspaceEnvCollection::create(workspace_id, workspace_file, context.project_dirs.data_dir()).await?;
+                let env_ids = collection.get_env_ids().await.into_iter().collect::<HashSet<String>>();
+                match env_ids.is_empty() {
+                    true => println!("Workspace '{}' has no environments.", workspace_id),
+                    false => context.sync_envs(&env_ids, EnvCheckStrategy::Auto).await?,
+                }
+            }
+            _ => {
+                println!("No workspace subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        Some(("snapshot", snapshot_matches)) => match snapshot_matches.subcommand() {
+            Some(("create", create_matches)) => {
+                debug!("running 'snapshot create' subcommand");
+                let env_id = create_matches
+                    .get_one::<String>("env-id")
+                    .expect("No environment name provided.");
+                let name = create_matches.get_one::<String>("name");
+                let name = context.create_snapshot(env_id, name.map(|n| n.as_str())).await?;
+                println!("Created snapshot of environment '{}': {}", env_id, name);
+            }
+            Some(("restore", restore_matches)) => {
+                debug!("running 'snapshot restore' subcommand");
+                let env_id = restore_matches
+                    .get_one::<String>("env-id")
+                    .expect("No environment name provided.");
+                let name = restore_matches.get_one::<String>("name");
+                let name = context.restore_snapshot(env_id, name.map(|n| n.as_str())).await?;
+                println!("Restored environment '{}' from snapshot: {}", env_id, name);
+            }
+            Some(("list", list_matches)) => {
+                debug!("running 'snapshot list' subcommand");
+                let env_id = list_matches
+                    .get_one::<String>("env-id")
+                    .expect("No environment name provided.");
+                for name in context.list_snapshots(env_id).await? {
+                    println!("{}", name);
+                }
+            }
+            Some(("delete", delete_matches)) => {
+                debug!("running 'snapshot delete' subcommand");
+                let env_id = delete_matches
+                    .get_one::<String>("env-id")
+                    .expect("No environment name provided.");
+                let name = delete_matches
+                    .get_one::<String>("name")
+                    .expect("No snapshot name provided.");
+                context.delete_snapshot(env_id, name).await?;
+                println!("Deleted snapshot of environment '{}': {}", env_id, name);
+            }
+            _ => {
+                println!("No snapshot subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
+        Some(("remote", remote_matches)) => match remote_matches.subcommand() {
+            Some(("run", remote_run_matches)) => {
+                debug!("running 'remote run' subcommand");
+                let host = remote_run_matches
+                    .get_one::<String>("host")
+                    .expect("No host provided.");
+                let env_id = remote_run_matches
+                    .get_one::<String>("env-id")
+                    .expect("No environment name provided.");
+                let viva_env_spec = extract_env_spec(remote_run_matches)?;
+                let cmd = remote_run_matches
+                    .get_many::<String>("cmd")
+                    .expect("No command provided.")
+                    .map(|s| s.to_string())
+                    .collect::<Vec<String>>();
+
+                let status = remote::remote_run(host, env_id, &viva_env_spec, &cmd).await?;
+                if !status.success() {
+                    std::process::exit(status.code().unwrap_or(1));
+                }
+            }
+            _ => {
+                println!("No remote subcommand provided, use the '--help' flag to get more information.)");
+            }
+        },
 
         _ => {
             println!("No subcommand provided, use the '--help' flag to get more information.)");
@@ -350,3 +3239,67 @@ async fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn run_cmd(args: &[&str]) -> Vec<String> {
+        let matches = create_command(&VivaConfig::default())
+            .try_get_matches_from(args)
+            .expect("Failed to parse arguments");
+        let (_, run_matches) = matches.subcommand().expect("No subcommand");
+        run_matches
+            .get_many::<String>("cmd")
+            .expect("No command")
+            .cloned()
+            .collect()
+    }
+
+    #[test]
+    fn test_passthrough_quotes() {
+        let cmd = run_cmd(&["viva", "run", "--env", "x", "--", "python", "-c", "print('a b')"]);
+        assert_eq!(cmd, vec!["python", "-c", "print('a b')"]);
+    }
+
+    #[test]
+    fn test_passthrough_child_flags() {
+        let cmd = run_cmd(&["viva", "run", "--", "ls", "--help", "-v", "--env", "y"]);
+        assert_eq!(cmd, vec!["ls", "--help", "-v", "--env", "y"]);
+
+        let cmd = run_cmd(&["viva", "run", "--", "git", "log", "--", "src"]);
+        assert_eq!(cmd, vec!["git", "log", "--", "src"]);
+    }
+
+    #[test]
+    fn test_passthrough_empty_args() {
+        let cmd = run_cmd(&["viva", "run", "--", "printf", "%s|", "", "x"]);
+        assert_eq!(cmd, vec!["printf", "%s|", "", "x"]);
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_shell_script() {
+        let script = shell_script(&["echo $HOME | wc -c".to_string()]);
+        assert_eq!(script, "echo $HOME | wc -c");
+
+        let script = shell_script(&["echo".to_string(), "a b".to_string(), "".to_string()]);
+        assert_eq!(script, "echo 'a b' ''");
+    }
+
+    #[test]
+    fn test_check_strategy() {
+        let parse = |args: &[&str]| {
+            let matches = create_command(&VivaConfig::default())
+                .try_get_matches_from(args)
+                .expect("Failed to parse arguments");
+            let (_, run_matches) = matches.subcommand().expect("No subcommand");
+            check_strategy(run_matches).expect("Invalid check strategy")
+        };
+        assert_eq!(parse(&["viva", "run", "--", "ls"]), EnvCheckStrategy::Auto);
+        assert_eq!(parse(&["viva", "run", "--check", "skip", "--", "ls"]), EnvCheckStrategy::Skip);
+        assert_eq!(parse(&["viva", "sync", "--check", "force"]), EnvCheckStrategy::Force);
+        // the old name of 'force'
+        assert_eq!(parse(&["viva", "run", "--check", "always", "--", "ls"]), EnvCheckStrategy::Force);
+    }
+}
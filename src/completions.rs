@@ -0,0 +1,458 @@
+// Shell completions.
+//
+// The completion scripts for bash, zsh and fish are generated from the clap command (so they know
+// all subcommands), but environment and app ids are looked up when completing, by calling
+// `viva completions <shell> --candidates envs|apps`, so they are always up-to-date. zsh and fish
+// show a hint next to each id: whether the environment is ready to use (or would be created or
+// synced first), and what it contains.
+
+use anyhow::{bail, Result};
+use clap::{ArgAction, Command};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::str::FromStr;
+
+use crate::models::app::VivaApp;
+use crate::models::environment::{EnvSyncStatus, VivaEnv};
+
+/// The maximum length of the spec summary in a completion hint.
+const HINT_SPECS_LENGTH: usize = 60;
+
+/// The shells completion scripts can be generated for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CompletionShell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+impl FromStr for CompletionShell {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "bash" => Ok(CompletionShell::Bash),
+            "zsh" => Ok(CompletionShell::Zsh),
+            "fish" => Ok(CompletionShell::Fish),
+            _ => bail!("Unsupported shell '{}' (available: bash, zsh, fish)", s),
+        }
+    }
+}
+
+/// An id that can be completed, with a hint for shells that can display one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    pub id: String,
+    pub hint: String,
+}
+
+/// A short summary of package specs (truncated if there are many).
+fn summarize_specs(pkg_specs: &[String]) -> String {
+    let summary = pkg_specs.join(", ");
+    match summary.chars().count() > HINT_SPECS_LENGTH {
+        true => format!("{}...", summary.chars().take(HINT_SPECS_LENGTH).collect::<String>()),
+        false => summary,
+    }
+}
+
+/// Whether an environment can be used right away.
+fn env_status(env: &VivaEnv) -> &'static str {
+    if !env.get_env_path().exists() {
+        return "not created";
+    }
+    match env.sync_status {
+        EnvSyncStatus::Synced => "ready",
+        _ => "needs sync",
+    }
+}
+
+/// The registered environments as completion candidates (the sync status of the environments
+/// should be checked before).
+pub fn env_candidates(envs: &BTreeMap<String, VivaEnv>) -> Vec<Candidate> {
+    envs.values()
+        .map(|env| {
+            let specs = summarize_specs(&env.spec.pkg_specs);
+            let hint = match specs.is_empty() {
+                true => String::from(env_status(env)),
+                false => format!("{}: {}", env_status(env), specs),
+            };
+            Candidate {
+                id: env.id.clone(),
+                hint,
+            }
+        })
+        .collect()
+}
+
+/// The registered apps as completion candidates, with the status of their environments.
+pub fn app_candidates(apps: &BTreeMap<String, VivaApp>, envs: &BTreeMap<String, VivaEnv>) -> Vec<Candidate> {
+    apps.values()
+        .map(|app| {
+            let env_id = app.get_env_id();
+            let status = match envs.get(env_id) {
+                Some(env) => env_status(env),
+                None => "env missing",
+            };
+            Candidate {
+                id: app.id.clone(),
+                hint: format!("{}: {} (env '{}')", status, app.spec.executable, env_id),
+            }
+        })
+        .collect()
+}
+
+/// Completion candidates in the format the completion script of a shell expects (one per line).
+pub fn render_candidates(shell: CompletionShell, candidates: &[Candidate]) -> String {
+    let mut rendered = String::new();
+    for candidate in candidates {
+        let _ = match shell {
+            CompletionShell::Bash => writeln!(rendered, "{}", candidate.id),
+            // for '_describe', colons in the id have to be escaped
+            CompletionShell::Zsh => writeln!(rendered, "{}:{}", candidate.id.replace(':', "\\:"), candidate.hint),
+            CompletionShell::Fish => writeln!(rendered, "{}\t{}", candidate.id, candidate.hint),
+        };
+    }
+    rendered
+}
+
+/// What the completion scripts need to know about the command tree.
+#[derive(Debug, Default)]
+struct CompletionTargets {
+    /// The (visible) subcommands of every command path (e.g. `viva env`).
+    subcommands: BTreeMap<String, Vec<String>>,
+    /// The command paths whose first positional argument is an environment id (or app id), and
+    /// whether it accepts multiple ones.
+    env_positionals: BTreeMap<String, bool>,
+    app_positionals: BTreeMap<String, bool>,
+    /// The options that take an environment id as value (e.g. `--env`).
+    env_options: Vec<String>,
+    /// All options that take a value (so their values are not mistaken for positional arguments).
+    value_options: Vec<String>,
+}
+
+fn collect_targets(cmd: &Command, path: &str, targets: &mut CompletionTargets) {
+    let subcommands = cmd
+        .get_subcommands()
+        .filter(|sub| !sub.is_hide_set())
+        .map(|sub| String::from(sub.get_name()))
+        .collect::<Vec<String>>();
+    targets.subcommands.insert(String::from(path), subcommands);
+
+    if let Some(positional) = cmd.get_arguments().find(|arg| arg.is_positional()) {
+        let multiple = matches!(positional.get_action(), ArgAction::Append);
+        match positional.get_id().as_str() {
+            "env-id" => {
+                targets.env_positionals.insert(String::from(path), multiple);
+            }
+            "app" => {
+                targets.app_positionals.insert(String::from(path), multiple);
+            }
+            _ => {}
+        }
+    }
+    for arg in cmd.get_arguments() {
+        if arg.is_positional() || !arg.get_action().takes_values() {
+            continue;
+        }
+        let names = arg
+            .get_long()
+            .map(|long| format!("--{}", long))
+            .into_iter()
+            .chain(arg.get_short().map(|short| format!("-{}", short)));
+        for name in names {
+            if matches!(arg.get_id().as_str(), "env-id" | "also-env") && !targets.env_options.contains(&name) {
+                targets.env_options.push(name.clone());
+            }
+            if !targets.value_options.contains(&name) {
+                targets.value_options.push(name);
+            }
+        }
+    }
+
+    for sub in cmd.get_subcommands().filter(|sub| !sub.is_hide_set()) {
+        collect_targets(sub, &format!("{} {}", path, sub.get_name()), targets);
+    }
+}
+
+/// A shell command that prints words one per line (or a no-op, if there are none).
+fn print_words(words: &[String], noop: &str) -> String {
+    match words.is_empty() {
+        true => String::from(noop),
+        false => format!("printf '%s\\n' {}", words.join(" ")),
+    }
+}
+
+/// The shell functions (the same for bash and zsh) that print the subcommands of a command path
+/// (failing for unknown paths), and the kind of ids its first positional argument takes (with a
+/// `+` suffix if it takes multiple ones).
+fn render_posix_functions(targets: &CompletionTargets) -> String {
+    let mut functions = String::from("_viva_children() {\n    case \"$1\" in\n");
+    for (path, subcommands) in &targets.subcommands {
+        let _ = writeln!(functions, "        \"{}\") {} ;;", path, print_words(subcommands, ":"));
+    }
+    functions.push_str("        *) return 1 ;;\n    esac\n}\n\n_viva_positional() {\n    case \"$1\" in\n");
+    for (kind, positionals) in [("envs", &targets.env_positionals), ("apps", &targets.app_positionals)] {
+        for (path, multiple) in positionals {
+            let _ = writeln!(
+                functions,
+                "        \"{}\") echo {}{} ;;",
+                path,
+                kind,
+                if *multiple { "+" } else { "" }
+            );
+        }
+    }
+    functions.push_str("        *) return 1 ;;\n    esac\n}\n");
+    functions
+}
+
+fn render_bash_script(targets: &CompletionTargets) -> String {
+    format!(
+        r#"# bash completion for viva, generated by 'viva completions bash'
+
+{functions}
+_viva() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}" prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+    local cmd_path=viva word positional=0 skip=0 kind i
+    COMPREPLY=()
+    case "$prev" in
+        {env_options})
+            COMPREPLY=($(compgen -W "$(viva completions bash --candidates envs 2>/dev/null)" -- "$cur"))
+            return ;;
+    esac
+    for ((i = 1; i < COMP_CWORD; i++)); do
+        word="${{COMP_WORDS[i]}}"
+        if ((skip)); then skip=0; continue; fi
+        case "$word" in
+            --) return ;;
+            {value_options}) skip=1; continue ;;
+            -*) continue ;;
+        esac
+        if _viva_children "$cmd_path $word" >/dev/null; then
+            cmd_path="$cmd_path $word"
+        else
+            ((positional++))
+        fi
+    done
+    if kind=$(_viva_positional "$cmd_path") && [[ $kind == *+ || $positional -eq 0 ]]; then
+        COMPREPLY+=($(compgen -W "$(viva completions bash --candidates "${{kind%+}}" 2>/dev/null)" -- "$cur"))
+    fi
+    if [[ $positional -eq 0 ]]; then
+        COMPREPLY+=($(compgen -W "$(_viva_children "$cmd_path")" -- "$cur"))
+    fi
+}}
+
+complete -o default -F _viva viva
+"#,
+        functions = render_posix_functions(targets),
+        env_options = targets.env_options.join("|"),
+        value_options = targets.value_options.join("|"),
+    )
+}
+
+fn render_zsh_script(targets: &CompletionTargets) -> String {
+    format!(
+        r#"#compdef viva
+# zsh completion for viva, generated by 'viva completions zsh'
+
+{functions}
+_viva_ids() {{
+    local -a candidates
+    candidates=(${{(f)"$(viva completions zsh --candidates $1 2>/dev/null)"}})
+    (( ${{#candidates}} )) && _describe -t "$1" "$1" candidates
+}}
+
+_viva() {{
+    local cmd_path=viva word positional=0 skip=0 kind i
+    case "${{words[CURRENT-1]}}" in
+        {env_options})
+            _viva_ids envs
+            return ;;
+    esac
+    for ((i = 2; i < CURRENT; i++)); do
+        word="${{words[i]}}"
+        if ((skip)); then skip=0; continue; fi
+        case "$word" in
+            --) _files; return ;;
+            {value_options}) skip=1; continue ;;
+            -*) continue ;;
+        esac
+        if _viva_children "$cmd_path $word" >/dev/null; then
+            cmd_path="$cmd_path $word"
+        else
+            ((positional++))
+        fi
+    done
+    if kind=$(_viva_positional "$cmd_path") && [[ $kind == *+ || $positional -eq 0 ]]; then
+        _viva_ids "${{kind%+}}"
+    fi
+    if ((positional == 0)); then
+        local -a children
+        children=(${{(f)"$(_viva_children "$cmd_path")"}})
+        (( ${{#children}} )) && compadd -a children
+    fi
+}}
+
+if [[ $zsh_eval_context[-1] == loadautofunc ]]; then
+    _viva "$@"
+else
+    compdef _viva viva
+fi
+"#,
+        functions = render_posix_functions(targets),
+        env_options = targets.env_options.join("|"),
+        value_options = targets.value_options.join("|"),
+    )
+}
+
+fn render_fish_script(targets: &CompletionTargets) -> String {
+    let mut children = String::new();
+    for (path, subcommands) in &targets.subcommands {
+        let _ = writeln!(children, "        case '{}'\n            {}", path, print_words(subcommands, "true"));
+    }
+    let mut positionals = String::new();
+    for (kind, paths) in [("envs", &targets.env_positionals), ("apps", &targets.app_positionals)] {
+        for (path, multiple) in paths {
+            let _ = writeln!(
+                positionals,
+                "        case '{}'\n            echo {}{}",
+                path,
+                kind,
+                if *multiple { "+" } else { "" }
+            );
+        }
+    }
+    format!(
+        r#"# fish completion for viva, generated by 'viva completions fish'
+
+function __viva_children
+    switch $argv[1]
+{children}        case '*'
+            return 1
+    end
+end
+
+function __viva_positional
+    switch $argv[1]
+{positionals}        case '*'
+            return 1
+    end
+end
+
+# prints the command path and the number of positional arguments before the cursor
+function __viva_state
+    set -l cmd_path viva
+    set -l positional 0
+    set -l skip 0
+    for word in (commandline -opc)[2..-1]
+        if test $skip -eq 1
+            set skip 0
+        else if test "$word" = --
+            echo --
+            echo 0
+            return
+        else if contains -- $word {value_options}
+            set skip 1
+        else if string match -q -- '-*' $word
+            continue
+        else if __viva_children "$cmd_path $word" >/dev/null
+            set cmd_path "$cmd_path $word"
+        else
+            set positional (math $positional + 1)
+        end
+    end
+    echo $cmd_path
+    echo $positional
+end
+
+function __viva_after_env_option
+    contains -- (commandline -opc)[-1] {env_options}
+end
+
+function __viva_complete_subcommands
+    __viva_after_env_option; and return 1
+    set -l state (__viva_state)
+    test $state[2] -eq 0; and __viva_children $state[1]
+end
+
+function __viva_complete_ids
+    if __viva_after_env_option
+        viva completions fish --candidates envs 2>/dev/null
+        return
+    end
+    set -l state (__viva_state)
+    set -l kind (__viva_positional $state[1]); or return 1
+    if string match -q -- '*+' $kind; or test $state[2] -eq 0
+        viva completions fish --candidates (string trim -r -c + -- $kind) 2>/dev/null
+    end
+end
+
+complete -c viva -f -n '__viva_complete_subcommands >/dev/null' -a '(__viva_complete_subcommands)'
+complete -c viva -f -n '__viva_after_env_option' -a '(__viva_complete_ids)'
+complete -c viva -n 'not __viva_after_env_option' -a '(__viva_complete_ids)'
+"#,
+        children = children,
+        positionals = positionals,
+        value_options = targets.value_options.join(" "),
+        env_options = targets.env_options.join(" "),
+    )
+}
+
+/// The completion script for a shell, for the provided (top-level) command.
+pub fn render_script(shell: CompletionShell, cmd: &Command) -> String {
+    let mut targets = CompletionTargets::default();
+    collect_targets(cmd, cmd.get_name(), &mut targets);
+    match shell {
+        CompletionShell::Bash => render_bash_script(&targets),
+        CompletionShell::Zsh => render_zsh_script(&targets),
+        CompletionShell::Fish => render_fish_script(&targets),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Arg;
+
+    fn test_command() -> Command {
+        Command::new("viva")
+            .subcommand(
+                Command::new("run")
+                    .arg(Arg::new("env-id").short('e').long("env"))
+                    .arg(Arg::new("also-env").long("also-env").action(ArgAction::Append))
+                    .arg(Arg::new("shell").long("shell").action(ArgAction::SetTrue)),
+            )
+            .subcommand(Command::new("sync").arg(Arg::new("env-id").action(ArgAction::Append)))
+            .subcommand(Command::new("app").subcommand(Command::new("run").arg(Arg::new("app").required(true))))
+    }
+
+    #[test]
+    fn test_collect_targets() {
+        let mut targets = CompletionTargets::default();
+        collect_targets(&test_command(), "viva", &mut targets);
+        assert_eq!(targets.subcommands["viva"], vec!["run", "sync", "app"]);
+        assert_eq!(targets.subcommands["viva app"], vec!["run"]);
+        assert_eq!(targets.env_positionals.get("viva sync"), Some(&true));
+        assert_eq!(targets.app_positionals.get("viva app run"), Some(&false));
+        assert_eq!(targets.env_options, vec!["--env", "-e", "--also-env"]);
+        assert!(!targets.value_options.contains(&String::from("--shell")));
+    }
+
+    #[test]
+    fn test_render_candidates() {
+        let candidates = vec![Candidate {
+            id: String::from("a:b"),
+            hint: String::from("ready: python"),
+        }];
+        assert_eq!(render_candidates(CompletionShell::Bash, &candidates), "a:b\n");
+        assert_eq!(render_candidates(CompletionShell::Zsh, &candidates), "a\\:b:ready: python\n");
+        assert_eq!(render_candidates(CompletionShell::Fish, &candidates), "a:b\tready: python\n");
+    }
+
+    #[test]
+    fn test_summarize_specs() {
+        assert_eq!(summarize_specs(&[String::from("python"), String::from("numpy")]), "python, numpy");
+        let many = vec![String::from("package-with-a-long-name"); 5];
+        assert!(summarize_specs(&many).ends_with("..."));
+    }
+}
@@ -0,0 +1,128 @@
+// Registration of environment prefixes in conda's list of known environments.
+//
+// conda keeps the paths of all prefixes it created in `~/.conda/environments.txt` (one path per
+// line), which is also where IDEs like PyCharm or VS Code look for conda environments. If enabled
+// (`register_conda_envs` in `viva.yaml`), the prefixes of synced environments are added to that
+// file, and removed again when they are deleted (or moved). conda itself ignores entries of
+// prefixes that don't exist anymore, so failing to remove one is not a problem.
+
+use anyhow::{anyhow, Result};
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::{debug, warn};
+
+use crate::config::VivaConfig;
+
+/// The path of conda's `environments.txt` file.
+fn environments_txt_path() -> Option<PathBuf> {
+    dirs::home_dir().map(|home| home.join(".conda").join("environments.txt"))
+}
+
+/// Whether a line of `environments.txt` is the provided prefix (paths are case-insensitive on
+/// Windows).
+fn is_prefix_line(line: &str, prefix: &str) -> bool {
+    let line = line.trim().trim_end_matches(['/', '\\']);
+    match cfg!(windows) {
+        true => line.eq_ignore_ascii_case(prefix),
+        false => line == prefix,
+    }
+}
+
+/// The content of `environments.txt` with the prefix added, `None` if it is listed already.
+fn add_prefix_line(content: &str, prefix: &str) -> Option<String> {
+    if content.lines().any(|line| is_prefix_line(line, prefix)) {
+        return None;
+    }
+    let mut content = String::from(content);
+    if !content.is_empty() && !content.ends_with('\n') {
+        content.push('\n');
+    }
+    content.push_str(prefix);
+    content.push('\n');
+    Some(content)
+}
+
+/// The content of `environments.txt` without the prefix, `None` if it is not listed.
+fn remove_prefix_line(content: &str, prefix: &str) -> Option<String> {
+    if !content.lines().any(|line| is_prefix_line(line, prefix)) {
+        return None;
+    }
+    let mut remaining = content
+        .lines()
+        .filter(|line| !is_prefix_line(line, prefix))
+        .collect::<Vec<&str>>()
+        .join("\n");
+    if !remaining.is_empty() {
+        remaining.push('\n');
+    }
+    Some(remaining)
+}
+
+/// Apply a change to the content of `environments.txt` (written via a temporary file, so conda
+/// never reads a partially written file).
+async fn update_environments_txt(prefix: &Path, change: fn(&str, &str) -> Option<String>) -> Result<()> {
+    let path = environments_txt_path().ok_or_else(|| anyhow!("Could not determine home directory"))?;
+    let prefix = prefix.to_string_lossy();
+    let prefix = prefix.trim_end_matches(['/', '\\']);
+    let content = match fs::read_to_string(&path).await {
+        Ok(content) => content,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e.into()),
+    };
+    let updated = match change(&content, prefix) {
+        Some(updated) => updated,
+        None => return Ok(()),
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    let tmp_path = path.with_extension(format!("txt.viva-{}", std::process::id()));
+    fs::write(&tmp_path, updated).await?;
+    fs::rename(&tmp_path, &path).await?;
+    debug!("Updated {} for prefix: {}", path.display(), prefix);
+    Ok(())
+}
+
+/// Add a prefix to conda's list of environments (if enabled in the config), failing to do so only
+/// results in a warning.
+pub(crate) async fn register_prefix(config: &VivaConfig, prefix: &Path) {
+    if !config.register_conda_envs {
+        return;
+    }
+    if let Err(e) = update_environments_txt(prefix, add_prefix_line).await {
+        warn!("Could not register '{}' in conda's environments.txt: {}", prefix.display(), e);
+    }
+}
+
+/// Remove a prefix from conda's list of environments (if enabled in the config), failing to do so
+/// only results in a warning.
+pub(crate) async fn unregister_prefix(config: &VivaConfig, prefix: &Path) {
+    if !config.register_conda_envs {
+        return;
+    }
+    if let Err(e) = update_environments_txt(prefix, remove_prefix_line).await {
+        warn!("Could not remove '{}' from conda's environments.txt: {}", prefix.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_add_prefix_line() {
+        assert_eq!(add_prefix_line("", "/envs/a").as_deref(), Some("/envs/a\n"));
+        assert_eq!(add_prefix_line("/opt/conda", "/envs/a").as_deref(), Some("/opt/conda\n/envs/a\n"));
+        assert_eq!(add_prefix_line("/opt/conda\n/envs/a/\n", "/envs/a"), None);
+    }
+
+    #[test]
+    fn test_remove_prefix_line() {
+        assert_eq!(
+            remove_prefix_line("/opt/conda\n/envs/a\n/envs/b\n", "/envs/a").as_deref(),
+            Some("/opt/conda\n/envs/b\n")
+        );
+        assert_eq!(remove_prefix_line("/envs/a\n", "/envs/a").as_deref(), Some(""));
+        assert_eq!(remove_prefix_line("/opt/conda\n", "/envs/a"), None);
+    }
+}
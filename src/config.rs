@@ -1 +1,311 @@
+use anyhow::{anyhow, Result};
+use rattler_conda_types::{Channel, ChannelConfig};
+use reqwest::Url;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
 
+use crate::auth::ChannelCredentials;
+use crate::defaults::{DEFAULT_CHANNELS, DEFAULT_PROTECTED_EXECUTABLES};
+
+/// What to do if the projected size of an environment exceeds its `max_size` budget.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SizeBudgetPolicy {
+    Warn,
+    Fail,
+}
+
+impl Default for SizeBudgetPolicy {
+    fn default() -> Self {
+        SizeBudgetPolicy::Warn
+    }
+}
+
+/// What to do with files of a package that already belong to another package of an environment.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PathConflictPolicy {
+    /// Don't link the package, and report the conflicting files.
+    Error,
+    /// Overwrite the files, with a warning that lists them (the default, like conda does it).
+    Clobber,
+    /// Keep the files of the other package (the rest of the package is linked).
+    Skip,
+}
+
+impl Default for PathConflictPolicy {
+    fn default() -> Self {
+        PathConflictPolicy::Clobber
+    }
+}
+
+/// What happens to an environment of a collection if a collection with higher precedence defines
+/// an environment with the same id.
+#[derive(Debug, Deserialize, Serialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum DuplicatePolicy {
+    /// Fail to load the collections.
+    Error,
+    /// Ignore it (without the warning that is shown if no policy is configured).
+    Skip,
+    /// Use it instead, despite the lower precedence.
+    Override,
+    /// Merge its spec into the one with higher precedence (whose values win where both have one).
+    Merge,
+}
+
+/// How the environments of a collection are layered with those of other collections.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct CollectionRules {
+    /// Collections with a higher priority take precedence, collections with the same priority (0
+    /// by default) in the order they are loaded (project, default, workspaces, remote, system).
+    #[serde(default)]
+    pub priority: i32,
+    /// What to do with environments that are also defined by a collection with higher
+    /// precedence (they are skipped, with a warning, if not set).
+    #[serde(default)]
+    pub on_duplicate: Option<DuplicatePolicy>,
+}
+
+/// The global viva configuration (usually read from `viva.yaml` in the user config dir).
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VivaConfig {
+    #[serde(default = "default_channels")]
+    pub default_channels: Vec<String>,
+    #[serde(default)]
+    pub size_budget_policy: SizeBudgetPolicy,
+    /// The maximum size of the package cache (e.g. `10GB`), enforced after syncs and by `viva cache prune`.
+    #[serde(default)]
+    pub cache_max_size: Option<String>,
+    /// The number of days after which package cache entries that were not used (installed into an
+    /// environment) are removed.
+    #[serde(default)]
+    pub cache_max_age_days: Option<u64>,
+    /// Ask for confirmation before syncs that download more than this many megabytes.
+    #[serde(default)]
+    pub confirm_above_mb: Option<u64>,
+    /// The url channel names (like `conda-forge`) are resolved against, instead of
+    /// `https://conda.anaconda.org` (e.g. an internal server that mirrors all used channels).
+    #[serde(default)]
+    pub channel_alias: Option<String>,
+    /// Mirror urls for channels (channel name or url -> list of urls). Mirrors are tried in order
+    /// of their latency, if a download from one of them fails, the next one is used.
+    #[serde(default)]
+    pub channel_mirrors: BTreeMap<String, Vec<String>>,
+    /// Credentials for private channels (host or url prefix -> `token:<token>`, `bearer:<token>`,
+    /// or `basic:<username>:<password>`).
+    #[serde(default)]
+    pub channel_auth: BTreeMap<String, ChannelCredentials>,
+    /// Limit the aggregate package download bandwidth (e.g. `2MB`, per second).
+    #[serde(default)]
+    pub limit_rate: Option<String>,
+    /// Consider a download stalled if no data arrived for this long (e.g. `30s`, defaults to
+    /// `60s`, `0s` disables the check). Stalled downloads are retried, then the next mirror is
+    /// tried (if there is one).
+    #[serde(default)]
+    pub stall_timeout: Option<String>,
+    /// How often a stalled download is retried (defaults to 2).
+    #[serde(default)]
+    pub stall_retries: Option<u32>,
+    /// Fail if solving an environment takes longer than this (e.g. `5m`), there is no limit by
+    /// default.
+    #[serde(default)]
+    pub solve_timeout: Option<String>,
+    /// What to do if a package contains files that belong to another package of the environment:
+    /// `clobber` (the default, with a warning for each package), `skip`, or `error` (usually set via
+    /// the `--clobber`, `--skip-conflicts` or `--fail-on-conflicts` command-line flags).
+    #[serde(default)]
+    pub path_conflicts: PathConflictPolicy,
+    /// Look up env and app ids case-insensitively (if the match is unambiguous).
+    #[serde(default)]
+    pub case_insensitive_ids: bool,
+    /// Fail on any collection problem (invalid specs, shadowed ids, unknown fields), instead of
+    /// just warning about it. Intended for CI and provisioning.
+    #[serde(default)]
+    pub strict: bool,
+    /// Executables of the system that environments should not shadow (e.g. `git`, `ssh`), checked
+    /// by `viva doctor` and when solving or syncing environments.
+    #[serde(default = "default_protected_executables")]
+    pub protected_executables: Vec<String>,
+    /// The directory app shims are created in (defaults to the `shims` folder in the viva data
+    /// directory), this needs to be on the PATH to be useful.
+    #[serde(default)]
+    pub shim_dir: Option<PathBuf>,
+    /// A prefix for the names of all app shims (e.g. `viva-`), can be overridden per app.
+    #[serde(default)]
+    pub shim_prefix: Option<String>,
+    /// The environment to use if none is specified (and there is no project spec), instead of
+    /// `default`.
+    #[serde(default)]
+    pub default_env: Option<String>,
+    /// The environments of apps in the default app collection (that don't specify one themselves):
+    /// `--collection_id--` (the default, one environment for all of them), `--app_id--`,
+    /// `--spec_hash--` (one environment per distinct environment spec), or an environment id.
+    #[serde(default)]
+    pub app_env_placement: Option<String>,
+    /// The directory of the read-only environment collection an administrator provides for all
+    /// users (defaults to `/etc/viva/envs`, or `C:\ProgramData\viva\envs` on Windows).
+    #[serde(default)]
+    pub system_envs_dir: Option<PathBuf>,
+    /// Precedence and duplicate handling of environment collections (collection id, e.g. `system`
+    /// or `remote:team` -> rules).
+    #[serde(default)]
+    pub collection_rules: BTreeMap<String, CollectionRules>,
+    /// Don't ask for confirmation, assume 'yes' (usually set via the `--yes` command-line flag).
+    #[serde(default)]
+    pub assume_yes: bool,
+    /// Don't access the network when creating environments: repodata and packages are only taken
+    /// from the cache, and syncs fail if something is missing from it (usually set via the
+    /// `--offline` command-line flag).
+    #[serde(default)]
+    pub offline: bool,
+    /// What to log to the console (`RUST_LOG` syntax, e.g. `info` or `warn,viva=debug`), defaults
+    /// to `warn`.
+    #[serde(default)]
+    pub log_level: Option<String>,
+    /// What to log to the log file (`RUST_LOG` syntax, e.g. `info,rattler=debug`), no log file is
+    /// written if this is not set.
+    #[serde(default)]
+    pub log_file_level: Option<String>,
+    /// The directory of the log file (defaults to the `logs` folder in the viva data directory).
+    #[serde(default)]
+    pub log_dir: Option<PathBuf>,
+    /// The size after which the log file is rotated (e.g. `10MB`, the default).
+    #[serde(default)]
+    pub log_max_size: Option<String>,
+    /// The number of rotated log files to keep (defaults to 5).
+    #[serde(default)]
+    pub log_max_files: Option<usize>,
+    /// The (zmq) endpoint `viva daemon` listens on, defaults to an ipc socket in the viva cache
+    /// directory (`tcp://127.0.0.1:47321` on Windows).
+    #[serde(default)]
+    pub daemon_endpoint: Option<String>,
+    /// Add the prefixes of synced environments to conda's `~/.conda/environments.txt` (and remove
+    /// them when they are deleted), so IDEs that list conda environments find them.
+    #[serde(default)]
+    pub register_conda_envs: bool,
+    /// The directory of the package cache (defaults to `pkgs` in the rattler cache directory), e.g.
+    /// to use a cache that is shared with other tools.
+    #[serde(default)]
+    pub pkgs_dir: Option<PathBuf>,
+    /// Use the package cache safely while other tools (pixi, or other rattler-based tools) use it
+    /// as well: package directories are locked like those tools do while they are extracted or
+    /// linked, locked ones are never deleted, and the cache is not pruned automatically.
+    #[serde(default)]
+    pub share_pkgs_dir: bool,
+}
+
+fn default_channels() -> Vec<String> {
+    DEFAULT_CHANNELS.iter().map(|c| c.to_string()).collect()
+}
+
+fn default_protected_executables() -> Vec<String> {
+    DEFAULT_PROTECTED_EXECUTABLES
+        .iter()
+        .map(|e| e.to_string())
+        .collect()
+}
+
+impl VivaConfig {
+    /// The configuration channel names are resolved with (using the configured channel alias).
+    pub fn channel_config(&self) -> Result<ChannelConfig> {
+        match &self.channel_alias {
+            Some(channel_alias) => Ok(ChannelConfig {
+                channel_alias: Url::parse(channel_alias)
+                    .map_err(|e| anyhow!("Invalid channel alias '{}': {}", channel_alias, e))?,
+            }),
+            None => Ok(ChannelConfig::default()),
+        }
+    }
+
+    /// The mirrors configured for a channel, which can be registered under the same channel string,
+    /// or any other one that resolves to the same url (e.g. `conda-forge` and
+    /// `https://conda.anaconda.org/conda-forge`).
+    pub fn channel_mirrors_for(&self, channel: &str) -> Result<Option<&Vec<String>>> {
+        if let Some(mirrors) = self.channel_mirrors.get(channel) {
+            return Ok(Some(mirrors));
+        }
+        let channel_config = self.channel_config()?;
+        let base_url = Channel::from_str(channel, &channel_config)?.base_url;
+        for (key, mirrors) in &self.channel_mirrors {
+            match Channel::from_str(key, &channel_config) {
+                Ok(key_channel) if key_channel.base_url == base_url => return Ok(Some(mirrors)),
+                _ => {}
+            }
+        }
+        Ok(None)
+    }
+
+    /// Whether an executable (file name, with or without `.exe` extension) is protected.
+    pub fn is_protected_executable(&self, executable: &str) -> bool {
+        let executable = executable.to_lowercase();
+        let executable = executable.strip_suffix(".exe").unwrap_or(&executable);
+        self.protected_executables
+            .iter()
+            .any(|e| e.to_lowercase() == executable)
+    }
+}
+
+impl Default for VivaConfig {
+    fn default() -> Self {
+        VivaConfig {
+            default_channels: default_channels(),
+            size_budget_policy: SizeBudgetPolicy::default(),
+            cache_max_size: None,
+            cache_max_age_days: None,
+            confirm_above_mb: None,
+            channel_alias: None,
+            channel_mirrors: BTreeMap::new(),
+            channel_auth: BTreeMap::new(),
+            limit_rate: None,
+            stall_timeout: None,
+            stall_retries: None,
+            solve_timeout: None,
+            path_conflicts: PathConflictPolicy::default(),
+            case_insensitive_ids: false,
+            strict: false,
+            protected_executables: default_protected_executables(),
+            shim_dir: None,
+            shim_prefix: None,
+            default_env: None,
+            app_env_placement: None,
+            system_envs_dir: None,
+            collection_rules: BTreeMap::new(),
+            assume_yes: false,
+            offline: false,
+            log_level: None,
+            log_file_level: None,
+            log_dir: None,
+            log_max_size: None,
+            log_max_files: None,
+            daemon_endpoint: None,
+            register_conda_envs: false,
+            pkgs_dir: None,
+            share_pkgs_dir: false,
+        }
+    }
+}
+
+/// Parse a human readable size (like `500MB`, `2 GiB`, or `1024`) into a number of bytes.
+pub fn parse_size(size: &str) -> Result<u64> {
+    let size = size.trim();
+    let split_at = size
+        .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+        .unwrap_or(size.len());
+    let (number, unit) = size.split_at(split_at);
+    let number: f64 = number
+        .parse()
+        .map_err(|_| anyhow!("Invalid size: {}", size))?;
+    let multiplier: u64 = match unit.trim().to_lowercase().as_str() {
+        "" | "b" => 1,
+        "k" | "kb" => 1000,
+        "kib" => 1024,
+        "m" | "mb" => 1000 * 1000,
+        "mib" => 1024 * 1024,
+        "g" | "gb" => 1000 * 1000 * 1000,
+        "gib" => 1024 * 1024 * 1024,
+        _ => return Err(anyhow!("Invalid size unit: {}", size)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
@@ -0,0 +1,255 @@
+// Reporting what went wrong when applying a transaction to an environment, per package: which
+// packages failed to download, link or be removed (and why), instead of only the first error.
+//
+// Linking a package fails if it contains files that belong to another package of the environment
+// (unless conflicts are configured to be clobbered or skipped). Files that no package owns (e.g.
+// written by pip, or by hand) are overwritten, like before.
+
+use anyhow::Result;
+use rattler::install::PythonInfo;
+use rattler_conda_types::package::{IndexJson, PackageFile, PathsJson};
+use rattler_conda_types::PrefixRecord;
+use std::collections::HashMap;
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::config::PathConflictPolicy;
+
+/// The number of conflicting files that are listed in an error message.
+const MAX_LISTED_CONFLICTS: usize = 10;
+
+/// The packages the files of an environment belong to (relative path -> package name), updated
+/// while a transaction links packages.
+#[derive(Debug, Default)]
+pub(crate) struct PathOwners {
+    owners: Mutex<HashMap<PathBuf, String>>,
+}
+
+impl PathOwners {
+    pub(crate) fn new(installed_packages: &[PrefixRecord]) -> PathOwners {
+        let mut owners = HashMap::new();
+        for record in installed_packages {
+            let package = record.repodata_record.package_record.name.clone();
+            for path in &record.files {
+                owners.insert(path.clone(), package.clone());
+            }
+        }
+        PathOwners {
+            owners: Mutex::new(owners),
+        }
+    }
+
+    /// Forget the files of a package (e.g. because it is removed).
+    pub(crate) fn release(&self, package: &str) {
+        self.owners
+            .lock()
+            .unwrap()
+            .retain(|_, owner| owner != package);
+    }
+
+    /// Claim files for a package, according to the policy. Returns the files that belong to other
+    /// packages (and keep belonging to them, unless they are clobbered).
+    pub(crate) fn claim(&self, package: &str, paths: &[PathBuf], policy: PathConflictPolicy) -> Vec<PathConflict> {
+        let mut owners = self.owners.lock().unwrap();
+        let conflicts = paths
+            .iter()
+            .filter_map(|path| match owners.get(path) {
+                Some(owner) if owner != package => Some(PathConflict {
+                    path: path.clone(),
+                    owner: owner.clone(),
+                }),
+                _ => None,
+            })
+            .collect::<Vec<PathConflict>>();
+        let claimed = paths.iter().filter(|path| match policy {
+            PathConflictPolicy::Clobber => true,
+            _ => !conflicts.iter().any(|conflict| &conflict.path == *path),
+        });
+        // a package that fails to link doesn't own anything
+        if conflicts.is_empty() || policy != PathConflictPolicy::Error {
+            for path in claimed {
+                owners.insert(path.clone(), String::from(package));
+            }
+        }
+        conflicts
+    }
+}
+
+/// A file of a package that already belongs to another package.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct PathConflict {
+    pub path: PathBuf,
+    pub owner: String,
+}
+
+impl fmt::Display for PathConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (from {})", self.path.display(), self.owner)
+    }
+}
+
+/// The error of a package whose files conflict with those of other packages.
+#[derive(Debug)]
+pub(crate) struct PathConflicts(pub Vec<PathConflict>);
+
+impl fmt::Display for PathConflicts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut listed = self
+            .0
+            .iter()
+            .take(MAX_LISTED_CONFLICTS)
+            .map(|conflict| conflict.to_string())
+            .collect::<Vec<String>>();
+        if self.0.len() > MAX_LISTED_CONFLICTS {
+            listed.push(format!("and {} more", self.0.len() - MAX_LISTED_CONFLICTS));
+        }
+        write!(
+            f,
+            "{} file(s) already belong to other packages: {} (use '--clobber' to overwrite them, or '--skip-conflicts' to keep them)",
+            self.0.len(),
+            listed.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for PathConflicts {}
+
+/// The paths of a package and the paths its files are linked to (relative to the prefix, noarch
+/// python files end up in the site-packages of the environment's python).
+pub(crate) fn package_paths(package_dir: &Path, python_info: Option<&PythonInfo>) -> Result<(PathsJson, Vec<PathBuf>)> {
+    let paths_json = PathsJson::from_package_directory_with_deprecated_fallback(package_dir)?;
+    let index_json = IndexJson::from_package_directory(package_dir)?;
+    let targets = paths_json
+        .paths
+        .iter()
+        .map(|entry| match (index_json.noarch.is_python(), python_info) {
+            (true, Some(python_info)) => python_info
+                .get_python_noarch_target_path(&entry.relative_path)
+                .into_owned(),
+            _ => entry.relative_path.clone(),
+        })
+        .collect();
+    Ok((paths_json, targets))
+}
+
+/// The step of a transaction operation that failed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum OperationStep {
+    Download,
+    Remove,
+    Link,
+}
+
+impl fmt::Display for OperationStep {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperationStep::Download => write!(f, "download"),
+            OperationStep::Remove => write!(f, "remove"),
+            OperationStep::Link => write!(f, "link"),
+        }
+    }
+}
+
+/// A package the transaction failed for.
+#[derive(Debug)]
+pub(crate) struct PackageFailure {
+    /// The name and version of the package.
+    pub package: String,
+    pub step: OperationStep,
+    pub error: anyhow::Error,
+}
+
+impl fmt::Display for PackageFailure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({}): {:#}", self.package, self.step, self.error)
+    }
+}
+
+/// The error of a transaction that failed for one or more packages.
+#[derive(Debug)]
+pub(crate) struct TransactionFailures(pub Vec<PackageFailure>);
+
+impl fmt::Display for TransactionFailures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} package(s) failed:", self.0.len())?;
+        for failure in &self.0 {
+            write!(f, "\n  - {}", failure)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for TransactionFailures {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn owners(files: &[(&str, &str)]) -> PathOwners {
+        let owners = PathOwners::default();
+        for (path, package) in files {
+            owners
+                .owners
+                .lock()
+                .unwrap()
+                .insert(PathBuf::from(path), String::from(*package));
+        }
+        owners
+    }
+
+    #[test]
+    fn test_claim() {
+        let paths = vec![PathBuf::from("ssl/cert.pem"), PathBuf::from("bin/openssl")];
+
+        let path_owners = owners(&[("ssl/cert.pem", "ca-certificates")]);
+        let conflicts = path_owners.claim("openssl", &paths, PathConflictPolicy::Error);
+        assert_eq!(
+            conflicts,
+            vec![PathConflict {
+                path: PathBuf::from("ssl/cert.pem"),
+                owner: String::from("ca-certificates"),
+            }]
+        );
+        // nothing is claimed if the package fails
+        assert!(path_owners.claim("other", &[PathBuf::from("bin/openssl")], PathConflictPolicy::Error).is_empty());
+
+        let path_owners = owners(&[("ssl/cert.pem", "ca-certificates")]);
+        path_owners.claim("openssl", &paths, PathConflictPolicy::Skip);
+        let conflicts = path_owners.claim("ca-certificates", &paths[..1], PathConflictPolicy::Error);
+        assert!(conflicts.is_empty());
+
+        let path_owners = owners(&[("ssl/cert.pem", "ca-certificates")]);
+        path_owners.claim("openssl", &paths, PathConflictPolicy::Clobber);
+        let conflicts = path_owners.claim("ca-certificates", &paths[..1], PathConflictPolicy::Error);
+        assert_eq!(conflicts[0].owner, "openssl");
+
+        // the files of released packages can be claimed
+        path_owners.release("openssl");
+        assert!(path_owners.claim("libssl", &paths, PathConflictPolicy::Error).is_empty());
+    }
+
+    #[test]
+    fn test_transaction_failures_display() {
+        let failures = TransactionFailures(vec![
+            PackageFailure {
+                package: String::from("openssl 3.1.0"),
+                step: OperationStep::Link,
+                error: PathConflicts(vec![PathConflict {
+                    path: PathBuf::from("ssl/cert.pem"),
+                    owner: String::from("ca-certificates"),
+                }])
+                .into(),
+            },
+            PackageFailure {
+                package: String::from("zlib 1.2.13"),
+                step: OperationStep::Download,
+                error: anyhow::anyhow!("connection reset"),
+            },
+        ]);
+        assert_eq!(
+            failures.to_string(),
+            "2 package(s) failed:\n  - openssl 3.1.0 (link): 1 file(s) already belong to other packages: ssl/cert.pem (from ca-certificates) (use '--clobber' to overwrite them, or '--skip-conflicts' to keep them)\n  - zlib 1.2.13 (download): connection reset"
+        );
+    }
+}
@@ -1,17 +1,31 @@
-use anyhow::{anyhow, bail, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use directories::ProjectDirs;
-use std::collections::{BTreeMap, HashMap, HashSet};
-
-use std::path::{PathBuf};
-
-use crate::defaults::{ENV_SPEC_FILENAME};
-use crate::models::app::{AppCollection, AppEnvPlacementStrategy, VivaApp, VivaAppSpec};
-use crate::models::environment::{EnvSyncStatus, EnvironmentCollection, VivaEnv, VivaEnvSpec};
-use crate::models::read_model_spec;
-use prettytable::{format, Table};
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use crate::conda_envs;
+use crate::config::{CollectionRules, DuplicatePolicy, VivaConfig};
+use crate::gc::{self, GcReport};
+use crate::journal::JournalOperation;
+use crate::listing::{ListOptions, Listing};
+use crate::watch::CollectionWatcher;
+use crate::defaults::{
+    CREDENTIALS_FILENAME, ENV_LOCATIONS_FILENAME, ENV_REVISIONS_DIRNAME, ENV_SPEC_FILENAME, PROFILE_COLLECTIONS_FILENAME,
+    TRUSTED_KEYS_FILENAME,
+};
+use crate::signing::TrustStore;
+use crate::models::app::{AppCollection, AppEnvPlacementStrategy, AppUninstallReport, DefaultAppCollection, VivaApp, VivaAppSpec};
+use crate::models::environment::{DefaultEnvCollection, EnvCheckStrategy, EnvSyncStatus, EnvironmentCollection, OutdatedPackage, VivaEnv, VivaEnvSpec};
+use crate::models::project::{DefaultEnv, ProjectEnvCollection, ProjectEnvsSpec, ProjectSpec};
+use crate::models::remote::RemoteEnvCollection;
+use crate::models::profile::{DriftReport, DriftStatus, ProvisionAction, ProvisioningProfile, SpecHashes};
+use crate::models::{read_model_spec, read_models_spec, write_models_spec};
 use tokio::fs;
 
-use tracing::debug;
+use tracing::{debug, warn};
 
 /// a struct that holds the global app configuration
 #[derive(Debug)]
@@ -19,9 +33,22 @@ pub struct VivaContext {
     pub project_dirs: ProjectDirs,
     env_collections: HashMap<String, Box<dyn EnvironmentCollection>>,
     app_collections: HashMap<String, Box<dyn AppCollection>>,
+    /// The ids of the env collections, in the order they were added (which determines precedence).
+    env_collection_ids: Vec<String>,
+    /// The ids and env placement strategies of the app collections, in the order they were added.
+    app_collection_ids: Vec<(String, AppEnvPlacementStrategy)>,
     registered_envs: BTreeMap<String, VivaEnv>,
     registered_apps: BTreeMap<String, VivaApp>,
+    /// The collections that define an environment id, in the order they were added.
+    env_sources: BTreeMap<String, Vec<String>>,
+    /// The collections a registered environment is made of (the one it belongs to first, then the
+    /// ones whose specs were merged into it).
+    env_layers: BTreeMap<String, Vec<String>>,
+    invalid_envs: BTreeMap<String, String>,
+    invalid_apps: BTreeMap<String, String>,
+    warnings: Vec<String>,
     base_env_path: PathBuf,
+    config: Arc<VivaConfig>,
 }
 
 impl VivaContext {
@@ -35,14 +62,36 @@ impl VivaContext {
             .expect("Cannot create project directories");
 
         let base_env_path = project_dirs.data_dir().join("envs");
+        crate::journal::set_journal_dir(project_dirs.data_dir().join("journal"));
+        crate::auth::set_credentials_file(project_dirs.data_dir().join(CREDENTIALS_FILENAME));
 
         VivaContext {
             project_dirs,
             env_collections: HashMap::new(),
             app_collections: HashMap::new(),
+            env_collection_ids: Vec::new(),
+            app_collection_ids: Vec::new(),
             registered_envs: BTreeMap::new(),
             registered_apps: BTreeMap::new(),
+            env_sources: BTreeMap::new(),
+            env_layers: BTreeMap::new(),
+            invalid_envs: BTreeMap::new(),
+            invalid_apps: BTreeMap::new(),
+            warnings: Vec::new(),
             base_env_path,
+            config: Arc::new(VivaConfig::default()),
+        }
+    }
+
+    pub fn get_config(&self) -> &VivaConfig {
+        &self.config
+    }
+
+    /// Set the global configuration, this also applies to all already registered environments.
+    pub fn set_config(&mut self, config: VivaConfig) {
+        self.config = Arc::new(config);
+        for env in self.registered_envs.values_mut() {
+            env.config = self.config.clone();
         }
     }
 
@@ -51,14 +100,131 @@ impl VivaContext {
         collection_id: &str,
         collection: Box<dyn EnvironmentCollection>,
     ) -> Result<()> {
-        for env_id in collection.get_env_ids().await {
-            let env_spec = collection.get_env(&env_id).await?;
-            self.add_registered_env(&env_id, collection_id, env_spec.clone(), true)
-                .await?;
-        }
-
         self.env_collections
             .insert(String::from(collection_id), collection);
+        self.env_collection_ids.push(String::from(collection_id));
+        self.register_env_collection(collection_id).await?;
+        self.check_strict()
+    }
+
+    /// The strategy that determines the environments of the apps of a collection (that don't
+    /// specify one themselves).
+    fn get_app_placement_strategy(&self, collection_id: &str) -> AppEnvPlacementStrategy {
+        self.app_collection_ids
+            .iter()
+            .find(|(id, _)| id == collection_id)
+            .map(|(_, strategy)| strategy.clone())
+            .unwrap_or(AppEnvPlacementStrategy::Default)
+    }
+
+    /// Register all environments of a collection, ids that other collections define as well are
+    /// layered according to the rules of the collections (see [`CollectionRules`]).
+    async fn register_env_collection(&mut self, collection_id: &str) -> Result<()> {
+        let collection = self
+            .env_collections
+            .get(collection_id)
+            .ok_or_else(|| anyhow!("Environment collection not found: {}", collection_id))?;
+
+        let env_ids = collection.get_env_ids().await;
+        let invalid_envs = collection.get_invalid_envs().await;
+        let warnings = collection.get_warnings().await;
+
+        for env_id in env_ids {
+            self.layer_registered_env(&env_id, collection_id).await?;
+        }
+        self.invalid_envs.extend(invalid_envs);
+        self.warnings.extend(warnings);
+        Ok(())
+    }
+
+    /// The layering rules of an environment collection (the defaults, if none are configured).
+    fn get_collection_rules(&self, collection_id: &str) -> CollectionRules {
+        self.config
+            .collection_rules
+            .get(collection_id)
+            .cloned()
+            .unwrap_or_default()
+    }
+
+    /// The precedence of an environment collection (lower sorts first): collections with a higher
+    /// priority come first, those with the same priority in the order they were added.
+    fn get_collection_rank(&self, collection_id: &str) -> (Reverse<i32>, usize) {
+        let position = self
+            .env_collection_ids
+            .iter()
+            .position(|id| id == collection_id)
+            .unwrap_or(usize::MAX);
+        (Reverse(self.get_collection_rules(collection_id).priority), position)
+    }
+
+    /// Register the environment of a collection, layered with the definitions of the same id in
+    /// other collections: the one with the highest precedence is used, the duplicate policies of
+    /// the others decide whether they are skipped, merged into it, override it, or are an error.
+    async fn layer_registered_env(&mut self, env_id: &str, collection_id: &str) -> Result<()> {
+        let sources = self.env_sources.entry(String::from(env_id)).or_default();
+        if !sources.iter().any(|source| source == collection_id) {
+            sources.push(String::from(collection_id));
+        }
+        let mut sources = sources.clone();
+        sources.sort_by_key(|source| self.get_collection_rank(source));
+
+        let mut definitions: Vec<(String, VivaEnvSpec)> = vec![];
+        for source in sources {
+            let collection = self
+                .env_collections
+                .get(&source)
+                .ok_or_else(|| anyhow!("Environment collection not found: {}", source))?;
+            let env_spec = collection.get_env(env_id).await?.clone();
+            definitions.push((source, env_spec));
+        }
+
+        let mut definitions = definitions.into_iter();
+        let (mut owner, mut env_spec) = definitions
+            .next()
+            .expect("the environment is defined by at least one collection");
+        let mut layers = vec![owner.clone()];
+        for (source, source_spec) in definitions {
+            let policy = self.get_collection_rules(&source).on_duplicate;
+            if policy == Some(DuplicatePolicy::Error)
+                || self.get_collection_rules(&owner).on_duplicate == Some(DuplicatePolicy::Error)
+            {
+                bail!(
+                    "Environment '{}' is defined by the collections '{}' and '{}' (duplicates are configured to be an error)",
+                    env_id,
+                    owner,
+                    source
+                );
+            }
+            match policy {
+                None => {
+                    let warning = format!(
+                        "Environment '{}' of collection '{}' is shadowed by collection '{}'",
+                        env_id, source, owner
+                    );
+                    if !self.warnings.contains(&warning) {
+                        self.warnings.push(warning);
+                    }
+                }
+                Some(DuplicatePolicy::Override) => {
+                    debug!("Environment '{}' of collection '{}' overrides the one of '{}'", env_id, source, owner);
+                    owner = source;
+                    env_spec = source_spec;
+                    layers = vec![owner.clone()];
+                }
+                Some(DuplicatePolicy::Merge) => {
+                    debug!("Merging environment '{}' of collection '{}' into the one of '{}'", env_id, source, owner);
+                    env_spec.underlay_spec(source_spec);
+                    layers.push(source);
+                }
+                Some(DuplicatePolicy::Skip) | Some(DuplicatePolicy::Error) => {
+                    debug!("Skipping environment '{}' of collection '{}'", env_id, source);
+                }
+            }
+        }
+
+        self.registered_envs.remove(env_id);
+        self.add_registered_env(env_id, &owner, env_spec, false).await?;
+        self.env_layers.insert(String::from(env_id), layers);
         Ok(())
     }
 
@@ -74,26 +240,375 @@ impl VivaContext {
             None => AppEnvPlacementStrategy::Default
         };
 
+        self.app_collections
+            .insert(String::from(collection_id), collection);
+        self.app_collection_ids.push((String::from(collection_id), placement_strategy));
+        self.register_app_collection(collection_id).await?;
+
+        for (app_id, env_id) in self.get_broken_apps() {
+            warn!(
+                "App '{}' uses an environment that does not exist: {} (use 'viva app fix {} --env <env-id>' to fix)",
+                app_id, env_id, app_id
+            );
+        }
+
+        self.check_strict()
+    }
+
+    /// Register all apps of a collection (existing ids are not overwritten).
+    async fn register_app_collection(&mut self, collection_id: &str) -> Result<()> {
+        let placement_strategy = self.get_app_placement_strategy(collection_id);
+        let collection = self
+            .app_collections
+            .get(collection_id)
+            .ok_or_else(|| anyhow!("App collection not found: {}", collection_id))?;
+
+        let mut app_specs: Vec<(String, VivaAppSpec)> = vec![];
         for app_id in collection.get_app_ids().await {
             let app_spec = collection.get_app(&app_id).await?;
+            app_specs.push((app_id, app_spec.clone()));
+        }
+        let invalid_apps = collection.get_invalid_apps().await;
+        let warnings = collection.get_warnings().await;
 
-            let env_id: String = self.get_env_id_for_app(&app_id, app_spec, collection_id, &placement_strategy);
+        for (app_id, app_spec) in app_specs {
+            let env_id: String = self.get_env_id_for_app(&app_id, &app_spec, collection_id, &placement_strategy);
+            let explicit_env = is_explicit_env(&app_spec, &placement_strategy);
 
-            self.add_registered_app(&app_id, app_spec.clone(), collection_id, env_id, true)
+            self.add_registered_app(&app_id, app_spec, collection_id, env_id, explicit_env, true)
                 .await?;
         }
+        self.invalid_apps.extend(invalid_apps);
+        self.warnings.extend(warnings);
+        Ok(())
+    }
 
-        self.app_collections
-            .insert(String::from(collection_id), collection);
+    /// Re-read all collections, and re-register their environments and apps.
+    ///
+    /// This picks up changes made on disk, for long-running processes that embed viva. As after
+    /// loading the collections initially, app specs need to be merged into their environments
+    /// again (using [`VivaContext::merge_all_apps`]).
+    pub async fn reload_collections(&mut self) -> Result<()> {
+        for collection in self.env_collections.values_mut() {
+            collection.reload().await?;
+        }
+        for collection in self.app_collections.values_mut() {
+            collection.reload().await?;
+        }
+
+        self.registered_envs.clear();
+        self.registered_apps.clear();
+        self.env_sources.clear();
+        self.env_layers.clear();
+        self.invalid_envs.clear();
+        self.invalid_apps.clear();
+        self.warnings.clear();
+
+        for collection_id in self.env_collection_ids.clone() {
+            self.register_env_collection(&collection_id).await?;
+        }
+        let app_collection_ids: Vec<String> = self
+            .app_collection_ids
+            .iter()
+            .map(|(id, _)| id.clone())
+            .collect();
+        for collection_id in app_collection_ids {
+            self.register_app_collection(&collection_id).await?;
+        }
+        self.check_strict()
+    }
+
+    /// Watch the files of all collections for changes.
+    ///
+    /// Use [`VivaContext::reload_if_changed`] (or [`CollectionWatcher::changed`] and
+    /// [`VivaContext::reload_collections`]) to pick up the changes.
+    pub async fn watch_collections(&self) -> Result<CollectionWatcher> {
+        let mut paths: Vec<PathBuf> = vec![];
+        for collection in self.env_collections.values() {
+            paths.extend(collection.watch_paths().await);
+        }
+        for collection in self.app_collections.values() {
+            paths.extend(collection.watch_paths().await);
+        }
+        paths.sort();
+        paths.dedup();
+        CollectionWatcher::create(&paths)
+    }
+
+    /// Reload the collections if the watcher noticed changes since the last check.
+    pub async fn reload_if_changed(&mut self, watcher: &mut CollectionWatcher) -> Result<bool> {
+        if !watcher.has_changed() {
+            return Ok(false);
+        }
+        self.reload_collections().await?;
+        Ok(true)
+    }
+
+    /// Environment spec entries that could not be loaded (id or file -> error message).
+    pub fn get_invalid_envs(&self) -> &BTreeMap<String, String> {
+        &self.invalid_envs
+    }
 
+    /// App spec entries that could not be loaded (id or file -> error message).
+    pub fn get_invalid_apps(&self) -> &BTreeMap<String, String> {
+        &self.invalid_apps
+    }
+
+    /// Problems found while loading collections that did not prevent loading.
+    pub fn get_warnings(&self) -> &Vec<String> {
+        &self.warnings
+    }
+
+    /// In strict mode, fail if there were any problems loading the collections.
+    fn check_strict(&self) -> Result<()> {
+        if !self.config.strict {
+            return Ok(());
+        }
+        let mut problems: Vec<String> = vec![];
+        for (env_id, error) in &self.invalid_envs {
+            problems.push(format!("invalid env '{}': {}", env_id, error));
+        }
+        for (app_id, error) in &self.invalid_apps {
+            problems.push(format!("invalid app '{}': {}", app_id, error));
+        }
+        problems.extend(self.warnings.iter().cloned());
+        if !problems.is_empty() {
+            bail!("Problems loading collections (strict mode):\n  {}", problems.join("\n  "));
+        }
         Ok(())
     }
 
+    pub fn has_env_collection(&self, collection_id: &str) -> bool {
+        self.env_collections.contains_key(collection_id)
+    }
+
+    pub fn has_app_collection(&self, collection_id: &str) -> bool {
+        self.app_collections.contains_key(collection_id)
+    }
+
+    /// Compute the actions needed to bring the current state in line with a provisioning profile.
+    ///
+    /// If `prune` is set, environments and apps of the 'default' collections that are not part of
+    /// the profile are scheduled for removal (environments are kept if an app still uses them), as
+    /// are shims of apps the profile doesn't list in its shims.
+    pub async fn plan_profile(&mut self, profile: &ProvisioningProfile, prune: bool) -> Result<Vec<ProvisionAction>> {
+        let mut actions: Vec<ProvisionAction> = vec![];
+
+        for collection_id in profile.collections.keys() {
+            if !self.has_env_collection(collection_id) || !self.has_app_collection(collection_id) {
+                actions.push(ProvisionAction::AddCollection(collection_id.clone()));
+            }
+        }
+
+        let mut envs_to_sync: Vec<String> = vec![];
+        for (env_id, env_spec) in &profile.envs {
+            env_spec.validate().with_context(|| format!("Invalid spec for environment: {}", env_id))?;
+            match self.has_env(env_id).await {
+                true => {
+                    let env = self.get_env_mut(env_id).await?;
+                    // also if the registered spec has packages the profile doesn't (anymore)
+                    if !env_spec.is_equivalent_to(&env.spec) {
+                        actions.push(ProvisionAction::UpdateEnv(env_id.clone()));
+                        envs_to_sync.push(env_id.clone());
+                    } else {
+                        env.check_and_update_sync_status();
+                        if env.sync_status != EnvSyncStatus::Synced {
+                            envs_to_sync.push(env_id.clone());
+                        }
+                    }
+                }
+                false => {
+                    actions.push(ProvisionAction::RegisterEnv(env_id.clone()));
+                    envs_to_sync.push(env_id.clone());
+                }
+            }
+        }
+
+        for (app_id, entry) in &profile.apps {
+            match self.registered_apps.get(app_id) {
+                Some(app) => {
+                    if app.spec != entry.spec {
+                        warn!("App '{}' is already registered with a different spec, not changing it.", app_id);
+                    }
+                }
+                None => {
+                    actions.push(ProvisionAction::RegisterApp(app_id.clone()));
+                }
+            }
+        }
+
+        let shimmed_app_ids: BTreeSet<String> = self.list_shims().await?.into_values().collect();
+        for app_id in &profile.shims {
+            if !shimmed_app_ids.contains(app_id) {
+                actions.push(ProvisionAction::InstallShim(app_id.clone()));
+            }
+        }
+
+        if prune {
+            for app_id in &shimmed_app_ids {
+                let in_default_collection = match self.registered_apps.get(app_id) {
+                    Some(app) => app.app_collection_id == "default",
+                    // shims of apps that don't exist anymore
+                    None => true,
+                };
+                if in_default_collection && !profile.shims.contains(app_id) {
+                    actions.push(ProvisionAction::RemoveShim(app_id.clone()));
+                }
+            }
+
+            let mut used_env_ids: HashSet<String> = HashSet::new();
+            for (app_id, app) in &self.registered_apps {
+                match profile.apps.contains_key(app_id) || app.app_collection_id != "default" {
+                    true => {
+                        used_env_ids.insert(String::from(app.get_env_id()));
+                    }
+                    false => {
+                        actions.push(ProvisionAction::RemoveApp(app_id.clone()));
+                    }
+                }
+            }
+            for (app_id, entry) in &profile.apps {
+                let placement_strategy = entry.get_placement_strategy()?;
+                used_env_ids.insert(self.get_env_id_for_app(app_id, &entry.spec, "default", &placement_strategy));
+            }
+            for (env_id, env) in &self.registered_envs {
+                if env.collection_id == "default"
+                    && !profile.envs.contains_key(env_id)
+                    && !used_env_ids.contains(env_id)
+                {
+                    actions.push(ProvisionAction::RemoveEnv(env_id.clone()));
+                }
+            }
+        }
+
+        for env_id in envs_to_sync {
+            actions.push(ProvisionAction::SyncEnv(env_id));
+        }
+
+        Ok(actions)
+    }
+
+    /// Compare the current state against a profile, without changing anything.
+    pub async fn drift_report(&mut self, profile: &ProvisioningProfile) -> Result<DriftReport> {
+        let mut report = DriftReport::default();
+
+        for (env_id, env_spec) in &profile.envs {
+            report.env_hashes.insert(
+                env_id.clone(),
+                SpecHashes {
+                    expected: env_spec.content_hash(),
+                    actual: self.registered_envs.get(env_id).map(|env| env.spec.content_hash()),
+                },
+            );
+            let status = match self.registered_envs.get_mut(env_id) {
+                None => DriftStatus::Missing,
+                Some(env) => {
+                    env.check_and_update_sync_status();
+                    if !env_spec.is_equivalent_to(&env.spec) {
+                        DriftStatus::SpecMismatch
+                    } else if env.sync_status != EnvSyncStatus::Synced {
+                        DriftStatus::NotSynced
+                    } else {
+                        DriftStatus::Ok
+                    }
+                }
+            };
+            report.envs.insert(env_id.clone(), status);
+        }
+
+        for (app_id, entry) in &profile.apps {
+            let status = match self.registered_apps.get(app_id) {
+                None => DriftStatus::Missing,
+                Some(app) => {
+                    if app.spec != entry.spec {
+                        DriftStatus::SpecMismatch
+                    } else if !self.registered_envs.contains_key(app.get_env_id()) {
+                        DriftStatus::EnvMissing
+                    } else {
+                        DriftStatus::Ok
+                    }
+                }
+            };
+            report.apps.insert(app_id.clone(), status);
+        }
+
+        report.in_sync = report.envs.values().chain(report.apps.values()).all(|s| *s == DriftStatus::Ok);
+        Ok(report)
+    }
+
+    /// Apply a provisioning profile, registering and syncing everything that is missing.
+    ///
+    /// Collections the profile adds are recorded (see [`VivaContext::read_profile_collections`]),
+    /// so they are loaded again the next time. Applying the same profile more than once is a no-op,
+    /// apart from syncing environments that went out of sync in the meantime.
+    ///
+    /// # Returns
+    ///
+    /// The list of actions that were taken.
+    pub async fn apply_profile(&mut self, profile: &ProvisioningProfile, prune: bool) -> Result<Vec<ProvisionAction>> {
+        let actions = self.plan_profile(profile, prune).await?;
+
+        let mut apps_to_add: BTreeMap<String, (VivaAppSpec, AppEnvPlacementStrategy)> = BTreeMap::new();
+        for action in &actions {
+            match action {
+                ProvisionAction::AddCollection(collection_id) => {
+                    let config_path = &profile.collections[collection_id];
+                    if !self.has_env_collection(collection_id) {
+                        let env_collection = Box::new(DefaultEnvCollection::create(config_path.clone()).await?);
+                        self.add_env_collection(collection_id, env_collection).await?;
+                    }
+                    if !self.has_app_collection(collection_id) {
+                        let app_collection = Box::new(DefaultAppCollection::create(config_path.clone()).await?);
+                        self.add_app_collection(collection_id, app_collection, None).await?;
+                    }
+                    self.add_profile_collection(collection_id, config_path).await?;
+                }
+                ProvisionAction::RegisterEnv(env_id) | ProvisionAction::UpdateEnv(env_id) => {
+                    let env_spec = &profile.envs[env_id];
+                    match self.has_env(env_id).await {
+                        true => self.replace_env_spec(env_id, env_spec).await?,
+                        false => {
+                            self.add_env(env_id, Some(env_spec.clone()), None).await?;
+                        }
+                    }
+                }
+                ProvisionAction::RegisterApp(app_id) => {
+                    let entry = &profile.apps[app_id];
+                    apps_to_add.insert(app_id.clone(), (entry.spec.clone(), entry.get_placement_strategy()?));
+                }
+                _ => {}
+            }
+        }
+        self.add_apps(apps_to_add, "default").await?;
+
+        for action in &actions {
+            match action {
+                ProvisionAction::InstallShim(app_id) => {
+                    self.install_app_shim(app_id, false).await?;
+                }
+                ProvisionAction::RemoveShim(app_id) => {
+                    self.remove_app_shims(app_id).await?;
+                }
+                ProvisionAction::RemoveApp(app_id) => {
+                    self.uninstall_app(app_id, false).await?;
+                }
+                ProvisionAction::RemoveEnv(env_id) => self.remove_env(env_id).await?,
+                ProvisionAction::SyncEnv(env_id) => {
+                    self.get_env_mut(env_id).await?.sync(EnvCheckStrategy::Auto).await?;
+                }
+                _ => {}
+            }
+        }
+
+        Ok(actions)
+    }
+
     pub async fn list_envs(&self) -> &BTreeMap<String, VivaEnv> {
         &self.registered_envs
     }
 
-    pub async fn sync_envs(&mut self, env_ids: &HashSet<String>) -> Result<()> {
+    /// Sync environments (all registered ones if no ids are provided), according to a check strategy.
+    pub async fn sync_envs(&mut self, env_ids: &HashSet<String>, check_strategy: EnvCheckStrategy) -> Result<()> {
 
         let mut missing: Vec<String> = vec![];
 
@@ -107,49 +622,751 @@ impl VivaContext {
             0 => {
                 debug!("Syncing environments: {:?}", &env_ids);
             }
-            _ => {
-                bail!("The following environments are not registered: {:?}", missing);
+            _ => {
+                bail!("The following environments are not registered: {:?}", missing);
+            }
+        }
+
+        let mut env_ids_to_sync: Vec<String> = env_ids.into_iter().cloned().collect();
+        if env_ids_to_sync.len() == 0 {
+            env_ids_to_sync = self.get_env_ids().await;
+        }
+
+        for env_id in env_ids_to_sync {
+            let env = self.get_env_mut(&env_id).await?;
+            if check_strategy != EnvCheckStrategy::Auto {
+                println!("Syncing environment: {} (check strategy: {})", env_id, check_strategy);
+                env.sync(check_strategy).await?;
+                continue;
+            }
+            match env.sync_status {
+                EnvSyncStatus::Unknown => {
+                    println!("Syncing environment: {}", env_id);
+                    env.check_and_update_sync_status();
+                    match env.sync_status {
+                        EnvSyncStatus::Synced => {
+                            println!("Environment {} is already synced", env_id);
+                        }
+                        _ => {
+                            println!("Syncing environment: {}", env_id);
+                            env.sync(check_strategy).await?;
+                        }
+                    }
+                }
+                EnvSyncStatus::Synced => {
+                    println!("Environment {} is already synced", env_id);
+                }
+                EnvSyncStatus::NotSynced => {
+                    println!("Syncing environment: {}", env_id);
+                    env.sync(check_strategy).await?;
+                }
+            }
+        }
+        Ok(())
+
+    }
+
+    pub async fn list_apps(&self) -> &BTreeMap<String, VivaApp> {
+
+        &self.registered_apps
+
+    }
+
+    /// The keys that are pinned for signed collections.
+    pub async fn get_trust_store(&self) -> Result<TrustStore> {
+        TrustStore::load(self.project_dirs.data_dir().join(TRUSTED_KEYS_FILENAME)).await
+    }
+
+    /// The file that records environments whose prefix doesn't live in the default location.
+    fn profile_collections_file(&self) -> PathBuf {
+        self.project_dirs.config_dir().join(PROFILE_COLLECTIONS_FILENAME)
+    }
+
+    /// The local collections provisioning profiles added (collection id -> config directory),
+    /// which are loaded along with the default collections.
+    pub async fn read_profile_collections(&self) -> Result<BTreeMap<String, PathBuf>> {
+        let collections_file = self.profile_collections_file();
+        match collections_file.exists() {
+            true => read_models_spec(&collections_file).await,
+            false => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Record a collection a provisioning profile added (relative paths are resolved against the
+    /// current directory).
+    async fn add_profile_collection(&self, collection_id: &str, config_path: &Path) -> Result<()> {
+        let config_path = match config_path.is_absolute() {
+            true => config_path.to_path_buf(),
+            false => std::env::current_dir()?.join(config_path),
+        };
+        let mut collections = self.read_profile_collections().await?;
+        if collections.get(collection_id) != Some(&config_path) {
+            collections.insert(String::from(collection_id), config_path);
+            if let Some(parent) = self.profile_collections_file().parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            write_models_spec(&self.profile_collections_file(), &collections).await?;
+        }
+        Ok(())
+    }
+
+    fn env_locations_file(&self) -> PathBuf {
+        self.project_dirs.data_dir().join(ENV_LOCATIONS_FILENAME)
+    }
+
+    async fn read_env_locations(&self) -> Result<BTreeMap<String, PathBuf>> {
+        let locations_file = self.env_locations_file();
+        match locations_file.exists() {
+            true => read_models_spec(&locations_file).await,
+            false => Ok(BTreeMap::new()),
+        }
+    }
+
+    /// Record a custom prefix location for an environment, or remove it if `env_path` is `None`.
+    async fn set_env_location(&self, env_id: &str, env_path: Option<&PathBuf>) -> Result<()> {
+        let mut locations = self.read_env_locations().await?;
+        let changed = match env_path {
+            Some(path) => locations.insert(String::from(env_id), path.clone()).as_ref() != Some(path),
+            None => locations.remove(env_id).is_some(),
+        };
+        if changed {
+            if let Some(parent) = self.env_locations_file().parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            write_models_spec(&self.env_locations_file(), &locations).await?;
+        }
+        Ok(())
+    }
+
+    /// The prefix path of an environment without a custom location: where its collection keeps
+    /// prefixes, or the default environment directory.
+    fn default_env_path(&self, env_id: &str, collection_id: &str) -> PathBuf {
+        self.env_collections
+            .get(collection_id)
+            .and_then(|collection| collection.get_env_path(env_id))
+            .unwrap_or_else(|| self.base_env_path.join(env_id))
+    }
+
+    /// Determine the prefix path of an environment.
+    async fn resolve_env_path(&self, env_id: &str, collection_id: &str) -> Result<PathBuf> {
+        match self.read_env_locations().await?.remove(env_id) {
+            Some(path) => Ok(path),
+            None => Ok(self.default_env_path(env_id, collection_id)),
+        }
+    }
+
+    /// Move the prefix of an environment to a new location, without re-downloading or re-installing packages.
+    ///
+    /// Returns the number of files that had to be rewritten.
+    pub async fn relocate_env(&mut self, env_id: &str, new_env_path: &PathBuf) -> Result<usize> {
+        let new_env_path = match new_env_path.is_absolute() {
+            true => new_env_path.clone(),
+            false => std::env::current_dir()?.join(new_env_path),
+        };
+
+        let env = self.get_env_mut(env_id).await?;
+        let rewritten = env.relocate(&new_env_path).await?;
+        let collection_id = env.collection_id.clone();
+
+        match new_env_path == self.default_env_path(env_id, &collection_id) {
+            true => self.set_env_location(env_id, None).await?,
+            false => self.set_env_location(env_id, Some(&new_env_path)).await?,
+        }
+        Ok(rewritten)
+    }
+
+    /// Rename an environment: its spec (and lockfile) in its collection, and its prefix, which is
+    /// moved without re-installing packages. Apps that use the environment are changed to use it
+    /// under its new id.
+    ///
+    /// Prefixes are moved like with [`VivaContext::relocate_env`], unless the new path is longer
+    /// than the old one (which binary files can't be rewritten for), in which case the installed
+    /// packages are linked into a new prefix from the package cache.
+    pub async fn rename_env(&mut self, env_id: &str, new_env_id: &str) -> Result<()> {
+        let env = self.get_env(env_id).await?.clone();
+        let env_id = env.id.clone();
+        if self.has_env(new_env_id).await {
+            bail!("Can't rename environment: id '{}' already registered.", new_env_id);
+        }
+        confirm_env_not_in_use(&env_id, env.get_env_path(), &self.config)?;
+
+        let old_env_path = env.get_env_path().clone();
+        let custom_location = self.read_env_locations().await?.contains_key(&env_id);
+        let new_env_path = match custom_location {
+            true => old_env_path.clone(),
+            false => self.default_env_path(new_env_id, &env.collection_id),
+        };
+        let prefix_exists = old_env_path.join("conda-meta").is_dir();
+        let relink = prefix_exists
+            && !custom_location
+            && new_env_path.as_os_str().len() > old_env_path.as_os_str().len();
+        if relink {
+            let mut source = env.clone();
+            source.check_and_update_sync_status();
+            if source.sync_status != EnvSyncStatus::Synced {
+                bail!(
+                    "Environment '{}' is not synced, sync it before renaming it (its prefix needs to be re-linked)",
+                    env_id
+                );
+            }
+        }
+        if new_env_path != old_env_path && new_env_path.exists() {
+            bail!("Can't rename environment, path exists already: {}", new_env_path.display());
+        }
+
+        // The prefix is moved (or re-linked, or its custom location registered for the new id) first,
+        // and only once that worked, the spec is renamed. If renaming the spec fails, the prefix is
+        // restored, so a failure leaves the environment as it was.
+        let revisions_dir = old_env_path.join(ENV_REVISIONS_DIRNAME);
+        let mut moved = env.clone();
+        if custom_location {
+            self.set_env_location(new_env_id, Some(&old_env_path)).await?;
+        } else if relink {
+            // the revisions of the environment are kept
+            fs::create_dir_all(&new_env_path).await?;
+            if revisions_dir.exists() {
+                fs::rename(&revisions_dir, new_env_path.join(ENV_REVISIONS_DIRNAME)).await?;
+            }
+            let mut relinked = VivaEnv::create(
+                String::from(new_env_id),
+                env.collection_id.clone(),
+                env.spec.clone(),
+                new_env_path.clone(),
+                VivaEnvSpec::new(),
+                new_env_path.join(ENV_SPEC_FILENAME),
+                EnvSyncStatus::NotSynced,
+            );
+            relinked.config = self.config.clone();
+            if let Err(e) = relinked.link_packages_from(&env).await {
+                self.restore_renamed_prefix(&env, &new_env_path, None).await;
+                return Err(e);
+            }
+        } else if prefix_exists {
+            moved.relocate(&new_env_path).await?;
+        }
+
+        let spec = match self.rename_env_spec(&env, new_env_id).await {
+            Ok(spec) => spec,
+            Err(e) => {
+                match custom_location {
+                    true => {
+                        let _ = self.set_env_location(new_env_id, None).await;
+                    }
+                    false if prefix_exists => {
+                        self.restore_renamed_prefix(&env, &new_env_path, (!relink).then_some(&mut moved))
+                            .await
+                    }
+                    false => {}
+                }
+                return Err(e);
+            }
+        };
+
+        if custom_location {
+            self.set_env_location(&env_id, None).await?;
+        }
+        if relink {
+            let _journal = crate::journal::begin(JournalOperation::DeletePrefix {
+                env_id: env_id.clone(),
+                prefix: old_env_path.clone(),
+            })?;
+            fs::remove_dir_all(&old_env_path).await?;
+            conda_envs::unregister_prefix(&self.config, &old_env_path).await;
+        }
+
+        self.registered_envs.remove(&env_id);
+        self.env_sources.remove(&env_id);
+        self.env_layers.remove(&env_id);
+        self.add_registered_env(new_env_id, &env.collection_id, spec, false)
+            .await?;
+
+        let app_ids = self
+            .registered_apps
+            .values()
+            .filter(|app| app.get_env_id() == env_id)
+            .map(|app| app.id.clone())
+            .collect::<Vec<String>>();
+        for app_id in app_ids {
+            self.point_app_to_env(&app_id, new_env_id).await?;
+            self.merge_app_into_env(&app_id).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Rename the spec (and lockfile) of an environment in its collection, returns the spec. Nothing
+    /// is changed if that fails.
+    async fn rename_env_spec(&mut self, env: &VivaEnv, new_env_id: &str) -> Result<VivaEnvSpec> {
+        let collection = self
+            .env_collections
+            .get_mut(&env.collection_id)
+            .ok_or_else(|| anyhow!("Environment collection not found: {}", env.collection_id))?;
+        let spec = collection.get_env(&env.id).await?.clone();
+        collection.set_env(new_env_id, &spec).await?;
+        let lockfiles = match (collection.get_lockfile_path(&env.id), collection.get_lockfile_path(new_env_id)) {
+            (Some(old_lockfile), Some(new_lockfile)) if old_lockfile.exists() => Some((old_lockfile, new_lockfile)),
+            _ => None,
+        };
+        if let Some((old_lockfile, new_lockfile)) = &lockfiles {
+            if let Err(e) = fs::rename(old_lockfile, new_lockfile).await {
+                let _ = collection.delete_env(new_env_id).await;
+                return Err(e.into());
+            }
+        }
+        if let Err(e) = collection.delete_env(&env.id).await {
+            if let Some((old_lockfile, new_lockfile)) = &lockfiles {
+                let _ = fs::rename(new_lockfile, old_lockfile).await;
+            }
+            let _ = collection.delete_env(new_env_id).await;
+            return Err(e);
+        }
+        Ok(spec)
+    }
+
+    /// Undo the prefix part of a rename that failed: move the prefix back (`moved`, the environment
+    /// with its new path), or remove a re-linked one (and move the revisions back), errors are only
+    /// logged.
+    async fn restore_renamed_prefix(&self, env: &VivaEnv, new_env_path: &PathBuf, moved: Option<&mut VivaEnv>) {
+        let result = match moved {
+            Some(moved) => moved.relocate(env.get_env_path()).await.map(|_| ()),
+            None => async {
+                let revisions_dir = new_env_path.join(ENV_REVISIONS_DIRNAME);
+                if revisions_dir.exists() {
+                    fs::rename(&revisions_dir, env.get_env_path().join(ENV_REVISIONS_DIRNAME)).await?;
+                }
+                conda_envs::unregister_prefix(&self.config, new_env_path).await;
+                fs::remove_dir_all(new_env_path).await?;
+                Ok(())
+            }
+            .await,
+        };
+        if let Err(e) = result {
+            warn!(
+                "Could not restore prefix of environment '{}' after a failed rename: {}",
+                env.id, e
+            );
+        }
+    }
+
+    /// Make an app use another environment, its spec is only changed if the environment can't be
+    /// derived from the app id and the placement strategy of its collection.
+    async fn point_app_to_env(&mut self, app_id: &str, env_id: &str) -> Result<()> {
+        let app = self.get_app(app_id).await?;
+        let placement_strategy = self.get_app_placement_strategy(&app.app_collection_id);
+        let mut derived_spec = app.spec.clone();
+        derived_spec.env_id = None;
+        let derived_env_id =
+            self.get_env_id_for_app(app_id, &derived_spec, &app.app_collection_id, &placement_strategy);
+        if derived_env_id != env_id || app.spec.env_id.is_some() {
+            return self.fix_app(app_id, env_id).await;
+        }
+
+        let mut app_instance = VivaApp::create(
+            String::from(app_id),
+            app.spec.clone(),
+            app.app_collection_id.clone(),
+            String::from(env_id),
+        );
+        app_instance.explicit_env = app.explicit_env;
+        self.registered_apps.insert(String::from(app_id), app_instance);
+        Ok(())
+    }
+
+    /// Rename an app (its spec in its collection, and its shims).
+    ///
+    /// If the environment of the app was derived from its id, and no other app uses it, the
+    /// environment is renamed as well (see [`VivaContext::rename_env`]), otherwise the app keeps
+    /// using its environment under the old id.
+    pub async fn rename_app(&mut self, app_id: &str, new_app_id: &str) -> Result<()> {
+        let app = self.get_app(app_id).await?.clone();
+        let app_id = app.id.clone();
+        if self.registered_apps.contains_key(new_app_id) {
+            bail!("Can't rename app: id '{}' already registered.", new_app_id);
+        }
+        let env_id = String::from(app.get_env_id());
+        let placement_strategy = self.get_app_placement_strategy(&app.app_collection_id);
+        let new_derived_env_id =
+            self.get_env_id_for_app(new_app_id, &app.spec, &app.app_collection_id, &placement_strategy);
+        let shared_env = self
+            .registered_apps
+            .values()
+            .any(|other| other.id != app_id && other.get_env_id() == env_id);
+        let rename_env = new_derived_env_id != env_id
+            && env_id == app_id
+            && !shared_env
+            && self.has_env(&env_id).await
+            && !self.has_env(&new_derived_env_id).await;
+
+        let mut app_spec = app.spec.clone();
+        if new_derived_env_id != env_id && !rename_env {
+            app_spec.env_id = Some(env_id.clone());
+        }
+
+        let removed_shims = self.remove_app_shims(&app_id).await?;
+        let app_col = self
+            .app_collections
+            .get_mut(&app.app_collection_id)
+            .ok_or_else(|| anyhow!("App collection not found: {}", app.app_collection_id))?;
+        app_col.set_app(new_app_id, &app_spec).await?;
+        app_col.delete_app(&app_id).await?;
+
+        self.registered_apps.remove(&app_id);
+        let explicit_env = app.explicit_env || app_spec.env_id.is_some();
+        self.add_registered_app(new_app_id, app_spec, &app.app_collection_id, env_id.clone(), explicit_env, false)
+            .await?;
+        if rename_env {
+            self.rename_env(&env_id, &new_derived_env_id).await?;
+        }
+
+        if !removed_shims.is_empty() {
+            self.install_app_shim(new_app_id, false).await?;
+        }
+        Ok(())
+    }
+
+    /// Create a temporary environment with the spec of an existing environment plus extra specs,
+    /// without changing the registered environment (or its spec file).
+    ///
+    /// The overlay gets its own prefix (packages are hard-linked from the package cache, like for
+    /// every other environment), and is not synced yet. Remove it with
+    /// [`VivaContext::remove_overlay_env`] once it's not needed anymore.
+    pub async fn create_overlay_env(&self, env_id: &str, extra_spec: &VivaEnvSpec) -> Result<VivaEnv> {
+        let env = self.get_env(env_id).await?;
+        let mut spec = env.spec.clone();
+        spec.append_spec(extra_spec.clone());
+
+        // a new directory for every overlay, so concurrent runs (in this, or another process) never
+        // share a prefix; the pid at the end lets 'viva gc' find overlays of processes that are gone
+        let overlays_dir = self.project_dirs.data_dir().join("overlays");
+        fs::create_dir_all(&overlays_dir).await?;
+        let overlay_path = tempfile::Builder::new()
+            .prefix(&format!("{}-", env.id.replace('/', "-")))
+            .suffix(&format!("-{}", std::process::id()))
+            .tempdir_in(&overlays_dir)
+            .with_context(|| format!("Failed to create overlay directory in: {}", overlays_dir.display()))?
+            .into_path();
+        let mut overlay = VivaEnv::create(
+            format!("{}+overlay", env.id),
+            env.collection_id.clone(),
+            spec,
+            overlay_path.clone(),
+            VivaEnvSpec::new(),
+            overlay_path.join(ENV_SPEC_FILENAME),
+            EnvSyncStatus::NotSynced,
+        );
+        overlay.config = self.config.clone();
+        Ok(overlay)
+    }
+
+    /// Create an environment for a single run from an ad-hoc spec, without registering it in any
+    /// collection.
+    ///
+    /// The prefix is a new temporary directory (`ephemeral/tmp-<random>-<pid>` in the data directory,
+    /// so packages can still be hard-linked from the package cache) that should be deleted with
+    /// [`VivaContext::remove_ephemeral_env`] after the run. With `keep`, the prefix is kept instead,
+    /// keyed by the hash of the spec, so later runs with the same spec can use it without syncing.
+    pub async fn create_ephemeral_env(&self, spec: VivaEnvSpec, keep: bool) -> Result<VivaEnv> {
+        let spec_hash = spec.prefix_hash();
+        let ephemeral_dir = self.project_dirs.data_dir().join("ephemeral");
+        let env_path = match keep {
+            true => ephemeral_dir.join(&spec_hash),
+            false => {
+                fs::create_dir_all(&ephemeral_dir).await?;
+                // unique for every run, also within this process
+                tempfile::Builder::new()
+                    .prefix("tmp-")
+                    .suffix(&format!("-{}", std::process::id()))
+                    .tempdir_in(&ephemeral_dir)
+                    .with_context(|| {
+                        format!("Failed to create ephemeral environment directory in: {}", ephemeral_dir.display())
+                    })?
+                    .into_path()
+            }
+        };
+        let actual_spec_path = env_path.join(ENV_SPEC_FILENAME);
+        let actual = match actual_spec_path.exists() {
+            true => read_model_spec(&actual_spec_path).await?,
+            false => VivaEnvSpec::new(),
+        };
+        let mut env = VivaEnv::create(
+            format!("ephemeral-{}", spec_hash),
+            String::from("ephemeral"),
+            spec,
+            env_path,
+            actual,
+            actual_spec_path,
+            EnvSyncStatus::Unknown,
+        );
+        env.check_and_update_sync_status();
+        env.config = self.config.clone();
+        Ok(env)
+    }
+
+    /// The environment with an additional spec merged into it (or a new one, if no environment with
+    /// the id is registered), without registering or changing anything, e.g. to plan a sync with
+    /// [`VivaEnv::plan_sync`].
+    pub async fn preview_env(&self, env_id: &str, extra_spec: &VivaEnvSpec) -> Result<VivaEnv> {
+        let mut env = match self.registered_envs.get(env_id) {
+            Some(env) => env.clone(),
+            None => {
+                self.create_env_instance(env_id, String::from("default"), None)
+                    .await?
+            }
+        };
+        env.spec.append_spec(extra_spec.clone());
+        env.check_and_update_sync_status();
+        Ok(env)
+    }
+
+    /// Restore a previous revision of an environment (see [`VivaEnv::rollback`]), and make its spec
+    /// the registered spec of the environment again.
+    pub async fn rollback_env(&mut self, env_id: &str, revision: u32) -> Result<()> {
+        let env = self.get_env_mut(env_id).await?;
+        env.rollback(revision).await?;
+        let env_spec = env.spec.clone();
+        self.set_env_spec(env_id, env_spec).await
+    }
+
+    /// Register a copy of an environment under a new id (in the collection of the original
+    /// environment, or the provided one).
+    ///
+    /// With `link_prefix`, the packages that are installed in the original environment are linked
+    /// into the prefix of the copy right away (see [`VivaEnv::link_packages_from`]), instead of
+    /// being installed with the next sync (which might resolve newer versions).
+    pub async fn clone_env(
+        &mut self,
+        source_env_id: &str,
+        target_env_id: &str,
+        collection_id: Option<&str>,
+        link_prefix: bool,
+    ) -> Result<()> {
+        let mut source = self.get_env(source_env_id).await?.clone();
+        if self.has_env(target_env_id).await {
+            bail!("Can't clone environment: id '{}' already registered.", target_env_id);
+        }
+        if link_prefix {
+            source.check_and_update_sync_status();
+            if source.sync_status != EnvSyncStatus::Synced {
+                bail!(
+                    "Environment '{}' is not synced, sync it first (or clone it without linking its packages)",
+                    source.id
+                );
+            }
+        }
+
+        let collection_id = String::from(collection_id.unwrap_or(&source.collection_id));
+        if !self.env_collections.contains_key(&collection_id) {
+            bail!("Environment collection not found: {}", collection_id);
+        }
+        self.add_env(target_env_id, Some(source.spec.clone()), Some(&collection_id))
+            .await?;
+        if link_prefix {
+            self.get_env_mut(target_env_id)
+                .await?
+                .link_packages_from(&source)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Delete the prefix of an overlay environment.
+    pub async fn remove_overlay_env(&self, overlay: &VivaEnv) -> Result<()> {
+        let overlay_path = overlay.get_env_path();
+        if overlay_path.exists() {
+            fs::remove_dir_all(overlay_path)
+                .await
+                .with_context(|| format!("Failed to delete overlay environment: {}", overlay_path.display()))?;
+        }
+        conda_envs::unregister_prefix(&self.config, overlay_path).await;
+        Ok(())
+    }
+
+    /// Delete the prefix of an ephemeral environment (see [`VivaContext::create_ephemeral_env`]).
+    pub async fn remove_ephemeral_env(&self, env: &VivaEnv) -> Result<()> {
+        let env_path = env.get_env_path();
+        if env_path.exists() {
+            fs::remove_dir_all(env_path)
+                .await
+                .with_context(|| format!("Failed to delete ephemeral environment: {}", env_path.display()))?;
+        }
+        conda_envs::unregister_prefix(&self.config, env_path).await;
+        Ok(())
+    }
+
+    /// Find environment directories that don't belong to any registered environment (or app),
+    /// and overlay or ephemeral environments of interrupted runs, and delete them (unless `dry_run` is set).
+    ///
+    /// Environments that are not registered, but might still be needed are kept: the ones of
+    /// all collections (also if another collection's environment with the same id is used), ones
+    /// whose spec is currently invalid, and all of configured remote collections (even if they are
+    /// not loaded).
+    ///
+    /// Deleting needs to be confirmed by the user, unless the `assume_yes` setting is set
+    /// (`--yes`). Directories of environments that are in use by running processes are skipped.
+    pub async fn garbage_collect(&mut self, dry_run: bool) -> Result<GcReport> {
+        self.merge_all_apps().await?;
+        let mut used: HashSet<PathBuf> = self
+            .registered_envs
+            .values()
+            .map(|env| env.get_env_path().clone())
+            .collect();
+        for (collection_id, collection) in &self.env_collections {
+            for env_id in collection.get_env_ids().await {
+                used.insert(self.default_env_path(&env_id, collection_id));
+            }
+        }
+        for env_id in self.invalid_envs.keys() {
+            used.insert(self.base_env_path.join(env_id));
+        }
+        // a used directory is skipped with everything in it
+        let remote_collections = RemoteEnvCollection::read_registry(self.project_dirs.config_dir())
+            .await
+            .unwrap_or_default();
+        for collection_id in remote_collections.keys() {
+            used.insert(self.base_env_path.join(collection_id));
+        }
+
+        let mut orphaned = gc::find_orphaned_prefixes(&self.base_env_path, &used);
+        orphaned.extend(gc::find_stale_overlays(&self.project_dirs.data_dir().join("overlays")));
+        orphaned.extend(gc::find_stale_overlays(&self.project_dirs.data_dir().join("ephemeral")));
+        orphaned.retain(|orphan| {
+            let pids = crate::locks::running_processes(&orphan.path);
+            if !pids.is_empty() {
+                warn!("Skipping '{}', it is in use by running processes", orphan.path.display());
+            }
+            pids.is_empty()
+        });
+
+        let mut report = GcReport {
+            orphaned,
+            ..Default::default()
+        };
+        if report.orphaned.is_empty() || dry_run {
+            return Ok(report);
+        }
+
+        if !self.config.assume_yes {
+            if !console::user_attended() {
+                bail!("Not running interactively, use '--yes' to delete orphaned environments.");
+            }
+            let mut question = String::from("Orphaned environment directories:\n");
+            for orphan in &report.orphaned {
+                question.push_str(&format!(
+                    "  {} ({}): {}\n",
+                    orphan.env_id,
+                    indicatif::HumanBytes(orphan.size),
+                    orphan.path.display()
+                ));
+            }
+            let total: u64 = report.orphaned.iter().map(|orphan| orphan.size).sum();
+            question.push_str(&format!("Delete them ({})? [y/N] ", indicatif::HumanBytes(total)));
+            if !ask_user(&question)? {
+                return Ok(report);
             }
         }
 
-        let mut env_ids_to_sync: Vec<String> = env_ids.into_iter().cloned().collect();
-        if env_ids_to_sync.len() == 0 {
-            env_ids_to_sync = self.get_env_ids().await;
+        for orphan in &report.orphaned {
+            let _journal = crate::journal::begin(JournalOperation::DeletePrefix {
+                env_id: orphan.env_id.clone(),
+                prefix: orphan.path.clone(),
+            })?;
+            fs::remove_dir_all(&orphan.path)
+                .await
+                .with_context(|| format!("Failed to delete: {}", orphan.path.display()))?;
+            conda_envs::unregister_prefix(&self.config, &orphan.path).await;
+            report.reclaimed_bytes += orphan.size;
         }
+        report.deleted = true;
+        Ok(report)
+    }
 
-        for env_id in env_ids_to_sync {
-            let env = self.get_env_mut(&env_id).await?;
-            match env.sync_status {
-                EnvSyncStatus::Unknown => {
-                    println!("Syncing environment: {}", env_id);
-                    env.check_and_update_sync_status();
-                    match env.sync_status {
-                        EnvSyncStatus::Synced => {
-                            println!("Environment {} is already synced", env_id);
-                        }
-                        _ => {
-                            println!("Syncing environment: {}", env_id);
-                            env.sync().await?;
-                        }
-                    }
-                }
-                EnvSyncStatus::Synced => {
-                    println!("Environment {} is already synced", env_id);
-                }
-                EnvSyncStatus::NotSynced => {
-                    println!("Syncing environment: {}", env_id);
-                    env.sync().await?;
-                }
+    /// Check for operations that were interrupted (e.g. by a crash, or a power loss), and recover
+    /// from them.
+    ///
+    /// Recovery happens automatically if `assume_yes` is set, otherwise the user is asked first (in
+    /// non-interactive sessions, the interrupted operations are only reported).
+    pub async fn recover_interrupted_operations(&self) -> Result<()> {
+        let stale = crate::journal::stale_entries();
+        if stale.is_empty() {
+            return Ok(());
+        }
+
+        for (_, entry) in &stale {
+            warn!("An earlier viva process (pid {}) was interrupted while {}.", entry.pid, entry.describe());
+        }
+        let recover = match self.config.assume_yes {
+            true => true,
+            false if console::user_attended() => ask_user("Recover from the interrupted operations now? [y/N] ")?,
+            false => {
+                warn!("Not running interactively, use '--yes' to recover from the interrupted operations.");
+                false
             }
+        };
+        if !recover {
+            return Ok(());
+        }
+        for (entry_file, entry) in &stale {
+            let result = crate::journal::recover(entry_file, entry)
+                .await
+                .with_context(|| format!("Failed to recover from interrupted operation: {}", entry.describe()))?;
+            eprintln!("Recovered from interrupted operation ({}): {}", entry.describe(), result);
         }
         Ok(())
+    }
 
+    /// Ask whether extra specs should be added to an environment permanently.
+    ///
+    /// Only asks in interactive sessions, and if `assume_yes` is not set, otherwise the answer is
+    /// 'no'.
+    pub fn confirm_persist_specs(&self, env_id: &str, extra_spec: &VivaEnvSpec) -> Result<bool> {
+        if self.config.assume_yes || !console::user_attended() {
+            return Ok(false);
+        }
+        let mut specs = extra_spec.pkg_specs.clone();
+        specs.extend(extra_spec.pip_specs.iter().map(|s| format!("{} (pip)", s)));
+        specs.extend(extra_spec.channels.iter().map(|c| format!("{} (channel)", c)));
+        ask_user(&format!(
+            "Add {} to environment '{}' permanently (otherwise they are only used for this run)? [y/N] ",
+            specs.join(", "),
+            env_id
+        ))
     }
 
-    pub async fn list_apps(&self) -> &BTreeMap<String, VivaApp> {
+    fn snapshots_dir(&self, env_id: &str) -> PathBuf {
+        self.project_dirs.data_dir().join("snapshots").join(env_id)
+    }
 
-        &self.registered_apps
+    /// Create a hard-link snapshot of the prefix of an environment, to be able to restore it
+    /// later (e.g. before a risky update).
+    ///
+    /// Returns the name of the snapshot (a timestamp, if no name is provided).
+    pub async fn create_snapshot(&self, env_id: &str, name: Option<&str>) -> Result<String> {
+        let env = self.get_env(env_id).await?;
+        crate::snapshot::create_snapshot(env.get_env_path(), &self.snapshots_dir(&env.id), name)
+            .await
+    }
+
+    /// Restore the prefix of an environment from a snapshot (the latest one, if no name is
+    /// provided).
+    ///
+    /// Returns the name of the restored snapshot.
+    pub async fn restore_snapshot(&mut self, env_id: &str, name: Option<&str>) -> Result<String> {
+        let resolved_id = self.get_env(env_id).await?.id.clone();
+        let snapshots_dir = self.snapshots_dir(&resolved_id);
+        let env = self.get_env_mut(env_id).await?;
+        env.restore_snapshot(&snapshots_dir, name).await
+    }
 
+    /// List the names of all snapshots of an environment, oldest first.
+    pub async fn list_snapshots(&self, env_id: &str) -> Result<Vec<String>> {
+        let env = self.get_env(env_id).await?;
+        crate::snapshot::list_snapshots(&self.snapshots_dir(&env.id))
+    }
+
+    /// Delete a snapshot of an environment.
+    pub async fn delete_snapshot(&self, env_id: &str, name: &str) -> Result<()> {
+        let env = self.get_env(env_id).await?;
+        crate::snapshot::delete_snapshot(&self.snapshots_dir(&env.id), name).await
     }
 
     async fn create_env_instance(
@@ -158,24 +1375,21 @@ impl VivaContext {
         collection_id: String,
         env_spec: Option<VivaEnvSpec>,
     ) -> Result<VivaEnv> {
-        let env_path = self.base_env_path.join(env_id);
+        let env_path = self.resolve_env_path(env_id, &collection_id).await?;
         let env_spec_file: PathBuf = env_path.join(ENV_SPEC_FILENAME);
         let actual_env_spec: VivaEnvSpec = match env_spec_file.exists() {
             true => {
                 let env_actual: VivaEnvSpec = read_model_spec(&env_spec_file).await?;
                 env_actual
             }
-            false => VivaEnvSpec {
-                channels: vec![],
-                pkg_specs: vec![],
-            },
+            false => VivaEnvSpec::new(),
         };
 
         let env_spec = match env_spec {
             Some(spec) => spec,
             None => VivaEnvSpec::new(),
         };
-        let viva_env: VivaEnv = VivaEnv::create(
+        let mut viva_env: VivaEnv = VivaEnv::create(
             String::from(env_id),
             String::from(collection_id),
             env_spec,
@@ -184,6 +1398,11 @@ impl VivaContext {
             env_spec_file,
             EnvSyncStatus::Unknown,
         );
+        viva_env.config = self.config.clone();
+        viva_env.lockfile_path = self
+            .env_collections
+            .get(&viva_env.collection_id)
+            .and_then(|collection| collection.get_lockfile_path(env_id));
 
         Ok(viva_env)
     }
@@ -194,12 +1413,17 @@ impl VivaContext {
         app_spec: VivaAppSpec,
         collection_id: &str,
         env_id: String,
+        explicit_env: bool,
         allow_duplicate: bool,
     ) -> Result<bool> {
         match self.registered_apps.contains_key(app_id) {
             true => {
                 if allow_duplicate {
                     debug!("Skipping duplicate app: {}",app_id);
+                    self.warnings.push(format!(
+                        "App '{}' of collection '{}' is shadowed by another collection",
+                        app_id, collection_id
+                    ));
                     Ok(false)
                 } else {
                     Err(anyhow!("Duplicate app: {}", &app_id))
@@ -208,12 +1432,13 @@ impl VivaContext {
             false => {
                 debug!("Registering app: {}", app_id);
 
-                let app_instance = VivaApp::create(
+                let mut app_instance = VivaApp::create(
                     String::from(app_id),
                     app_spec.clone(),
                     String::from(collection_id),
                     env_id
                 );
+                app_instance.explicit_env = explicit_env;
                 self.registered_apps
                     .insert(String::from(app_id), app_instance);
                 Ok(true)
@@ -222,11 +1447,53 @@ impl VivaContext {
 
     }
 
+    /// Check the environments of the specified apps (or all apps, if empty) for outdated packages.
+    ///
+    /// Environments that are shared between apps are only solved once.
+    pub async fn find_outdated_apps(&self, app_ids: &[String]) -> Result<BTreeMap<String, Vec<OutdatedPackage>>> {
+        let app_ids: Vec<String> = match app_ids.is_empty() {
+            true => self.get_app_ids().await,
+            false => app_ids.to_vec(),
+        };
+
+        let mut env_results: HashMap<String, Vec<OutdatedPackage>> = HashMap::new();
+        let mut result: BTreeMap<String, Vec<OutdatedPackage>> = BTreeMap::new();
+        for app_id in app_ids {
+            let env_id = self.get_app_env_id(&app_id).await?;
+            if !env_results.contains_key(&env_id) {
+                let env = self.get_env(&env_id).await?;
+                env_results.insert(env_id.clone(), env.find_outdated_packages().await?);
+            }
+            result.insert(app_id, env_results[&env_id].clone());
+        }
+        Ok(result)
+    }
+
+    /// Update the environments of the specified apps to the newest package versions.
+    pub async fn update_apps(&mut self, app_ids: &[String]) -> Result<()> {
+        let mut env_ids: Vec<String> = vec![];
+        for app_id in app_ids {
+            let env_id = self.get_app_env_id(app_id).await?;
+            if !env_ids.contains(&env_id) {
+                env_ids.push(env_id);
+            }
+        }
+        for env_id in env_ids {
+            self.get_env_mut(&env_id).await?.update().await?;
+        }
+        Ok(())
+    }
+
     pub async fn merge_all_apps(&mut self) -> Result<()> {
 
         let app_ids: Vec<String> = self.get_app_ids().await;
 
+        let broken_apps = self.get_broken_apps();
         for app_id in app_ids {
+            if broken_apps.contains_key(&app_id) {
+                debug!("Not merging broken app: {}", app_id);
+                continue;
+            }
             self.merge_app_into_env(&app_id).await?;
         }
 
@@ -237,7 +1504,9 @@ impl VivaContext {
 
         let app_env_spec = self.get_app(app_id).await?;
         let env_id = String::from(app_env_spec.get_env_id());
-        let app_env_spec = app_env_spec.spec.env_spec.clone();
+        let source = format!("app '{}' (collection '{}')", app_id, app_env_spec.app_collection_id);
+        let mut app_env_spec = app_env_spec.spec.env_spec.clone();
+        app_env_spec.record_provenance(&source);
         let env = self.get_env_mut(&env_id).await?;
 
         env.merge_spec(&app_env_spec)?;
@@ -256,6 +1525,10 @@ impl VivaContext {
             true => {
                 if allow_duplicate {
                     debug!("Skipping duplicate environment: {}", &env_id);
+                    self.warnings.push(format!(
+                        "Environment '{}' of collection '{}' is shadowed by another collection",
+                        env_id, collection_id
+                    ));
                     Ok(false)
                 } else {
                     Err(anyhow!("Duplicate environment: {}", &env_id))
@@ -273,17 +1546,79 @@ impl VivaContext {
         }
     }
 
+    /// Write the spec of an environment to its collection.
+    ///
+    /// If specs of other collections are merged into the environment, only its own part is written
+    /// (see [`VivaContext::own_env_spec`]).
     pub async fn set_env_spec(&mut self, env_id: &str, env_spec: VivaEnvSpec) -> Result<()> {
-        let col_id = &self.get_env(env_id).await?.collection_id.clone();
+        let env = self.get_env(env_id).await?;
+        let (env_id, col_id) = (env.id.clone(), env.collection_id.clone());
+        let env_spec = self.own_env_spec(&env_id, env_spec).await?;
         let env_col = self
             .env_collections
-            .get_mut(col_id)
+            .get_mut(&col_id)
             .expect(format!("Can't find env collection: {}", col_id).as_str());
 
-        env_col.set_env(env_id, &env_spec).await?;
+        env_col.set_env(&env_id, &env_spec).await?;
         Ok(())
     }
 
+    /// The part of a spec of a registered environment that belongs to the collection it is
+    /// registered in, without what the collections that are merged into it add (see
+    /// [`VivaEnvSpec::without_underlay`]).
+    async fn own_env_spec(&self, env_id: &str, env_spec: VivaEnvSpec) -> Result<VivaEnvSpec> {
+        let layers = match self.env_layers.get(env_id) {
+            Some(layers) if layers.len() > 1 => layers,
+            _ => return Ok(env_spec),
+        };
+        let mut layer_specs = vec![];
+        for layer in layers {
+            let collection = self
+                .env_collections
+                .get(layer)
+                .ok_or_else(|| anyhow!("Environment collection not found: {}", layer))?;
+            layer_specs.push(collection.get_env(env_id).await?.clone());
+        }
+        let mut layer_specs = layer_specs.into_iter();
+        let own = layer_specs.next().expect("the environment has an own layer");
+        let mut lower = layer_specs.next().expect("the environment has a merged layer");
+        for layer_spec in layer_specs {
+            lower.underlay_spec(layer_spec);
+        }
+        Ok(env_spec.without_underlay(&own, &lower))
+    }
+
+    /// Remove package specs from an environment (and its spec file), the packages are uninstalled
+    /// with the next sync of the environment.
+    ///
+    /// Returns the package specs that were actually removed.
+    pub async fn remove_env_pkg_specs(&mut self, env_id: &str, pkg_specs: &Vec<String>) -> Result<Vec<String>> {
+        let env = self.get_env_mut(env_id).await?;
+        let removed = env
+            .spec
+            .pkg_specs
+            .iter()
+            .filter(|s| pkg_specs.contains(s))
+            .cloned()
+            .collect::<Vec<String>>();
+        env.remove_pkg_specs(pkg_specs)?;
+        let updated_spec = env.spec.clone();
+        if !removed.is_empty() {
+            self.set_env_spec(env_id, updated_spec).await?;
+        }
+        Ok(removed)
+    }
+
+    /// Replace the spec of an environment (and its spec file). Unlike with
+    /// [`VivaContext::merge_env_specs`], channels and package specs that are not part of the new
+    /// spec are removed, their packages are uninstalled with the next sync of the environment.
+    pub async fn replace_env_spec(&mut self, env_id: &str, env_spec: &VivaEnvSpec) -> Result<()> {
+        let env = self.get_env_mut(env_id).await?;
+        env.spec = env_spec.clone();
+        env.check_and_update_sync_status();
+        self.set_env_spec(env_id, env_spec.clone()).await
+    }
+
     pub async fn merge_env_specs(
         &mut self,
         target_env_id: &str,
@@ -340,7 +1675,7 @@ impl VivaContext {
     }
 
     pub async fn has_env(&self, env_name: &str) -> bool {
-        self.registered_envs.contains_key(env_name)
+        self.resolve_id(&self.registered_envs, env_name).is_some()
     }
 
     pub async fn add_app(
@@ -352,6 +1687,7 @@ impl VivaContext {
     ) -> Result<&VivaApp>{
 
         let env_id = self.get_env_id_for_app(app_id, &app_spec, collection_id, &placement_strategy);
+        let explicit_env = is_explicit_env(&app_spec, &placement_strategy);
 
         let app_col = self
             .app_collections
@@ -359,13 +1695,97 @@ impl VivaContext {
             .expect(format!("App collection not found: {}", collection_id).as_str());
 
         app_col.set_app(app_id, &app_spec).await?;
-        self.add_registered_app(app_id, app_spec, collection_id, env_id, false).await?;
+        self.add_registered_app(app_id, app_spec, collection_id, env_id, explicit_env, false).await?;
 
         let app = self.get_app(app_id).await?;
         Ok(app)
 
     }
 
+    /// Register multiple apps at once.
+    ///
+    /// All apps are validated before any of them is registered, so either all or none of the apps
+    /// end up in the collection.
+    pub async fn add_apps(
+        &mut self,
+        apps: BTreeMap<String, (VivaAppSpec, AppEnvPlacementStrategy)>,
+        collection_id: &str,
+    ) -> Result<Vec<String>> {
+        if !self.app_collections.contains_key(collection_id) {
+            return Err(anyhow!("App collection not found: {}", collection_id));
+        }
+
+        let mut problems: Vec<String> = vec![];
+        for (app_id, (app_spec, placement_strategy)) in &apps {
+            if self.registered_apps.contains_key(app_id) {
+                problems.push(format!("{}: app already registered", app_id));
+            }
+            if app_spec.executable.is_empty() {
+                problems.push(format!("{}: no executable specified", app_id));
+            }
+            if let Err(e) = app_spec.env_spec.validate() {
+                problems.push(format!("{}: {:#}", app_id, e));
+            }
+            let env_id = self.get_env_id_for_app(app_id, app_spec, collection_id, placement_strategy);
+            if let AppEnvPlacementStrategy::Custom(_) = placement_strategy {
+                if !self.has_env(&env_id).await {
+                    problems.push(format!("{}: environment not registered: {}", app_id, env_id));
+                }
+            }
+        }
+
+        if !problems.is_empty() {
+            bail!("Invalid app manifest, no apps were registered:\n  {}", problems.join("\n  "));
+        }
+
+        let mut added: Vec<String> = vec![];
+        for (app_id, (app_spec, placement_strategy)) in apps {
+            self.add_app(&app_id, app_spec, collection_id, placement_strategy).await?;
+            added.push(app_id);
+        }
+        Ok(added)
+    }
+
+    /// Register an app that uses an executable from an already existing environment.
+    ///
+    /// The app spec inherits the channels and package specs of the environment, and is bound to it
+    /// (using the `Custom` placement strategy), so no new environment will be created for it.
+    pub async fn add_app_from_env(
+        &mut self,
+        app_id: &str,
+        env_id: &str,
+        executable: &str,
+        args: Vec<String>,
+        collection_id: &str,
+    ) -> Result<&VivaApp> {
+        if self.registered_apps.contains_key(app_id) {
+            return Err(anyhow!("Can't add app: id '{}' already registered.", app_id));
+        }
+
+        let env = self.get_env(env_id).await?;
+        if env.find_executable(executable).is_none() {
+            return Err(anyhow!(
+                "Executable '{}' not found in environment (is it synced?): {}",
+                executable,
+                env_id
+            ));
+        }
+
+        let app_spec = VivaAppSpec {
+            executable: String::from(executable),
+            args,
+            env_spec: env.spec.clone(),
+            env_id: Some(String::from(env_id)),
+            shim_prefix: None,
+            runtime_stacks: vec![],
+            runtime_vars: BTreeMap::new(),
+        };
+
+        let placement_strategy = AppEnvPlacementStrategy::Custom(String::from(env_id));
+        self.add_app(app_id, app_spec, collection_id, placement_strategy)
+            .await
+    }
+
     pub async fn add_env(
         &mut self,
         env_id: &str,
@@ -400,24 +1820,93 @@ impl VivaContext {
     }
 
     pub async fn get_app(&self, app_name: &str) -> Result<&VivaApp> {
-        match self.registered_apps.get(app_name) {
-            Some(app) => Ok(app),
-            None => Err(anyhow!("App not found: {}", app_name)),
+        match self.resolve_id(&self.registered_apps, app_name) {
+            Some(app_id) => Ok(&self.registered_apps[&app_id]),
+            None => Err(not_found_error("App", app_name, self.registered_apps.keys())),
+        }
+    }
+
+    /// Find the registered id matching the provided one (ignoring case, if configured).
+    fn resolve_id<T>(&self, registered: &BTreeMap<String, T>, id: &str) -> Option<String> {
+        if registered.contains_key(id) {
+            return Some(String::from(id));
+        }
+        if !self.config.case_insensitive_ids {
+            return None;
+        }
+        let matches: Vec<&String> = registered
+            .keys()
+            .filter(|k| k.to_lowercase() == id.to_lowercase())
+            .collect();
+        match matches.len() {
+            1 => Some(matches[0].clone()),
+            _ => None,
+        }
+    }
+
+    /// Returns all apps that are bound to an environment that does not exist (app id -> env id).
+    pub fn get_broken_apps(&self) -> BTreeMap<String, String> {
+        self.registered_apps
+            .iter()
+            .filter(|(_, app)| app.explicit_env && !self.registered_envs.contains_key(app.get_env_id()))
+            .map(|(app_id, app)| (app_id.clone(), String::from(app.get_env_id())))
+            .collect()
+    }
+
+    /// Returns the id of the environment of an app, failing if the app is broken.
+    pub async fn get_app_env_id(&self, app_id: &str) -> Result<String> {
+        let app = self.get_app(app_id).await?;
+        let env_id = String::from(app.get_env_id());
+        if app.explicit_env && !self.registered_envs.contains_key(&env_id) {
+            bail!(
+                "App '{}' is broken, its environment does not exist: {} (use 'viva app fix {} --env <env-id>' to fix)",
+                app_id,
+                env_id,
+                app_id
+            );
+        }
+        Ok(env_id)
+    }
+
+    /// Re-point an app to another (existing) environment.
+    pub async fn fix_app(&mut self, app_id: &str, env_id: &str) -> Result<()> {
+        if !self.has_env(env_id).await {
+            bail!("No environment registered with id '{}'.", env_id);
         }
+        let app = self.get_app(app_id).await?;
+        let collection_id = app.app_collection_id.clone();
+        let mut app_spec = app.spec.clone();
+        app_spec.env_id = Some(String::from(env_id));
+
+        let app_col = self
+            .app_collections
+            .get_mut(&collection_id)
+            .ok_or_else(|| anyhow!("App collection not found: {}", collection_id))?;
+        app_col.set_app(app_id, &app_spec).await?;
+
+        let mut app_instance = VivaApp::create(
+            String::from(app_id),
+            app_spec,
+            collection_id,
+            String::from(env_id),
+        );
+        app_instance.explicit_env = true;
+        self.registered_apps.insert(String::from(app_id), app_instance);
+        Ok(())
     }
 
     pub async fn get_env(&self, env_name: &str) -> Result<&VivaEnv> {
-        match self.registered_envs.get(env_name) {
-            Some(env) => Ok(env),
-            None => Err(anyhow!("Environment not found: {}", env_name)),
+        match self.resolve_id(&self.registered_envs, env_name) {
+            Some(env_id) => Ok(&self.registered_envs[&env_id]),
+            None => Err(not_found_error("Environment", env_name, self.registered_envs.keys())),
         }
     }
 
     pub async fn get_env_mut(&mut self, env_id: &str) -> Result<&mut VivaEnv> {
 
-        match self.registered_envs.get_mut(env_id) {
-            Some(env) => Ok(env),
-            None => Err(anyhow!("Environment not found: {}", env_id)),
+        match self.resolve_id(&self.registered_envs, env_id) {
+            Some(env_id) => Ok(self.registered_envs.get_mut(&env_id).unwrap()),
+            None => Err(not_found_error("Environment", env_id, self.registered_envs.keys())),
         }
     }
 
@@ -433,7 +1922,11 @@ impl VivaContext {
         Ok(())
     }
 
-    pub fn get_env_id_for_app(&self, app_id: &str, _app_spec: &VivaAppSpec, collection_id: &str, placement_strategy: &AppEnvPlacementStrategy) -> String {
+    pub fn get_env_id_for_app(&self, app_id: &str, app_spec: &VivaAppSpec, collection_id: &str, placement_strategy: &AppEnvPlacementStrategy) -> String {
+
+        if let Some(env_id) = &app_spec.env_id {
+            return env_id.clone();
+        }
 
         match placement_strategy {
             AppEnvPlacementStrategy::Default => {
@@ -446,7 +1939,227 @@ impl VivaContext {
             AppEnvPlacementStrategy::AppId => {
                 String::from(app_id)
             },
+            AppEnvPlacementStrategy::SpecHash => app_spec.env_spec.content_addressed_env_id(),
+        }
+    }
+
+    pub async fn remove_app(&mut self, app_id: &str) -> Result<()> {
+        let app = self.get_app(app_id).await?;
+        let app_col_name = app.app_collection_id.clone();
+        let app_id = &app.id.clone();
+
+        let app_col = self
+            .app_collections
+            .get_mut(&app_col_name)
+            .expect(format!("App collection not found: {}", app_col_name).as_str());
+
+        app_col.delete_app(app_id).await?;
+        self.registered_apps.remove(app_id);
+
+        Ok(())
+    }
+
+    /// Remove an app, including its spec and shims.
+    ///
+    /// If `purge_env` is set, the environment of the app (including its prefix and snapshots) is
+    /// removed as well, if it was created for the app (see [`VivaApp::has_own_env`]), and no other
+    /// apps use it.
+    pub async fn uninstall_app(&mut self, app_id: &str, purge_env: bool) -> Result<AppUninstallReport> {
+        let app = self.get_app(app_id).await?;
+        let app_id = app.id.clone();
+        let env_id = String::from(app.get_env_id());
+        let own_env = app.has_own_env();
+        let mut report = AppUninstallReport {
+            app_id: app_id.clone(),
+            app_collection_id: app.app_collection_id.clone(),
+            removed_shims: vec![],
+            removed_env: None,
+            removed_snapshots: vec![],
+            kept_env_reason: None,
+        };
+
+        report.removed_shims = self.remove_app_shims(&app_id).await?;
+        self.remove_app(&app_id).await?;
+
+        if !purge_env {
+            return Ok(report);
+        }
+
+        let other_apps: Vec<String> = self
+            .registered_apps
+            .values()
+            .filter(|app| app.get_env_id() == env_id)
+            .map(|app| app.id.clone())
+            .collect();
+        if !own_env {
+            report.kept_env_reason = Some(format!("not created for the app: {}", env_id));
+        } else if !other_apps.is_empty() {
+            report.kept_env_reason = Some(format!("still used by: {}", other_apps.join(", ")));
+        } else if !self.registered_envs.contains_key(&env_id) {
+            report.kept_env_reason = Some(format!("environment does not exist: {}", env_id));
+        } else {
+            let env_path = self.get_env(&env_id).await?.get_env_path().clone();
+            report.removed_snapshots = self.list_snapshots(&env_id).await?;
+            self.remove_env(&env_id).await?;
+            let snapshots_dir = self.snapshots_dir(&env_id);
+            if snapshots_dir.exists() {
+                fs::remove_dir_all(&snapshots_dir).await?;
+            }
+            report.removed_env = Some((env_id, env_path));
+        }
+        Ok(report)
+    }
+
+    /// Find the environments file of the project in the provided directory (or one of its
+    /// parents), see [`ProjectEnvCollection::find`].
+    ///
+    /// The viva config file has the same name as a project environments file, so it is never
+    /// returned.
+    pub fn find_project_envs_file(&self, start_dir: &Path) -> Option<PathBuf> {
+        ProjectEnvCollection::find(start_dir)
+            .filter(|envs_file| envs_file.parent() != Some(self.project_dirs.config_dir()))
+    }
+
+    /// Determine the environment to use if none is specified explicitly.
+    ///
+    /// In order of precedence, this is the environment of a project spec (in the current
+    /// directory, or one of its parents), the default environment of a project environments file
+    /// (found the same way), the `default_env` configuration value, or `default`.
+    pub async fn resolve_default_env(&self) -> Result<DefaultEnv> {
+        let current_dir = std::env::current_dir()?;
+        if let Some((spec_file, project_spec)) = ProjectSpec::find(&current_dir).await? {
+            return Ok(DefaultEnv {
+                env_id: project_spec.get_env_id(&spec_file),
+                source: format!("project spec: {}", spec_file.display()),
+                env_spec: project_spec.env_spec,
+            });
+        }
+        if let Some(envs_file) = self.find_project_envs_file(&current_dir) {
+            match read_model_spec::<ProjectEnvsSpec>(&envs_file).await {
+                Ok(project) => {
+                    if let Some(env_id) = project.get_default_env_id() {
+                        return Ok(DefaultEnv {
+                            env_id,
+                            source: format!("project environments: {}", envs_file.display()),
+                            env_spec: None,
+                        });
+                    }
+                }
+                Err(e) => warn!("Ignoring project environments file '{}': {:#}", envs_file.display(), e),
+            }
+        }
+        if let Some(env_id) = &self.config.default_env {
+            return Ok(DefaultEnv {
+                env_id: env_id.clone(),
+                source: String::from("'default_env' config value"),
+                env_spec: None,
+            });
+        }
+        Ok(DefaultEnv {
+            env_id: String::from("default"),
+            source: String::from("no project spec or 'default_env' config value"),
+            env_spec: None,
+        })
+    }
+
+    /// The directory app shims are created in.
+    pub fn get_shim_dir(&self) -> PathBuf {
+        match &self.config.shim_dir {
+            Some(shim_dir) => shim_dir.clone(),
+            None => self.project_dirs.data_dir().join("shims"),
+        }
+    }
+
+    /// Create (or update) the shim for an app, returns the path of the shim.
+    ///
+    /// If `force` is set, existing files (that were not created by viva for this app) are
+    /// overwritten, and shims for protected executables are allowed.
+    pub async fn install_app_shim(&self, app_id: &str, force: bool) -> Result<PathBuf> {
+        let app = self.get_app(app_id).await?;
+
+        let shim_prefix = app
+            .spec
+            .shim_prefix
+            .as_deref()
+            .or(self.config.shim_prefix.as_deref());
+        let shim_name = crate::shims::shim_name(&app.id, shim_prefix);
+        if self.config.is_protected_executable(&shim_name) && !force {
+            bail!(
+                "Not creating shim '{}', it would shadow a protected system executable (use '--force', or a shim prefix).",
+                shim_name
+            );
+        }
+
+        let viva_exe = std::env::current_exe().context("Can't determine the path of the viva executable")?;
+        crate::shims::install_shim(&self.get_shim_dir(), &shim_name, &app.id, &viva_exe, force).await
+    }
+
+    /// Sync the environment of an app and create (or update) its shim (see
+    /// [`VivaContext::install_app_shim`]), so it can be started like any other executable, returns
+    /// the path of the shim.
+    pub async fn install_app(&mut self, app_id: &str, force: bool) -> Result<PathBuf> {
+        self.merge_all_apps().await?;
+        let env_id = self.get_app_env_id(app_id).await?;
+        self.get_env_mut(&env_id)
+            .await?
+            .sync(EnvCheckStrategy::Auto)
+            .await
+            .with_context(|| format!("Environment of app '{}' is not ready", app_id))?;
+        self.install_app_shim(app_id, force).await
+    }
+
+    /// Whether the shim directory is on the PATH.
+    pub fn is_shim_dir_on_path(&self) -> bool {
+        crate::shims::is_on_path(&self.get_shim_dir())
+    }
+
+    /// Remove all shims viva created for an app, returns the paths of the removed files.
+    pub async fn remove_app_shims(&self, app_id: &str) -> Result<Vec<PathBuf>> {
+        let app_id = match self.get_app(app_id).await {
+            Ok(app) => app.id.clone(),
+            // shims of apps that were removed already can still be cleaned up
+            Err(_) => String::from(app_id),
+        };
+        crate::shims::remove_shims(&self.get_shim_dir(), &app_id).await
+    }
+
+    /// All shims viva created (shim name -> app id).
+    pub async fn list_shims(&self) -> Result<BTreeMap<String, String>> {
+        crate::shims::list_shims(&self.get_shim_dir()).await
+    }
+
+    /// Restore the previous version of an environment spec (see [`EnvironmentCollection::undo_env`]).
+    ///
+    /// The environment prefix is not changed until the next sync.
+    pub async fn undo_env(&mut self, env_id: &str) -> Result<Option<VivaEnvSpec>> {
+        let (env_id, collection_id) = match self.get_env(env_id).await {
+            Ok(env) => (env.id.clone(), env.collection_id.clone()),
+            // the last change might have been the removal of the env
+            Err(_) => (String::from(env_id), String::from("default")),
+        };
+
+        let env_col = self
+            .env_collections
+            .get_mut(&collection_id)
+            .ok_or_else(|| anyhow!("Environment collection not found: {}", collection_id))?;
+        let restored = env_col.undo_env(&env_id).await?;
+
+        match &restored {
+            Some(env_spec) => match self.registered_envs.get_mut(&env_id) {
+                Some(env) => {
+                    env.spec = env_spec.clone();
+                    env.check_and_update_sync_status();
+                }
+                None => {
+                    self.add_registered_env(&env_id, &collection_id, env_spec.clone(), false)
+                        .await?;
+                }
+            },
+            None => {
+                self.registered_envs.remove(&env_id);
+            }
         }
+        Ok(restored)
     }
 
     pub async fn remove_env(&mut self, env_id: &str) -> Result<()> {
@@ -456,8 +2169,10 @@ impl VivaContext {
         }
 
         let env = self.get_env(&env_id).await?;
+        confirm_env_not_in_use(&env.id, env.get_env_path(), &self.config)?;
 
         let env_col_name = &env.collection_id.clone();
+        let env_id = &env.id.clone();
 
         let env_col = self
             .env_collections
@@ -466,11 +2181,18 @@ impl VivaContext {
             .as_mut();
 
         env_col.delete_env(env_id).await?;
-        self.registered_envs.remove(env_id);
-        let env_path = self.base_env_path.join(env_id);
+        let env_path = self.registered_envs.remove(env_id).unwrap().get_env_path().clone();
+        self.env_sources.remove(env_id);
+        self.env_layers.remove(env_id);
+        self.set_env_location(env_id, None).await?;
         match env_path.exists() {
             true => {
-                fs::remove_dir_all(env_path).await?;
+                let _journal = crate::journal::begin(JournalOperation::DeletePrefix {
+                    env_id: env_id.clone(),
+                    prefix: env_path.clone(),
+                })?;
+                fs::remove_dir_all(&env_path).await?;
+                conda_envs::unregister_prefix(&self.config, &env_path).await;
             },
             false => {
                 debug!("No environment path exists for env '{}', doing nothing.", env_id);
@@ -481,61 +2203,471 @@ impl VivaContext {
         Ok(())
     }
 
-    pub async fn pretty_print_envs(&self) {
-        let envs = self.list_envs().await;
-        let mut env_names: Vec<String> = envs.keys().map(|k| k.to_string()).collect();
-        env_names.sort();
+    /// Print a table of all environments (including invalid ones), see [`ListOptions`].
+    pub async fn pretty_print_envs(&self, options: &ListOptions) -> Result<()> {
+        let mut listing = Listing::new(vec!["name", "source", "path", "specs", "channels", "status"]);
+        for (env_id, viva_env) in self.list_envs().await {
+            // the collection of the environment, and the ones merged into it
+            let source = match self.env_layers.get(env_id) {
+                Some(layers) if layers.first() == Some(&viva_env.collection_id) => layers
+                    .iter()
+                    .enumerate()
+                    .map(|(index, layer)| match index {
+                        0 => layer.clone(),
+                        _ => format!("+{}", layer),
+                    })
+                    .collect(),
+                _ => vec![viva_env.collection_id.clone()],
+            };
+            listing.add_row(vec![
+                vec![env_id.clone()],
+                source,
+                vec![viva_env.get_env_path().to_string_lossy().to_string()],
+                viva_env.spec.pkg_specs.clone(),
+                viva_env.spec.channels.clone(),
+                vec![viva_env.sync_status.to_string()],
+            ]);
+        }
+        for (env_id, error) in &self.invalid_envs {
+            listing.add_row(vec![
+                vec![env_id.clone()],
+                vec![],
+                vec![],
+                vec![error.clone()],
+                vec![],
+                vec![String::from("Invalid")],
+            ]);
+        }
+        listing.print(options)
+    }
+
+    /// Print a table of all apps (including invalid ones), see [`ListOptions`].
+    pub async fn pretty_print_apps(&self, options: &ListOptions) -> Result<()> {
+        let mut listing = Listing::new(vec!["name", "cmd", "pkg_specs", "channels", "env_id", "status"]);
+        for (app_id, viva_app) in self.list_apps().await {
+            let status = match self.get_env(&viva_app.get_env_id()).await {
+                Ok(viva_env) => viva_env.sync_status.to_string(),
+                Err(_) => "Broken (env missing)".to_string(),
+            };
+            listing.add_row(vec![
+                vec![app_id.clone()],
+                vec![viva_app.spec.get_full_cmd().join(" ")],
+                viva_app.spec.env_spec.pkg_specs.clone(),
+                viva_app.spec.env_spec.channels.clone(),
+                vec![viva_app.get_env_id().to_string()],
+                vec![status],
+            ]);
+        }
+        for (app_id, error) in &self.invalid_apps {
+            listing.add_row(vec![
+                vec![app_id.clone()],
+                vec![],
+                vec![error.clone()],
+                vec![],
+                vec![],
+                vec![String::from("Invalid")],
+            ]);
+        }
+        listing.print(options)
+    }
+}
+
+/// Whether an app is bound to an environment explicitly, instead of via the placement strategy of
+/// its collection.
+fn is_explicit_env(app_spec: &VivaAppSpec, placement_strategy: &AppEnvPlacementStrategy) -> bool {
+    app_spec.env_id.is_some() || matches!(placement_strategy, AppEnvPlacementStrategy::Custom(_))
+}
+
+/// Make sure no process viva launched is still using an environment that is about to be deleted.
+///
+/// If there are, the user is asked for confirmation (in non-interactive sessions, this fails
+/// unless `assume_yes` is set).
+fn confirm_env_not_in_use(env_id: &str, env_path: &Path, config: &VivaConfig) -> Result<()> {
+    let pids = crate::locks::running_processes(env_path);
+    if pids.is_empty() {
+        return Ok(());
+    }
 
-        let mut table = Table::new();
-        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-        table.set_titles(prettytable::row![
-            "name", "path", "specs", "channels", "status"
-        ]);
+    let msg = format!(
+        "Environment '{}' is in use by running processes (pids: {}).",
+        env_id,
+        pids.iter().map(|p| p.to_string()).collect::<Vec<_>>().join(", ")
+    );
+    if config.assume_yes {
+        warn!("{} Deleting it anyway.", msg);
+        return Ok(());
+    }
+    if !console::user_attended() {
+        bail!("{} Not running interactively, use '--yes' to delete it anyway.", msg);
+    }
 
-        let compact: bool = false;
-        for env in env_names {
-            if !compact {
-                table.add_row(prettytable::row!["", "", "", "", ""]);
-            }
-            let viva_env = envs.get(&env).unwrap();
-            let path = viva_env.get_env_path().to_str().unwrap();
-            let specs = viva_env.spec.pkg_specs.join("\n");
-            let channels = viva_env.spec.channels.join("\n");
-            let status = &viva_env.sync_status;
-            table.add_row(prettytable::row![env, path, specs, channels, status]);
+    match ask_user(&format!("{} Delete it anyway? [y/N] ", msg))? {
+        true => Ok(()),
+        false => bail!("Deletion of environment '{}' cancelled.", env_id),
+    }
+}
+
+/// Ask the user a yes/no question on the terminal (the default is 'no').
+fn ask_user(question: &str) -> Result<bool> {
+    crate::rattler::global_multi_progress().suspend(|| -> Result<bool> {
+        let term = console::Term::stderr();
+        term.write_str(question)?;
+        let answer = term.read_line()?;
+        Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+    })
+}
+
+/// Create a 'not found' error, including the closest matching ids as suggestions.
+fn not_found_error<'a, I: Iterator<Item = &'a String>>(kind: &str, id: &str, candidates: I) -> anyhow::Error {
+    let suggestions = closest_matches(id, candidates);
+    match suggestions.is_empty() {
+        true => anyhow!("{} not found: {}", kind, id),
+        false => anyhow!("{} not found: {} (did you mean: {}?)", kind, id, suggestions.join(", ")),
+    }
+}
+
+/// Returns the (up to 3) candidates that are closest to the provided id, by edit distance.
+fn closest_matches<'a, I: Iterator<Item = &'a String>>(id: &str, candidates: I) -> Vec<String> {
+    let id = id.to_lowercase();
+    let max_distance = std::cmp::max(2, id.chars().count() / 3);
+    let mut matches: Vec<(usize, &String)> = candidates
+        .map(|c| (levenshtein(&id, &c.to_lowercase()), c))
+        .filter(|(distance, _)| *distance <= max_distance)
+        .collect();
+    matches.sort();
+    matches.into_iter().take(3).map(|(_, c)| c.clone()).collect()
+}
+
+/// The Levenshtein edit distance between two strings.
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let cost = if ca == *cb { 0 } else { 1 };
+            current.push(
+                (previous[j] + cost)
+                    .min(previous[j + 1] + 1)
+                    .min(current[j] + 1),
+            );
         }
-        table.printstd();
+        previous = current;
     }
+    previous[b.len()]
+}
 
-    pub async fn pretty_print_apps(&self) {
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_trait::async_trait;
+    use tempfile::TempDir;
+
+    /// A collection that keeps its specs in memory, and (optionally) fails to delete one of them.
+    #[derive(Debug, Default)]
+    struct MemoryEnvCollection {
+        envs: BTreeMap<String, VivaEnvSpec>,
+        fail_delete: Option<String>,
+    }
 
-        let apps = self.list_apps().await;
-        let mut app_names: Vec<String> = apps.keys().map(|k| k.to_string()).collect();
-        app_names.sort();
+    #[async_trait]
+    impl EnvironmentCollection for MemoryEnvCollection {
+        async fn get_env_ids(&self) -> Vec<String> {
+            self.envs.keys().cloned().collect()
+        }
 
-        let mut table = Table::new();
-        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
-        table.set_titles(prettytable::row![
-            "name", "cmd", "pkg_specs", "channels", "env_id", "status"
-        ]);
+        async fn get_env(&self, env_id: &str) -> Result<&VivaEnvSpec> {
+            self.envs
+                .get(env_id)
+                .ok_or_else(|| anyhow!("Environment not found: {}", env_id))
+        }
 
-        let compact: bool = false;
-        for app in app_names {
-            if !compact {
-                table.add_row(prettytable::row!["", "", "", "", "", ""]);
+        async fn delete_env(&mut self, env_id: &str) -> Result<()> {
+            if self.fail_delete.as_deref() == Some(env_id) {
+                bail!("Failed to delete: {}", env_id);
             }
-            let viva_app = apps.get(&app).unwrap();
-            let cmd = viva_app.spec.get_full_cmd().join(" ");
-            let env_id = viva_app.get_env_id();
-            let specs = viva_app.spec.env_spec.pkg_specs.join("\n");
-            let channels = viva_app.spec.env_spec.channels.join("\n");
-            let viva_env = self.get_env(&viva_app.get_env_id()).await.unwrap();
-            // let specs = viva_env.spec.pkg_specs.join("\n");
-            // let channels = viva_env.spec.channels.join("\n");
-            let status = &viva_env.sync_status;
+            self.envs.remove(env_id);
+            Ok(())
+        }
+
+        async fn set_env(&mut self, env_id: &str, env: &VivaEnvSpec) -> Result<()> {
+            self.envs.insert(String::from(env_id), env.clone());
+            Ok(())
+        }
+    }
 
-            table.add_row(prettytable::row![app, cmd, specs, channels, env_id, status]);
+    /// A context with the collections 'a', 'b' and 'c' (added in that order), which all define the
+    /// environment 'data'.
+    async fn layered_context(base: &Path, rules: &[(&str, CollectionRules)]) -> (VivaContext, Result<()>) {
+        let mut context = VivaContext::init();
+        context.base_env_path = base.to_path_buf();
+        let mut config = VivaConfig::default();
+        for (collection_id, collection_rules) in rules {
+            config
+                .collection_rules
+                .insert(String::from(*collection_id), collection_rules.clone());
+        }
+        context.config = Arc::new(config);
+
+        let mut a = VivaEnvSpec::new();
+        a.pkg_specs = vec![String::from("python=3.11")];
+        let mut b = VivaEnvSpec::new();
+        b.channels = vec![String::from("bioconda")];
+        b.pkg_specs = vec![String::from("python=3.10"), String::from("samtools")];
+        b.env_vars.insert(String::from("MODE"), String::from("prod"));
+        let mut c = VivaEnvSpec::new();
+        c.pkg_specs = vec![String::from("numpy")];
+
+        for (collection_id, env_spec) in [("a", a), ("b", b), ("c", c)] {
+            let collection = MemoryEnvCollection {
+                envs: BTreeMap::from([(String::from("data"), env_spec)]),
+                fail_delete: None,
+            };
+            if let Err(e) = context.add_env_collection(collection_id, Box::new(collection)).await {
+                return (context, Err(e));
+            }
         }
-        table.printstd();
+        (context, Ok(()))
+    }
+
+    fn rules(priority: i32, on_duplicate: Option<DuplicatePolicy>) -> CollectionRules {
+        CollectionRules { priority, on_duplicate }
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_policies() {
+        let base = tempfile::tempdir().unwrap();
+        // without a policy, the first collection wins, and the others are shadowed
+        let (context, result) = layered_context(base.path(), &[]).await;
+        result.unwrap();
+        let env = context.get_env("data").await.unwrap();
+        assert_eq!(env.collection_id, "a");
+        assert_eq!(env.spec.pkg_specs, vec!["python=3.11"]);
+        assert!(context.warnings.iter().any(|warning| warning.contains("'b' is shadowed")));
+
+        let (context, result) = layered_context(base.path(), &[("b", rules(0, Some(DuplicatePolicy::Skip)))]).await;
+        result.unwrap();
+        assert_eq!(context.get_env("data").await.unwrap().collection_id, "a");
+        assert!(!context.warnings.iter().any(|warning| warning.contains("'b' is shadowed")));
+
+        let (context, result) =
+            layered_context(base.path(), &[("b", rules(0, Some(DuplicatePolicy::Override)))]).await;
+        result.unwrap();
+        let env = context.get_env("data").await.unwrap();
+        assert_eq!(env.collection_id, "b");
+        assert_eq!(env.spec.pkg_specs, vec!["python=3.10", "samtools"]);
+
+        let (context, result) = layered_context(base.path(), &[("b", rules(0, Some(DuplicatePolicy::Merge)))]).await;
+        result.unwrap();
+        let env = context.get_env("data").await.unwrap();
+        assert_eq!(env.collection_id, "a");
+        assert_eq!(env.spec.pkg_specs, vec!["python=3.11", "samtools"]);
+        assert_eq!(env.spec.env_vars["MODE"], "prod");
+        assert_eq!(context.env_layers["data"], vec!["a", "b"]);
+
+        let (_, result) = layered_context(base.path(), &[("b", rules(0, Some(DuplicatePolicy::Error)))]).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_collection_priority() {
+        let base = tempfile::tempdir().unwrap();
+        let (context, result) = layered_context(base.path(), &[("c", rules(1, None))]).await;
+        result.unwrap();
+        let env = context.get_env("data").await.unwrap();
+        assert_eq!(env.collection_id, "c");
+        assert_eq!(env.spec.pkg_specs, vec!["numpy"]);
+
+        // the collection with the highest priority is the one the others are merged into
+        let (context, result) = layered_context(
+            base.path(),
+            &[("a", rules(0, Some(DuplicatePolicy::Merge))), ("c", rules(1, None))],
+        )
+        .await;
+        result.unwrap();
+        let env = context.get_env("data").await.unwrap();
+        assert_eq!(env.spec.pkg_specs, vec!["numpy", "python=3.11"]);
+        assert_eq!(context.env_layers["data"], vec!["c", "a"]);
+    }
+
+    #[tokio::test]
+    async fn test_merged_env_persists_own_spec() {
+        let base = tempfile::tempdir().unwrap();
+        let (mut context, result) =
+            layered_context(base.path(), &[("b", rules(0, Some(DuplicatePolicy::Merge)))]).await;
+        result.unwrap();
+        let mut extra = VivaEnvSpec::new();
+        extra.pkg_specs = vec![String::from("pandas")];
+        context.merge_env_specs("data", &extra, true, false).await.unwrap();
+
+        let env = context.get_env("data").await.unwrap();
+        assert!(env.spec.pkg_specs.contains(&String::from("samtools")));
+        assert!(env.spec.pkg_specs.contains(&String::from("pandas")));
+
+        // the spec of 'a' only gets the change, not what 'b' adds to it
+        let own = context.env_collections["a"].get_env("data").await.unwrap();
+        assert_eq!(own.pkg_specs, vec!["python=3.11", "pandas"]);
+        assert!(own.channels.iter().all(|channel| channel != "bioconda"));
+        assert!(own.env_vars.is_empty());
+        let lower = context.env_collections["b"].get_env("data").await.unwrap();
+        assert_eq!(lower.pkg_specs, vec!["python=3.10", "samtools"]);
+    }
+
+    async fn test_context(fail_delete: Option<&str>) -> (VivaContext, TempDir) {
+        let base = tempfile::tempdir().unwrap();
+        // a minimal prefix, with a file that contains its path
+        let prefix = base.path().join("old_env");
+        std::fs::create_dir_all(prefix.join("conda-meta")).unwrap();
+        std::fs::write(prefix.join("activate.sh"), format!("export PREFIX={}\n", prefix.display())).unwrap();
+
+        let mut context = VivaContext::init();
+        context.base_env_path = base.path().to_path_buf();
+        let collection = MemoryEnvCollection {
+            envs: BTreeMap::from([(String::from("old_env"), VivaEnvSpec::new())]),
+            fail_delete: fail_delete.map(String::from),
+        };
+        context
+            .add_env_collection("default", Box::new(collection))
+            .await
+            .unwrap();
+        (context, base)
+    }
+
+    #[tokio::test]
+    async fn test_rename_env() {
+        let (mut context, tmp) = test_context(None).await;
+        let base = tmp.path();
+        context.rename_env("old_env", "new_env").await.unwrap();
+
+        assert!(!context.has_env("old_env").await);
+        assert_eq!(context.get_env("new_env").await.unwrap().get_env_path(), &base.join("new_env"));
+        assert!(!base.join("old_env").exists());
+        let activate = std::fs::read_to_string(base.join("new_env").join("activate.sh")).unwrap();
+        assert_eq!(activate, format!("export PREFIX={}\n", base.join("new_env").display()));
+    }
+
+    /// Add an app collection with the app 'tool' (which uses 'old_env') to a [`test_context`], and
+    /// use a shim directory in its base directory. Returns the directory of the app collection.
+    async fn add_test_app(context: &mut VivaContext, base: &Path) -> PathBuf {
+        let mut config = (*context.config).clone();
+        config.shim_dir = Some(base.join("shims"));
+        context.config = Arc::new(config);
+        let apps_dir = base.join("apps");
+        std::fs::create_dir_all(&apps_dir).unwrap();
+        std::fs::write(
+            apps_dir.join("apps.yaml"),
+            "tool:\n  executable: tool\n  env_spec:\n    channels: []\n    pkg_specs: []\n  env_id: old_env\n",
+        )
+        .unwrap();
+        let collection = DefaultAppCollection::create(apps_dir.clone()).await.unwrap();
+        context.add_app_collection("default", Box::new(collection), None).await.unwrap();
+        apps_dir
+    }
+
+    #[tokio::test]
+    async fn test_rename_env_keeps_shims() {
+        let (mut context, tmp) = test_context(None).await;
+        let base = tmp.path();
+        add_test_app(&mut context, base).await;
+        let shim_path = context.install_app_shim("tool", false).await.unwrap();
+        let shim = std::fs::read_to_string(&shim_path).unwrap();
+
+        context.rename_env("old_env", "new_env").await.unwrap();
+        assert_eq!(context.get_app_env_id("tool").await.unwrap(), "new_env");
+        // the shim runs the app by its id, so it doesn't need to change with the environment
+        assert!(!shim.contains(&base.join("old_env").display().to_string()));
+        assert_eq!(std::fs::read_to_string(&shim_path).unwrap(), shim);
+    }
+
+    #[tokio::test]
+    async fn test_drift_report_dropped_package() {
+        let (mut context, _tmp) = test_context(None).await;
+        let mut profile = ProvisioningProfile::default();
+        profile.envs.insert(String::from("old_env"), VivaEnvSpec::new());
+        let report = context.drift_report(&profile).await.unwrap();
+        assert_ne!(report.envs["old_env"], DriftStatus::SpecMismatch);
+
+        // the registered spec has a package the profile doesn't (anymore)
+        let mut extra = VivaEnvSpec::new();
+        extra.pkg_specs = vec![String::from("numpy")];
+        context.merge_env_specs("old_env", &extra, true, false).await.unwrap();
+        let report = context.drift_report(&profile).await.unwrap();
+        assert_eq!(report.envs["old_env"], DriftStatus::SpecMismatch);
+        assert!(!report.in_sync);
+    }
+
+    #[tokio::test]
+    async fn test_profile_removes_packages() {
+        let (mut context, _tmp) = test_context(None).await;
+        let mut extra = VivaEnvSpec::new();
+        extra.pkg_specs = vec![String::from("python"), String::from("numpy")];
+        context.merge_env_specs("old_env", &extra, true, false).await.unwrap();
+
+        let mut profile = ProvisioningProfile::default();
+        let mut env_spec = VivaEnvSpec::new();
+        env_spec.pkg_specs = vec![String::from("python")];
+        profile.envs.insert(String::from("old_env"), env_spec.clone());
+        let actions = context.plan_profile(&profile, false).await.unwrap();
+        assert!(actions.contains(&ProvisionAction::UpdateEnv(String::from("old_env"))));
+
+        context.replace_env_spec("old_env", &env_spec).await.unwrap();
+        assert_eq!(context.get_env("old_env").await.unwrap().spec.pkg_specs, vec!["python"]);
+        let collection = context.env_collections.get("default").unwrap();
+        assert_eq!(collection.get_env("old_env").await.unwrap().pkg_specs, vec!["python"]);
+        let actions = context.plan_profile(&profile, false).await.unwrap();
+        assert!(!actions.contains(&ProvisionAction::UpdateEnv(String::from("old_env"))));
+    }
+
+    #[tokio::test]
+    async fn test_plan_profile_shims() {
+        let (mut context, tmp) = test_context(None).await;
+        add_test_app(&mut context, tmp.path()).await;
+        let mut profile = ProvisioningProfile::default();
+        profile.shims = vec![String::from("tool")];
+
+        let actions = context.plan_profile(&profile, false).await.unwrap();
+        assert_eq!(actions, vec![ProvisionAction::InstallShim(String::from("tool"))]);
+        context.install_app_shim("tool", false).await.unwrap();
+        assert!(context.plan_profile(&profile, false).await.unwrap().is_empty());
+
+        let actions = context.plan_profile(&ProvisioningProfile::default(), true).await.unwrap();
+        assert!(actions.contains(&ProvisionAction::RemoveShim(String::from("tool"))));
+        assert!(actions.contains(&ProvisionAction::RemoveApp(String::from("tool"))));
+    }
+
+    #[tokio::test]
+    async fn test_uninstall_app() {
+        let (mut context, tmp) = test_context(None).await;
+        let base = tmp.path();
+        let apps_dir = add_test_app(&mut context, base).await;
+        let shim_path = context.install_app_shim("tool", false).await.unwrap();
+
+        let report = context.uninstall_app("tool", true).await.unwrap();
+        assert_eq!(report.removed_shims, vec![shim_path.clone()]);
+        assert!(!shim_path.exists());
+        assert!(context.list_shims().await.unwrap().is_empty());
+        // the spec is gone from the collection file, not only from the loaded collection
+        let collection = DefaultAppCollection::create(apps_dir).await.unwrap();
+        assert!(collection.get_app_ids().await.is_empty());
+        assert!(context.get_app("tool").await.is_err());
+        // the environment was chosen explicitly, so it's not the app's to delete
+        assert!(report.removed_env.is_none());
+        assert!(context.has_env("old_env").await);
+    }
+
+    #[tokio::test]
+    async fn test_failed_rename_restores_prefix() {
+        let (mut context, tmp) = test_context(Some("old_env")).await;
+        let base = tmp.path();
+        assert!(context.rename_env("old_env", "new_env").await.is_err());
+
+        // the spec and the prefix are where they were
+        assert!(context.has_env("old_env").await);
+        assert!(!context.has_env("new_env").await);
+        let collection = context.env_collections.get("default").unwrap();
+        assert_eq!(collection.get_env_ids().await, vec![String::from("old_env")]);
+        assert!(!base.join("new_env").exists());
+        let activate = std::fs::read_to_string(base.join("old_env").join("activate.sh")).unwrap();
+        assert_eq!(activate, format!("export PREFIX={}\n", base.join("old_env").display()));
     }
 }
@@ -0,0 +1,452 @@
+// A background process that keeps the collections (and the sync status of their environments)
+// loaded, so launching an app doesn't have to read and validate every spec file first.
+//
+// The CLI asks the daemon for the launch plan of an app (executable, arguments and environment
+// variables) over a zmq socket, and runs it itself. If there is no daemon, or it can't provide a
+// plan (e.g. because the environment needs to be synced first), the CLI does the work itself, so the
+// daemon is never required for anything to work. It only speeds up 'app run' for apps with synced
+// environments, it doesn't keep repodata or solve results around.
+//
+// Every request has to contain the token the daemon wrote to its info file (only readable by the
+// user), since the endpoint itself (a tcp port on Windows) might be reachable by other users.
+
+use anyhow::{anyhow, bail, Context, Result};
+use ring::constant_time;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::time::SystemTime;
+use sysinfo::{Pid, PidExt, System, SystemExt};
+use tokio::process::Command;
+use tokio::sync::{mpsc, oneshot};
+use tracing::{debug, warn};
+
+use crate::defaults::{DAEMON_INFO_FILENAME, DAEMON_SOCKET_FILENAME, ENV_SPEC_FILENAME};
+use crate::models::app::{resolve_runtime_vars, RuntimeVar};
+use crate::models::environment::EnvSyncStatus;
+use crate::VivaContext;
+
+#[cfg(windows)]
+const DEFAULT_TCP_ENDPOINT: &str = "tcp://127.0.0.1:47321";
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "request", rename_all = "kebab-case")]
+pub enum DaemonRequest {
+    Ping,
+    LaunchPlan { app_id: String },
+    Shutdown,
+}
+
+/// A request, as it is sent to the daemon.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+struct DaemonMessage {
+    token: String,
+    #[serde(flatten)]
+    request: DaemonRequest,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+#[serde(tag = "response", rename_all = "kebab-case")]
+pub enum DaemonResponse {
+    Pong {
+        pid: u32,
+        version: String,
+        /// The number of apps with a cached launch plan.
+        cached_plans: usize,
+    },
+    LaunchPlan(LaunchPlan),
+    /// The daemon can't provide what was requested, the caller should do it itself.
+    Unavailable { reason: String },
+    Error { message: String },
+    ShuttingDown,
+}
+
+/// Everything that is needed to launch an app, without loading any collections.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct LaunchPlan {
+    pub app_id: String,
+    pub env_id: String,
+    pub env_path: PathBuf,
+    pub executable: PathBuf,
+    pub args: Vec<String>,
+    /// Directories to prepend to the `PATH` of the current process.
+    pub path_prepend: Vec<PathBuf>,
+    /// Runtime variables of the app, the ones that should only be set if they are not set already
+    /// depend on the environment of the client.
+    #[serde(default)]
+    pub runtime_vars: Vec<RuntimeVar>,
+    pub env_vars: BTreeMap<String, String>,
+}
+
+impl LaunchPlan {
+    /// Run the app (with additional arguments), and wait until it exits.
+    pub async fn run(&self, extra_args: &[String]) -> Result<ExitStatus> {
+        let mut paths = self.path_prepend.clone();
+        if let Some(current_path) = std::env::var_os("PATH") {
+            paths.extend(std::env::split_paths(&current_path));
+        }
+        let mut command = Command::new(&self.executable);
+        command
+            .args(&self.args)
+            .args(extra_args)
+            .env("PATH", std::env::join_paths(paths)?)
+            .envs(resolve_runtime_vars(&self.runtime_vars))
+            // a 'PATH' in the spec overrides the computed one
+            .envs(&self.env_vars);
+        let mut child = command
+            .spawn()
+            .with_context(|| format!("Failed to spawn subprocess: {}", self.executable.display()))?;
+        let _lock = child
+            .id()
+            .map(|pid| crate::locks::RunningLock::acquire(&[self.env_path.as_path()], pid));
+        Ok(child.wait().await?)
+    }
+}
+
+/// Written to the cache directory while the daemon runs, so clients know whether (and where) to
+/// connect.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct DaemonInfo {
+    pub pid: u32,
+    pub endpoint: String,
+    /// The token clients have to send with their requests.
+    pub token: String,
+}
+
+fn daemon_info_path(context: &VivaContext) -> PathBuf {
+    context.project_dirs.cache_dir().join(DAEMON_INFO_FILENAME)
+}
+
+/// The endpoint the daemon listens on.
+pub fn daemon_endpoint(context: &VivaContext) -> String {
+    if let Some(endpoint) = &context.get_config().daemon_endpoint {
+        return endpoint.clone();
+    }
+    #[cfg(windows)]
+    return String::from(DEFAULT_TCP_ENDPOINT);
+    #[cfg(not(windows))]
+    return format!(
+        "ipc://{}",
+        context.project_dirs.cache_dir().join(DAEMON_SOCKET_FILENAME).display()
+    );
+}
+
+/// The details of the running daemon, if there is one.
+///
+/// The info file of a daemon that didn't exit cleanly is removed, so clients don't wait for it.
+pub fn read_daemon_info(context: &VivaContext) -> Option<DaemonInfo> {
+    let info_path = daemon_info_path(context);
+    let content = std::fs::read_to_string(&info_path).ok()?;
+    let info: DaemonInfo = serde_json::from_str(&content).ok()?;
+    if !System::new().refresh_process(Pid::from_u32(info.pid)) {
+        debug!("Removing info file of daemon that is not running anymore (pid {})", info.pid);
+        let _ = std::fs::remove_file(&info_path);
+        return None;
+    }
+    Some(info)
+}
+
+/// Send a request to the running daemon, `None` if no daemon is running.
+pub fn send_request(
+    context: &VivaContext,
+    request: &DaemonRequest,
+    timeout_ms: i32,
+) -> Result<Option<DaemonResponse>> {
+    let info = match read_daemon_info(context) {
+        Some(info) => info,
+        None => return Ok(None),
+    };
+    let zmq_context = zmq::Context::new();
+    let socket = zmq_context.socket(zmq::REQ)?;
+    socket.set_linger(0)?;
+    socket.set_sndtimeo(timeout_ms)?;
+    socket.set_rcvtimeo(timeout_ms)?;
+    socket.connect(&info.endpoint)?;
+    let message = DaemonMessage {
+        token: info.token.clone(),
+        request: request.clone(),
+    };
+    socket.send(serde_json::to_string(&message)?.as_bytes(), 0)?;
+    let response = match socket.recv_string(0) {
+        Ok(Ok(response)) => response,
+        Ok(Err(_)) => bail!("Invalid response from daemon (not utf-8)"),
+        Err(zmq::Error::EAGAIN) => bail!(
+            "Daemon (pid {}) did not respond within {}ms: {}",
+            info.pid,
+            timeout_ms,
+            info.endpoint
+        ),
+        Err(e) => return Err(e.into()),
+    };
+    Ok(Some(serde_json::from_str(&response)?))
+}
+
+/// Ask the running daemon for the launch plan of an app.
+///
+/// Returns `None` if there is no daemon, or it can't provide a plan, errors are only logged.
+pub fn request_launch_plan(context: &VivaContext, app_id: &str) -> Option<LaunchPlan> {
+    let request = DaemonRequest::LaunchPlan {
+        app_id: String::from(app_id),
+    };
+    match send_request(context, &request, 1000) {
+        Ok(Some(DaemonResponse::LaunchPlan(plan))) => Some(plan),
+        Ok(Some(DaemonResponse::Unavailable { reason })) => {
+            debug!("Daemon can't launch app '{}': {}", app_id, reason);
+            None
+        }
+        Ok(Some(DaemonResponse::Error { message })) => {
+            debug!("Daemon failed to create launch plan for app '{}': {}", app_id, message);
+            None
+        }
+        Ok(Some(response)) => {
+            debug!("Unexpected response from daemon: {:?}", response);
+            None
+        }
+        Ok(None) => None,
+        Err(e) => {
+            debug!("Could not contact daemon: {}", e);
+            None
+        }
+    }
+}
+
+struct CachedPlan {
+    plan: LaunchPlan,
+    /// When the prefix was last synced, the plan is outdated if it was synced again since.
+    synced_at: Option<SystemTime>,
+}
+
+fn synced_at(env_path: &Path) -> Option<SystemTime> {
+    std::fs::metadata(env_path.join(ENV_SPEC_FILENAME))
+        .and_then(|metadata| metadata.modified())
+        .ok()
+}
+
+struct DaemonState {
+    watcher: crate::CollectionWatcher,
+    plans: HashMap<String, CachedPlan>,
+}
+
+impl DaemonState {
+    async fn handle(&mut self, context: &mut VivaContext, request: DaemonRequest) -> DaemonResponse {
+        match request {
+            DaemonRequest::Ping => DaemonResponse::Pong {
+                pid: std::process::id(),
+                version: String::from(env!("CARGO_PKG_VERSION")),
+                cached_plans: self.plans.len(),
+            },
+            DaemonRequest::LaunchPlan { app_id } => match self.launch_plan(context, &app_id).await {
+                Ok(response) => response,
+                Err(e) => DaemonResponse::Error {
+                    message: format!("{:#}", e),
+                },
+            },
+            DaemonRequest::Shutdown => DaemonResponse::ShuttingDown,
+        }
+    }
+
+    async fn launch_plan(&mut self, context: &mut VivaContext, app_id: &str) -> Result<DaemonResponse> {
+        if context.reload_if_changed(&mut self.watcher).await? {
+            debug!("Collections changed, dropping cached launch plans");
+            context.merge_all_apps().await?;
+            self.plans.clear();
+        }
+
+        if let Some(cached) = self.plans.get(app_id) {
+            if cached.plan.executable.is_file() && cached.synced_at == synced_at(&cached.plan.env_path) {
+                return Ok(DaemonResponse::LaunchPlan(cached.plan.clone()));
+            }
+        }
+
+        let env_id = context.get_app_env_id(app_id).await?;
+        let app_spec = context.get_app(app_id).await?.spec.clone();
+        let cmd = app_spec.get_full_cmd();
+        let env = context.get_env_mut(&env_id).await?;
+        env.reload_actual().await?;
+        if env.sync_status != EnvSyncStatus::Synced {
+            return Ok(DaemonResponse::Unavailable {
+                reason: format!("environment '{}' is not synced", env_id),
+            });
+        }
+        let executable = match env.find_executable(&cmd[0]) {
+            Some(executable) => executable,
+            None => {
+                return Ok(DaemonResponse::Unavailable {
+                    reason: format!("executable '{}' not found in environment '{}'", cmd[0], env_id),
+                })
+            }
+        };
+        let activation = env.activation();
+        let runtime_vars = app_spec.get_runtime_vars(env.get_env_path())?;
+        let plan = LaunchPlan {
+            app_id: String::from(app_id),
+            env_id,
+            env_path: env.get_env_path().clone(),
+            executable,
+            args: cmd[1..].to_vec(),
+            path_prepend: activation.path_prepend,
+            runtime_vars,
+            env_vars: activation.env_vars,
+        };
+        self.plans.insert(
+            String::from(app_id),
+            CachedPlan {
+                synced_at: synced_at(&plan.env_path),
+                plan: plan.clone(),
+            },
+        );
+        Ok(DaemonResponse::LaunchPlan(plan))
+    }
+}
+
+type PendingRequest = (DaemonRequest, oneshot::Sender<DaemonResponse>);
+
+/// A random token (hex-encoded).
+fn generate_token() -> Result<String> {
+    let mut token = [0u8; 32];
+    SystemRandom::new()
+        .fill(&mut token)
+        .map_err(|_| anyhow!("Could not generate daemon token"))?;
+    Ok(token.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Parse a message from a client, the error is the response if it is invalid, or doesn't contain
+/// the token of the daemon.
+fn parse_message(message: &[u8], token: &str) -> std::result::Result<DaemonRequest, DaemonResponse> {
+    let message = serde_json::from_slice::<DaemonMessage>(message).map_err(|e| DaemonResponse::Error {
+        message: format!("Invalid request: {}", e),
+    })?;
+    match constant_time::verify_slices_are_equal(message.token.as_bytes(), token.as_bytes()) {
+        Ok(()) => Ok(message.request),
+        Err(_) => Err(DaemonResponse::Error {
+            message: String::from("Invalid token"),
+        }),
+    }
+}
+
+/// Serve requests on the zmq socket (which blocks), handing them to the async side of the daemon.
+fn serve_socket(socket: zmq::Socket, token: String, requests: mpsc::Sender<PendingRequest>) -> Result<()> {
+    loop {
+        let message = socket.recv_bytes(0)?;
+        let response = match parse_message(&message, &token) {
+            Ok(request) => {
+                let (reply, response) = oneshot::channel();
+                if requests.blocking_send((request, reply)).is_err() {
+                    return Ok(());
+                }
+                response.blocking_recv().unwrap_or(DaemonResponse::ShuttingDown)
+            }
+            Err(response) => response,
+        };
+        socket.send(serde_json::to_string(&response)?.as_bytes(), 0)?;
+        if matches!(response, DaemonResponse::ShuttingDown) {
+            return Ok(());
+        }
+    }
+}
+
+/// Write the info file of the daemon, only readable by the user (the cache directory of the user is
+/// private on Windows).
+fn write_info_file(info_path: &Path, info: &DaemonInfo) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(info_path)?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+    }
+    file.write_all(serde_json::to_string_pretty(info)?.as_bytes())?;
+    Ok(())
+}
+
+/// Run the daemon in the current process, until it is asked to shut down.
+pub async fn run_daemon(context: &mut VivaContext) -> Result<()> {
+    if let Some(info) = read_daemon_info(context) {
+        if send_request(context, &DaemonRequest::Ping, 1000).is_ok() {
+            bail!("Daemon is already running (pid {}): {}", info.pid, info.endpoint);
+        }
+    }
+
+    context.merge_all_apps().await?;
+    let mut state = DaemonState {
+        watcher: context.watch_collections().await?,
+        plans: HashMap::new(),
+    };
+
+    let endpoint = daemon_endpoint(context);
+    std::fs::create_dir_all(context.project_dirs.cache_dir())?;
+    let zmq_context = zmq::Context::new();
+    let socket = zmq_context.socket(zmq::REP)?;
+    socket
+        .bind(&endpoint)
+        .map_err(|e| anyhow!("Could not listen on '{}': {}", endpoint, e))?;
+
+    let info_path = daemon_info_path(context);
+    let info = DaemonInfo {
+        pid: std::process::id(),
+        endpoint: endpoint.clone(),
+        token: generate_token()?,
+    };
+    write_info_file(&info_path, &info)?;
+    debug!("Daemon listening on: {}", endpoint);
+
+    let (sender, mut requests) = mpsc::channel::<PendingRequest>(16);
+    let token = info.token.clone();
+    let server = tokio::task::spawn_blocking(move || serve_socket(socket, token, sender));
+
+    while let Some((request, reply)) = requests.recv().await {
+        debug!("Daemon request: {:?}", request);
+        let response = state.handle(context, request).await;
+        let shutdown = matches!(response, DaemonResponse::ShuttingDown);
+        let _ = reply.send(response);
+        if shutdown {
+            break;
+        }
+    }
+
+    drop(requests);
+    if let Err(e) = server.await? {
+        warn!("Daemon socket error: {}", e);
+    }
+    let _ = std::fs::remove_file(&info_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_message() {
+        let token = generate_token().unwrap();
+        assert_eq!(token.len(), 64);
+        assert_ne!(token, generate_token().unwrap());
+
+        let message = |token: &str| {
+            serde_json::to_vec(&DaemonMessage {
+                token: String::from(token),
+                request: DaemonRequest::Shutdown,
+            })
+            .unwrap()
+        };
+        assert!(matches!(parse_message(&message(&token), &token), Ok(DaemonRequest::Shutdown)));
+        assert!(matches!(
+            parse_message(&message("wrong"), &token),
+            Err(DaemonResponse::Error { .. })
+        ));
+        // requests without a token are rejected
+        assert!(matches!(
+            parse_message(br#"{"request": "shutdown"}"#, &token),
+            Err(DaemonResponse::Error { .. })
+        ));
+    }
+}
@@ -1,7 +1,11 @@
-
+use std::time::Duration;
 
 pub const DEFAULT_CHANNELS: [&'static str; 1] = ["conda-forge"];
 
+/// System tools environments should not shadow, unless configured otherwise.
+pub const DEFAULT_PROTECTED_EXECUTABLES: [&'static str; 9] =
+    ["git", "ssh", "scp", "sftp", "ssh-agent", "gpg", "sudo", "sh", "bash"];
+
 #[cfg(windows)]
 pub const CONDA_BIN_DIRNAME: &str = "Scripts";
 
@@ -9,3 +13,88 @@ pub const CONDA_BIN_DIRNAME: &str = "Scripts";
 pub const CONDA_BIN_DIRNAME: &str = "bin";
 
 pub const ENV_SPEC_FILENAME: &str = ".viva_env";
+
+/// The directory of the read-only, system-wide environment collection, if none is configured.
+#[cfg(windows)]
+pub const SYSTEM_ENVS_DIR: &str = "C:\\ProgramData\\viva\\envs";
+
+#[cfg(unix)]
+pub const SYSTEM_ENVS_DIR: &str = "/etc/viva/envs";
+
+/// The name (without extension) of project-local spec files, that select the environment to use.
+pub const PROJECT_SPEC_FILENAME: &str = "viva-project";
+
+/// The name (without extension) of the file that declares the environments of a project, in the
+/// project directory (alternatively, `envs.<ext>` in the project's `.viva` directory).
+pub const PROJECT_ENVS_FILENAME: &str = "viva";
+
+/// The directory (in a project) that contains the lockfiles and prefixes of project environments.
+pub const PROJECT_DIRNAME: &str = ".viva";
+
+/// The extension of environment lockfiles, which live next to the environment specs.
+pub const LOCKFILE_EXTENSION: &str = "lock";
+
+pub const ENV_LOCATIONS_FILENAME: &str = "env_locations.json";
+
+/// The file (in the viva data dir) that contains the channel credentials stored by 'auth login'.
+pub const CREDENTIALS_FILENAME: &str = "credentials.json";
+
+/// The file (in the viva config dir) that lists the remote collections added with 'collection add'.
+pub const REMOTE_COLLECTIONS_FILENAME: &str = "remote_collections.yaml";
+
+/// The file (in the viva config dir) that lists the local collections provisioning profiles added
+/// (collection id -> config directory).
+pub const PROFILE_COLLECTIONS_FILENAME: &str = "profile_collections.yaml";
+
+/// The directory (in the viva data dir) remote collections are cached in, one subdirectory each.
+pub const REMOTE_COLLECTIONS_DIRNAME: &str = "collections";
+
+/// The name (without extension) of the index file of a remote collection.
+pub const COLLECTION_INDEX_FILENAME: &str = "index";
+
+/// The file (next to the index of a signed collection) that contains its minisign public key.
+pub const COLLECTION_KEY_FILENAME: &str = "minisign.pub";
+
+/// The file (in the viva data dir) that records the keys pinned for signed collections.
+pub const TRUSTED_KEYS_FILENAME: &str = "trusted_keys.json";
+
+pub const IGNORE_FILENAME: &str = ".vivaignore";
+
+/// The file (in the viva cache dir) that contains the pid and endpoint of the running daemon.
+#[cfg(feature = "daemon")]
+pub const DAEMON_INFO_FILENAME: &str = "daemon.json";
+
+/// The ipc socket (in the viva cache dir) the daemon listens on, if no endpoint is configured.
+#[cfg(feature = "daemon")]
+pub const DAEMON_SOCKET_FILENAME: &str = "daemon.sock";
+
+/// The directory (in the package cache) with one file per package, recording when it was last used.
+pub const PACKAGE_LAST_USED_DIRNAME: &str = ".viva_last_used";
+
+/// The file (in the shim directory) that records which shims viva created, for which app.
+pub const SHIM_REGISTRY_FILENAME: &str = ".viva_shims.json";
+
+/// The directory (in an environment prefix) that records the processes viva launched from it.
+pub const RUNNING_PIDS_DIRNAME: &str = ".viva_running";
+
+pub const ENV_HISTORY_DIRNAME: &str = ".history";
+
+/// The directory (in an environment prefix) that contains the revisions recorded by syncs.
+pub const ENV_REVISIONS_DIRNAME: &str = ".viva_revisions";
+
+/// How long repodata that was fetched without the repodata gateway (by a custom fetcher, or for a
+/// channel with credentials), and so without cache headers, is used from the cache before it is
+/// fetched again.
+pub const FETCHER_REPODATA_MAX_AGE: Duration = Duration::from_secs(30 * 60);
+
+/// The number of revisions of an environment prefix that are kept for 'env rollback'.
+pub const ENV_REVISIONS_LENGTH: usize = 20;
+
+/// The number of previous versions of an environment spec that are kept for 'env undo'.
+pub const ENV_HISTORY_LENGTH: usize = 10;
+
+/// The directory (in a collection config directory) that contains workspaces, one subdirectory each.
+pub const WORKSPACES_DIRNAME: &str = "workspaces";
+
+/// The name (without extension) of the spec file of a workspace.
+pub const WORKSPACE_SPEC_FILENAME: &str = "workspace";
@@ -0,0 +1,300 @@
+// Freezing environments: the exact packages of an environment as an explicit lock (the format of
+// `conda list --explicit`), and Docker build contexts that reproduce an environment in a container
+// image, either with micromamba (from the explicit lock), or with viva itself (from the spec and a
+// lockfile, so apps and `viva run` work the same way in the container as they do locally).
+
+use anyhow::{bail, Context, Result};
+use rattler_conda_types::RepoDataRecord;
+use std::fmt::Write;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use tokio::fs;
+
+use crate::models::environment::{EnvSyncStatus, VivaEnv};
+use crate::models::lockfile::EnvLockfile;
+use crate::rattler::commands::create::find_installed_packages;
+use crate::config::VivaConfig;
+
+const MICROMAMBA_IMAGE: &str = "mambaorg/micromamba:1.5.8";
+const VIVA_BASE_IMAGE: &str = "debian:bookworm-slim";
+
+/// Where viva keeps its config, data and cache in images built from a viva-based Dockerfile.
+const CONTAINER_VIVA_DIR: &str = "/opt/viva";
+
+/// What a generated Dockerfile uses to install the packages of an environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DockerBase {
+    Micromamba,
+    Viva,
+}
+
+impl FromStr for DockerBase {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "micromamba" => Ok(DockerBase::Micromamba),
+            "viva" => Ok(DockerBase::Viva),
+            _ => bail!("Invalid Docker base '{}' (available: micromamba, viva)", s),
+        }
+    }
+}
+
+/// The exact packages of an environment for a platform.
+///
+/// The packages of the lockfile are used if it was resolved for the current spec, then the
+/// installed packages (if the environment is synced, and for the same platform), otherwise the spec
+/// is solved for the platform.
+pub async fn frozen_packages(env: &VivaEnv, platform: &str, config: &VivaConfig) -> Result<Vec<RepoDataRecord>> {
+    if let Some(lockfile_path) = &env.lockfile_path {
+        if let Some(lockfile) = EnvLockfile::read(lockfile_path).await? {
+            if let Some(packages) = lockfile.get_packages_for(&env.spec, platform) {
+                return Ok(packages.clone());
+            }
+        }
+    }
+    if platform == env.spec.get_platform_name() && env.sync_status == EnvSyncStatus::Synced {
+        let installed = find_installed_packages(env.get_env_path(), 100)
+            .await
+            .with_context(|| format!("Failed to read installed packages of environment: {}", &env.id))?;
+        return Ok(installed.into_iter().map(|record| record.repodata_record).collect());
+    }
+    EnvLockfile::resolve_packages(&env.spec, Some(platform), config).await
+}
+
+/// An explicit lock of packages (urls with their md5 hashes), which conda, mamba and micromamba can
+/// install without solving.
+pub fn render_explicit_lock(env_id: &str, platform: &str, packages: &[RepoDataRecord]) -> String {
+    let mut urls = packages
+        .iter()
+        .map(|record| match &record.package_record.md5 {
+            Some(md5) => format!("{}#{}", record.url, md5),
+            None => record.url.to_string(),
+        })
+        .collect::<Vec<String>>();
+    urls.sort();
+    let mut lock = format!(
+        "# explicit lock of viva environment '{}'\n# platform: {}\n@EXPLICIT\n",
+        env_id, platform
+    );
+    for url in urls {
+        lock.push_str(&url);
+        lock.push('\n');
+    }
+    lock
+}
+
+/// Quote a string for a Dockerfile (json for exec form arguments, which also works for `ENV`).
+fn quote(value: &str) -> String {
+    serde_json::to_string(value).expect("Failed to serialize string")
+}
+
+/// The file name of an environment id (ids of app environments can contain slashes).
+fn file_stem(env_id: &str) -> String {
+    env_id.replace('/', "-")
+}
+
+fn render_micromamba_dockerfile(env: &VivaEnv, lock_file: &str, image: &str) -> String {
+    let mut dockerfile = String::new();
+    let _ = writeln!(dockerfile, "# generated by 'viva env freeze --docker' from environment '{}'", env.id);
+    let _ = writeln!(dockerfile, "FROM {}\n", image);
+    let _ = writeln!(dockerfile, "COPY --chown=$MAMBA_USER:$MAMBA_USER {} /tmp/{}", lock_file, lock_file);
+    let _ = writeln!(
+        dockerfile,
+        "RUN micromamba install --yes --name base --file /tmp/{} && \\\n    micromamba clean --all --yes",
+        lock_file
+    );
+    if !env.spec.pip_specs.is_empty() {
+        let pip_specs = env.spec.pip_specs.iter().map(|spec| quote(spec)).collect::<Vec<_>>();
+        let _ = writeln!(
+            dockerfile,
+            "RUN micromamba run --name base python -m pip install --no-cache-dir {}",
+            pip_specs.join(" ")
+        );
+    }
+    for (key, value) in &env.spec.env_vars {
+        let _ = writeln!(dockerfile, "ENV {}={}", key, quote(value));
+    }
+    // the entrypoint of the micromamba image activates the base environment
+    let _ = writeln!(dockerfile, "\nENTRYPOINT [\"/usr/local/bin/_entrypoint.sh\"]");
+    let _ = writeln!(dockerfile, "CMD [\"/bin/bash\"]");
+    dockerfile
+}
+
+fn render_viva_dockerfile(env: &VivaEnv, image: &str) -> String {
+    let mut dockerfile = String::new();
+    let _ = writeln!(dockerfile, "# generated by 'viva env freeze --docker' from environment '{}'", env.id);
+    let _ = writeln!(dockerfile, "FROM {}\n", image);
+    let _ = writeln!(
+        dockerfile,
+        "ENV XDG_CONFIG_HOME={dir}/config XDG_DATA_HOME={dir}/data XDG_CACHE_HOME={dir}/cache",
+        dir = CONTAINER_VIVA_DIR
+    );
+    let _ = writeln!(dockerfile, "COPY viva /usr/local/bin/viva");
+    let _ = writeln!(dockerfile, "COPY envs/ {}/config/viva/envs/", CONTAINER_VIVA_DIR);
+    // the lockfile next to the spec makes the sync install exactly the locked packages
+    let _ = writeln!(
+        dockerfile,
+        "RUN viva sync {} && \\\n    viva cache clean --all",
+        quote(&env.id)
+    );
+    let _ = writeln!(
+        dockerfile,
+        "\nENTRYPOINT [\"/usr/local/bin/viva\", \"run\", \"--env\", {}, \"--\"]",
+        quote(&env.id)
+    );
+    let _ = writeln!(dockerfile, "CMD [\"sh\"]");
+    dockerfile
+}
+
+/// The platform of the viva binary that is running, if it can run in a (linux) container.
+fn container_platform_of_current_exe() -> Option<&'static str> {
+    match (std::env::consts::OS, std::env::consts::ARCH) {
+        ("linux", "x86_64") => Some("linux-64"),
+        ("linux", "aarch64") => Some("linux-aarch64"),
+        _ => None,
+    }
+}
+
+/// Write a Docker build context (a Dockerfile, and the files it copies) that reproduces an
+/// environment to a directory, returns the paths of the written files.
+///
+/// Images built from it contain exactly the packages of the environment (see [`frozen_packages`])
+/// for the provided (linux) platform.
+pub async fn write_docker_context(
+    env: &VivaEnv,
+    base: DockerBase,
+    platform: &str,
+    image: Option<&str>,
+    output_dir: &Path,
+    config: &VivaConfig,
+) -> Result<Vec<PathBuf>> {
+    if !platform.starts_with("linux-") {
+        bail!("Docker images need packages for a linux platform (e.g. 'linux-64'), not: {}", platform);
+    }
+    if base == DockerBase::Viva && container_platform_of_current_exe() != Some(platform) {
+        bail!(
+            "The viva binary that is running can't be copied into an image for platform '{}', use the micromamba base instead.",
+            platform
+        );
+    }
+
+    let packages = frozen_packages(env, platform, config).await?;
+    fs::create_dir_all(output_dir)
+        .await
+        .with_context(|| format!("Failed to create directory: {}", output_dir.display()))?;
+    let mut written = vec![];
+
+    let dockerfile = match base {
+        DockerBase::Micromamba => {
+            let lock_file = format!("{}-{}.lock", file_stem(&env.id), platform);
+            let lock_path = output_dir.join(&lock_file);
+            fs::write(&lock_path, render_explicit_lock(&env.id, platform, &packages)).await?;
+            written.push(lock_path);
+            render_micromamba_dockerfile(env, &lock_file, image.unwrap_or(MICROMAMBA_IMAGE))
+        }
+        DockerBase::Viva => {
+            let envs_dir = output_dir.join("envs");
+            fs::create_dir_all(&envs_dir).await?;
+            // without the platform, the spec (and the lockfile) applies to the platform of the container
+            let mut spec = env.spec.normalized();
+            spec.platform = None;
+            let spec_path = envs_dir.join(format!("{}.yaml", env.id));
+            if let Some(parent) = spec_path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(&spec_path, serde_yaml::to_string(&spec)?).await?;
+            written.push(spec_path);
+
+            let mut lockfile = EnvLockfile::new(&spec);
+            lockfile.set_packages(&spec, platform, packages);
+            let lockfile_path = envs_dir.join(format!("{}.lock", env.id));
+            lockfile.write(&lockfile_path).await?;
+            written.push(lockfile_path);
+
+            let viva_path = output_dir.join("viva");
+            let current_exe = std::env::current_exe().context("Could not determine path of viva executable")?;
+            fs::copy(&current_exe, &viva_path)
+                .await
+                .with_context(|| format!("Failed to copy viva executable to: {}", viva_path.display()))?;
+            written.push(viva_path);
+
+            render_viva_dockerfile(env, image.unwrap_or(VIVA_BASE_IMAGE))
+        }
+    };
+    let dockerfile_path = output_dir.join("Dockerfile");
+    fs::write(&dockerfile_path, dockerfile).await?;
+    written.push(dockerfile_path);
+    Ok(written)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::environment::VivaEnvSpec;
+
+    fn record(name: &str, md5: Option<&str>) -> RepoDataRecord {
+        let file_name = format!("{}-1.0-0.conda", name);
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "version": "1.0",
+            "build": "0",
+            "build_number": 0,
+            "subdir": "linux-64",
+            "md5": md5,
+            "fn": file_name,
+            "url": format!("https://conda.anaconda.org/conda-forge/linux-64/{}", file_name),
+            "channel": "https://conda.anaconda.org/conda-forge/",
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn test_render_explicit_lock() {
+        let packages = vec![record("zlib", None), record("python", Some("0123456789abcdef0123456789abcdef"))];
+        assert_eq!(
+            render_explicit_lock("data", "linux-64", &packages),
+            "\
+# explicit lock of viva environment 'data'
+# platform: linux-64
+@EXPLICIT
+https://conda.anaconda.org/conda-forge/linux-64/python-1.0-0.conda#0123456789abcdef0123456789abcdef
+https://conda.anaconda.org/conda-forge/linux-64/zlib-1.0-0.conda
+"
+        );
+    }
+
+    #[test]
+    fn test_render_dockerfiles() {
+        let mut spec = VivaEnvSpec::new();
+        spec.pip_specs = vec![String::from("some-package>=1.0")];
+        spec.env_vars.insert(String::from("DATA_DIR"), String::from("/data dir"));
+        let env = VivaEnv::create(
+            String::from("apps/data"),
+            String::from("default"),
+            spec,
+            PathBuf::from("/envs/data"),
+            VivaEnvSpec::new(),
+            PathBuf::from("/envs/data/.viva_env.json"),
+            EnvSyncStatus::NotSynced,
+        );
+        assert_eq!(file_stem(&env.id), "apps-data");
+
+        let dockerfile = render_micromamba_dockerfile(&env, "apps-data-linux-64.lock", MICROMAMBA_IMAGE);
+        assert!(dockerfile.contains("FROM mambaorg/micromamba:1.5.8\n"));
+        assert!(dockerfile.contains("--file /tmp/apps-data-linux-64.lock"));
+        assert!(dockerfile.contains("python -m pip install --no-cache-dir \"some-package>=1.0\"\n"));
+        assert!(dockerfile.contains("ENV DATA_DIR=\"/data dir\"\n"));
+
+        let dockerfile = render_viva_dockerfile(&env, VIVA_BASE_IMAGE);
+        assert!(dockerfile.contains("RUN viva sync \"apps/data\""));
+        assert!(dockerfile.contains("ENTRYPOINT [\"/usr/local/bin/viva\", \"run\", \"--env\", \"apps/data\", \"--\"]"));
+    }
+
+    #[test]
+    fn test_docker_base() {
+        assert_eq!(DockerBase::from_str("viva").unwrap(), DockerBase::Viva);
+        assert_eq!(DockerBase::from_str("micromamba").unwrap(), DockerBase::Micromamba);
+        assert!(DockerBase::from_str("conda").is_err());
+    }
+}
@@ -0,0 +1,342 @@
+use crate::availability::check_availability;
+use crate::executable::find_executable_in_prefix;
+use crate::models::environment::{EnvSyncStatus, ExecutableSource};
+use crate::VivaContext;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::path::Path;
+
+/// The severity of a problem found by [`diagnose`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem with the current viva setup.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// What the problem is about (e.g. an env or app id, or a file).
+    pub subject: String,
+    pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "[{}] {}: {}", severity, self.subject, self.message)
+    }
+}
+
+/// Check the loaded collections for problems.
+pub async fn diagnose(context: &VivaContext) -> Vec<Diagnostic> {
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+
+    for (env_id, error) in context.get_invalid_envs() {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            subject: format!("env '{}'", env_id),
+            message: format!("invalid spec: {}", error),
+        });
+    }
+
+    for (app_id, error) in context.get_invalid_apps() {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            subject: format!("app '{}'", app_id),
+            message: format!("invalid spec: {}", error),
+        });
+    }
+
+    for (app_id, env_id) in context.get_broken_apps() {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            subject: format!("app '{}'", app_id),
+            message: format!(
+                "environment does not exist: {} (use 'viva app fix {} --env <env-id>' to fix)",
+                env_id, app_id
+            ),
+        });
+    }
+
+    for env in context.list_envs().await.values() {
+        for executable in env.find_protected_executables().await {
+            diagnostics.push(Diagnostic {
+                severity: Severity::Warning,
+                subject: format!("env '{}'", env.id),
+                message: format!(
+                    "shadows the protected system executable '{}' when the environment is on the PATH",
+                    executable
+                ),
+            });
+        }
+    }
+
+    for warning in context.get_warnings() {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            subject: "collections".to_string(),
+            message: warning.clone(),
+        });
+    }
+
+    diagnostics
+}
+
+/// Check that the packages of all registered environments are available on their channels, for
+/// the provided platforms (or the platform of each environment, if none are provided).
+///
+/// Uses the repodata cache where possible, channels that can't be reached result in a warning
+/// (the environments that use them are not checked for the affected platforms).
+pub async fn diagnose_availability(context: &VivaContext, platforms: &[String]) -> Result<Vec<Diagnostic>> {
+    let envs = context
+        .list_envs()
+        .await
+        .values()
+        .map(|env| (env.id.clone(), env.spec.clone()))
+        .collect::<Vec<_>>();
+    let report = check_availability(&envs, platforms, context.get_config()).await?;
+
+    let mut diagnostics: Vec<Diagnostic> = vec![];
+    for missing in report.missing {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Error,
+            subject: format!("env '{}'", missing.env_id),
+            message: missing.to_string(),
+        });
+    }
+    for (subdir, error) in report.unchecked {
+        diagnostics.push(Diagnostic {
+            severity: Severity::Warning,
+            subject: format!("channel '{}'", subdir),
+            message: format!("could not check package availability: {}", error),
+        });
+    }
+    Ok(diagnostics)
+}
+
+/// The outcome of a single check of [`diagnose_app`].
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CheckStatus {
+    Ok,
+    Warning,
+    Error,
+}
+
+/// A check of whether an app can be launched.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AppCheck {
+    pub check: String,
+    pub status: CheckStatus,
+    pub message: String,
+}
+
+impl AppCheck {
+    fn new(check: &str, status: CheckStatus, message: impl Into<String>) -> AppCheck {
+        AppCheck {
+            check: String::from(check),
+            status,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for AppCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let status = match self.status {
+            CheckStatus::Ok => "ok",
+            CheckStatus::Warning => "warning",
+            CheckStatus::Error => "error",
+        };
+        write!(f, "[{}] {}: {}", status, self.check, self.message)
+    }
+}
+
+/// Names of variables that are referenced in a value (`$NAME` or `${NAME}`).
+fn referenced_vars(value: &str) -> Vec<String> {
+    let mut vars = vec![];
+    let mut rest = value;
+    while let Some(idx) = rest.find('$') {
+        rest = &rest[idx + 1..];
+        let name = match rest.strip_prefix('{') {
+            Some(braced) => braced.split('}').next().unwrap_or(""),
+            None => {
+                let end = rest
+                    .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+                    .unwrap_or(rest.len());
+                &rest[..end]
+            }
+        };
+        if !name.is_empty() && !vars.iter().any(|v| v == name) {
+            vars.push(String::from(name));
+        }
+    }
+    vars
+}
+
+/// Check whether an app can be launched, without creating its environment if possible: that its
+/// environment exists and is solvable, that the executable is (or would be) installed, and that its
+/// arguments and environment variables are usable.
+///
+/// If the environment is synced already, the executable is looked up in its prefix. Otherwise the
+/// environment is solved, and the executable looked up in the file lists of the solved packages
+/// that are in the package cache.
+pub async fn diagnose_app(context: &VivaContext, app_id: &str) -> Result<Vec<AppCheck>> {
+    let app = context.get_app(app_id).await?;
+    let executable = app.spec.executable.clone();
+    let mut checks: Vec<AppCheck> = vec![];
+
+    let env_id = match context.get_app_env_id(&app.id).await {
+        Ok(env_id) => env_id,
+        Err(e) => {
+            checks.push(AppCheck::new("environment", CheckStatus::Error, e.to_string()));
+            return Ok(checks);
+        }
+    };
+    let env = match context.get_env(&env_id).await {
+        Ok(env) => env,
+        Err(_) => {
+            checks.push(AppCheck::new(
+                "environment",
+                CheckStatus::Ok,
+                format!("environment '{}' will be created from the app spec on first launch", env_id),
+            ));
+            return Ok(checks);
+        }
+    };
+    checks.push(AppCheck::new(
+        "environment",
+        CheckStatus::Ok,
+        format!("uses environment '{}' ({})", env.id, env.get_env_path().display()),
+    ));
+
+    if context.get_config().is_protected_executable(&executable) {
+        checks.push(AppCheck::new(
+            "executable",
+            CheckStatus::Warning,
+            format!("'{}' is a protected system executable", executable),
+        ));
+    }
+
+    let synced = env.explain_sync().sync_status == EnvSyncStatus::Synced;
+    if synced {
+        checks.push(AppCheck::new("solve", CheckStatus::Ok, "environment is synced"));
+        let found = std::iter::once(env.get_env_path().as_path())
+            .chain(env.spec.base_prefix.as_deref())
+            .find_map(|prefix| find_executable_in_prefix(prefix, &executable));
+        checks.push(match found {
+            Some(path) => AppCheck::new("executable", CheckStatus::Ok, format!("found: {}", path.display())),
+            None => AppCheck::new(
+                "executable",
+                CheckStatus::Error,
+                format!("'{}' is not installed in the environment", executable),
+            ),
+        });
+    } else {
+        match env.find_executable_in_solution(&executable).await {
+            Err(e) => {
+                checks.push(AppCheck::new("solve", CheckStatus::Error, format!("{:#}", e)));
+            }
+            Ok(source) => {
+                checks.push(AppCheck::new("solve", CheckStatus::Ok, "environment is solvable"));
+                checks.push(match source {
+                    ExecutableSource::Package { name } => AppCheck::new(
+                        "executable",
+                        CheckStatus::Ok,
+                        format!("'{}' would be installed by package '{}'", executable, name),
+                    ),
+                    ExecutableSource::NotFound => AppCheck::new(
+                        "executable",
+                        CheckStatus::Error,
+                        format!("none of the packages of the environment installs '{}'", executable),
+                    ),
+                    ExecutableSource::Unknown { uncached_packages } => AppCheck::new(
+                        "executable",
+                        CheckStatus::Warning,
+                        format!(
+                            "'{}' is not installed by any of the cached packages, {} package(s) can't be checked without downloading them",
+                            executable, uncached_packages
+                        ),
+                    ),
+                });
+            }
+        }
+    }
+
+    let mut env_var_problems = 0;
+    for (key, value) in &env.spec.env_vars {
+        if key.is_empty() || key.contains('=') || key.contains('\0') || value.contains('\0') {
+            checks.push(AppCheck::new(
+                "env vars",
+                CheckStatus::Error,
+                format!("invalid environment variable: '{}'", key),
+            ));
+            env_var_problems += 1;
+        }
+    }
+    let values = env
+        .spec
+        .env_vars
+        .iter()
+        .map(|(key, value)| (format!("env var '{}'", key), value))
+        .chain(app.spec.args.iter().map(|arg| (String::from("argument"), arg)));
+    for (subject, value) in values {
+        for var in referenced_vars(value) {
+            checks.push(AppCheck::new(
+                "env vars",
+                CheckStatus::Warning,
+                format!(
+                    "{} references '${}', which is passed to the app literally (variables are not expanded)",
+                    subject, var
+                ),
+            ));
+            env_var_problems += 1;
+        }
+    }
+    if env_var_problems == 0 {
+        checks.push(AppCheck::new(
+            "env vars",
+            CheckStatus::Ok,
+            format!("{} environment variable(s) set by the spec", env.spec.env_vars.len()),
+        ));
+    }
+
+    if !app.spec.runtime_stacks.is_empty() || !app.spec.runtime_vars.is_empty() {
+        checks.push(match app.spec.get_runtime_vars(env.get_env_path()) {
+            Err(e) => AppCheck::new("runtime vars", CheckStatus::Error, e.to_string()),
+            Ok(runtime_vars) => {
+                // values that point into the prefix should exist once the environment is synced
+                let missing: Vec<&str> = runtime_vars
+                    .iter()
+                    .filter(|var| {
+                        synced
+                            && var.value.starts_with(env.get_env_path().to_string_lossy().as_ref())
+                            && !Path::new(&var.value).exists()
+                    })
+                    .map(|var| var.name.as_str())
+                    .collect();
+                match missing.is_empty() {
+                    true => AppCheck::new(
+                        "runtime vars",
+                        CheckStatus::Ok,
+                        format!("{} runtime variable(s) set on launch", runtime_vars.len()),
+                    ),
+                    false => AppCheck::new(
+                        "runtime vars",
+                        CheckStatus::Warning,
+                        format!("runtime variable(s) point to paths that don't exist: {}", missing.join(", ")),
+                    ),
+                }
+            }
+        });
+    }
+
+    Ok(checks)
+}
@@ -0,0 +1,71 @@
+use anyhow::{Context, Result};
+
+use crate::context::VivaContext;
+use crate::models::environment::{EnvCheckStrategy, VivaEnv, VivaEnvSpec};
+use crate::models::{parse_model_spec, parse_model_spec_json, parse_model_spec_yaml};
+
+/// Embed an environment spec file into the binary, at compile time.
+///
+/// The path is relative to the root of the crate that uses the macro (where its `Cargo.toml` lives).
+///
+/// ```ignore
+/// let spec = viva::embed_spec!("env.yaml");
+/// let env = spec.ensure(&mut context, "my_app").await?;
+/// ```
+#[macro_export]
+macro_rules! embed_spec {
+    ($path:expr) => {
+        $crate::EmbeddedSpec::new(
+            include_str!(concat!(env!("CARGO_MANIFEST_DIR"), "/", $path)),
+            $path,
+        )
+    };
+}
+
+/// An environment spec that was embedded into a binary via [`embed_spec!`].
+#[derive(Debug, Clone, Copy)]
+pub struct EmbeddedSpec {
+    content: &'static str,
+    file_name: &'static str,
+}
+
+impl EmbeddedSpec {
+    pub const fn new(content: &'static str, file_name: &'static str) -> EmbeddedSpec {
+        EmbeddedSpec { content, file_name }
+    }
+
+    /// Parse the embedded spec content.
+    pub fn parse(&self) -> Result<VivaEnvSpec> {
+        let spec = if self.file_name.ends_with(".json") {
+            parse_model_spec_json(self.content)
+        } else if self.file_name.ends_with(".yaml") || self.file_name.ends_with(".yml") {
+            parse_model_spec_yaml(self.content)
+        } else {
+            parse_model_spec(self.content)
+        };
+        spec.with_context(|| format!("Invalid embedded environment spec: {}", self.file_name))
+    }
+
+    /// Make sure the environment described by the embedded spec exists and is synced.
+    ///
+    /// If the environment isn't registered yet, it is added to the 'default' env collection. If it
+    /// is registered, but doesn't satisfy the embedded spec, the embedded spec is merged into it.
+    /// This is a no-op (apart from a sync status check) on every launch after the first one.
+    pub async fn ensure<'a>(&self, context: &'a mut VivaContext, env_id: &str) -> Result<&'a VivaEnv> {
+        let spec = self.parse()?;
+
+        match context.has_env(env_id).await {
+            true => {
+                if !spec.is_satisfied_by(&context.get_env(env_id).await?.spec) {
+                    context.merge_env_specs(env_id, &spec, true, false).await?;
+                }
+            }
+            false => {
+                context.add_env(env_id, Some(spec), None).await?;
+            }
+        }
+
+        context.get_env_mut(env_id).await?.sync(EnvCheckStrategy::Auto).await?;
+        context.get_env(env_id).await
+    }
+}
@@ -0,0 +1,169 @@
+// Cross-platform lookup of executables in environment prefixes.
+//
+// On Windows, conda packages don't only install `.exe` files into a prefix, but also `.bat` and
+// `.cmd` wrappers (mostly into `Scripts`), and executables live in several directories of the
+// prefix. Like the shell, the lookup tries the extensions of the `PATHEXT` variable, in order.
+
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+
+use crate::activation::prefix_bin_dirs;
+
+/// The extensions that are tried when `PATHEXT` is not set.
+const DEFAULT_PATHEXT: &str = ".COM;.EXE;.BAT;.CMD";
+
+/// The extensions to try for executables (from the value of `PATHEXT`), on Windows. Empty on
+/// other platforms, where executables don't need an extension.
+pub(crate) fn executable_extensions(pathext: Option<&OsStr>, windows: bool) -> Vec<String> {
+    if !windows {
+        return vec![];
+    }
+    let pathext = pathext
+        .map(|pathext| pathext.to_string_lossy().to_string())
+        .filter(|pathext| !pathext.trim().is_empty())
+        .unwrap_or_else(|| String::from(DEFAULT_PATHEXT));
+
+    let mut extensions: Vec<String> = vec![];
+    for extension in pathext.split(';') {
+        let extension = extension.trim().to_lowercase();
+        if extension.is_empty() {
+            continue;
+        }
+        let extension = match extension.starts_with('.') {
+            true => extension,
+            false => format!(".{}", extension),
+        };
+        if !extensions.contains(&extension) {
+            extensions.push(extension);
+        }
+    }
+    extensions
+}
+
+/// The file names to try for an executable, in order.
+///
+/// A name that already ends with one of the extensions is tried as is first. Otherwise the
+/// extensions are appended, with the bare name only used if there are no extensions (i.e. not on
+/// Windows).
+fn candidate_names(executable: &str, extensions: &[String]) -> Vec<String> {
+    if extensions.is_empty() {
+        return vec![String::from(executable)];
+    }
+    let lowercase = executable.to_lowercase();
+    let mut candidates = vec![];
+    if extensions.iter().any(|extension| lowercase.ends_with(extension.as_str())) {
+        candidates.push(String::from(executable));
+    }
+    candidates.extend(
+        extensions
+            .iter()
+            .map(|extension| format!("{}{}", executable, extension)),
+    );
+    candidates
+}
+
+/// Find an executable in the provided directories (in order), trying the provided extensions (in
+/// order) in each of them.
+pub(crate) fn find_executable_in_dirs(
+    dirs: &[PathBuf],
+    executable: &str,
+    extensions: &[String],
+) -> Option<PathBuf> {
+    let candidates = candidate_names(executable, extensions);
+    dirs.iter()
+        .flat_map(|dir| candidates.iter().map(move |name| dir.join(name)))
+        .find(|path| path.is_file())
+}
+
+/// The paths (relative to a prefix, with `/` as separator, like in package file lists) an
+/// executable can be installed to, in the order they are looked up.
+pub(crate) fn executable_paths_in_prefix(executable: &str) -> Vec<String> {
+    let extensions = executable_extensions(std::env::var_os("PATHEXT").as_deref(), cfg!(windows));
+    let candidates = candidate_names(executable, &extensions);
+    prefix_bin_dirs(Path::new(""))
+        .iter()
+        .flat_map(|dir| candidates.iter().map(move |name| dir.join(name)))
+        .map(|path| path.to_string_lossy().replace('\\', "/"))
+        .collect()
+}
+
+/// Find an executable in the directories of a prefix that are on the `PATH` of processes in the
+/// environment, using the `PATHEXT` semantics of the current platform.
+///
+/// `.bat` and `.cmd` files can be used with `std::process::Command` like other executables, the
+/// standard library runs them through `cmd.exe`.
+pub(crate) fn find_executable_in_prefix(prefix: &Path, executable: &str) -> Option<PathBuf> {
+    let extensions = executable_extensions(std::env::var_os("PATHEXT").as_deref(), cfg!(windows));
+    find_executable_in_dirs(&prefix_bin_dirs(prefix), executable, &extensions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn touch(path: &Path) {
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, "").unwrap();
+    }
+
+    #[test]
+    fn test_extensions_from_pathext() {
+        let extensions = executable_extensions(Some(OsStr::new(".EXE;.Bat;;cmd;.exe")), true);
+        assert_eq!(extensions, vec![".exe", ".bat", ".cmd"]);
+
+        let extensions = executable_extensions(None, true);
+        assert_eq!(extensions, vec![".com", ".exe", ".bat", ".cmd"]);
+
+        let extensions = executable_extensions(Some(OsStr::new(".EXE")), false);
+        assert!(extensions.is_empty());
+    }
+
+    #[test]
+    fn test_candidate_names() {
+        let extensions = executable_extensions(None, true);
+        assert_eq!(
+            candidate_names("jupyter", &extensions),
+            vec!["jupyter.com", "jupyter.exe", "jupyter.bat", "jupyter.cmd"]
+        );
+        assert_eq!(candidate_names("python.EXE", &extensions)[0], "python.EXE");
+        assert_eq!(candidate_names("python", &[]), vec!["python"]);
+    }
+
+    #[test]
+    fn test_finds_bat_and_cmd_wrappers() {
+        let dir = tempfile::tempdir().unwrap();
+        let scripts = dir.path().join("Scripts");
+        touch(&scripts.join("jupyter.cmd"));
+        touch(&scripts.join("conda.bat"));
+        // a file without extension (e.g. a python entry point script) is not an executable on windows
+        touch(&scripts.join("pytest"));
+
+        let extensions = executable_extensions(None, true);
+        let dirs = vec![scripts.clone()];
+        assert_eq!(find_executable_in_dirs(&dirs, "jupyter", &extensions), Some(scripts.join("jupyter.cmd")));
+        assert_eq!(find_executable_in_dirs(&dirs, "conda", &extensions), Some(scripts.join("conda.bat")));
+        assert_eq!(find_executable_in_dirs(&dirs, "conda.bat", &extensions), Some(scripts.join("conda.bat")));
+        assert_eq!(find_executable_in_dirs(&dirs, "pytest", &extensions), None);
+        assert_eq!(find_executable_in_dirs(&dirs, "pytest", &[]), Some(scripts.join("pytest")));
+    }
+
+    #[test]
+    fn test_pathext_and_dir_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let scripts = dir.path().join("Scripts");
+        touch(&root.join("python.exe"));
+        touch(&scripts.join("python.bat"));
+        touch(&scripts.join("tool.bat"));
+        touch(&scripts.join("tool.exe"));
+
+        let extensions = executable_extensions(Some(OsStr::new(".EXE;.BAT")), true);
+        let dirs = vec![root.clone(), scripts.clone()];
+        // earlier directories win over earlier extensions
+        assert_eq!(find_executable_in_dirs(&dirs, "python", &extensions), Some(root.join("python.exe")));
+        assert_eq!(find_executable_in_dirs(&dirs, "tool", &extensions), Some(scripts.join("tool.exe")));
+
+        let extensions = executable_extensions(Some(OsStr::new(".BAT;.EXE")), true);
+        assert_eq!(find_executable_in_dirs(&dirs, "tool", &extensions), Some(scripts.join("tool.bat")));
+    }
+}
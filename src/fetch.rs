@@ -0,0 +1,170 @@
+use crate::auth::display_url;
+use crate::throttle::RateLimiter;
+use crate::watchdog::Heartbeat;
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::StreamExt;
+use once_cell::sync::Lazy;
+use reqwest::header::{HeaderValue, AUTHORIZATION};
+use reqwest::{Client, Url};
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+use tokio::io::AsyncWriteExt;
+
+/// A transport to download repodata and package archives.
+///
+/// Https downloads are handled by viva itself, other transports (e.g. s3, oci, ipfs) can be made
+/// available by registering an implementation of this trait with [`register_fetcher`]. A
+/// registered fetcher takes precedence over the built-in handling of its url schemes (and over the
+/// [`FileFetcher`], which is registered by default).
+///
+/// Repodata fetched by a registered fetcher is cached without any cache headers, so it is used for
+/// a fixed amount of time before it is fetched again.
+#[async_trait]
+pub trait Fetcher: Send + Sync {
+    /// The url schemes this fetcher can handle (e.g. `ipfs`, `s3`).
+    fn schemes(&self) -> Vec<String>;
+
+    /// Download the resource at `url` into the file `destination`.
+    async fn fetch(&self, url: &Url, destination: &Path) -> Result<()>;
+}
+
+/// The default fetcher for `http` and `https` urls.
+pub struct HttpsFetcher {
+    client: Client,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    /// The `Authorization` header sent with every request (the credentials of a channel).
+    authorization: Option<HeaderValue>,
+}
+
+impl HttpsFetcher {
+    pub fn new(client: Client) -> HttpsFetcher {
+        HttpsFetcher {
+            client,
+            rate_limiter: None,
+            authorization: None,
+        }
+    }
+
+    pub(crate) fn with_rate_limiter(
+        client: Client,
+        rate_limiter: Arc<RateLimiter>,
+    ) -> HttpsFetcher {
+        HttpsFetcher {
+            client,
+            rate_limiter: Some(rate_limiter),
+            authorization: None,
+        }
+    }
+
+    /// Send the provided `Authorization` header with every request (see
+    /// [`crate::auth::ChannelAuth::authenticate`]).
+    pub(crate) fn with_authorization(mut self, authorization: Option<HeaderValue>) -> HttpsFetcher {
+        self.authorization = authorization;
+        self
+    }
+
+    /// Download the resource at `url` into the file `destination`, reporting every chunk of data
+    /// to the heartbeat (if there is one).
+    pub(crate) async fn fetch_with_heartbeat(
+        &self,
+        url: &Url,
+        destination: &Path,
+        heartbeat: Option<&Heartbeat>,
+    ) -> Result<()> {
+        let mut request = self.client.get(url.clone());
+        if let Some(authorization) = &self.authorization {
+            request = request.header(AUTHORIZATION, authorization.clone());
+        }
+        let response = request
+            .send()
+            .await
+            .and_then(|r| r.error_for_status())
+            // the url might contain a conda token
+            .map_err(|e| e.without_url())
+            .with_context(|| format!("Failed to download: {}", display_url(url)))?;
+        let mut file = tokio::fs::File::create(destination).await?;
+        let mut stream = response.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            if let Some(heartbeat) = heartbeat {
+                heartbeat.beat(chunk.len() as u64);
+            }
+            if let Some(rate_limiter) = &self.rate_limiter {
+                rate_limiter.consume(chunk.len() as u64).await;
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Fetcher for HttpsFetcher {
+    fn schemes(&self) -> Vec<String> {
+        vec!["http".to_string(), "https".to_string()]
+    }
+
+    async fn fetch(&self, url: &Url, destination: &Path) -> Result<()> {
+        self.fetch_with_heartbeat(url, destination, None).await
+    }
+}
+
+/// A fetcher for `file` urls, mostly useful for channels on network shares (registered by default).
+pub struct FileFetcher;
+
+#[async_trait]
+impl Fetcher for FileFetcher {
+    fn schemes(&self) -> Vec<String> {
+        vec!["file".to_string()]
+    }
+
+    async fn fetch(&self, url: &Url, destination: &Path) -> Result<()> {
+        let source = url
+            .to_file_path()
+            .map_err(|_| anyhow!("Invalid file url: {}", url))?;
+        tokio::fs::copy(&source, destination)
+            .await
+            .with_context(|| format!("Failed to copy: {}", source.display()))?;
+        Ok(())
+    }
+}
+
+static FETCHERS: Lazy<RwLock<Vec<Arc<dyn Fetcher>>>> =
+    Lazy::new(|| RwLock::new(vec![Arc::new(FileFetcher)]));
+
+/// Register a custom fetcher, it will be used for all urls with one of its schemes.
+///
+/// Fetchers registered later take precedence over earlier ones.
+pub fn register_fetcher(fetcher: Arc<dyn Fetcher>) {
+    // the list is only ever inserted into, a panic while holding the lock can't leave it
+    // inconsistent
+    FETCHERS
+        .write()
+        .unwrap_or_else(PoisonError::into_inner)
+        .insert(0, fetcher);
+}
+
+/// Returns the registered custom fetcher for the scheme of the provided url, if there is one.
+pub(crate) fn custom_fetcher_for(url: &Url) -> Option<Arc<dyn Fetcher>> {
+    FETCHERS
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .iter()
+        .find(|fetcher| fetcher.schemes().iter().any(|s| s == url.scheme()))
+        .cloned()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_file_fetcher_is_registered() {
+        let url = Url::parse("file:///srv/channels/internal/linux-64/").unwrap();
+        let fetcher = custom_fetcher_for(&url).unwrap();
+        assert_eq!(fetcher.schemes(), vec!["file"]);
+        assert!(custom_fetcher_for(&Url::parse("https://conda.anaconda.org/conda-forge/").unwrap()).is_none());
+    }
+}
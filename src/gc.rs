@@ -0,0 +1,143 @@
+// Garbage collection of environment prefixes that don't belong to any registered environment
+// anymore (e.g. because their spec was removed by hand, or their collection is gone), and of
+// overlay environments that were left behind by processes that didn't exit cleanly.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use sysinfo::{Pid, PidExt, System, SystemExt};
+
+use crate::cache::path_size;
+use crate::defaults::ENV_SPEC_FILENAME;
+
+/// An environment directory that is not used by any registered environment.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct OrphanedEnv {
+    /// The id the environment had (derived from its path).
+    pub env_id: String,
+    pub path: PathBuf,
+    /// The (apparent) size of the directory. Files are hard-linked from the package cache, so
+    /// the space that is actually reclaimed can be smaller.
+    pub size: u64,
+    /// Whether this is the overlay (or ephemeral) environment of a `run` that was interrupted.
+    pub overlay: bool,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct GcReport {
+    pub orphaned: Vec<OrphanedEnv>,
+    /// Whether the orphaned directories were deleted.
+    pub deleted: bool,
+    pub reclaimed_bytes: u64,
+}
+
+/// Whether a directory is (or was) an environment prefix.
+fn is_prefix(path: &Path) -> bool {
+    path.join("conda-meta").is_dir() || path.join(ENV_SPEC_FILENAME).is_file()
+}
+
+/// Find all prefixes below the base directory of environments that are not in use.
+///
+/// Environment ids can contain `/`, so directories that are not prefixes themselves are searched
+/// for nested prefixes. Directories that contain the path of a used prefix are never orphaned.
+pub(crate) fn find_orphaned_prefixes(base_env_path: &Path, used: &HashSet<PathBuf>) -> Vec<OrphanedEnv> {
+    let mut orphaned = vec![];
+    collect_orphaned_prefixes(base_env_path, base_env_path, used, &mut orphaned);
+    orphaned.sort_by(|a, b| a.env_id.cmp(&b.env_id));
+    orphaned
+}
+
+fn collect_orphaned_prefixes(
+    base_env_path: &Path,
+    dir: &Path,
+    used: &HashSet<PathBuf>,
+    orphaned: &mut Vec<OrphanedEnv>,
+) {
+    let entries = match std::fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if !path.is_dir() || used.contains(&path) {
+            continue;
+        }
+        if used.iter().any(|used_path| used_path.starts_with(&path)) || !is_prefix(&path) {
+            collect_orphaned_prefixes(base_env_path, &path, used, orphaned);
+            continue;
+        }
+        let env_id = path
+            .strip_prefix(base_env_path)
+            .map(|p| p.to_string_lossy().replace('\\', "/"))
+            .unwrap_or_else(|_| path.to_string_lossy().to_string());
+        orphaned.push(OrphanedEnv {
+            env_id,
+            size: path_size(&path),
+            path,
+            overlay: false,
+        });
+    }
+}
+
+/// Find the overlay (`<env>-<random>-<pid>`) or ephemeral (`tmp-<random>-<pid>`) environments of
+/// processes that are not running anymore.
+pub(crate) fn find_stale_overlays(overlays_path: &Path) -> Vec<OrphanedEnv> {
+    let entries = match std::fs::read_dir(overlays_path) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+    let mut system = System::new();
+    let mut stale = vec![];
+    for entry in entries.flatten() {
+        let name = entry.file_name().to_string_lossy().to_string();
+        let pid = match name.rsplit_once('-').and_then(|(_, pid)| pid.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if pid == std::process::id() || system.refresh_process(Pid::from_u32(pid)) {
+            continue;
+        }
+        stale.push(OrphanedEnv {
+            env_id: name,
+            size: path_size(&entry.path()),
+            path: entry.path(),
+            overlay: true,
+        });
+    }
+    stale.sort_by(|a, b| a.env_id.cmp(&b.env_id));
+    stale
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_find_orphaned_prefixes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base = tmp.path();
+        for prefix in ["default", "old", "team/analysis", "team/plotting", "lab/analysis"] {
+            std::fs::create_dir_all(base.join(prefix).join("conda-meta")).unwrap();
+        }
+
+        // 'team' is a collection that is configured, but not loaded: all of it is kept
+        let used = HashSet::from([base.join("default"), base.join("team"), base.join("lab/analysis")]);
+        let orphaned = find_orphaned_prefixes(base, &used);
+        let orphaned_ids = orphaned.iter().map(|orphan| orphan.env_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(orphaned_ids, vec!["old"]);
+    }
+
+    #[test]
+    fn test_find_stale_overlays() {
+        let tmp = tempfile::tempdir().unwrap();
+        let own = format!("tmp-a1b2c3-{}", std::process::id());
+        // kept ephemeral environments are named after the hash of their spec
+        for name in [own.as_str(), "tmp-d4e5f6-4294967290", "python-g7h8i9-4294967291", "0123abcd"] {
+            std::fs::create_dir_all(tmp.path().join(name)).unwrap();
+        }
+
+        let stale = find_stale_overlays(tmp.path());
+        let stale_ids = stale.iter().map(|overlay| overlay.env_id.as_str()).collect::<Vec<_>>();
+        assert_eq!(stale_ids, vec!["python-g7h8i9-4294967291", "tmp-d4e5f6-4294967290"]);
+    }
+}
@@ -0,0 +1,96 @@
+// Output for GitHub Actions.
+//
+// Workflow commands (https://docs.github.com/en/actions/using-workflow-commands-for-github-actions)
+// are lines on stdout the runner interprets: they fold the log into groups, and turn errors into
+// annotations. Step outputs are appended to the file in `GITHUB_OUTPUT`.
+
+use anyhow::{Context, Result};
+use std::io::Write;
+
+/// Start a collapsible group in the log (until [`end_group`]).
+pub fn start_group(title: &str) {
+    println!("::group::{}", escape_data(title));
+}
+
+pub fn end_group() {
+    println!("::endgroup::");
+}
+
+/// Report an error, shown as an annotation of the workflow run.
+pub fn error(title: &str, message: &str) {
+    println!(
+        "::error title={}::{}",
+        escape_property(title),
+        escape_data(message)
+    );
+}
+
+/// Set an output of the current step (written to the `GITHUB_OUTPUT` file, or printed if it is not
+/// set, i.e. when not running in GitHub Actions).
+pub fn set_output(name: &str, value: &str) -> Result<()> {
+    match std::env::var_os("GITHUB_OUTPUT") {
+        Some(output_file) => {
+            let mut file = std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&output_file)
+                .with_context(|| format!("Failed to open GITHUB_OUTPUT file: {:?}", output_file))?;
+            writeln!(file, "{}", format_output(name, value))?;
+        }
+        None => println!("{}={}", name, value),
+    }
+    Ok(())
+}
+
+/// An entry of the `GITHUB_OUTPUT` file: `name=value`, or, for values with line breaks,
+/// `name<<delimiter`, the value, and the delimiter on lines of their own.
+fn format_output(name: &str, value: &str) -> String {
+    if !value.contains(['\n', '\r']) {
+        return format!("{}={}", name, value);
+    }
+    let mut delimiter = String::from("VIVA_EOF");
+    while value.contains(&delimiter) {
+        delimiter.push('_');
+    }
+    format!("{}<<{}\n{}\n{}", name, delimiter, value, delimiter)
+}
+
+/// A string that can be used in the name of an output (only alphanumeric characters, `-` and `_`).
+pub fn output_name(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+            true => c,
+            false => '_',
+        })
+        .collect()
+}
+
+fn escape_data(value: &str) -> String {
+    value
+        .replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+fn escape_property(value: &str) -> String {
+    escape_data(value).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_output() {
+        assert_eq!(format_output("cache-key", "viva-linux-64-abc"), "cache-key=viva-linux-64-abc");
+        assert_eq!(
+            format_output("paths", "/a\n/b"),
+            "paths<<VIVA_EOF\n/a\n/b\nVIVA_EOF"
+        );
+        assert_eq!(
+            format_output("text", "VIVA_EOF\nVIVA_EOF_"),
+            "text<<VIVA_EOF__\nVIVA_EOF\nVIVA_EOF_\nVIVA_EOF__"
+        );
+    }
+}
@@ -0,0 +1,179 @@
+// Sharing a viva context between tasks and threads, for applications that embed viva (e.g. a
+// server that runs commands in environments on behalf of its clients).
+//
+// The context itself is `Send + Sync`, but most of its methods need exclusive access, so the
+// handle puts it behind a read-write lock. Methods that return references into the context are
+// available through the lock guards; the handle adds owned variants of the common ones, and a way
+// to sync an environment without blocking other users of the context while packages are
+// downloaded and linked.
+
+use anyhow::Result;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::context::VivaContext;
+use crate::models::app::VivaApp;
+use crate::models::environment::{EnvCheckStrategy, VivaEnv};
+
+/// The locks of the environments that are synced through a handle.
+type SyncLocks = Mutex<HashMap<String, Arc<tokio::sync::Mutex<()>>>>;
+
+/// A clone-able handle to a [`VivaContext`], that can be shared between tasks and threads.
+///
+/// All clones of a handle refer to the same context.
+#[derive(Debug, Clone)]
+pub struct VivaHandle {
+    context: Arc<RwLock<VivaContext>>,
+    /// One lock per environment that is being synced through the handle, so concurrent syncs of
+    /// the same environment don't link into the same prefix at the same time.
+    syncing: Arc<SyncLocks>,
+}
+
+impl VivaHandle {
+    pub fn new(context: VivaContext) -> VivaHandle {
+        VivaHandle {
+            context: Arc::new(RwLock::new(context)),
+            syncing: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Shared access to the context (waits while the context is changed).
+    pub async fn read(&self) -> RwLockReadGuard<'_, VivaContext> {
+        self.context.read().await
+    }
+
+    /// Exclusive access to the context, e.g. to add collections or to change environment specs
+    /// (waits until all other users of the context released it).
+    pub async fn write(&self) -> RwLockWriteGuard<'_, VivaContext> {
+        self.context.write().await
+    }
+
+    pub async fn get_env_ids(&self) -> Vec<String> {
+        self.read().await.get_env_ids().await
+    }
+
+    pub async fn get_app_ids(&self) -> Vec<String> {
+        self.read().await.get_app_ids().await
+    }
+
+    /// A copy of a registered environment.
+    pub async fn get_env(&self, env_id: &str) -> Result<VivaEnv> {
+        Ok(self.read().await.get_env(env_id).await?.clone())
+    }
+
+    /// A copy of a registered app.
+    pub async fn get_app(&self, app_id: &str) -> Result<VivaApp> {
+        Ok(self.read().await.get_app(app_id).await?.clone())
+    }
+
+    /// Reload all collections of the context (e.g. after their spec files changed).
+    pub async fn reload_collections(&self) -> Result<()> {
+        self.write().await.reload_collections().await
+    }
+
+    /// Sync a registered environment. Unlike `VivaContext::sync_envs`, the context is only locked
+    /// to look up the environment and to record its sync status, not for the whole sync.
+    ///
+    /// Returns whether the environment needed to be synced.
+    pub async fn sync_env(&self, env_id: &str, check_strategy: EnvCheckStrategy) -> Result<bool> {
+        let env_lock = EnvSyncLock::new(&self.syncing, env_id);
+        let _guard = env_lock.lock.lock().await;
+
+        // the spec might have changed while waiting for another sync to finish
+        let mut env = self.get_env(env_id).await?;
+        let synced = env.sync(check_strategy).await?;
+
+        let mut context = self.write().await;
+        if let Ok(registered) = context.get_env_mut(env_id).await {
+            // only if the synced environment still matches the (possibly changed) registered one
+            if registered.get_env_path() == env.get_env_path() && registered.spec.is_equivalent_to(&env.spec) {
+                registered.sync_status = env.sync_status.clone();
+            }
+        }
+        Ok(synced)
+    }
+}
+
+/// The lock of an environment that is synced through a handle, it's removed from the handle again
+/// once no sync (running, or waiting for the lock) uses it anymore.
+struct EnvSyncLock<'a> {
+    syncing: &'a SyncLocks,
+    env_id: String,
+    lock: Arc<tokio::sync::Mutex<()>>,
+}
+
+impl<'a> EnvSyncLock<'a> {
+    fn new(syncing: &'a SyncLocks, env_id: &str) -> EnvSyncLock<'a> {
+        let lock = syncing
+            .lock()
+            .unwrap()
+            .entry(String::from(env_id))
+            .or_default()
+            .clone();
+        EnvSyncLock {
+            syncing,
+            env_id: String::from(env_id),
+            lock,
+        }
+    }
+}
+
+impl Drop for EnvSyncLock<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut syncing) = self.syncing.lock() {
+            // only referenced by the map and this sync, so nobody else is waiting for it
+            let unused = Arc::strong_count(&self.lock) == 2
+                && matches!(syncing.get(&self.env_id), Some(lock) if Arc::ptr_eq(lock, &self.lock));
+            if unused {
+                syncing.remove(&self.env_id);
+            }
+        }
+    }
+}
+
+impl From<VivaContext> for VivaHandle {
+    fn from(context: VivaContext) -> Self {
+        VivaHandle::new(context)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_send_sync<T: Send + Sync>() {}
+
+    #[test]
+    fn test_send_sync() {
+        assert_send_sync::<VivaContext>();
+        assert_send_sync::<VivaHandle>();
+    }
+
+    #[tokio::test]
+    async fn test_shared_between_tasks() {
+        let handle = VivaHandle::new(VivaContext::init());
+        let tasks = (0..4)
+            .map(|_| {
+                let handle = handle.clone();
+                tokio::spawn(async move { handle.get_env_ids().await })
+            })
+            .collect::<Vec<_>>();
+        for task in tasks {
+            assert!(task.await.unwrap().is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_sync_lock_is_removed() {
+        let handle = VivaHandle::new(VivaContext::init());
+        assert!(handle.sync_env("missing", EnvCheckStrategy::Auto).await.is_err());
+        assert!(handle.syncing.lock().unwrap().is_empty());
+
+        let waiting = EnvSyncLock::new(&handle.syncing, "env");
+        drop(EnvSyncLock::new(&handle.syncing, "env"));
+        assert!(handle.syncing.lock().unwrap().contains_key("env"));
+        drop(waiting);
+        assert!(handle.syncing.lock().unwrap().is_empty());
+    }
+}
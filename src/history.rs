@@ -0,0 +1,172 @@
+// The revision history of environment prefixes.
+//
+// Every successful sync (or update, or rollback) records a revision in the prefix: the spec it was
+// synced to, and the exact packages that were installed (conda packages, and the `pip freeze`
+// output of environments with pip specs). Rolling back to a revision installs those packages again
+// (from the package cache, or re-downloaded), so unlike snapshots, revisions don't keep any package
+// files around, and survive a deletion of the package cache.
+
+use anyhow::{anyhow, Context, Result};
+use rattler_conda_types::RepoDataRecord;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+use crate::defaults::{ENV_REVISIONS_DIRNAME, ENV_REVISIONS_LENGTH};
+use crate::models::environment::VivaEnvSpec;
+
+/// The state of an environment prefix after a sync.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct Revision {
+    /// Revisions are numbered from 1, in the order they were recorded.
+    pub revision: u32,
+    /// Seconds since the unix epoch.
+    pub timestamp: u64,
+    /// What created the revision (e.g. `sync`, `update`, `rollback to 3`).
+    pub operation: String,
+    pub platform: String,
+    pub spec: VivaEnvSpec,
+    pub packages: Vec<RepoDataRecord>,
+    /// The pip packages that were installed (as pinned requirements, see [`parse_pip_freeze`]).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pip_packages: Vec<String>,
+}
+
+fn revisions_dir(prefix: &Path) -> PathBuf {
+    prefix.join(ENV_REVISIONS_DIRNAME)
+}
+
+fn revision_path(prefix: &Path, revision: u32) -> PathBuf {
+    revisions_dir(prefix).join(format!("{}.json", revision))
+}
+
+/// The numbers of the revisions recorded for a prefix, in ascending order.
+async fn revision_numbers(prefix: &Path) -> Result<Vec<u32>> {
+    let mut numbers = vec![];
+    let mut entries = match fs::read_dir(revisions_dir(prefix)).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(numbers),
+        Err(e) => return Err(e.into()),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+        let file_name = entry.file_name().to_string_lossy().to_string();
+        if let Some(number) = file_name.strip_suffix(".json").and_then(|n| n.parse::<u32>().ok()) {
+            numbers.push(number);
+        }
+    }
+    numbers.sort();
+    Ok(numbers)
+}
+
+/// Record a new revision for a prefix, returns its number.
+///
+/// Only the last [`ENV_REVISIONS_LENGTH`] revisions are kept.
+pub(crate) async fn record_revision(
+    prefix: &Path,
+    operation: &str,
+    spec: &VivaEnvSpec,
+    packages: Vec<RepoDataRecord>,
+    pip_packages: Vec<String>,
+) -> Result<u32> {
+    let numbers = revision_numbers(prefix).await?;
+    let revision = Revision {
+        revision: numbers.last().map(|last| last + 1).unwrap_or(1),
+        timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default(),
+        operation: String::from(operation),
+        platform: spec.get_platform_name(),
+        spec: spec.normalized(),
+        packages,
+        pip_packages,
+    };
+
+    fs::create_dir_all(revisions_dir(prefix)).await?;
+    fs::write(
+        revision_path(prefix, revision.revision),
+        serde_json::to_string(&revision)?,
+    )
+    .await?;
+
+    let expired = (numbers.len() + 1).saturating_sub(ENV_REVISIONS_LENGTH);
+    for number in numbers.iter().take(expired) {
+        let _ = fs::remove_file(revision_path(prefix, *number)).await;
+    }
+    Ok(revision.revision)
+}
+
+/// The pinned requirements in the output of `pip freeze` that pip can install again: comments,
+/// options and editable installs are left out, and so are packages that were installed from local
+/// files (conda packages show up like this, and their files are usually gone).
+pub(crate) fn parse_pip_freeze(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#') && !line.starts_with('-'))
+        .filter(|line| !line.contains(" @ file:"))
+        .map(String::from)
+        .collect()
+}
+
+/// All revisions recorded for a prefix (that are still kept), oldest first.
+pub async fn read_revisions(prefix: &Path) -> Result<Vec<Revision>> {
+    let mut revisions = vec![];
+    for number in revision_numbers(prefix).await? {
+        revisions.push(read_revision(prefix, number).await?);
+    }
+    Ok(revisions)
+}
+
+/// A single revision of a prefix.
+pub async fn read_revision(prefix: &Path, revision: u32) -> Result<Revision> {
+    let path = revision_path(prefix, revision);
+    let content = fs::read_to_string(&path)
+        .await
+        .map_err(|_| anyhow!("No revision {} recorded for environment: {}", revision, prefix.display()))?;
+    serde_json::from_str(&content).with_context(|| format!("Invalid revision file: {}", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_pip_freeze() {
+        let output = "\
+# a comment
+-e git+https://example.com/repo.git#egg=editable
+certifi @ file:///home/conda/feedstock_root/build_artifacts/certifi_1678/work/certifi
+requests==2.31.0
+some-pypi-only-package==1.0.2
+
+";
+        assert_eq!(
+            parse_pip_freeze(output),
+            vec!["requests==2.31.0", "some-pypi-only-package==1.0.2"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_revisions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let prefix = tmp.path().join("env");
+        let spec = VivaEnvSpec::new();
+
+        assert!(read_revisions(&prefix).await.unwrap().is_empty());
+        for i in 0..ENV_REVISIONS_LENGTH + 2 {
+            let pip_packages = vec![format!("package=={}", i)];
+            let revision = record_revision(&prefix, "sync", &spec, vec![], pip_packages).await.unwrap();
+            assert_eq!(revision as usize, i + 1);
+        }
+
+        // only the last ones are kept
+        let revisions = read_revisions(&prefix).await.unwrap();
+        assert_eq!(revisions.len(), ENV_REVISIONS_LENGTH);
+        assert_eq!(revisions[0].revision, 3);
+        let last = read_revision(&prefix, (ENV_REVISIONS_LENGTH + 2) as u32).await.unwrap();
+        assert_eq!(last.pip_packages, vec![format!("package=={}", ENV_REVISIONS_LENGTH + 1)]);
+        assert!(read_revision(&prefix, 1).await.is_err());
+    }
+}
@@ -0,0 +1,286 @@
+// A journal of in-progress destructive operations.
+//
+// Before viva deletes an environment prefix, links packages into one, or overwrites a spec file, it
+// writes a journal entry, which is removed again once the operation is over. Entries of processes
+// that are not alive anymore belong to operations that were interrupted (e.g. by a crash, or a
+// power loss), and can be recovered from the next time viva starts.
+
+use anyhow::{Context, Result};
+use once_cell::sync::OnceCell;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{SystemTime, UNIX_EPOCH};
+use sysinfo::{Pid, PidExt, System, SystemExt};
+use tracing::debug;
+
+use crate::defaults::ENV_SPEC_FILENAME;
+use crate::models::environment::fnv1a_hash;
+use crate::models::tmp_spec_path;
+
+static JOURNAL_DIR: OnceCell<PathBuf> = OnceCell::new();
+static ENTRY_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Set the directory journal entries are written to (only the first call has an effect). Without
+/// one, nothing is journaled.
+pub(crate) fn set_journal_dir(journal_dir: PathBuf) {
+    let _ = JOURNAL_DIR.set(journal_dir);
+}
+
+/// An operation that leaves things in an inconsistent state if it is interrupted.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+#[serde(tag = "operation", rename_all = "snake_case")]
+pub enum JournalOperation {
+    /// Deleting an environment prefix (whose spec was already removed).
+    DeletePrefix { env_id: String, prefix: PathBuf },
+    /// Installing, removing or updating packages in an environment prefix.
+    LinkPrefix { env_id: String, prefix: PathBuf },
+    /// Overwriting a spec file, with the content it had before (if it existed), and the hash of the
+    /// content it is written with.
+    WriteSpec {
+        path: PathBuf,
+        previous: Option<String>,
+        content_hash: String,
+    },
+    /// Moving a prefix, and replacing its files with the relocated ones prepared in a staging
+    /// directory.
+    RelocatePrefix {
+        old_prefix: PathBuf,
+        new_prefix: PathBuf,
+        staging: PathBuf,
+    },
+}
+
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct JournalEntry {
+    /// The process that started the operation.
+    pub pid: u32,
+    /// When the operation was started (seconds since the unix epoch).
+    pub started: u64,
+    #[serde(flatten)]
+    pub operation: JournalOperation,
+}
+
+impl JournalEntry {
+    /// A human readable description of the interrupted operation.
+    pub fn describe(&self) -> String {
+        match &self.operation {
+            JournalOperation::DeletePrefix { env_id, prefix } => format!(
+                "deleting environment '{}' ({})",
+                env_id,
+                prefix.display()
+            ),
+            JournalOperation::LinkPrefix { env_id, prefix } => format!(
+                "installing packages into environment '{}' ({})",
+                env_id,
+                prefix.display()
+            ),
+            JournalOperation::WriteSpec { path, .. } => {
+                format!("writing spec file '{}'", path.display())
+            }
+            JournalOperation::RelocatePrefix {
+                old_prefix, new_prefix, ..
+            } => format!(
+                "moving prefix '{}' to '{}'",
+                old_prefix.display(),
+                new_prefix.display()
+            ),
+        }
+    }
+}
+
+/// A journal entry for an operation that is in progress, removed when dropped.
+pub(crate) struct JournalGuard {
+    entry_file: Option<PathBuf>,
+}
+
+impl Drop for JournalGuard {
+    fn drop(&mut self) {
+        if let Some(entry_file) = &self.entry_file {
+            let _ = std::fs::remove_file(entry_file);
+        }
+    }
+}
+
+/// Record that an operation is about to start.
+///
+/// The entry is removed once the returned guard is dropped (also if the operation failed, only
+/// operations that were interrupted leave their entry behind).
+pub(crate) fn begin(operation: JournalOperation) -> Result<JournalGuard> {
+    let journal_dir = match JOURNAL_DIR.get() {
+        Some(journal_dir) => journal_dir,
+        None => return Ok(JournalGuard { entry_file: None }),
+    };
+    let entry = JournalEntry {
+        pid: std::process::id(),
+        started: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0),
+        operation,
+    };
+    let entry_file = journal_dir.join(format!(
+        "{}-{}.json",
+        entry.pid,
+        ENTRY_COUNTER.fetch_add(1, Ordering::SeqCst)
+    ));
+
+    std::fs::create_dir_all(journal_dir)?;
+    std::fs::write(&entry_file, serde_json::to_string_pretty(&entry)?)
+        .with_context(|| format!("Failed to write journal entry: {}", entry_file.display()))?;
+    Ok(JournalGuard {
+        entry_file: Some(entry_file),
+    })
+}
+
+/// Record that a spec file is about to be overwritten with `content` (with its current content, if
+/// any).
+pub(crate) fn begin_write_spec(path: &Path, content: &str) -> Result<JournalGuard> {
+    let previous = match path.is_file() {
+        true => Some(std::fs::read_to_string(path)?),
+        false => None,
+    };
+    begin(JournalOperation::WriteSpec {
+        path: path.to_path_buf(),
+        previous,
+        content_hash: fnv1a_hash(content.as_bytes()),
+    })
+}
+
+/// All entries of operations that were interrupted (whose process is not alive anymore).
+pub(crate) fn stale_entries() -> Vec<(PathBuf, JournalEntry)> {
+    let entries = match JOURNAL_DIR.get().map(std::fs::read_dir) {
+        Some(Ok(entries)) => entries,
+        _ => return vec![],
+    };
+
+    let mut system = System::new();
+    let mut stale = vec![];
+    for dir_entry in entries.flatten() {
+        let entry: JournalEntry = match std::fs::read_to_string(dir_entry.path())
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+        {
+            Some(entry) => entry,
+            None => {
+                debug!("Ignoring invalid journal entry: {}", dir_entry.path().display());
+                continue;
+            }
+        };
+        if entry.pid != std::process::id() && !system.refresh_process(Pid::from_u32(entry.pid)) {
+            stale.push((dir_entry.path(), entry));
+        }
+    }
+    stale.sort_by_key(|(_, entry)| entry.started);
+    stale
+}
+
+/// Bring things back into a consistent state after an interrupted operation, and remove its entry.
+///
+/// Returns a description of what was done.
+pub(crate) async fn recover(entry_file: &Path, entry: &JournalEntry) -> Result<String> {
+    let result = match &entry.operation {
+        JournalOperation::DeletePrefix { prefix, .. } => match prefix.exists() {
+            true => {
+                tokio::fs::remove_dir_all(prefix).await?;
+                format!("finished deleting: {}", prefix.display())
+            }
+            false => String::from("nothing left to delete"),
+        },
+        JournalOperation::LinkPrefix { env_id, prefix } => {
+            // without the actual spec, the environment is synced again the next time it's used,
+            // which fixes up all packages that were not (un-)installed completely
+            let actual_spec_file = prefix.join(ENV_SPEC_FILENAME);
+            if actual_spec_file.exists() {
+                tokio::fs::remove_file(&actual_spec_file).await?;
+            }
+            format!("environment '{}' will be synced again when it's used next", env_id)
+        }
+        JournalOperation::WriteSpec {
+            path,
+            previous,
+            content_hash,
+        } => {
+            let tmp_path = tmp_spec_path(path);
+            if tmp_path.exists() {
+                tokio::fs::remove_file(&tmp_path).await?;
+            }
+            // the spec file is intact if it has either its new or its previous content
+            let intact = match tokio::fs::read_to_string(path).await {
+                Ok(content) => {
+                    &fnv1a_hash(content.as_bytes()) == content_hash
+                        || previous.as_ref() == Some(&content)
+                }
+                Err(_) => false,
+            };
+            match (intact, previous) {
+                (true, _) => String::from("spec file is intact"),
+                (false, Some(previous)) => {
+                    tokio::fs::write(path, previous).await?;
+                    format!("restored previous content of: {}", path.display())
+                }
+                (false, None) => {
+                    if path.exists() {
+                        tokio::fs::remove_file(path).await?;
+                    }
+                    format!("removed incomplete spec file: {}", path.display())
+                }
+            }
+        }
+        JournalOperation::RelocatePrefix {
+            old_prefix,
+            new_prefix,
+            staging,
+        } => match old_prefix.exists() {
+            // the prefix wasn't moved yet, the prepared files are not needed anymore
+            true => {
+                if staging.exists() {
+                    tokio::fs::remove_dir_all(staging).await?;
+                }
+                format!("prefix was not moved: {}", old_prefix.display())
+            }
+            false => {
+                crate::relocate::finish_relocation(staging, new_prefix)?;
+                format!("finished moving prefix to: {}", new_prefix.display())
+            }
+        },
+    };
+    tokio::fs::remove_file(entry_file).await?;
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn recover_write_spec(dir: &Path, current: &str, previous: &str, new: &str) -> String {
+        let path = dir.join("env.yaml");
+        tokio::fs::write(&path, current).await.unwrap();
+        let entry_file = dir.join("entry.json");
+        tokio::fs::write(&entry_file, "{}").await.unwrap();
+        let entry = JournalEntry {
+            pid: 0,
+            started: 0,
+            operation: JournalOperation::WriteSpec {
+                path: path.clone(),
+                previous: Some(String::from(previous)),
+                content_hash: fnv1a_hash(new.as_bytes()),
+            },
+        };
+        recover(&entry_file, &entry).await.unwrap();
+        tokio::fs::read_to_string(&path).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_recover_write_spec() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+
+        let previous = "channels: []\npkg_specs: [python]\n";
+        let new = "channels: []\npkg_specs: [python, numpy]\n";
+        assert_eq!(recover_write_spec(dir, new, previous, new).await, new);
+        assert_eq!(recover_write_spec(dir, previous, previous, new).await, previous);
+        // valid yaml, but neither the previous nor the new content
+        assert_eq!(recover_write_spec(dir, "channels: []\n", previous, new).await, previous);
+    }
+}
@@ -3,13 +3,46 @@
 
 
 
+pub mod activation;
+pub mod auth;
+mod availability;
+pub mod bundle;
+pub mod cache;
+pub mod completions;
+mod conda_envs;
+mod conflicts;
 mod config;
 mod context;
+#[cfg(feature = "daemon")]
+pub mod daemon;
 mod defaults;
+pub mod docker;
+pub mod doctor;
+mod embed;
 mod errors;
+mod executable;
+pub mod fetch;
+pub mod gc;
+pub mod gha;
+mod handle;
+pub mod history;
+mod journal;
+pub mod listing;
+mod locks;
+pub mod logging;
 pub mod models;
+pub mod progress;
 mod rattler;
+mod relocate;
+pub mod remote;
+mod shims;
+pub mod signing;
+mod snapshot;
 mod status;
+pub mod support;
+mod throttle;
+mod watch;
+mod watchdog;
 
 extern crate prettytable;
 
@@ -17,5 +50,9 @@ pub use crate::rattler::global_multi_progress;
 pub use crate::rattler::writer::IndicatifWriter;
 pub use defaults::DEFAULT_CHANNELS;
 
+pub use crate::config::{PathConflictPolicy, SizeBudgetPolicy, VivaConfig};
 pub use crate::context::VivaContext;
+pub use crate::handle::VivaHandle;
+pub use crate::watch::CollectionWatcher;
+pub use crate::embed::EmbeddedSpec;
 pub use crate::models::environment::VivaEnvSpec;
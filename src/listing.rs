@@ -0,0 +1,394 @@
+// Listings (`list-envs`, `list-apps`): tables that can be filtered, sorted and paged, so
+// installations with lots of environments and apps stay navigable. Besides a table for the
+// terminal, they can be rendered as CSV (for spreadsheets) or Markdown (for docs).
+//
+// Sorting is meant for humans, but it is not locale-aware: case and the accents of common latin
+// letters are ignored (so `Émile` sorts next to `emile`, not after `zoe`), and numbers in names are
+// compared by their value (`py3.9` before `py3.10`). Everything else is compared by code point.
+
+use anyhow::{anyhow, bail, Result};
+use globset::{GlobBuilder, GlobMatcher};
+use prettytable::{format, Cell, Row, Table};
+use std::cmp::Ordering;
+
+/// The format listings are printed in.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ListFormat {
+    #[default]
+    Table,
+    Csv,
+    Markdown,
+}
+
+impl std::str::FromStr for ListFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "table" => Ok(ListFormat::Table),
+            "csv" => Ok(ListFormat::Csv),
+            "markdown" | "md" => Ok(ListFormat::Markdown),
+            _ => bail!("Invalid output format '{}' (available: table, csv, markdown)", s),
+        }
+    }
+}
+
+/// How to filter, sort, page and render a listing.
+#[derive(Debug, Clone, Default)]
+pub struct ListOptions {
+    /// Only show rows that contain this text (case-insensitive), or match this glob (if it contains
+    /// wildcards). With `column=value`, only the provided column is checked.
+    pub filter: Option<String>,
+    /// The column to sort by (defaults to the first one).
+    pub sort_by: Option<String>,
+    pub reverse: bool,
+    /// The number of rows per page (all rows if not set).
+    pub page_size: Option<usize>,
+    /// The page to show (starting at 1).
+    pub page: usize,
+    /// One line per row (multi-value cells are joined with commas), without separator lines.
+    pub compact: bool,
+    pub format: ListFormat,
+}
+
+/// A table of rows, where each cell can have multiple values (e.g. the package specs of an
+/// environment), shown on separate lines unless the listing is compact.
+pub struct Listing {
+    titles: Vec<&'static str>,
+    rows: Vec<Vec<Vec<String>>>,
+}
+
+enum Filter {
+    Text(String),
+    Glob(GlobMatcher),
+}
+
+impl Filter {
+    fn parse(value: &str) -> Result<Filter> {
+        let value = fold(value);
+        match value.contains(['*', '?', '[']) {
+            true => Ok(Filter::Glob(
+                GlobBuilder::new(&value)
+                    .build()
+                    .map_err(|e| anyhow!("Invalid filter '{}': {}", value, e))?
+                    .compile_matcher(),
+            )),
+            false => Ok(Filter::Text(value)),
+        }
+    }
+
+    fn matches(&self, value: &str) -> bool {
+        let value = fold(value);
+        match self {
+            Filter::Text(text) => value.contains(text.as_str()),
+            Filter::Glob(matcher) => matcher.is_match(&value),
+        }
+    }
+}
+
+impl Listing {
+    pub fn new(titles: Vec<&'static str>) -> Listing {
+        Listing { titles, rows: vec![] }
+    }
+
+    pub fn add_row(&mut self, row: Vec<Vec<String>>) {
+        self.rows.push(row);
+    }
+
+    fn column_index(&self, column: &str) -> Result<usize> {
+        self.titles
+            .iter()
+            .position(|title| title.eq_ignore_ascii_case(column))
+            .ok_or_else(|| anyhow!("Unknown column '{}' (available: {})", column, self.titles.join(", ")))
+    }
+
+    /// Apply the filter, sort order and page of the options, returns the remaining rows, and the
+    /// number of rows that matched the filter.
+    ///
+    /// A filter is only restricted to a column if the text before the first `=` is the title of
+    /// one, otherwise the whole filter is matched against all columns (e.g. `python=3.11`).
+    fn select(&self, options: &ListOptions) -> Result<(Vec<&Vec<Vec<String>>>, usize)> {
+        let (column, filter) = match &options.filter {
+            Some(filter) => match filter
+                .split_once('=')
+                .and_then(|(column, value)| Some((self.column_index(column.trim()).ok()?, value)))
+            {
+                Some((column, value)) => (Some(column), Some(Filter::parse(value)?)),
+                None => (None, Some(Filter::parse(filter)?)),
+            },
+            None => (None, None),
+        };
+        let mut rows: Vec<&Vec<Vec<String>>> = self
+            .rows
+            .iter()
+            .filter(|row| match &filter {
+                None => true,
+                Some(filter) => row
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| column.map_or(true, |column| column == *index))
+                    .any(|(_, values)| values.iter().any(|value| filter.matches(value))),
+            })
+            .collect();
+
+        let sort_index = match &options.sort_by {
+            Some(column) => self.column_index(column)?,
+            None => 0,
+        };
+        rows.sort_by(|a, b| {
+            let ordering = collate(&a[sort_index].join(" "), &b[sort_index].join(" "))
+                // keep the order stable (and deterministic) for equal values
+                .then_with(|| collate(&a[0].join(" "), &b[0].join(" ")));
+            match options.reverse {
+                true => ordering.reverse(),
+                false => ordering,
+            }
+        });
+
+        let matched = rows.len();
+        if let Some(page_size) = options.page_size {
+            if page_size == 0 {
+                bail!("Invalid page size: 0");
+            }
+            let start = options.page.max(1).saturating_sub(1).saturating_mul(page_size);
+            rows = rows.into_iter().skip(start).take(page_size).collect();
+        }
+        Ok((rows, matched))
+    }
+
+    /// Print the listing to stdout.
+    pub fn print(&self, options: &ListOptions) -> Result<()> {
+        match options.format {
+            ListFormat::Table => self.print_table(options),
+            ListFormat::Csv => {
+                print!("{}", self.render_csv(options)?);
+                Ok(())
+            }
+            ListFormat::Markdown => {
+                print!("{}", self.render_markdown(options)?);
+                Ok(())
+            }
+        }
+    }
+
+    /// Render the listing as CSV (RFC 4180), multiple values of a cell are separated by commas.
+    pub fn render_csv(&self, options: &ListOptions) -> Result<String> {
+        let (rows, _) = self.select(options)?;
+        let quote = |value: &str| match value.contains([',', '"', '\n', '\r']) {
+            true => format!("\"{}\"", value.replace('"', "\"\"")),
+            false => String::from(value),
+        };
+        let mut csv = self.titles.iter().map(|title| quote(title)).collect::<Vec<_>>().join(",");
+        csv.push_str("\r\n");
+        for row in rows {
+            let line = row
+                .iter()
+                .map(|values| quote(&values.join(", ")))
+                .collect::<Vec<_>>()
+                .join(",");
+            csv.push_str(&line);
+            csv.push_str("\r\n");
+        }
+        Ok(csv)
+    }
+
+    /// Render the listing as a (GitHub flavored) Markdown table, multiple values of a cell are
+    /// separated by line breaks.
+    pub fn render_markdown(&self, options: &ListOptions) -> Result<String> {
+        let (rows, _) = self.select(options)?;
+        let escape = |value: &str| value.replace('\\', "\\\\").replace('|', "\\|").replace('\n', " ");
+        let separator = match options.compact {
+            true => ", ",
+            false => "<br>",
+        };
+        let mut markdown = format!("| {} |\n", self.titles.join(" | "));
+        markdown.push_str(&format!("|{}\n", " --- |".repeat(self.titles.len())));
+        for row in rows {
+            let cells = row
+                .iter()
+                .map(|values| values.iter().map(|value| escape(value)).collect::<Vec<_>>().join(separator))
+                .collect::<Vec<_>>();
+            markdown.push_str(&format!("| {} |\n", cells.join(" | ")));
+        }
+        Ok(markdown)
+    }
+
+    fn print_table(&self, options: &ListOptions) -> Result<()> {
+        let (rows, matched) = self.select(options)?;
+
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_NO_BORDER_LINE_SEPARATOR);
+        table.set_titles(Row::new(self.titles.iter().map(|title| Cell::new(title)).collect()));
+        let separator = match options.compact {
+            true => ", ",
+            false => "\n",
+        };
+        for row in &rows {
+            if !options.compact {
+                table.add_row(Row::new(self.titles.iter().map(|_| Cell::new("")).collect()));
+            }
+            table.add_row(Row::new(
+                row.iter()
+                    .map(|values| Cell::new(&values.join(separator)))
+                    .collect(),
+            ));
+        }
+        table.printstd();
+
+        if let Some(page_size) = options.page_size {
+            let pages = ((matched + page_size - 1) / page_size).max(1);
+            println!("\nPage {}/{} ({} entries)", options.page.max(1), pages, matched);
+        } else if matched != self.rows.len() {
+            println!("\n{} of {} entries", matched, self.rows.len());
+        }
+        Ok(())
+    }
+}
+
+/// Lowercase a string, and remove the accents of the latin letters in a fixed table (no unicode
+/// normalization, letters that are not in the table are kept as they are).
+fn fold(value: &str) -> String {
+    value
+        .chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| match c {
+            'à' | 'á' | 'â' | 'ã' | 'ä' | 'å' | 'ā' | 'ą' => 'a',
+            'ç' | 'ć' | 'č' => 'c',
+            'ď' => 'd',
+            'è' | 'é' | 'ê' | 'ë' | 'ē' | 'ę' | 'ě' => 'e',
+            'ì' | 'í' | 'î' | 'ï' | 'ī' => 'i',
+            'ł' => 'l',
+            'ñ' | 'ń' | 'ň' => 'n',
+            'ò' | 'ó' | 'ô' | 'õ' | 'ö' | 'ø' | 'ō' => 'o',
+            'ř' => 'r',
+            'ś' | 'š' => 's',
+            'ť' => 't',
+            'ù' | 'ú' | 'û' | 'ü' | 'ū' | 'ů' => 'u',
+            'ý' | 'ÿ' => 'y',
+            'ź' | 'ż' | 'ž' => 'z',
+            c => c,
+        })
+        .collect()
+}
+
+/// Compare two strings with a simple accent-folding sort: ignoring case and accents (see `fold`),
+/// and comparing (ascii) numbers by their value, but not following the collation rules of any
+/// locale. Strings that only differ in case or accents are compared as they are.
+pub fn collate(a: &str, b: &str) -> Ordering {
+    let (folded_a, folded_b) = (fold(a), fold(b));
+    let mut a_chars = folded_a.chars().peekable();
+    let mut b_chars = folded_b.chars().peekable();
+    loop {
+        match (a_chars.peek().copied(), b_chars.peek().copied()) {
+            (None, None) => return a.cmp(b),
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let take_number = |chars: &mut std::iter::Peekable<std::str::Chars>| {
+                    let mut digits = String::new();
+                    while let Some(c) = chars.next_if(|c| c.is_ascii_digit()) {
+                        digits.push(c);
+                    }
+                    digits
+                };
+                let (x, y) = (take_number(&mut a_chars), take_number(&mut b_chars));
+                let (x_trimmed, y_trimmed) = (x.trim_start_matches('0'), y.trim_start_matches('0'));
+                let ordering = x_trimmed
+                    .len()
+                    .cmp(&y_trimmed.len())
+                    .then_with(|| x_trimmed.cmp(y_trimmed));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a_chars.next();
+                b_chars.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_collate() {
+        let mut names = vec!["zoe", "Émile", "py3.10", "emile", "py3.9", "Alpha"];
+        names.sort_by(|a, b| collate(a, b));
+        assert_eq!(names, vec!["Alpha", "emile", "Émile", "py3.9", "py3.10", "zoe"]);
+    }
+
+    #[test]
+    fn test_select() {
+        let mut listing = Listing::new(vec!["name", "status"]);
+        for (name, status) in [("b-env", "Synced"), ("a-env", "NotSynced"), ("other", "Synced")] {
+            listing.add_row(vec![vec![String::from(name)], vec![String::from(status)]]);
+        }
+        let names = |options: &ListOptions| {
+            let (rows, _) = listing.select(options).unwrap();
+            rows.iter().map(|row| row[0][0].clone()).collect::<Vec<_>>()
+        };
+
+        let options = ListOptions {
+            filter: Some(String::from("*-ENV")),
+            ..Default::default()
+        };
+        assert_eq!(names(&options), vec!["a-env", "b-env"]);
+
+        let options = ListOptions {
+            filter: Some(String::from("status=synced")),
+            sort_by: Some(String::from("name")),
+            reverse: true,
+            ..Default::default()
+        };
+        assert_eq!(names(&options), vec!["other", "b-env", "a-env"]);
+
+        let options = ListOptions {
+            filter: Some(String::from("status=not*")),
+            ..Default::default()
+        };
+        assert_eq!(names(&options), vec!["a-env"]);
+
+        let options = ListOptions {
+            page_size: Some(2),
+            page: 2,
+            ..Default::default()
+        };
+        assert_eq!(names(&options), vec!["other"]);
+
+        // not a column, so the whole filter is matched against all columns
+        let mut listing = Listing::new(vec!["name", "specs"]);
+        listing.add_row(vec![vec![String::from("py")], vec![String::from("python=3.11")]]);
+        listing.add_row(vec![vec![String::from("other")], vec![String::from("python=3.10")]]);
+        let options = ListOptions {
+            filter: Some(String::from("python=3.11")),
+            ..Default::default()
+        };
+        let (rows, _) = listing.select(&options).unwrap();
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0][0][0], "py");
+    }
+
+    #[test]
+    fn test_render() {
+        let mut listing = Listing::new(vec!["name", "specs"]);
+        listing.add_row(vec![
+            vec![String::from("a|b")],
+            vec![String::from("python"), String::from("say \"hi\"")],
+        ]);
+
+        let csv = listing.render_csv(&ListOptions::default()).unwrap();
+        assert_eq!(csv, "name,specs\r\na|b,\"python, say \"\"hi\"\"\"\r\n");
+
+        let markdown = listing.render_markdown(&ListOptions::default()).unwrap();
+        assert_eq!(
+            markdown,
+            "| name | specs |\n| --- | --- |\n| a\\|b | python<br>say \"hi\" |\n"
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// Tracking of processes viva launched from an environment.
+//
+// For every running process, a file named after its pid is created in a directory inside the
+// environment prefix. The file is removed once the process finished. Files of processes that are not
+// alive anymore (e.g. because viva was killed) are ignored, and cleaned up when they are found.
+//
+// Cache entries that are shared between (concurrently running) viva processes are protected with
+// lock files that contain the pid of their owner, so locks of processes that died can be broken.
+//
+// Package directories are protected the way other rattler-based tools (e.g. pixi) do it, so the
+// cache can be shared with them: with advisory (`flock`-style) locks on a `<entry>.lock` file next
+// to each package directory. Those files are never removed, and the operating system releases the
+// locks of processes that died.
+
+use anyhow::{anyhow, Context, Result};
+use fs4::FileExt;
+use std::fs::File;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use sysinfo::{Pid, PidExt, System, SystemExt};
+use tracing::debug;
+
+use crate::defaults::RUNNING_PIDS_DIRNAME;
+
+/// Marks an environment prefix as in use by a process, until it is dropped.
+pub(crate) struct RunningLock {
+    pid_files: Vec<PathBuf>,
+}
+
+impl RunningLock {
+    /// Record that the process with the provided pid uses the provided prefixes.
+    ///
+    /// Failing to record the process is not fatal, it only means the prefixes are not protected.
+    pub(crate) fn acquire(prefixes: &[&Path], pid: u32) -> RunningLock {
+        let mut pid_files = vec![];
+        for prefix in prefixes {
+            let pids_dir = prefix.join(RUNNING_PIDS_DIRNAME);
+            let pid_file = pids_dir.join(pid.to_string());
+            match std::fs::create_dir_all(&pids_dir).and_then(|_| std::fs::write(&pid_file, "")) {
+                Ok(()) => pid_files.push(pid_file),
+                Err(e) => debug!("Could not record running process in {}: {}", prefix.display(), e),
+            }
+        }
+        RunningLock { pid_files }
+    }
+}
+
+impl Drop for RunningLock {
+    fn drop(&mut self) {
+        for pid_file in &self.pid_files {
+            let _ = std::fs::remove_file(pid_file);
+        }
+    }
+}
+
+/// The pids of all (still alive) processes viva launched from the provided prefix.
+pub(crate) fn running_processes(prefix: &Path) -> Vec<u32> {
+    let entries = match std::fs::read_dir(prefix.join(RUNNING_PIDS_DIRNAME)) {
+        Ok(entries) => entries,
+        Err(_) => return vec![],
+    };
+
+    let mut system = System::new();
+    let mut pids = vec![];
+    for entry in entries.flatten() {
+        let pid = match entry.file_name().to_string_lossy().parse::<u32>() {
+            Ok(pid) => pid,
+            Err(_) => continue,
+        };
+        match system.refresh_process(Pid::from_u32(pid)) {
+            true => pids.push(pid),
+            false => {
+                debug!("Removing stale pid file: {}", entry.path().display());
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+    pids.sort();
+    pids
+}
+
+/// An exclusive lock on a cache entry, shared by all tasks and processes, until it is dropped.
+pub(crate) struct CacheLock {
+    path: PathBuf,
+}
+
+impl CacheLock {
+    /// Wait until the lock file at the provided path can be created.
+    ///
+    /// The file contains the pid of the owner, locks of processes that are not alive anymore are
+    /// removed. Other tasks of the same process wait as well.
+    pub(crate) async fn acquire(path: &Path) -> Result<CacheLock> {
+        loop {
+            if let Some(lock) = CacheLock::try_acquire(path)? {
+                return Ok(lock);
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    }
+
+    /// Create the lock file at the provided path, `None` if another (living) process or task holds
+    /// the lock.
+    pub(crate) fn try_acquire(path: &Path) -> Result<Option<CacheLock>> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let pid = std::process::id();
+        let mut system = System::new();
+        loop {
+            match std::fs::OpenOptions::new().write(true).create_new(true).open(path) {
+                Ok(mut file) => {
+                    file.write_all(pid.to_string().as_bytes())?;
+                    return Ok(Some(CacheLock {
+                        path: path.to_path_buf(),
+                    }));
+                }
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+                Err(e) => {
+                    return Err(e).with_context(|| format!("Failed to create lock file: {}", path.display()))
+                }
+            }
+
+            let owner = std::fs::read_to_string(path)
+                .ok()
+                .and_then(|content| content.trim().parse::<u32>().ok());
+            match owner {
+                Some(owner) if owner != pid && !system.refresh_process(Pid::from_u32(owner)) => {
+                    debug!("Removing stale lock of process {}: {}", owner, path.display());
+                    let _ = std::fs::remove_file(path);
+                    continue;
+                }
+                // the owner died before it could write its pid
+                None if is_older_than(path, Duration::from_secs(10)) => {
+                    debug!("Removing incomplete lock: {}", path.display());
+                    let _ = std::fs::remove_file(path);
+                    continue;
+                }
+                _ => return Ok(None),
+            }
+        }
+    }
+}
+
+impl Drop for CacheLock {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+fn is_older_than(path: &Path, age: Duration) -> bool {
+    std::fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .ok()
+        .and_then(|modified| modified.elapsed().ok())
+        .map(|elapsed| elapsed > age)
+        .unwrap_or(false)
+}
+
+/// An advisory lock on an entry of the package cache, released when it is dropped.
+pub(crate) struct PackageCacheLock {
+    _file: File,
+}
+
+impl PackageCacheLock {
+    /// Wait for the lock of a package directory in the package cache: an exclusive one to
+    /// validate, extract or delete the package, or a shared one to link files from it.
+    pub(crate) async fn acquire(package_dir: &Path, exclusive: bool) -> Result<PackageCacheLock> {
+        let lock_path = package_lock_path(package_dir);
+        let file = tokio::task::spawn_blocking(move || -> Result<File> {
+            let file = open_lock_file(&lock_path)?;
+            match exclusive {
+                true => file.lock_exclusive(),
+                false => file.lock_shared(),
+            }
+            .with_context(|| format!("Failed to lock: {}", lock_path.display()))?;
+            Ok(file)
+        })
+        .await
+        .map_err(|e| anyhow!(e))??;
+        Ok(PackageCacheLock { _file: file })
+    }
+
+    /// The exclusive lock of a package directory, `None` if another process holds a lock on it.
+    pub(crate) fn try_acquire_exclusive(package_dir: &Path) -> Result<Option<PackageCacheLock>> {
+        let file = open_lock_file(&package_lock_path(package_dir))?;
+        match file.try_lock_exclusive() {
+            Ok(()) => Ok(Some(PackageCacheLock { _file: file })),
+            Err(_) => Ok(None),
+        }
+    }
+}
+
+/// The lock file of a package directory (the name of the directory contains dots, so this can't
+/// use `with_extension`).
+fn package_lock_path(package_dir: &Path) -> PathBuf {
+    let mut file_name = package_dir.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".lock");
+    package_dir.with_file_name(file_name)
+}
+
+fn open_lock_file(lock_path: &Path) -> Result<File> {
+    if let Some(parent) = lock_path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .open(lock_path)
+        .with_context(|| format!("Failed to open lock file: {}", lock_path.display()))
+}
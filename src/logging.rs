@@ -0,0 +1,186 @@
+// Logging setup: log messages go to the console (through the progress bars, so they don't get
+// garbled), and optionally also to a log file, each with its own filter. That way app launches can
+// keep the console quiet, while still leaving a log (e.g. including rattler internals) behind that
+// can be used to diagnose problems.
+
+use anyhow::{anyhow, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::SubscriberExt;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{EnvFilter, Layer};
+
+use crate::config::{parse_size, VivaConfig};
+use crate::rattler::global_multi_progress;
+use crate::rattler::writer::IndicatifWriter;
+
+/// The name of the (current) log file, rotated files get a numeric suffix (`viva.log.1`, ...).
+const LOG_FILE_NAME: &str = "viva.log";
+
+/// Where, and what to log (filters use the `RUST_LOG` syntax, e.g. `warn,viva=debug,rattler=trace`).
+#[derive(Debug, Clone)]
+pub struct LogSettings {
+    pub console_filter: String,
+    /// If set, logs are also written to a file in `log_dir`.
+    pub file_filter: Option<String>,
+    pub log_dir: PathBuf,
+    /// The size after which the log file is rotated.
+    pub max_file_size: u64,
+    /// The number of rotated log files to keep.
+    pub max_files: usize,
+}
+
+impl LogSettings {
+    /// The settings from the configuration, with the provided default log directory.
+    pub fn from_config(config: &VivaConfig, default_log_dir: &Path) -> Result<LogSettings> {
+        Ok(LogSettings {
+            console_filter: config
+                .log_level
+                .clone()
+                .unwrap_or_else(|| String::from("warn")),
+            file_filter: config.log_file_level.clone(),
+            log_dir: config
+                .log_dir
+                .clone()
+                .unwrap_or_else(|| default_log_dir.to_path_buf()),
+            max_file_size: match &config.log_max_size {
+                Some(max_size) => parse_size(max_size)?,
+                None => 10 * 1024 * 1024,
+            },
+            max_files: config.log_max_files.unwrap_or(5),
+        })
+    }
+}
+
+/// Install the global log subscriber (only the first call has an effect).
+pub fn init_logging(settings: &LogSettings) -> Result<()> {
+    let console_filter = EnvFilter::try_new(&settings.console_filter)
+        .map_err(|e| anyhow!("Invalid log filter '{}': {}", settings.console_filter, e))?;
+    let console_layer = tracing_subscriber::fmt::layer()
+        .with_writer(IndicatifWriter::new(global_multi_progress()))
+        .without_time()
+        .with_filter(console_filter);
+
+    let file_layer = match &settings.file_filter {
+        Some(file_filter) => {
+            let filter = EnvFilter::try_new(file_filter)
+                .map_err(|e| anyhow!("Invalid log filter '{}': {}", file_filter, e))?;
+            let writer = RotatingFileWriter::open(
+                settings.log_dir.join(LOG_FILE_NAME),
+                settings.max_file_size,
+                settings.max_files,
+            )?;
+            Some(
+                tracing_subscriber::fmt::layer()
+                    .with_writer(writer)
+                    .with_ansi(false)
+                    .with_filter(filter),
+            )
+        }
+        None => None,
+    };
+
+    let _ = tracing_subscriber::registry()
+        .with(console_layer)
+        .with(file_layer)
+        .try_init();
+    Ok(())
+}
+
+/// A log file that is rotated (`viva.log` -> `viva.log.1` -> ...) once it grows above a size.
+#[derive(Clone)]
+pub struct RotatingFileWriter {
+    inner: Arc<Mutex<RotatingFile>>,
+}
+
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    max_size: u64,
+    max_files: usize,
+}
+
+impl RotatingFileWriter {
+    pub fn open(path: PathBuf, max_size: u64, max_files: usize) -> Result<RotatingFileWriter> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        let mut rotating_file = RotatingFile {
+            path,
+            file,
+            size,
+            max_size,
+            max_files,
+        };
+        if rotating_file.size >= rotating_file.max_size {
+            rotating_file.rotate()?;
+        }
+        Ok(RotatingFileWriter {
+            inner: Arc::new(Mutex::new(rotating_file)),
+        })
+    }
+}
+
+impl RotatingFile {
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut name = self.path.as_os_str().to_owned();
+        name.push(format!(".{}", index));
+        PathBuf::from(name)
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        self.file.flush()?;
+        if self.max_files == 0 {
+            self.file = File::create(&self.path)?;
+            self.size = 0;
+            return Ok(());
+        }
+        let _ = fs::remove_file(self.rotated_path(self.max_files));
+        for index in (1..self.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(&from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.path, self.rotated_path(1))?;
+        self.file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+impl Write for RotatingFileWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut file = self
+            .inner
+            .lock()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "log file lock poisoned"))?;
+        if file.size > 0 && file.size + buf.len() as u64 > file.max_size {
+            file.rotate()?;
+        }
+        let written = file.file.write(buf)?;
+        file.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.inner.lock() {
+            Ok(mut file) => file.file.flush(),
+            Err(_) => Ok(()),
+        }
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFileWriter {
+    type Writer = RotatingFileWriter;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        self.clone()
+    }
+}
@@ -1,7 +1,8 @@
 
+use crate::defaults::IGNORE_FILENAME;
 use crate::models::environment::VivaEnvSpec;
-use crate::models::{read_model_spec, read_models_spec, write_model_spec};
-use anyhow::{anyhow, Result};
+use crate::models::{find_spec_files, find_unknown_fields, read_models_spec, read_raw_spec, read_spec_files, write_model_spec, write_models_spec, SpecIgnore, SPEC_FILE_EXTENSIONS};
+use anyhow::{anyhow, bail, Result};
 use async_trait::async_trait;
 
 use serde::{Deserialize, Serialize};
@@ -9,16 +10,21 @@ use serde::{Deserialize, Serialize};
 
 use std::collections::BTreeMap;
 use std::fmt::Debug;
-use std::path::{PathBuf};
+use std::path::{Path, PathBuf};
+use tokio::fs;
 
 
 
-use tracing::debug;
+use tracing::{debug, warn};
 
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum AppEnvPlacementStrategy {
     Default,
     CollectionId,
     AppId,
+    /// A content-addressed environment (see [`VivaEnvSpec::content_addressed_env_id`]), so apps
+    /// with identical environment specs share one prefix.
+    SpecHash,
     Custom(String),
 }
 
@@ -28,6 +34,7 @@ impl AppEnvPlacementStrategy {
             "--default--" => Ok(AppEnvPlacementStrategy::Default),
             "--collection_id--" => Ok(AppEnvPlacementStrategy::CollectionId),
             "--app_id--" => Ok(AppEnvPlacementStrategy::AppId),
+            "--spec_hash--" => Ok(AppEnvPlacementStrategy::SpecHash),
             _ => Ok(AppEnvPlacementStrategy::Custom(strategy.to_string())),
         }
     }
@@ -37,8 +44,121 @@ impl AppEnvPlacementStrategy {
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VivaAppSpec {
     pub executable: String,
+    #[serde(default)]
     pub args: Vec<String>,
     pub env_spec: VivaEnvSpec,
+    /// An (optional) environment id this app is bound to, overrides the collection placement strategy.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_id: Option<String>,
+    /// An (optional) prefix for the name of the shim of this app, overrides the global `shim_prefix`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub shim_prefix: Option<String>,
+    /// Well-known stacks whose runtime variables the app needs (e.g. `r`, `jupyter`, `fontconfig`,
+    /// see [`RUNTIME_STACKS`]), `auto` selects the ones that are installed in the environment.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub runtime_stacks: Vec<String>,
+    /// Additional runtime variables of the app, `{prefix}` in values is replaced with the path of
+    /// the environment prefix.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub runtime_vars: BTreeMap<String, String>,
+}
+
+/// A variable that is set when an app is launched (from the command line, or its shim).
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+pub struct RuntimeVar {
+    pub name: String,
+    pub value: String,
+    /// Only set the variable if it is not set already (e.g. the locale).
+    #[serde(default)]
+    pub if_unset: bool,
+}
+
+/// The runtime variables a stack needs, `{prefix}` in values is replaced with the environment
+/// prefix. The stack counts as installed (for `auto`) if its marker path exists in the prefix.
+pub struct RuntimeStack {
+    pub name: &'static str,
+    pub marker: &'static str,
+    pub vars: &'static [(&'static str, &'static str)],
+    /// Variables that are only set if they are not set already.
+    pub defaults: &'static [(&'static str, &'static str)],
+}
+
+#[cfg(target_os = "macos")]
+const DEFAULT_LANG: &str = "en_US.UTF-8";
+#[cfg(not(target_os = "macos"))]
+const DEFAULT_LANG: &str = "C.UTF-8";
+
+/// The built-in knowledge base of runtime variables (paths follow the layout of unix prefixes).
+///
+/// Apps launched from a desktop environment or a shim often don't get the variables a shell
+/// profile would set, which some stacks (R in particular) need to find their data files, or to not
+/// fall back to the `C` locale.
+pub const RUNTIME_STACKS: &[RuntimeStack] = &[
+    RuntimeStack {
+        name: "r",
+        marker: "lib/R",
+        vars: &[("R_HOME", "{prefix}/lib/R"), ("R_LIBS", "{prefix}/lib/R/library")],
+        defaults: &[("LANG", DEFAULT_LANG)],
+    },
+    RuntimeStack {
+        name: "jupyter",
+        marker: "share/jupyter",
+        vars: &[("JUPYTER_DATA_DIR", "{prefix}/share/jupyter")],
+        defaults: &[],
+    },
+    RuntimeStack {
+        name: "fontconfig",
+        marker: "etc/fonts/fonts.conf",
+        vars: &[
+            ("FONTCONFIG_PATH", "{prefix}/etc/fonts"),
+            ("FONTCONFIG_FILE", "{prefix}/etc/fonts/fonts.conf"),
+        ],
+        defaults: &[],
+    },
+    RuntimeStack {
+        name: "gdal",
+        marker: "share/gdal",
+        vars: &[("GDAL_DATA", "{prefix}/share/gdal")],
+        defaults: &[],
+    },
+    RuntimeStack {
+        name: "proj",
+        marker: "share/proj",
+        vars: &[("PROJ_DATA", "{prefix}/share/proj"), ("PROJ_LIB", "{prefix}/share/proj")],
+        defaults: &[],
+    },
+    RuntimeStack {
+        name: "tzdata",
+        marker: "share/zoneinfo",
+        vars: &[("TZDIR", "{prefix}/share/zoneinfo")],
+        defaults: &[],
+    },
+    RuntimeStack {
+        name: "ssl",
+        marker: "ssl/cacert.pem",
+        vars: &[],
+        defaults: &[("SSL_CERT_FILE", "{prefix}/ssl/cacert.pem")],
+    },
+    RuntimeStack {
+        name: "locale",
+        marker: "",
+        vars: &[],
+        defaults: &[("LANG", DEFAULT_LANG)],
+    },
+];
+
+/// Replace the `{prefix}` placeholder in a runtime variable value.
+fn expand_prefix(value: &str, prefix: &Path) -> String {
+    value.replace("{prefix}", &prefix.to_string_lossy())
+}
+
+/// The values of runtime variables, for the current process: variables that should only be set
+/// if they are not set already are left out if they are.
+pub fn resolve_runtime_vars(vars: &[RuntimeVar]) -> BTreeMap<String, String> {
+    vars.iter()
+        .filter(|var| !var.if_unset || std::env::var_os(&var.name).is_none())
+        .map(|var| (var.name.clone(), var.value.clone()))
+        .collect()
 }
 
 impl PartialEq for VivaAppSpec {
@@ -55,6 +175,18 @@ impl PartialEq for VivaAppSpec {
             return false;
         }
 
+        if self.env_id != other.env_id {
+            return false;
+        }
+
+        if self.shim_prefix != other.shim_prefix {
+            return false;
+        }
+
+        if self.runtime_stacks != other.runtime_stacks || self.runtime_vars != other.runtime_vars {
+            return false;
+        }
+
         true
     }
 }
@@ -71,6 +203,77 @@ impl VivaAppSpec {
         cmd
     }
 
+    /// The runtime variables of this app (of its stacks, then its own ones), for the provided
+    /// environment prefix.
+    pub fn get_runtime_vars(&self, prefix: &Path) -> Result<Vec<RuntimeVar>> {
+        let mut stacks: Vec<&RuntimeStack> = vec![];
+        for name in &self.runtime_stacks {
+            if name == "auto" {
+                stacks.extend(
+                    RUNTIME_STACKS
+                        .iter()
+                        .filter(|stack| !stack.marker.is_empty() && prefix.join(stack.marker).exists()),
+                );
+                continue;
+            }
+            match RUNTIME_STACKS.iter().find(|stack| stack.name == name.as_str()) {
+                Some(stack) => stacks.push(stack),
+                None => bail!(
+                    "Unknown runtime stack '{}' (available: auto, {})",
+                    name,
+                    RUNTIME_STACKS.iter().map(|stack| stack.name).collect::<Vec<_>>().join(", ")
+                ),
+            }
+        }
+
+        let mut vars: Vec<RuntimeVar> = vec![];
+        let mut add = |name: &str, value: String, if_unset: bool| {
+            vars.retain(|var| var.name != name);
+            vars.push(RuntimeVar {
+                name: String::from(name),
+                value,
+                if_unset,
+            });
+        };
+        for stack in stacks {
+            for &(name, value) in stack.vars {
+                add(name, expand_prefix(value, prefix), false);
+            }
+            for &(name, value) in stack.defaults {
+                add(name, expand_prefix(value, prefix), true);
+            }
+        }
+        for (name, value) in &self.runtime_vars {
+            add(name, expand_prefix(value, prefix), false);
+        }
+        Ok(vars)
+    }
+
+}
+
+/// A single entry in an app manifest file, used to register multiple apps at once.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct VivaAppManifestEntry {
+    #[serde(flatten)]
+    pub spec: VivaAppSpec,
+    /// The env placement strategy for this app (`--default--`, `--collection_id--`, `--app_id--`,
+    /// `--spec_hash--`, or an env id).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub placement: Option<String>,
+}
+
+impl VivaAppManifestEntry {
+    pub fn get_placement_strategy(&self) -> Result<AppEnvPlacementStrategy> {
+        match &self.placement {
+            Some(placement) => AppEnvPlacementStrategy::from_str(placement),
+            None => Ok(AppEnvPlacementStrategy::CollectionId),
+        }
+    }
+}
+
+/// Read an app manifest file (a map of app ids to manifest entries).
+pub async fn read_app_manifest(manifest_file: &PathBuf) -> Result<BTreeMap<String, VivaAppManifestEntry>> {
+    read_models_spec(manifest_file).await
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -78,7 +281,11 @@ pub struct VivaApp {
     pub id: String,
     pub spec: VivaAppSpec,
     pub app_collection_id: String,
-    env_id: String
+    env_id: String,
+    /// Whether the environment was explicitly chosen for this app (instead of being derived from
+    /// the placement strategy), in which case it won't be created automatically.
+    #[serde(skip)]
+    pub(crate) explicit_env: bool,
 }
 
 impl VivaApp {
@@ -94,26 +301,68 @@ impl VivaApp {
             spec: spec,
             app_collection_id,
             env_id: env_id,
+            explicit_env: false,
         }
     }
 
     pub fn get_env_id(&self) -> &str {
         &self.env_id
     }
+
+    /// Whether the environment of the app was derived from the app itself (from its id, or its
+    /// spec), instead of being a shared one (like 'default') or one chosen explicitly.
+    pub fn has_own_env(&self) -> bool {
+        !self.explicit_env
+            && (self.env_id == self.id || self.env_id == self.spec.env_spec.content_addressed_env_id())
+    }
+}
+
+/// Everything that was removed when uninstalling an app.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct AppUninstallReport {
+    pub app_id: String,
+    /// The collection the app spec was removed from.
+    pub app_collection_id: String,
+    /// The shims that were removed.
+    pub removed_shims: Vec<PathBuf>,
+    /// The id and prefix of the environment, if it was removed.
+    pub removed_env: Option<(String, PathBuf)>,
+    /// The snapshots of the environment that were removed along with it.
+    pub removed_snapshots: Vec<String>,
+    /// Why the environment was kept, if its removal was requested.
+    pub kept_env_reason: Option<String>,
 }
 
 #[async_trait]
-pub trait AppCollection: Debug {
+pub trait AppCollection: Debug + Send + Sync {
     async fn get_app_ids(&self) -> Vec<String>;
     async fn get_app(&self, app_id: &str) -> Result<&VivaAppSpec>;
-    async fn delete_app(&mut self, app_id: &str) -> Option<VivaAppSpec>;
+    async fn delete_app(&mut self, app_id: &str) -> Result<Option<VivaAppSpec>>;
     async fn set_app(&mut self, app_id: &str, app_spec: &VivaAppSpec) -> Result<()>;
+    /// Spec entries that could not be loaded (id or file -> error message).
+    async fn get_invalid_apps(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+    /// Problems that didn't prevent loading (e.g. shadowed ids, or unknown fields).
+    async fn get_warnings(&self) -> Vec<String> {
+        vec![]
+    }
+    /// Re-read the collection from its source.
+    async fn reload(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// The paths to watch for changes to the collection.
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![]
+    }
 }
 
 #[derive(Debug)]
 pub struct DefaultAppCollection {
     base_config_path: PathBuf,
     registered_apps: Option<BTreeMap<String, VivaAppSpec>>,
+    invalid_apps: BTreeMap<String, String>,
+    warnings: Vec<String>,
 }
 
 impl DefaultAppCollection {
@@ -121,6 +370,8 @@ impl DefaultAppCollection {
         let mut env = DefaultAppCollection {
             base_config_path,
             registered_apps: None,
+            invalid_apps: BTreeMap::new(),
+            warnings: Vec::new(),
         };
 
         env.load_registered_apps(false).await?;
@@ -133,6 +384,8 @@ impl DefaultAppCollection {
     ) -> Result<&BTreeMap<String, VivaAppSpec>> {
         match self.registered_apps.is_none() || force_update {
             true => {
+                let mut invalid_apps: BTreeMap<String, String> = BTreeMap::new();
+                let mut warnings: Vec<String> = vec![];
                 let apps: BTreeMap<String, VivaAppSpec> = match self.base_config_path.exists() {
                     true => {
                         let mut app_file = self.base_config_path.join("apps.json");
@@ -140,36 +393,65 @@ impl DefaultAppCollection {
                             app_file.set_extension("yaml");
                         }
 
-                        let mut parsed_models: BTreeMap<String, VivaAppSpec> =
-                            match app_file.exists() {
-                                true => read_models_spec(&app_file).await?,
-                                false => BTreeMap::new(),
-                            };
+                        let mut parsed_models: BTreeMap<String, VivaAppSpec> = BTreeMap::new();
+                        if app_file.exists() {
+                            match read_models_spec(&app_file).await {
+                                Ok(apps) => {
+                                    if let Ok(raw) = read_raw_spec(&app_file).await {
+                                        for field in find_unknown_fields::<BTreeMap<String, VivaAppSpec>>(&raw) {
+                                            warnings.push(format!("{}: unknown field '{}'", app_file.display(), field));
+                                        }
+                                    }
+                                    parsed_models = apps
+                                }
+                                Err(e) => {
+                                    warn!("Invalid apps file '{}': {:#}", app_file.display(), e);
+                                    invalid_apps.insert(app_file.display().to_string(), format!("{:#}", e));
+                                }
+                            }
+                        }
 
                         let apps_dir = self.base_config_path.join("apps");
                         if apps_dir.is_dir() {
-                            for entry in std::fs::read_dir(apps_dir).unwrap() {
-                                let entry = entry.unwrap();
-                                let spec_config_file: &PathBuf = &entry.path();
-                                if spec_config_file.is_file() {
-                                    let app_spec: VivaAppSpec =
-                                        read_model_spec(&spec_config_file).await?;
-                                    // TODO: check if to_string_lossy is good enough here
-                                    let app_name: String = spec_config_file
-                                        .file_stem()
-                                        .unwrap()
-                                        .to_string_lossy()
-                                        .into();
-
-                                    if parsed_models.contains_key(&app_name) {
-                                        debug!(
-                                            "Overwriting app {}, as it has it's own spec file.",
-                                            &app_name
-                                        );
+                            let ignore = match SpecIgnore::load(&self.base_config_path) {
+                                Ok(ignore) => ignore,
+                                Err(e) => {
+                                    warn!("{:#}", e);
+                                    invalid_apps.insert(IGNORE_FILENAME.to_string(), format!("{:#}", e));
+                                    SpecIgnore::default()
+                                }
+                            };
+                            let spec_files = find_spec_files(&apps_dir, &ignore, &mut warnings).await;
+                            for read_spec in read_spec_files::<VivaAppSpec>(spec_files).await {
+                                let (app_name, spec_config_file) = (read_spec.id, read_spec.path);
+                                let app_spec: VivaAppSpec = match read_spec.result {
+                                    Ok((app_spec, unknown_fields)) => {
+                                        for field in unknown_fields {
+                                            warnings.push(format!("{}: unknown field '{}'", spec_config_file.display(), field));
+                                        }
+                                        app_spec
                                     }
-
-                                    parsed_models.insert(app_name, app_spec);
+                                    Err(e) => {
+                                        warn!("Invalid app spec '{}': {:#}", spec_config_file.display(), e);
+                                        invalid_apps.insert(app_name, format!("{:#}", e));
+                                        continue;
+                                    }
+                                };
+
+                                if parsed_models.contains_key(&app_name) {
+                                    debug!(
+                                        "Overwriting app {}, as it has it's own spec file.",
+                                        &app_name
+                                    );
+                                    warnings.push(format!(
+                                        "App '{}' is defined in '{}', and shadowed by: {}",
+                                        app_name,
+                                        app_file.display(),
+                                        spec_config_file.display()
+                                    ));
                                 }
+
+                                parsed_models.insert(app_name, app_spec);
                             }
                         }
                         parsed_models
@@ -178,6 +460,8 @@ impl DefaultAppCollection {
                 };
 
                 self.registered_apps = Some(apps);
+                self.invalid_apps = invalid_apps;
+                self.warnings = warnings;
             }
             false => {}
         };
@@ -188,6 +472,23 @@ impl DefaultAppCollection {
 
 #[async_trait]
 impl AppCollection for DefaultAppCollection {
+    async fn get_invalid_apps(&self) -> BTreeMap<String, String> {
+        self.invalid_apps.clone()
+    }
+
+    async fn get_warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+
+    async fn reload(&mut self) -> Result<()> {
+        self.load_registered_apps(true).await?;
+        Ok(())
+    }
+
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![self.base_config_path.clone()]
+    }
+
     async fn get_app_ids(&self) -> Vec<String> {
         self.registered_apps
             .as_ref()
@@ -207,19 +508,139 @@ impl AppCollection for DefaultAppCollection {
         env
     }
 
-    async fn delete_app(&mut self, _app_id: &str) -> Option<VivaAppSpec> {
-        todo!()
-        // self.registered_envs.as_mut().unwrap().remove(env_name)
+    async fn delete_app(&mut self, app_id: &str) -> Result<Option<VivaAppSpec>> {
+        let removed = self.registered_apps.as_mut().unwrap().remove(app_id);
+        if removed.is_none() {
+            return Ok(None);
+        }
+
+        let mut deleted_single_file = false;
+        for ext in SPEC_FILE_EXTENSIONS {
+            let app_spec_file = self.base_config_path.join("apps").join(format!("{}.{}", app_id, ext));
+            if app_spec_file.exists() {
+                fs::remove_file(&app_spec_file).await?;
+                deleted_single_file = true;
+            }
+        }
+
+        if !deleted_single_file {
+            let mut app_file = self.base_config_path.join("apps.json");
+            if !app_file.exists() {
+                app_file.set_extension("yaml");
+            }
+            if app_file.exists() {
+                let mut collected: BTreeMap<String, VivaAppSpec> = read_models_spec(&app_file).await?;
+                if collected.remove(app_id).is_some() {
+                    write_models_spec(&app_file, &collected).await?;
+                }
+            }
+        }
+
+        debug!("Deleted app: {}", app_id);
+        Ok(removed)
     }
 
     async fn set_app(&mut self, app_id: &str, app_spec: &VivaAppSpec) -> Result<()> {
         let app_spec_file = self.base_config_path.join("apps").join(format!("{}.json", app_id));
         // TOOD: check if it already exists?
 
-        write_model_spec(&app_spec_file, app_spec).await?;
+        let mut normalized = app_spec.clone();
+        normalized.env_spec = app_spec.env_spec.normalized();
+        write_model_spec(&app_spec_file, &normalized).await?;
         self.registered_apps.as_mut().unwrap().insert(app_id.to_string(), app_spec.clone());
 
         Ok(())
 
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn app_spec(runtime_stacks: &[&str], runtime_vars: &[(&str, &str)]) -> VivaAppSpec {
+        VivaAppSpec {
+            executable: String::from("R"),
+            args: vec![],
+            env_spec: VivaEnvSpec::new(),
+            env_id: None,
+            shim_prefix: None,
+            runtime_stacks: runtime_stacks.iter().map(|stack| String::from(*stack)).collect(),
+            runtime_vars: runtime_vars
+                .iter()
+                .map(|(name, value)| (String::from(*name), String::from(*value)))
+                .collect(),
+        }
+    }
+
+    fn var(name: &str, value: &str, if_unset: bool) -> RuntimeVar {
+        RuntimeVar {
+            name: String::from(name),
+            value: String::from(value),
+            if_unset,
+        }
+    }
+
+    #[test]
+    fn test_has_own_env() {
+        let spec = app_spec(&[], &[]);
+        let hashed_env_id = spec.env_spec.content_addressed_env_id();
+        assert!(VivaApp::create(String::from("r"), spec.clone(), String::from("default"), String::from("r")).has_own_env());
+        assert!(VivaApp::create(String::from("r"), spec.clone(), String::from("default"), hashed_env_id).has_own_env());
+        assert!(!VivaApp::create(String::from("r"), spec.clone(), String::from("default"), String::from("default")).has_own_env());
+
+        let mut explicit = VivaApp::create(String::from("r"), spec, String::from("default"), String::from("r"));
+        explicit.explicit_env = true;
+        assert!(!explicit.has_own_env());
+    }
+
+    #[test]
+    fn test_runtime_vars() {
+        let prefix = Path::new("/envs/r");
+        let vars = app_spec(&["r", "tzdata"], &[("R_LIBS", "{prefix}/custom")])
+            .get_runtime_vars(prefix)
+            .unwrap();
+        assert_eq!(
+            vars,
+            vec![
+                var("R_HOME", "/envs/r/lib/R", false),
+                var("LANG", DEFAULT_LANG, true),
+                var("TZDIR", "/envs/r/share/zoneinfo", false),
+                // the app's own variables override the ones of its stacks
+                var("R_LIBS", "/envs/r/custom", false),
+            ]
+        );
+
+        assert!(app_spec(&["jupyter"], &[])
+            .get_runtime_vars(prefix)
+            .unwrap()
+            .iter()
+            .all(|var| var.name != "JUPYTER_CONFIG_DIR"));
+        assert!(app_spec(&["unknown"], &[]).get_runtime_vars(prefix).is_err());
+    }
+
+    #[test]
+    fn test_runtime_vars_auto() {
+        let dir = tempfile::tempdir().unwrap();
+        let prefix = dir.path();
+        std::fs::create_dir_all(prefix.join("share/gdal")).unwrap();
+
+        let vars = app_spec(&["auto"], &[]).get_runtime_vars(prefix).unwrap();
+        let gdal_data = format!("{}/share/gdal", prefix.to_string_lossy());
+        assert_eq!(vars, vec![var("GDAL_DATA", &gdal_data, false)]);
+    }
+
+    #[test]
+    fn test_resolve_runtime_vars() {
+        std::env::set_var("VIVA_TEST_RUNTIME_VAR_SET", "1");
+        let vars = vec![
+            var("VIVA_TEST_RUNTIME_VAR_SET", "2", true),
+            var("VIVA_TEST_RUNTIME_VAR_UNSET", "3", true),
+            var("VIVA_TEST_RUNTIME_VAR_FORCED", "4", false),
+        ];
+        let resolved = resolve_runtime_vars(&vars);
+        assert_eq!(resolved.get("VIVA_TEST_RUNTIME_VAR_SET"), None);
+        assert_eq!(resolved.get("VIVA_TEST_RUNTIME_VAR_UNSET").map(|v| v.as_str()), Some("3"));
+        assert_eq!(resolved.get("VIVA_TEST_RUNTIME_VAR_FORCED").map(|v| v.as_str()), Some("4"));
+    }
+}
@@ -1,26 +1,49 @@
 
 use std::collections::{BTreeMap, HashSet};
-use std::fmt::Debug;
-use std::path::{PathBuf};
-use std::process::Stdio;
+use std::ffi::OsString;
+use std::fmt::{self, Debug};
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
+use std::str::FromStr;
+use std::sync::Arc;
 
 
-use anyhow::{anyhow, Context, Result};
+use anyhow::{anyhow, bail, Context, Result};
 use async_trait::async_trait;
 
+use rattler_conda_types::{Channel, ChannelConfig, MatchSpec, Platform, RepoDataRecord};
 use rattler_repodata_gateway::fetch::CacheAction;
 
 
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
 use tokio::fs;
 
 
 use tokio::process::Command;
-use tracing::debug;
-
-
-use crate::defaults::{CONDA_BIN_DIRNAME};
-use crate::models::{read_model_spec, read_models_spec, write_model_spec, write_models_spec};
+use tracing::{debug, warn};
+
+
+use crate::activation::{Activation, ShellType};
+use crate::conda_envs;
+use crate::config::{parse_size, VivaConfig};
+use crate::executable::{executable_paths_in_prefix, find_executable_in_prefix};
+use crate::defaults::{
+    CONDA_BIN_DIRNAME, ENV_HISTORY_DIRNAME, ENV_HISTORY_LENGTH, ENV_SPEC_FILENAME, IGNORE_FILENAME,
+    LOCKFILE_EXTENSION,
+};
+use crate::history;
+use crate::journal::{self, JournalOperation};
+use crate::models::app::{resolve_runtime_vars, RuntimeVar};
+use crate::models::lockfile::EnvLockfile;
+use crate::progress::{check_cancelled, ProgressEvent, SyncHandle};
+use crate::rattler::commands::create::{
+    apply, find_installed_packages, from_locked_packages, pip_wheels_dir, solve, SolvedEnvironment,
+};
+use crate::rattler::global_multi_progress;
+use crate::models::{
+    find_spec_files, find_unknown_fields, read_model_spec, read_models_spec, read_raw_spec,
+    read_spec_files, write_model_spec, write_models_spec, SpecIgnore, SPEC_FILE_EXTENSIONS,
+};
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
 pub enum EnvSyncStatus {
@@ -39,16 +62,348 @@ impl ToString for EnvSyncStatus {
     }
 }
 
+/// How an environment is checked (and synced) before it is used.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum EnvCheckStrategy {
+    /// Sync the environment if its spec is not satisfied by what is installed.
+    #[default]
+    Auto,
+    /// Always solve the spec again (ignoring the lockfile), and apply the result.
+    Force,
+    /// Never touch the environment, it only has to exist.
+    Skip,
+}
+
+impl FromStr for EnvCheckStrategy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "auto" => Ok(EnvCheckStrategy::Auto),
+            // 'always' is what 'app run --check' used to call it
+            "force" | "always" => Ok(EnvCheckStrategy::Force),
+            "skip" => Ok(EnvCheckStrategy::Skip),
+            _ => bail!("Invalid environment check strategy '{}' (available: auto, force, skip)", s),
+        }
+    }
+}
+
+impl fmt::Display for EnvCheckStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EnvCheckStrategy::Auto => write!(f, "auto"),
+            EnvCheckStrategy::Force => write!(f, "force"),
+            EnvCheckStrategy::Skip => write!(f, "skip"),
+        }
+    }
+}
+
+/// A package for which a newer version is available than the one installed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct OutdatedPackage {
+    pub name: String,
+    pub installed_version: String,
+    pub available_version: String,
+}
+
+/// An estimate of the work a sync of an environment requires, computed from a solve.
+#[derive(Debug, Serialize, Deserialize, Clone, Default, PartialEq, Eq)]
+pub struct SyncEstimate {
+    /// The number of packages the environment will contain.
+    pub total_count: usize,
+    /// The number of packages that need to be installed.
+    pub install_count: usize,
+    /// The number of packages that need to be downloaded.
+    pub download_count: usize,
+    /// The total (compressed) download size, in bytes.
+    pub download_bytes: u64,
+}
+
+/// What a sync would change in the prefix of an environment, see [`VivaEnv::plan_sync`].
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SyncPlan {
+    pub env_id: String,
+    pub env_path: PathBuf,
+    pub platform: String,
+    /// Whether the packages come from the lockfile of the environment (instead of a solve).
+    pub from_lockfile: bool,
+    /// The changes to the packages of the prefix, sorted by package name.
+    pub operations: Vec<PlannedOperation>,
+    /// Installed with pip after the conda packages.
+    pub pip_specs: Vec<String>,
+    pub estimate: SyncEstimate,
+}
+
+/// What happens to a package of a prefix.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum PlannedAction {
+    Install,
+    Upgrade,
+    Downgrade,
+    /// A different build of the same version.
+    Change,
+    /// Relinked (e.g. noarch python packages, if the python version changes).
+    Reinstall,
+    Remove,
+}
+
+impl fmt::Display for PlannedAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = match self {
+            PlannedAction::Install => "install",
+            PlannedAction::Upgrade => "upgrade",
+            PlannedAction::Downgrade => "downgrade",
+            PlannedAction::Change => "change",
+            PlannedAction::Reinstall => "reinstall",
+            PlannedAction::Remove => "remove",
+        };
+        write!(f, "{}", action)
+    }
+}
+
+/// A change to a single package of a prefix.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PlannedOperation {
+    pub action: PlannedAction,
+    pub name: String,
+    /// The installed version (and build), if the package is installed.
+    pub installed: Option<String>,
+    /// The version (and build) that will be installed, if any.
+    pub version: Option<String>,
+    pub channel: Option<String>,
+    /// The (compressed) size of the package, if it needs to be downloaded.
+    pub download_bytes: Option<u64>,
+}
+
+/// A package that is part of the solution for an environment.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SolvedPackage {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub channel: String,
+}
+
+/// A package that is installed in the prefix of an environment (from its `conda-meta` record).
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct InstalledPackage {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub channel: String,
+    /// The size of the files the package installed into the prefix, in bytes (the size of the
+    /// package archive, if the record doesn't contain file sizes).
+    pub size: u64,
+    /// The spec the package was requested with, for packages that were not installed as a dependency.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub requested_spec: Option<String>,
+}
+
+/// Where an executable would come from, if a solved environment was installed.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(tag = "result", rename_all = "snake_case")]
+pub enum ExecutableSource {
+    /// The executable is installed by this package.
+    Package { name: String },
+    /// None of the packages installs the executable.
+    NotFound,
+    /// The executable is not installed by any of the packages in the package cache, but the
+    /// contents of the others are not known (without downloading them).
+    Unknown { uncached_packages: usize },
+}
+
+/// Explains why a specific version/build of a package was chosen when solving an environment.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PackageExplanation {
+    pub name: String,
+    pub version: String,
+    pub build: String,
+    pub channel: String,
+    /// The chains of requirements that pull the package in, each starting with a spec entry.
+    pub chains: Vec<Vec<String>>,
+    /// Newer versions of the package that were available, but not chosen.
+    pub rejected_newer: Vec<RejectedCandidate>,
+}
+
+/// A package candidate that was not chosen by the solver.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RejectedCandidate {
+    pub version: String,
+    pub build: String,
+    pub channel: String,
+    /// The constraints (of the spec, or of other chosen packages) the candidate doesn't satisfy. If
+    /// empty, the candidate was excluded indirectly (e.g. through its own dependencies).
+    pub excluded_by: Vec<String>,
+}
+
+/// Explains the sync status of an environment, by comparing its spec with the actual spec of the
+/// environment prefix.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SyncExplanation {
+    pub env_id: String,
+    pub sync_status: EnvSyncStatus,
+    /// Whether the environment prefix does not exist (yet).
+    pub prefix_missing: bool,
+    /// Whether the actual-spec file in the environment prefix is missing.
+    pub actual_spec_missing: bool,
+    /// Package specs that are in the spec, but not in the actual spec.
+    pub new_pkg_specs: Vec<String>,
+    /// Pip specs that are in the spec, but not in the actual spec.
+    pub new_pip_specs: Vec<String>,
+    /// Package specs that are in the actual spec, but not in the spec (anymore).
+    pub removed_pkg_specs: Vec<String>,
+    /// Channels that are in the spec, but not in the actual spec.
+    pub new_channels: Vec<String>,
+    /// Whether the base prefix of the spec differs from the one the environment was synced with.
+    pub base_prefix_changed: bool,
+    /// Whether the channel views of the spec differ from the ones the environment was synced with.
+    #[serde(default)]
+    pub channel_views_changed: bool,
+    /// Whether packages in the prefix were changed outside of viva, after the last sync.
+    pub prefix_modified: bool,
+}
+
+impl SyncExplanation {
+    /// Human readable reasons for the sync status.
+    pub fn reasons(&self) -> Vec<String> {
+        let mut reasons = vec![];
+        if self.prefix_missing {
+            reasons.push("the environment prefix does not exist".to_string());
+        } else if self.actual_spec_missing {
+            reasons.push(format!(
+                "the environment prefix has no '{}' file, it was not created by viva or its last sync did not finish",
+                ENV_SPEC_FILENAME
+            ));
+        }
+        for pkg_spec in &self.new_pkg_specs {
+            reasons.push(format!("new package spec: {}", pkg_spec));
+        }
+        for pip_spec in &self.new_pip_specs {
+            reasons.push(format!("new pip spec: {}", pip_spec));
+        }
+        for pkg_spec in &self.removed_pkg_specs {
+            reasons.push(format!("removed package spec: {}", pkg_spec));
+        }
+        for channel in &self.new_channels {
+            reasons.push(format!("new channel: {}", channel));
+        }
+        if self.base_prefix_changed {
+            reasons.push("the base prefix changed".to_string());
+        }
+        if self.channel_views_changed {
+            reasons.push("the channel views changed".to_string());
+        }
+        if self.prefix_modified {
+            reasons.push(
+                "packages in the prefix were changed outside of viva after the last sync"
+                    .to_string(),
+            );
+        }
+        reasons
+    }
+}
+
 /// Represents the Viva environment specification.
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct VivaEnvSpec {
     pub channels: Vec<String>,
     pub pkg_specs: Vec<String>,
+    /// An (optional) read-only prefix this environment extends, only packages that are not already
+    /// in the base prefix are installed into this environment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub base_prefix: Option<PathBuf>,
+    /// An (optional) size budget for the environment (e.g. `500MB`), checked after the solve.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_size: Option<String>,
+    /// The platform (conda subdir, e.g. `linux-64` or `osx-arm64`) to solve for, instead of the
+    /// current one. Environments for other platforms can be solved and locked, but usually not run.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub platform: Option<String>,
+    /// Python packages to install with pip (from PyPI), after the conda packages are installed.
+    /// If there are any, `pip` is added to the conda package specs.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pip_specs: Vec<String>,
+    /// Environment variables for processes that run in the environment, they override the ones viva
+    /// sets (`PATH`, `CONDA_PREFIX`, ...). Changing them doesn't require a re-sync.
+    #[serde(
+        default,
+        skip_serializing_if = "BTreeMap::is_empty",
+        deserialize_with = "deserialize_env_vars"
+    )]
+    pub env_vars: BTreeMap<String, String>,
+    /// Curated views of channels (channel -> package names, or patterns like `r-*`): the solver
+    /// only sees the listed packages of those channels, including dependencies (which have to be
+    /// listed as well). Channels without a view are used unrestricted.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channel_views: BTreeMap<String, Vec<String>>,
+    /// Where the channels and package specs came from (not considered when comparing specs).
+    #[serde(default, skip_serializing_if = "SpecProvenance::is_empty")]
+    pub provenance: SpecProvenance,
+}
+
+/// Records where the entries of an environment spec came from (entry -> source), e.g. a spec
+/// file, an app, or a command-line invocation.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct SpecProvenance {
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub channels: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub pkg_specs: BTreeMap<String, String>,
+}
+
+impl SpecProvenance {
+    pub fn is_empty(&self) -> bool {
+        self.channels.is_empty() && self.pkg_specs.is_empty()
+    }
+}
+
+/// A conda `environment.yml` file.
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq, Eq)]
+pub struct CondaEnvironmentFile {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    #[serde(default)]
+    pub channels: Vec<String>,
+    #[serde(default)]
+    pub dependencies: Vec<CondaDependency>,
+}
+
+/// An entry in the `dependencies` list of a conda `environment.yml` file.
+#[derive(Debug, Deserialize, Serialize, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum CondaDependency {
+    /// A conda package spec (e.g. `python=3.10`).
+    Spec(String),
+    /// A list of pip requirements.
+    Pip { pip: Vec<String> },
 }
 
 impl PartialEq for VivaEnvSpec {
     fn eq(&self, other: &Self) -> bool {
-        if self.pkg_specs != other.pkg_specs {
+        // the order of package specs is not significant (spec files are written sorted)
+        if !pkg_specs_are_equal(&self.pkg_specs, &other.pkg_specs) {
+            return false;
+        }
+
+        if self.base_prefix != other.base_prefix {
+            return false;
+        }
+
+        if self.max_size != other.max_size {
+            return false;
+        }
+
+        if self.platform != other.platform {
+            return false;
+        }
+
+        if self.channel_views != other.channel_views {
+            return false;
+        }
+
+        if !pkg_specs_are_equal(&self.pip_specs, &other.pip_specs) {
             return false;
         }
 
@@ -82,7 +437,6 @@ fn join_channels(channel_1: &Vec<String>, channel_2: &Vec<String>) -> Vec<String
     return specs.into_iter().collect();
 }
 
-#[allow(unused)]
 fn pkg_specs_are_equal(spec_1: &Vec<String>, spec_2: &Vec<String>) -> bool {
     let mut specs_1: HashSet<String> = HashSet::new();
     specs_1.extend(spec_1.iter().cloned());
@@ -135,8 +489,37 @@ impl VivaEnvSpec {
         if !new_matchspecs.is_empty() {
             return false;
         }
+
+        let new_pip_specs = check_for_new_pkg_specs(&other_spec.pip_specs, &self.pip_specs);
+        if !new_pip_specs.is_empty() {
+            return false;
+        }
+
+        if self.base_prefix.is_some() && self.base_prefix != other_spec.base_prefix {
+            return false;
+        }
+
+        if self.get_platform_name() != other_spec.get_platform_name() {
+            return false;
+        }
+
+        // a view restricts the packages that can be installed, so a changed view needs a re-solve
+        if self.channel_views != other_spec.channel_views {
+            return false;
+        }
         return true;
     }
+
+    /// Whether this spec results in the same environment as the other one: like
+    /// [`VivaEnvSpec::is_satisfied_by`], but the other spec must not contain channels or package
+    /// specs this one doesn't (anymore) either, whose packages would still be installed.
+    pub fn is_equivalent_to(&self, other_spec: &VivaEnvSpec) -> bool {
+        self.is_satisfied_by(other_spec)
+            && check_for_new_channels(&self.channels, &other_spec.channels).is_empty()
+            && check_for_new_pkg_specs(&self.pkg_specs, &other_spec.pkg_specs).is_empty()
+            && check_for_new_pkg_specs(&self.pip_specs, &other_spec.pip_specs).is_empty()
+            && self.base_prefix == other_spec.base_prefix
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -148,6 +531,12 @@ pub struct VivaEnv {
     actual_spec_path: PathBuf,
     actual: VivaEnvSpec,
     pub sync_status: EnvSyncStatus,
+    #[serde(skip)]
+    pub(crate) config: Arc<VivaConfig>,
+    /// Where the resolved package set of this environment is recorded (if its collection supports
+    /// lockfiles).
+    #[serde(skip)]
+    pub(crate) lockfile_path: Option<PathBuf>,
 }
 
 impl VivaEnvSpec {
@@ -155,8 +544,362 @@ impl VivaEnvSpec {
         VivaEnvSpec {
             channels: vec![],
             pkg_specs: vec![],
+            base_prefix: None,
+            max_size: None,
+            platform: None,
+            pip_specs: vec![],
+            env_vars: BTreeMap::new(),
+            channel_views: BTreeMap::new(),
+            provenance: SpecProvenance::default(),
+        }
+    }
+
+    /// The spec in its canonical form, as it is written to spec files: channels, package specs and
+    /// pip specs are de-duplicated and sorted, and provenance entries of entries that are not part
+    /// of the spec are dropped.
+    ///
+    /// Channel order has no meaning for solving, and the package specs keep where they came from in
+    /// the provenance, so this only keeps diffs of spec files in version control minimal.
+    pub fn normalized(&self) -> VivaEnvSpec {
+        let sorted = |entries: &Vec<String>| {
+            let mut entries = entries.clone();
+            entries.sort();
+            entries.dedup();
+            entries
+        };
+        let mut spec = self.clone();
+        spec.channels = sorted(&self.channels);
+        spec.pkg_specs = sorted(&self.pkg_specs);
+        spec.pip_specs = sorted(&self.pip_specs);
+        for view in spec.channel_views.values_mut() {
+            *view = sorted(view);
+        }
+        spec.provenance
+            .channels
+            .retain(|channel, _| self.channels.contains(channel));
+        spec.provenance
+            .pkg_specs
+            .retain(|pkg_spec, _| self.pkg_specs.contains(pkg_spec));
+        spec
+    }
+
+    /// A stable hash of the content of the spec (16 hex characters).
+    ///
+    /// The hash is the 64 bit FNV-1a hash of the compact json serialization of the normalized spec
+    /// (see [`VivaEnvSpec::normalized`]), without provenance. So it only changes if the content of
+    /// the spec changes: entry order, duplicate entries and provenance are not part of it.
+    ///
+    /// Stability: the hash of a spec stays the same across viva versions, and platforms. Fields
+    /// added in later versions are left out of the serialization when they are not set, so specs
+    /// that don't use them keep their hash. Should the serialization of an existing field ever have
+    /// to change, it will be called out in the changelog.
+    pub fn content_hash(&self) -> String {
+        let mut spec = self.normalized();
+        spec.provenance = SpecProvenance::default();
+        let canonical = serde_json::to_string(&spec).expect("Cannot serialize environment spec to JSON");
+        fnv1a_hash(canonical.as_bytes())
+    }
+
+    /// A stable hash of the parts of the spec that determine the content of a prefix (channels,
+    /// package specs, pip specs and the platform, resolved so a spec for the current platform hashes
+    /// the same as one that doesn't set a platform).
+    ///
+    /// Unlike [`VivaEnvSpec::content_hash`] (which lockfiles and cache keys are based on, and which
+    /// stays unchanged), environment variables and the size budget are left out: they don't change
+    /// what gets installed, so specs that only differ in them can share a prefix.
+    pub fn prefix_hash(&self) -> String {
+        let mut spec = self.normalized();
+        spec.platform = Some(self.get_platform_name());
+        spec.env_vars = BTreeMap::new();
+        spec.max_size = None;
+        spec.provenance = SpecProvenance::default();
+        let canonical = serde_json::to_string(&spec).expect("Cannot serialize environment spec to JSON");
+        fnv1a_hash(canonical.as_bytes())
+    }
+
+    /// The id of the content-addressed environment of the spec (`spec-<hash>`, see
+    /// [`VivaEnvSpec::prefix_hash`]): specs that install the same packages get the same environment
+    /// (and so the same prefix), no matter which app or run they come from.
+    pub fn content_addressed_env_id(&self) -> String {
+        format!("spec-{}", self.prefix_hash())
+    }
+
+    /// Record the source of all channels and package specs that don't have one yet.
+    pub fn record_provenance(&mut self, source: &str) {
+        for channel in &self.channels {
+            self.provenance
+                .channels
+                .entry(channel.clone())
+                .or_insert_with(|| String::from(source));
         }
+        for pkg_spec in &self.pkg_specs {
+            self.provenance
+                .pkg_specs
+                .entry(pkg_spec.clone())
+                .or_insert_with(|| String::from(source));
+        }
+    }
+
+    /// Append the channels and package specs of another spec (the ones of this spec take
+    /// precedence), the base prefix, size budget and platform of the other spec override the ones of
+    /// this spec.
+    pub fn append_spec(&mut self, other: VivaEnvSpec) {
+        for channel in other.channels {
+            if !self.channels.contains(&channel) {
+                self.channels.push(channel);
+            }
+        }
+        for pkg_spec in other.pkg_specs {
+            if !self.pkg_specs.contains(&pkg_spec) {
+                self.pkg_specs.push(pkg_spec);
+            }
+        }
+        for pip_spec in other.pip_specs {
+            if !self.pip_specs.contains(&pip_spec) {
+                self.pip_specs.push(pip_spec);
+            }
+        }
+        self.env_vars.extend(other.env_vars);
+        self.channel_views.extend(other.channel_views);
+        for (channel, source) in other.provenance.channels {
+            self.provenance.channels.entry(channel).or_insert(source);
+        }
+        for (pkg_spec, source) in other.provenance.pkg_specs {
+            self.provenance.pkg_specs.entry(pkg_spec).or_insert(source);
+        }
+        if other.base_prefix.is_some() {
+            self.base_prefix = other.base_prefix;
+        }
+        if other.max_size.is_some() {
+            self.max_size = other.max_size;
+        }
+        if other.platform.is_some() {
+            self.platform = other.platform;
+        }
+    }
+
+    /// Add the channels and package specs of a spec with lower precedence (e.g. the one of the same
+    /// environment in another collection) after the ones of this spec, package specs only for
+    /// packages this spec doesn't have one for. Everything else is only taken from the other spec
+    /// if this spec doesn't set it.
+    pub fn underlay_spec(&mut self, lower: VivaEnvSpec) {
+        for channel in lower.channels {
+            if !self.channels.contains(&channel) {
+                self.channels.push(channel);
+            }
+        }
+        let pkg_names = self
+            .pkg_specs
+            .iter()
+            .map(|pkg_spec| pkg_spec_name(pkg_spec).to_string())
+            .collect::<HashSet<String>>();
+        for pkg_spec in lower.pkg_specs {
+            if !pkg_names.contains(pkg_spec_name(&pkg_spec)) {
+                self.pkg_specs.push(pkg_spec);
+            }
+        }
+        for pip_spec in lower.pip_specs {
+            if !self.pip_specs.contains(&pip_spec) {
+                self.pip_specs.push(pip_spec);
+            }
+        }
+        for (key, value) in lower.env_vars {
+            self.env_vars.entry(key).or_insert(value);
+        }
+        for (channel, view) in lower.channel_views {
+            self.channel_views.entry(channel).or_insert(view);
+        }
+        for (channel, source) in lower.provenance.channels {
+            self.provenance.channels.entry(channel).or_insert(source);
+        }
+        for (pkg_spec, source) in lower.provenance.pkg_specs {
+            self.provenance.pkg_specs.entry(pkg_spec).or_insert(source);
+        }
+        if self.base_prefix.is_none() {
+            self.base_prefix = lower.base_prefix;
+        }
+        if self.max_size.is_none() {
+            self.max_size = lower.max_size;
+        }
+        if self.platform.is_none() {
+            self.platform = lower.platform;
+        }
+    }
+
+    /// The part of this spec that is not provided by the spec it was layered with (see
+    /// [`VivaEnvSpec::underlay_spec`]), e.g. to persist changes to a merged spec without the entries
+    /// of the other collection. Everything `own` (the spec before it was layered) has is kept, as
+    /// is everything that is not in `lower`, or differs from it.
+    pub fn without_underlay(&self, own: &VivaEnvSpec, lower: &VivaEnvSpec) -> VivaEnvSpec {
+        let mut spec = self.clone();
+        spec.channels
+            .retain(|channel| own.channels.contains(channel) || !lower.channels.contains(channel));
+        spec.pkg_specs
+            .retain(|pkg_spec| own.pkg_specs.contains(pkg_spec) || !lower.pkg_specs.contains(pkg_spec));
+        spec.pip_specs
+            .retain(|pip_spec| own.pip_specs.contains(pip_spec) || !lower.pip_specs.contains(pip_spec));
+        spec.env_vars
+            .retain(|key, value| own.env_vars.contains_key(key) || lower.env_vars.get(key) != Some(value));
+        spec.channel_views.retain(|channel, view| {
+            own.channel_views.contains_key(channel) || lower.channel_views.get(channel) != Some(view)
+        });
+        spec.provenance
+            .channels
+            .retain(|channel, _| spec.channels.contains(channel));
+        spec.provenance
+            .pkg_specs
+            .retain(|pkg_spec, _| spec.pkg_specs.contains(pkg_spec));
+        if own.base_prefix.is_none() && spec.base_prefix == lower.base_prefix {
+            spec.base_prefix = None;
+        }
+        if own.max_size.is_none() && spec.max_size == lower.max_size {
+            spec.max_size = None;
+        }
+        if own.platform.is_none() && spec.platform == lower.platform {
+            spec.platform = None;
+        }
+        spec
+    }
+
+    /// Read an environment spec file, either in viva's own format, or a conda `environment.yml`
+    /// file (detected by its `dependencies` key).
+    pub async fn read_env_spec(spec_file: &PathBuf) -> Result<VivaEnvSpec> {
+        let raw = read_raw_spec(spec_file).await?;
+        let is_conda_env = raw
+            .as_object()
+            .map(|o| o.contains_key("dependencies"))
+            .unwrap_or(false);
+        if !is_conda_env {
+            return read_model_spec(spec_file).await;
+        }
+
+        let conda_env: CondaEnvironmentFile = serde_json::from_value(raw).with_context(|| {
+            format!("Invalid conda environment file: {}", spec_file.display())
+        })?;
+        let mut env_spec = VivaEnvSpec::from_conda_env(&conda_env);
+        env_spec.record_provenance(&format!("conda environment file: {}", spec_file.display()));
+        Ok(env_spec)
     }
+
+    /// Create a spec from the contents of a conda `environment.yml` file.
+    ///
+    /// Conda's `name=version[=build]` pins are translated into match specs, pip requirements become
+    /// pip specs.
+    pub fn from_conda_env(conda_env: &CondaEnvironmentFile) -> VivaEnvSpec {
+        let mut env_spec = VivaEnvSpec::new();
+        env_spec.channels = conda_env
+            .channels
+            .iter()
+            // 'nodefaults' only tells conda to not add its default channels
+            .filter(|channel| channel.as_str() != "nodefaults")
+            .cloned()
+            .collect();
+        for dependency in &conda_env.dependencies {
+            match dependency {
+                CondaDependency::Spec(spec) => env_spec.pkg_specs.push(conda_dep_to_match_spec(spec)),
+                CondaDependency::Pip { pip } => env_spec.pip_specs.extend(pip.iter().cloned()),
+            }
+        }
+        env_spec
+    }
+
+    /// Check that all channels and package specs of this spec can be parsed.
+    pub fn validate(&self) -> Result<()> {
+        let channel_config = ChannelConfig::default();
+        for channel in &self.channels {
+            Channel::from_str(channel, &channel_config)
+                .with_context(|| format!("Invalid channel: {}", channel))?;
+        }
+        for pkg_spec in &self.pkg_specs {
+            MatchSpec::from_str(pkg_spec)
+                .with_context(|| format!("Invalid package spec: {}", pkg_spec))?;
+        }
+        for name in self.env_vars.keys() {
+            validate_env_var_name(name)?;
+        }
+        self.get_max_size_bytes()?;
+        self.get_platform()?;
+        Ok(())
+    }
+
+    /// The name of the platform to solve for (the current one, if none is set).
+    pub fn get_platform_name(&self) -> String {
+        match &self.platform {
+            Some(platform) => platform.clone(),
+            None => Platform::current().to_string(),
+        }
+    }
+
+    /// The platform to solve for (the current one, if none is set).
+    pub fn get_platform(&self) -> Result<Platform> {
+        match &self.platform {
+            Some(platform) => Platform::from_str(platform)
+                .map_err(|e| anyhow!("Invalid platform '{}': {}", platform, e)),
+            None => Ok(Platform::current()),
+        }
+    }
+
+    /// The size budget of this environment, in bytes.
+    pub fn get_max_size_bytes(&self) -> Result<Option<u64>> {
+        match &self.max_size {
+            Some(max_size) => Ok(Some(parse_size(max_size)?)),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Environments for other platforms can be created, but their executables usually don't work.
+fn warn_if_other_platform(env_id: &str, platform: Platform) {
+    if platform != Platform::current() {
+        warn!(
+            "Environment '{}' is for platform '{}', its executables might not run on this machine.",
+            env_id, platform
+        );
+    }
+}
+
+/// Check that an environment variable name is a valid shell identifier, since names end up in
+/// shell code (`env activate`) and Dockerfiles (`ENV`).
+pub fn validate_env_var_name(name: &str) -> Result<()> {
+    let mut chars = name.chars();
+    let valid = matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+    match valid {
+        true => Ok(()),
+        false => bail!(
+            "Invalid environment variable name '{}' (only letters, digits and '_' are allowed, and it must not start with a digit)",
+            name
+        ),
+    }
+}
+
+fn deserialize_env_vars<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, String>, D::Error> {
+    let env_vars = BTreeMap::<String, String>::deserialize(deserializer)?;
+    for name in env_vars.keys() {
+        validate_env_var_name(name).map_err(serde::de::Error::custom)?;
+    }
+    Ok(env_vars)
+}
+
+/// The 64 bit FNV-1a hash of the provided data, as 16 hex characters.
+pub(crate) fn fnv1a_hash(data: &[u8]) -> String {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in data {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    format!("{:016x}", hash)
+}
+
+/// The package name of a package spec (e.g. `python` for `python>=3.10`).
+pub(crate) fn pkg_spec_name(pkg_spec: &str) -> &str {
+    let pkg_spec = pkg_spec.trim();
+    let end = pkg_spec
+        .find(|c: char| c.is_whitespace() || "=<>!~[".contains(c))
+        .unwrap_or(pkg_spec.len());
+    &pkg_spec[..end]
 }
 
 impl VivaEnv {
@@ -164,6 +907,65 @@ impl VivaEnv {
         &self.env_path
     }
 
+    /// A key for caching the prefix of this environment (e.g. in CI), that changes whenever the
+    /// content of the spec, the locked packages (if there is a lockfile for the current spec), or
+    /// the platform changes.
+    ///
+    /// The key is `viva-<platform>-<spec hash>[-<lockfile hash>]`. Syncing writes a lockfile if
+    /// the collection supports them, so the key is only the same before and after the first sync if
+    /// the lockfile is committed (CI caches should be restored and saved with the key computed before
+    /// syncing).
+    pub async fn cache_key(&self) -> String {
+        let mut key = format!("viva-{}-{}", self.spec.get_platform_name(), self.spec.content_hash());
+        let lockfile = match &self.lockfile_path {
+            Some(lockfile_path) => EnvLockfile::read(lockfile_path).await.unwrap_or_else(|e| {
+                warn!("Not using lockfile for cache key: {:?}", e);
+                None
+            }),
+            None => None,
+        };
+        if let Some(packages_hash) = lockfile.and_then(|lockfile| lockfile.packages_hash(&self.spec)) {
+            key.push('-');
+            key.push_str(&packages_hash);
+        }
+        key
+    }
+
+    /// Move the prefix of this environment to a new location.
+    ///
+    /// Returns the number of files that needed to be rewritten because they referenced the old location.
+    pub(crate) async fn relocate(&mut self, new_env_path: &PathBuf) -> Result<usize> {
+        let rewritten = crate::relocate::relocate_prefix(&self.env_path, new_env_path).await?;
+        conda_envs::unregister_prefix(&self.config, &self.env_path).await;
+        self.env_path = new_env_path.clone();
+        self.actual_spec_path = new_env_path.join(ENV_SPEC_FILENAME);
+        conda_envs::register_prefix(&self.config, &self.env_path).await;
+        Ok(rewritten)
+    }
+
+    /// Restore the prefix of this environment from a snapshot in the provided snapshots directory.
+    ///
+    /// Returns the name of the restored snapshot.
+    pub(crate) async fn restore_snapshot(
+        &mut self,
+        snapshots_dir: &Path,
+        name: Option<&str>,
+    ) -> Result<String> {
+        let name = crate::snapshot::restore_snapshot(&self.env_path, snapshots_dir, name).await?;
+        self.reload_actual().await?;
+        Ok(name)
+    }
+
+    /// Re-read the state of the prefix (it might have been synced by another process since).
+    pub(crate) async fn reload_actual(&mut self) -> Result<()> {
+        self.actual = match self.actual_spec_path.exists() {
+            true => read_model_spec(&self.actual_spec_path).await?,
+            false => VivaEnvSpec::new(),
+        };
+        self.check_and_update_sync_status();
+        Ok(())
+    }
+
     pub fn create(
         id: String,
         collection_id: String,
@@ -181,6 +983,8 @@ impl VivaEnv {
             actual: actual,
             actual_spec_path: actual_spec_path,
             sync_status: sync_status,
+            config: Arc::new(VivaConfig::default()),
+            lockfile_path: None,
         }
     }
 
@@ -240,69 +1044,622 @@ impl VivaEnv {
     ///
     /// # Arguments
     ///
-    /// * `update_spec_file` - whether to update the spec file for the environment (if there is one)
+    /// * `check_strategy` - whether to sync only if the spec is not satisfied (`Auto`), to always
+    ///   solve the spec again (`Force`), or to not touch the environment at all (`Skip`, which fails
+    ///   if the environment does not exist)
     ///
     /// # Returns
     ///
     /// Returns false if the environment didn't need to be synced, true if it did, and an error if there was a problem.
-    pub async fn sync(&mut self) -> Result<bool> {
+    pub async fn sync(&mut self, check_strategy: EnvCheckStrategy) -> Result<bool> {
+        match check_strategy {
+            EnvCheckStrategy::Skip => {
+                if !self.env_path.join("conda-meta").is_dir() {
+                    bail!(
+                        "Environment '{}' does not exist (and the check strategy is 'skip'): {}",
+                        &self.id,
+                        self.env_path.display()
+                    );
+                }
+                return Ok(false);
+            }
+            EnvCheckStrategy::Force => {
+                debug!("Forcing sync of environment: {:?}", &self.id);
+            }
+            EnvCheckStrategy::Auto => {
+                if self.sync_status == EnvSyncStatus::Unknown {
+                    debug!("Calculating sync status for environment: {:?}", &self.id);
+                    self.check_and_update_sync_status();
+                }
+
+                if self.sync_status == EnvSyncStatus::Synced {
+                    debug!(
+                        "Environment does not need to be updated, status is synced: {:?}",
+                        &self
+                    );
+                    return Ok(false);
+                }
+            }
+        }
+
+        debug!("Updating environment: {:?}", &self);
+
+        let use_lockfile = check_strategy != EnvCheckStrategy::Force;
+        let (solved, lockfile, _) = self.solve_for_sync(use_lockfile).await?;
+        let resolved_packages = solved.required_packages.clone();
+        warn_if_other_platform(&self.id, solved.platform);
+        confirm_download(&self.id, &solved.estimate(), &self.config)?;
+        let _journal = journal::begin(JournalOperation::LinkPrefix {
+            env_id: self.id.clone(),
+            prefix: self.env_path.clone(),
+        })?;
+        let create_result = apply(&self.env_path, &self.spec, solved, &self.config)
+            .await
+            .with_context(|| format!("Failed to create environment: {:?}", &self));
+
+        debug!("Environment created: {:?}", &create_result);
+        match create_result {
+            Ok(_) => {
+                // TODO: delete created env if this fails?
+                self.install_pip_specs(false).await?;
+                self.record_synced().await?;
+                self.record_revision("sync", &resolved_packages).await;
+                self.record_locked(lockfile, resolved_packages).await;
+                for executable in self.find_protected_executables().await {
+                    warn!(
+                        "Environment '{}' shadows the protected system executable: {}",
+                        &self.id, executable
+                    );
+                }
+                crate::cache::prune_cache_opportunistically(&self.config).await;
+                Ok(true)
+            }
+            Err(e) => {
+                debug!("Failed to create environment: {:?}", &e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Solve the spec of this environment the way a sync does: if the spec didn't change since the
+    /// last solve, the packages of the lockfile are used (so exactly the same packages are
+    /// installed again), unless `use_lockfile` is false. Returns the solution, the lockfile, and
+    /// whether the lockfile was used.
+    async fn solve_for_sync(&self, use_lockfile: bool) -> Result<(SolvedEnvironment, Option<EnvLockfile>, bool)> {
+        let lockfile = match &self.lockfile_path {
+            Some(lockfile_path) => EnvLockfile::read(lockfile_path).await?,
+            None => None,
+        };
+        let locked_packages = lockfile
+            .as_ref()
+            .filter(|_| use_lockfile)
+            .and_then(|lockfile| lockfile.get_packages(&self.spec))
+            .cloned();
+
+        match locked_packages {
+            Some(packages) => {
+                debug!("Installing environment '{}' from lockfile", &self.id);
+                let solved = from_locked_packages(&self.env_path, &self.spec, packages, &self.config).await?;
+                Ok((solved, lockfile, true))
+            }
+            None => {
+                let cache_action = CacheAction::CacheOrFetch;
+                let solved = solve(&self.env_path, &self.spec, cache_action, true, &self.config)
+                    .await
+                    .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+                Ok((solved, lockfile, false))
+            }
+        }
+    }
+
+    /// What a sync of this environment would change in its prefix, without changing anything
+    /// (the solve fetches repodata if necessary, packages are not downloaded).
+    ///
+    /// Returns `None` if the environment is synced already.
+    pub async fn plan_sync(&mut self) -> Result<Option<SyncPlan>> {
         if self.sync_status == EnvSyncStatus::Unknown {
-            debug!("Calculating sync status for environment: {:?}", &self.id);
             self.check_and_update_sync_status();
         }
-
         if self.sync_status == EnvSyncStatus::Synced {
-            debug!(
-                "Environment does not need to be updated, status is synced: {:?}",
-                &self
+            return Ok(None);
+        }
+
+        let (solved, _, from_lockfile) = self.solve_for_sync(true).await?;
+        Ok(Some(SyncPlan {
+            env_id: self.id.clone(),
+            env_path: self.env_path.clone(),
+            platform: solved.platform.to_string(),
+            from_lockfile,
+            operations: solved.plan_operations()?,
+            pip_specs: self.spec.pip_specs.clone(),
+            estimate: solved.estimate(),
+        }))
+    }
+
+    /// The python of the environment, to run pip with.
+    fn pip_python(&self) -> Result<PathBuf> {
+        #[cfg(windows)]
+        let python = self.env_path.join("python.exe");
+        #[cfg(unix)]
+        let python = self.get_bin_path().join("python");
+        if !python.exists() {
+            bail!(
+                "Can't install pip specs, environment '{}' does not contain python: {}",
+                &self.id,
+                python.display()
             );
-            return Ok(false);
         }
+        Ok(python)
+    }
+
+    /// Install the pip specs of the environment, using the python of the environment.
+    async fn install_pip_specs(&self, upgrade: bool) -> Result<()> {
+        if self.spec.pip_specs.is_empty() {
+            return Ok(());
+        }
+        let mut args = vec![];
+        if upgrade {
+            args.push(String::from("--upgrade"));
+        }
+        args.extend(self.spec.pip_specs.iter().cloned());
+        self.pip_install(&args).await
+    }
 
-        debug!("Updating environment: {:?}", &self);
+    /// Install exactly the provided (pinned) pip packages, without resolving their dependencies
+    /// again (they are part of the list).
+    async fn install_pip_packages(&self, packages: &[String]) -> Result<()> {
+        let mut args = vec![String::from("--no-deps")];
+        args.extend(packages.iter().cloned());
+        self.pip_install(&args).await
+    }
+
+    /// The pip packages that are installed in the environment (see [`history::parse_pip_freeze`]),
+    /// empty if the environment has no pip specs.
+    async fn pip_freeze(&self) -> Result<Vec<String>> {
+        if self.spec.pip_specs.is_empty() {
+            return Ok(vec![]);
+        }
+        let output = Command::new(self.pip_python()?)
+            .args(["-m", "pip", "freeze", "--disable-pip-version-check"])
+            .env("CONDA_PREFIX", &self.env_path)
+            .output()
+            .await
+            .with_context(|| format!("Failed to run pip in environment: {}", &self.id))?;
+        if !output.status.success() {
+            bail!(
+                "'pip freeze' failed in environment '{}': {}",
+                &self.id,
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+        }
+        Ok(history::parse_pip_freeze(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Run `pip install` with the provided arguments, using the python of the environment.
+    async fn pip_install(&self, args: &[String]) -> Result<()> {
+        let mut command = Command::new(self.pip_python()?);
+        command.args(["-m", "pip", "install", "--disable-pip-version-check"]);
+        if self.config.offline {
+            let wheels_dir = pip_wheels_dir()?;
+            if !wheels_dir.is_dir() {
+                bail!(
+                    "Can't install pip specs of environment '{}' in offline mode, no local packages in: {}",
+                    &self.id,
+                    wheels_dir.display()
+                );
+            }
+            command.arg("--no-index").arg("--find-links").arg(wheels_dir);
+        }
+        command.args(args);
+        command.env("CONDA_PREFIX", &self.env_path);
+        check_cancelled()?;
+        // progress output, which must not end up in the output of commands (e.g. 'env activate')
+        command.stdout(std::process::Stdio::from(std::io::stderr()));
+
+        ProgressEvent::new("pip", "started").emit();
+        let status = command
+            .status()
+            .await
+            .with_context(|| format!("Failed to run pip in environment: {}", &self.id))?;
+        if !status.success() {
+            ProgressEvent::new("pip", "failed").emit();
+            bail!(
+                "Failed to install pip specs in environment '{}': {}",
+                &self.id,
+                args.join(", ")
+            );
+        }
+        ProgressEvent::new("pip", "finished").emit();
+        Ok(())
+    }
+
+    /// Record the resolved packages for the current spec in the lockfile of the environment (if it
+    /// has one), failing to do so only results in a warning.
+    async fn record_locked(&self, lockfile: Option<EnvLockfile>, packages: Vec<RepoDataRecord>) {
+        let lockfile_path = match &self.lockfile_path {
+            Some(lockfile_path) => lockfile_path,
+            None => return,
+        };
+        let mut lockfile = lockfile.unwrap_or_else(|| EnvLockfile::new(&self.spec));
+        let platform = self.spec.get_platform_name();
+        lockfile.set_packages(&self.spec, &platform, packages);
+        match find_installed_packages(&self.env_path, 100).await {
+            Ok(installed) => lockfile.set_served_by(&platform, &installed),
+            Err(e) => debug!("Could not read installed packages of '{}': {}", &self.id, e),
+        }
+        if let Err(e) = lockfile.write(lockfile_path).await {
+            warn!("Could not write lockfile '{}': {}", lockfile_path.display(), e);
+        }
+    }
+
+    /// Record the current spec and the installed packages as a new revision of the environment
+    /// (see [`crate::history`]), failing to do so only results in a warning.
+    async fn record_revision(&self, operation: &str, packages: &[RepoDataRecord]) {
+        let pip_packages = match self.pip_freeze().await {
+            Ok(pip_packages) => pip_packages,
+            Err(e) => {
+                warn!("Could not record pip packages of environment '{}': {}", &self.id, e);
+                vec![]
+            }
+        };
+        if let Err(e) =
+            history::record_revision(&self.env_path, operation, &self.spec, packages.to_vec(), pip_packages).await
+        {
+            warn!("Could not record revision of environment '{}': {}", &self.id, e);
+        }
+    }
+
+    /// Restore the state of a previous revision of this environment: its spec, and exactly the
+    /// packages that were installed (see [`crate::history`]). Pip packages are re-installed from the
+    /// pip specs for revisions that were recorded without them.
+    ///
+    /// The spec is only changed for this instance, persisting it is up to the caller.
+    pub async fn rollback(&mut self, revision: u32) -> Result<()> {
+        let revision = history::read_revision(&self.env_path, revision).await?;
+        self.spec = revision.spec.clone();
+        let solved = from_locked_packages(&self.env_path, &self.spec, revision.packages.clone(), &self.config).await?;
+        confirm_download(&self.id, &solved.estimate(), &self.config)?;
+        let _journal = journal::begin(JournalOperation::LinkPrefix {
+            env_id: self.id.clone(),
+            prefix: self.env_path.clone(),
+        })?;
+        apply(&self.env_path, &self.spec, solved, &self.config)
+            .await
+            .with_context(|| format!("Failed to roll back environment: {}", &self.id))?;
+        match revision.pip_packages.is_empty() {
+            true => self.install_pip_specs(false).await?,
+            false => self.install_pip_packages(&revision.pip_packages).await?,
+        }
+        self.record_synced().await?;
+        self.record_revision(&format!("rollback to {}", revision.revision), &revision.packages)
+            .await;
+        Ok(())
+    }
+
+    /// Install exactly the packages that are installed in another environment into the prefix of
+    /// this one, linked from the package cache (hard-linked where possible, so they don't take up
+    /// additional space), and only downloaded again if they are not in the package cache anymore.
+    ///
+    /// The spec of this environment is expected to be the one of the other environment, which
+    /// must be synced.
+    pub async fn link_packages_from(&mut self, source: &VivaEnv) -> Result<()> {
+        let packages = find_installed_packages(&source.env_path, 100)
+            .await
+            .with_context(|| format!("Failed to read installed packages of environment: {}", &source.id))?
+            .into_iter()
+            .map(|record| record.repodata_record)
+            .collect::<Vec<RepoDataRecord>>();
+        if packages.is_empty() {
+            bail!("Environment '{}' has no installed packages to link", &source.id);
+        }
+
+        let solved = from_locked_packages(&self.env_path, &self.spec, packages.clone(), &self.config).await?;
+        confirm_download(&self.id, &solved.estimate(), &self.config)?;
+        let _journal = journal::begin(JournalOperation::LinkPrefix {
+            env_id: self.id.clone(),
+            prefix: self.env_path.clone(),
+        })?;
+        apply(&self.env_path, &self.spec, solved, &self.config)
+            .await
+            .with_context(|| format!("Failed to link packages of '{}' into environment: {}", &source.id, &self.id))?;
+        self.install_pip_specs(false).await?;
+        self.record_synced().await?;
+        self.record_revision(&format!("linked from {}", &source.id), &packages).await;
+        self.record_locked(None, packages).await;
+        Ok(())
+    }
+
+    /// Write the current spec as the actual spec of the environment, and mark it as synced.
+    async fn record_synced(&mut self) -> Result<()> {
+        let env_spec_file = &self.actual_spec_path;
+
+        let env_spec_json = serde_json::to_string(&self.spec.normalized()).expect(&format!(
+            "Cannot serialize environment spec to JSON: {}",
+            &env_spec_file.to_string_lossy()
+        ));
+
+        if let Some(parent_dir) = env_spec_file.parent() {
+            tokio::fs::create_dir_all(parent_dir).await?;
+        }
+
+        tokio::fs::write(&env_spec_file, env_spec_json)
+            .await
+            .expect(&format!(
+                "Cannot write environment spec to file: {}",
+                &env_spec_file.to_string_lossy()
+            ));
+
+        conda_envs::register_prefix(&self.config, &self.env_path).await;
+
+        self.actual = self.spec.clone();
+        self.sync_status = EnvSyncStatus::Synced;
+        Ok(())
+    }
+
+    /// Solve the environment spec (without changing anything) and estimate how many packages
+    /// need to be downloaded and installed to sync the environment.
+    ///
+    /// This is intended for GUI wrappers, to show accurate progress information before a (first)
+    /// sync, for example "Installing… 340 MB".
+    pub async fn estimate_sync(&self) -> Result<SyncEstimate> {
+        let solved = solve(
+            &self.env_path,
+            &self.spec,
+            CacheAction::CacheOrFetch,
+            true,
+            &self.config,
+        )
+        .await
+        .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+        Ok(solved.estimate())
+    }
+
+    /// Re-solve the environment spec against fresh repodata, ignoring the currently installed
+    /// versions, and report all packages for which a newer version would be installed.
+    ///
+    /// Nothing in the environment is changed.
+    pub async fn find_outdated_packages(&self) -> Result<Vec<OutdatedPackage>> {
+        let solved = solve(
+            &self.env_path,
+            &self.spec,
+            CacheAction::NoCache,
+            false,
+            &self.config,
+        )
+        .await
+        .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+
+        let mut outdated: Vec<OutdatedPackage> = vec![];
+        for installed in &solved.installed_packages {
+            let installed = &installed.repodata_record.package_record;
+            let available = solved
+                .required_packages
+                .iter()
+                .map(|record| &record.package_record)
+                .find(|record| record.name == installed.name);
+            if let Some(available) = available {
+                if available.version > installed.version {
+                    outdated.push(OutdatedPackage {
+                        name: installed.name.clone(),
+                        installed_version: installed.version.to_string(),
+                        available_version: available.version.to_string(),
+                    });
+                } else if available.version == installed.version
+                    && available.build_number > installed.build_number
+                {
+                    // a newer build of the same version
+                    outdated.push(OutdatedPackage {
+                        name: installed.name.clone(),
+                        installed_version: format!("{} ({})", installed.version, installed.build),
+                        available_version: format!("{} ({})", available.version, available.build),
+                    });
+                }
+            }
+        }
+        outdated.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(outdated)
+    }
+
+    /// Update the environment to the newest package versions that satisfy its spec, using fresh repodata.
+    ///
+    /// Only packages whose version or build changed are re-installed.
+    pub async fn update(&mut self) -> Result<()> {
+        // read before anything changes, an invalid lockfile should not leave an updated prefix behind
+        let lockfile = match &self.lockfile_path {
+            Some(lockfile_path) => EnvLockfile::read(lockfile_path).await?,
+            None => None,
+        };
+        let solved = solve(
+            &self.env_path,
+            &self.spec,
+            CacheAction::NoCache,
+            false,
+            &self.config,
+        )
+        .await
+        .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+        let resolved_packages = solved.required_packages.clone();
+        warn_if_other_platform(&self.id, solved.platform);
+        confirm_download(&self.id, &solved.estimate(), &self.config)?;
+        let _journal = journal::begin(JournalOperation::LinkPrefix {
+            env_id: self.id.clone(),
+            prefix: self.env_path.clone(),
+        })?;
+        apply(&self.env_path, &self.spec, solved, &self.config)
+            .await
+            .with_context(|| format!("Failed to update environment: {}", &self.id))?;
+        self.install_pip_specs(true).await?;
+        self.record_synced().await?;
+        self.record_revision("update", &resolved_packages).await;
+        self.record_locked(lockfile, resolved_packages).await;
+        crate::cache::prune_cache_opportunistically(&self.config).await;
+        Ok(())
+    }
 
-        let cache_action = CacheAction::CacheOrFetch;
-        let create_result =
-            crate::rattler::commands::create::create(&self.env_path, &self.spec, cache_action)
-                .await
-                .with_context(|| format!("Failed to create environment: {:?}", &self));
+    /// Start syncing the environment, returns a handle to follow its progress, and to cancel it
+    /// (e.g. from the UI of an application that embeds viva).
+    ///
+    /// Awaiting the handle results in the same as [`VivaEnv::sync`]. If the sync is cancelled, the
+    /// prefix might only be partially updated, so the environment is marked as not synced (also
+    /// on disk), and synced again the next time it is used.
+    pub fn sync_with_handle(&mut self, check_strategy: EnvCheckStrategy) -> SyncHandle<'_> {
+        SyncHandle::new(move |cancelled| async move {
+            match self.sync(check_strategy).await {
+                Err(_) if *cancelled.borrow() => {
+                    if self.actual_spec_path.exists() {
+                        fs::remove_file(&self.actual_spec_path).await?;
+                    }
+                    self.sync_status = EnvSyncStatus::NotSynced;
+                    bail!("Sync of environment '{}' was cancelled", &self.id)
+                }
+                result => result,
+            }
+        })
+    }
 
-        debug!("Environment created: {:?}", &create_result);
-        match create_result {
-            Ok(_) => {
-                // TODO: delete created env if this fails?
-                let env_spec_file = &self.actual_spec_path;
+    /// Solve the environment (with fresh repodata, ignoring installed packages), and explain why
+    /// the chosen version of a package was picked.
+    pub async fn explain_package(&self, package_name: &str, platform: Option<&str>) -> Result<PackageExplanation> {
+        let (target_prefix, spec) = self.solve_target(platform);
+        let solved = solve(
+            &target_prefix,
+            &spec,
+            CacheAction::CacheOrFetch,
+            false,
+            &self.config,
+        )
+        .await
+        .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+        solved.explain_package(package_name)
+    }
 
-                let env_spec_json = serde_json::to_string(&self.spec).expect(&format!(
-                    "Cannot serialize environment spec to JSON: {}",
-                    &env_spec_file.to_string_lossy()
-                ));
+    /// The prefix and spec to solve with, if a platform other than the one of the spec is requested
+    /// the installed packages of the prefix are not taken into account.
+    fn solve_target(&self, platform: Option<&str>) -> (PathBuf, VivaEnvSpec) {
+        match platform {
+            Some(platform) if platform != self.spec.get_platform_name() => {
+                let mut spec = self.spec.clone();
+                spec.platform = Some(String::from(platform));
+                // a prefix that doesn't exist, so no installed packages are taken into account
+                let target_prefix = std::env::temp_dir().join(format!("viva-solve-{}", std::process::id()));
+                (target_prefix, spec)
+            }
+            _ => (self.env_path.clone(), self.spec.clone()),
+        }
+    }
 
-                if let Some(parent_dir) = env_spec_file.parent() {
-                    tokio::fs::create_dir_all(parent_dir).await?;
+    /// List the packages a solve of the environment (as it would be synced) results in, optionally
+    /// for another platform.
+    pub async fn solve_packages(&self, platform: Option<&str>) -> Result<Vec<SolvedPackage>> {
+        let (target_prefix, spec) = self.solve_target(platform);
+        let solved = solve(
+            &target_prefix,
+            &spec,
+            CacheAction::CacheOrFetch,
+            true,
+            &self.config,
+        )
+        .await
+        .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+        let mut packages = solved
+            .required_packages
+            .iter()
+            .map(|record| {
+                let package = &record.package_record;
+                SolvedPackage {
+                    name: package.name.clone(),
+                    version: package.version.to_string(),
+                    build: package.build.clone(),
+                    channel: record.channel.clone(),
                 }
+            })
+            .collect::<Vec<_>>();
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(packages)
+    }
 
-                tokio::fs::write(&env_spec_file, env_spec_json)
-                    .await
-                    .expect(&format!(
-                        "Cannot write environment spec to file: {}",
-                        &env_spec_file.to_string_lossy()
-                    ));
+    /// Solve the environment (optionally for another platform), and find the protected system
+    /// executables (see [`VivaConfig::protected_executables`]) that packages of the solution would
+    /// install, using the file lists of the packages in the package cache.
+    ///
+    /// Returns pairs of executable and package name.
+    pub async fn find_protected_executables_in_solution(
+        &self,
+        platform: Option<&str>,
+    ) -> Result<Vec<(String, String)>> {
+        let (target_prefix, spec) = self.solve_target(platform);
+        let solved = solve(
+            &target_prefix,
+            &spec,
+            CacheAction::CacheOrFetch,
+            true,
+            &self.config,
+        )
+        .await
+        .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+        let mut protected = vec![];
+        for executable in &self.config.protected_executables {
+            if let ExecutableSource::Package { name } =
+                solved.find_executable(&executable_paths_in_prefix(executable))
+            {
+                protected.push((executable.clone(), name));
+            }
+        }
+        Ok(protected)
+    }
 
-                self.actual = self.spec.clone();
-                self.sync_status = EnvSyncStatus::Synced;
+    /// Solve the environment (without installing anything), and determine which package would
+    /// install the executable, using the file lists of the packages in the package cache.
+    pub async fn find_executable_in_solution(&self, executable: &str) -> Result<ExecutableSource> {
+        let solved = solve(
+            &self.env_path,
+            &self.spec,
+            CacheAction::CacheOrFetch,
+            true,
+            &self.config,
+        )
+        .await
+        .with_context(|| format!("Failed to solve environment: {}", &self.id))?;
+        Ok(solved.find_executable(&executable_paths_in_prefix(executable)))
+    }
 
-                Ok(true)
-            }
-            Err(e) => {
-                debug!("Failed to create environment: {:?}", &e);
-                Err(e)
-            }
+    /// Explain why the environment is (or is not) considered synced.
+    pub fn explain_sync(&self) -> SyncExplanation {
+        let prefix_missing = !self.env_path.exists();
+        let actual_spec_missing = !self.actual_spec_path.exists();
+
+        // the 'conda-meta' folder changes whenever packages are installed or removed, if that
+        // happened after the actual spec was written, the prefix was modified by another tool
+        let prefix_modified = match (
+            std::fs::metadata(self.env_path.join("conda-meta")).and_then(|m| m.modified()),
+            std::fs::metadata(&self.actual_spec_path).and_then(|m| m.modified()),
+        ) {
+            (Ok(meta_modified), Ok(spec_modified)) => meta_modified > spec_modified,
+            _ => false,
+        };
+
+        SyncExplanation {
+            env_id: self.id.clone(),
+            sync_status: match self.spec.is_equivalent_to(&self.actual) {
+                true => EnvSyncStatus::Synced,
+                false => EnvSyncStatus::NotSynced,
+            },
+            prefix_missing,
+            actual_spec_missing,
+            new_pkg_specs: check_for_new_pkg_specs(&self.actual.pkg_specs, &self.spec.pkg_specs),
+            new_pip_specs: check_for_new_pkg_specs(&self.actual.pip_specs, &self.spec.pip_specs),
+            removed_pkg_specs: check_for_new_pkg_specs(&self.spec.pkg_specs, &self.actual.pkg_specs),
+            new_channels: check_for_new_channels(&self.actual.channels, &self.spec.channels),
+            base_prefix_changed: self.spec.base_prefix.is_some()
+                && self.spec.base_prefix != self.actual.base_prefix,
+            channel_views_changed: self.spec.channel_views != self.actual.channel_views,
+            prefix_modified,
         }
     }
 
     pub fn check_and_update_sync_status(&mut self) {
-        let sync_status = match self.spec.is_satisfied_by(&self.actual) {
+        // a spec with removed package specs needs a sync as well, to uninstall their packages
+        let sync_status = match self.spec.is_equivalent_to(&self.actual) {
             true => EnvSyncStatus::Synced,
             false => EnvSyncStatus::NotSynced,
         };
@@ -314,6 +1671,50 @@ impl VivaEnv {
             .expect("Failed to merge channels");
         self.add_pkg_specs(&spec.pkg_specs)
             .expect("Failed to merge package specs");
+        for pip_spec in &spec.pip_specs {
+            if !self.spec.pip_specs.contains(pip_spec) {
+                self.spec.pip_specs.push(pip_spec.clone());
+            }
+        }
+        self.spec
+            .env_vars
+            .extend(spec.env_vars.iter().map(|(key, value)| (key.clone(), value.clone())));
+        self.check_and_update_sync_status();
+        for (channel, source) in &spec.provenance.channels {
+            if self.spec.channels.contains(channel) {
+                self.spec
+                    .provenance
+                    .channels
+                    .entry(channel.clone())
+                    .or_insert_with(|| source.clone());
+            }
+        }
+        for (pkg_spec, source) in &spec.provenance.pkg_specs {
+            if self.spec.pkg_specs.contains(pkg_spec) {
+                self.spec
+                    .provenance
+                    .pkg_specs
+                    .entry(pkg_spec.clone())
+                    .or_insert_with(|| source.clone());
+            }
+        }
+        if spec.max_size.is_some() {
+            self.spec.max_size = spec.max_size.clone();
+        }
+        if spec.platform.is_some() && spec.platform != self.spec.platform {
+            self.spec.platform = spec.platform.clone();
+            self.check_and_update_sync_status();
+        }
+        if spec.base_prefix.is_some() && spec.base_prefix != self.spec.base_prefix {
+            self.spec.base_prefix = spec.base_prefix.clone();
+            self.check_and_update_sync_status();
+        }
+        if !spec.channel_views.is_empty() {
+            self.spec
+                .channel_views
+                .extend(spec.channel_views.iter().map(|(channel, view)| (channel.clone(), view.clone())));
+            self.check_and_update_sync_status();
+        }
         Ok(())
     }
 
@@ -330,6 +1731,7 @@ impl VivaEnv {
 
     pub fn remove_channels(&mut self, channels: Vec<String>) -> Result<&Vec<String>> {
         self.spec.channels.retain(|c| !channels.contains(c));
+        self.spec.provenance.channels.retain(|c, _| !channels.contains(c));
         Ok(&self.spec.channels)
     }
 
@@ -344,6 +1746,32 @@ impl VivaEnv {
         Ok(&self.spec.pkg_specs)
     }
 
+    /// Remove package specs from the spec of this environment.
+    ///
+    /// The environment is not in sync anymore as long as the actual spec still contains the removed
+    /// specs, the next sync re-solves it and uninstalls the packages that are not required anymore.
+    pub fn remove_pkg_specs(&mut self, pkg_specs: &Vec<String>) -> Result<&Vec<String>> {
+        self.spec.pkg_specs.retain(|s| !pkg_specs.contains(s));
+        self.spec.provenance.pkg_specs.retain(|s, _| !pkg_specs.contains(s));
+        self.check_and_update_sync_status();
+        Ok(&self.spec.pkg_specs)
+    }
+
+    /// Find the full path of an executable within the environments' bin directories (respecting
+    /// `PATHEXT` on Windows, so `.bat` and `.cmd` wrappers are found as well).
+    ///
+    /// Returns `None` if the executable does not exist (yet).
+    pub fn find_executable(&self, executable: &str) -> Option<PathBuf> {
+        if let Some(full_exe_path) = find_executable_in_prefix(&self.env_path, executable) {
+            return Some(full_exe_path);
+        }
+
+        match &self.spec.base_prefix {
+            Some(base_prefix) => find_executable_in_prefix(base_prefix, executable),
+            None => None,
+        }
+    }
+
     /// Creates a command in the environment, with the specified environment-check  & package-install strategy..
     pub async fn create_command_in_env<S: AsRef<str>, I: AsRef<[S]>>(
         &self,
@@ -360,32 +1788,18 @@ impl VivaEnv {
         } else {
             return Err(anyhow!("No command provided"));
         }
-        let mut full_exe_path = self.env_path.join(CONDA_BIN_DIRNAME).join(executable);
-
-        let final_exe_path: PathBuf = match full_exe_path.exists() {
-            true => full_exe_path,
-            false => {
-                match full_exe_path.ends_with(".exe") {
-                    true => {
-                        full_exe_path.set_extension("");
-                    }
-                    false => {
-                        full_exe_path.set_extension("exe");
-                    }
-                }
-                match full_exe_path.exists() {
-                    true => full_exe_path,
-                    false => {
-                        return Err(anyhow!(
-                            "Could not find executable (after setup env phase): {}",
-                            executable
-                        ));
-                    }
-                }
+        let final_exe_path: PathBuf = match self.find_executable(executable) {
+            Some(exe_path) => exe_path,
+            None => {
+                return Err(anyhow!(
+                    "Could not find executable (after setup env phase): {}",
+                    executable
+                ));
             }
         };
 
         let mut command = Command::new(final_exe_path);
+        self.prepare_command_env(&mut command, &[])?;
 
         if cmd_args.len() > 0 {
             command.args(cmd_args);
@@ -394,53 +1808,482 @@ impl VivaEnv {
         Ok(command)
     }
 
-    /// Runs a command in the context of the environment, using the specified environment-check & package-install strategy.
+    /// Runs a command in the context of the environment, using the specified environment-check strategy.
     ///
     /// # Arguments
     ///
     /// * `cmd` - A sequence of strings representing the command and its arguments.
-    /// * `env_check_strategy` - The strategy to use when checking for the environment.
-    /// * `pkg_install_strategy` - The strategy to use when installing packages.
+    /// * `env_check_strategy` - The strategy to use when checking for the environment (see [`VivaEnv::sync`]).
     ///
     /// # Returns
     ///
-    /// Returns `Ok(())` if the command runs successfully, or an error if there is a problem.
-    pub async fn run_command_in_env<S: AsRef<str>, I: AsRef<[S]>>(&self, cmd: I) -> Result<()> {
+    /// Returns the exit status of the command (its output is not captured), or an error if it could
+    /// not be started.
+    pub async fn run_command_in_env<S: AsRef<str>, I: AsRef<[S]>>(
+        &mut self,
+        cmd: I,
+        env_check_strategy: EnvCheckStrategy,
+    ) -> Result<ExitStatus> {
+        self.sync(env_check_strategy).await?;
+        let command = self.create_command_in_env(&cmd).await?;
+        run_command(command, cmd, &[self.env_path.as_path()]).await
+    }
+
+    /// Runs the command of an app in the context of the environment, with the runtime variables of
+    /// the app (see [`crate::models::app::VivaAppSpec::get_runtime_vars`]).
+    pub async fn run_app_command_in_env<S: AsRef<str>, I: AsRef<[S]>>(
+        &self,
+        cmd: I,
+        runtime_vars: &[RuntimeVar],
+    ) -> Result<ExitStatus> {
         let mut command = self.create_command_in_env(&cmd).await?;
+        command.envs(self.runtime_env_vars(runtime_vars));
+        run_command(command, cmd, &[self.env_path.as_path()]).await
+    }
+
+    /// The values of runtime variables for a process in this environment, variables that are set in
+    /// the spec of this environment take precedence.
+    pub fn runtime_env_vars(&self, runtime_vars: &[RuntimeVar]) -> BTreeMap<String, String> {
+        let mut env_vars = resolve_runtime_vars(runtime_vars);
+        env_vars.retain(|name, _| !self.spec.env_vars.contains_key(name));
+        env_vars
+    }
+
+    /// Creates a command that can use executables from this, and a list of additional environments.
+    ///
+    /// The executable is looked up in this environment first, then in the additional environments
+    /// (in the order they are provided). The `PATH` of the resulting command follows the same
+    /// precedence: this environments' bin directory comes first, followed by the bin directories of
+    /// the additional environments, followed by the `PATH` of the current process.
+    pub async fn create_command_in_layered_envs<S: AsRef<str>, I: AsRef<[S]>>(
+        &self,
+        also_envs: &[&VivaEnv],
+        cmd: I,
+    ) -> Result<Command> {
+        let mut iter = cmd.as_ref().iter();
+        let executable: &str = match iter.next() {
+            Some(first) => first.as_ref(),
+            None => return Err(anyhow!("No command provided")),
+        };
+        let cmd_args: Vec<&str> = iter.map(|s| s.as_ref()).collect();
+
+        let mut layers: Vec<&VivaEnv> = vec![self];
+        layers.extend(also_envs.iter().copied());
+
+        let final_exe_path = layers
+            .iter()
+            .find_map(|env| env.find_executable(executable))
+            .ok_or(anyhow!(
+                "Could not find executable in any of the environments ({}): {}",
+                layers.iter().map(|e| e.id.as_str()).collect::<Vec<&str>>().join(", "),
+                executable
+            ))?;
+
+        let mut command = Command::new(final_exe_path);
+        self.prepare_command_env(&mut command, also_envs)?;
+        if cmd_args.len() > 0 {
+            command.args(cmd_args);
+        }
+        Ok(command)
+    }
+
+    /// Runs a command using executables from this, and a list of additional environments.
+    ///
+    /// See [`VivaEnv::create_command_in_layered_envs`] for details on the lookup precedence.
+    pub async fn run_command_in_layered_envs<S: AsRef<str>, I: AsRef<[S]>>(
+        &self,
+        also_envs: &[&VivaEnv],
+        cmd: I,
+    ) -> Result<ExitStatus> {
+        let command = self.create_command_in_layered_envs(also_envs, &cmd).await?;
+        let mut prefixes = vec![self.env_path.as_path()];
+        prefixes.extend(also_envs.iter().map(|env| env.env_path.as_path()));
+        run_command(command, cmd, &prefixes).await
+    }
+
+    /// The packages installed in the prefix of this environment, sorted by name.
+    ///
+    /// Returns an empty list if the environment was not created (yet).
+    pub async fn installed_packages(&self) -> Result<Vec<InstalledPackage>> {
+        if !self.env_path.join("conda-meta").is_dir() {
+            return Ok(vec![]);
+        }
+        let records = find_installed_packages(&self.env_path, 100)
+            .await
+            .with_context(|| format!("Failed to read installed packages of environment: {}", &self.id))?;
+        let mut packages: Vec<InstalledPackage> = records
+            .into_iter()
+            .map(|record| {
+                let package = &record.repodata_record.package_record;
+                let file_sizes: Option<u64> = record
+                    .paths_data
+                    .paths
+                    .iter()
+                    .map(|entry| entry.size_in_bytes)
+                    .sum();
+                InstalledPackage {
+                    name: package.name.clone(),
+                    version: package.version.to_string(),
+                    build: package.build.clone(),
+                    channel: record.repodata_record.channel.clone(),
+                    size: file_sizes.or(package.size).unwrap_or(0),
+                    requested_spec: record.requested_spec.clone(),
+                }
+            })
+            .collect();
+        packages.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(packages)
+    }
 
-        let child = command.stdout(Stdio::piped()).spawn().expect(
-            format!(
-                "Failed to spawn subprocess: {}",
-                cmd.as_ref()
+    /// Export this environment in the format of a conda `environment.yml` file.
+    ///
+    /// If `pinned` is set, the dependencies are the exact packages installed in the environment
+    /// (`name=version=build`), which requires the environment to be synced. Otherwise, the package
+    /// specs of the environment spec are used.
+    pub async fn export_conda_env(&self, pinned: bool) -> Result<CondaEnvironmentFile> {
+        let dependencies: Vec<CondaDependency> = match pinned {
+            true => {
+                let mut installed = find_installed_packages(&self.env_path, 100)
+                    .await
+                    .with_context(|| format!("Failed to read installed packages of environment: {}", &self.id))?;
+                if installed.is_empty() {
+                    bail!(
+                        "No packages installed in environment '{}', sync it first (or export the spec only).",
+                        &self.id
+                    );
+                }
+                installed.sort_by(|a, b| {
+                    a.repodata_record
+                        .package_record
+                        .name
+                        .cmp(&b.repodata_record.package_record.name)
+                });
+                installed
                     .iter()
-                    .map(|s| s.as_ref())
-                    .collect::<Vec<&str>>()
-                    .join(" ")
-            )
-            .as_str(),
-        );
+                    .map(|record| {
+                        let package = &record.repodata_record.package_record;
+                        CondaDependency::Spec(format!(
+                            "{}={}={}",
+                            package.name, package.version, package.build
+                        ))
+                    })
+                    .collect()
+            }
+            false => self
+                .spec
+                .pkg_specs
+                .iter()
+                .map(|pkg_spec| CondaDependency::Spec(pkg_spec.clone()))
+                .collect(),
+        };
 
-        let output = child.wait_with_output().await?;
-        // unsafe { child.detach() };fffbbb
+        let mut dependencies = dependencies;
+        if !self.spec.pip_specs.is_empty() {
+            dependencies.push(CondaDependency::Pip {
+                pip: self.spec.pip_specs.clone(),
+            });
+        }
 
-        if output.status.success() {
-            let stdout = String::from_utf8_lossy(&output.stdout);
-            println!("{}", stdout);
-        } else {
-            eprintln!("{:?}", output);
+        Ok(CondaEnvironmentFile {
+            name: Some(self.id.replace('/', "-")),
+            channels: self.spec.channels.clone(),
+            dependencies,
+        })
+    }
+
+    /// The directory containing the executables of this environment.
+    pub fn get_bin_path(&self) -> PathBuf {
+        self.env_path.join(CONDA_BIN_DIRNAME)
+    }
+
+    /// Creates a command that runs a shell snippet (e.g. a pipeline) through the platform shell
+    /// (`sh -c` or `cmd /C`), with the executables of this, and a list of additional environments
+    /// on the `PATH` (in the same order as [`VivaEnv::create_command_in_layered_envs`]).
+    pub fn create_shell_command_in_layered_envs(
+        &self,
+        also_envs: &[&VivaEnv],
+        script: &str,
+    ) -> Result<Command> {
+        #[cfg(unix)]
+        let mut command = {
+            let mut command = Command::new("sh");
+            command.arg("-c").arg(script);
+            command
+        };
+        #[cfg(windows)]
+        let mut command = {
+            let mut command = Command::new("cmd");
+            command.arg("/C").arg(script);
+            command
+        };
+        self.prepare_command_env(&mut command, also_envs)?;
+        Ok(command)
+    }
+
+    /// Runs a shell snippet in the context of this, and a list of additional environments.
+    ///
+    /// See [`VivaEnv::create_shell_command_in_layered_envs`] for details.
+    pub async fn run_shell_in_layered_envs(&self, also_envs: &[&VivaEnv], script: &str) -> Result<ExitStatus> {
+        let command = self.create_shell_command_in_layered_envs(also_envs, script)?;
+        let mut prefixes = vec![self.env_path.as_path()];
+        prefixes.extend(also_envs.iter().map(|env| env.env_path.as_path()));
+        run_command(command, [script], &prefixes).await
+    }
+
+    /// The executables in the bin directory of this environment that shadow protected system
+    /// executables (see [`VivaConfig::protected_executables`]).
+    pub async fn find_protected_executables(&self) -> Vec<String> {
+        let mut protected = vec![];
+        let mut entries = match fs::read_dir(self.get_bin_path()).await {
+            Ok(entries) => entries,
+            Err(_) => return protected,
+        };
+        while let Ok(Some(entry)) = entries.next_entry().await {
+            let file_name = entry.file_name().to_string_lossy().to_string();
+            if self.config.is_protected_executable(&file_name) {
+                protected.push(file_name);
+            }
         }
+        protected.sort();
+        protected
+    }
+
+    /// What needs to change in a shell to activate this environment.
+    pub fn activation(&self) -> Activation {
+        let mut activation = Activation::create(&self.id, &self.env_path, self.spec.base_prefix.as_deref());
+        activation.env_vars.extend(
+            self.spec
+                .env_vars
+                .iter()
+                .map(|(key, value)| (key.clone(), value.clone())),
+        );
+        activation
+    }
 
+    /// Set the environment variables of a command that runs in this environment: the `PATH` (with
+    /// the executable directories of this, then of the additional environments first), the
+    /// variables conda sets on activation, and the ones of the spec of this environment.
+    fn prepare_command_env(&self, command: &mut Command, also_envs: &[&VivaEnv]) -> Result<()> {
+        let activation = self.activation();
+        let mut paths = activation.path_prepend.clone();
+        for env in also_envs {
+            paths.extend(env.activation().path_prepend);
+        }
+        if let Some(current_path) = std::env::var_os("PATH") {
+            paths.extend(std::env::split_paths(&current_path));
+        }
+        command.env("PATH", std::env::join_paths(paths)?);
+        // a 'PATH' in the spec overrides the computed one
+        command.envs(&activation.env_vars);
         Ok(())
     }
+
+    /// The environment variables (including the `PATH`) of processes that run in this (activated)
+    /// environment, in addition to the ones of the current process.
+    pub fn activated_env_vars(&self) -> Result<BTreeMap<String, OsString>> {
+        let activation = self.activation();
+        let mut env_vars = BTreeMap::new();
+        env_vars.insert(
+            String::from("PATH"),
+            activation.activated_path(std::env::var_os("PATH").as_deref())?,
+        );
+        // a 'PATH' in the spec overrides the computed one
+        env_vars.extend(
+            activation
+                .env_vars
+                .iter()
+                .map(|(key, value)| (key.clone(), OsString::from(value))),
+        );
+        Ok(env_vars)
+    }
+
+    /// Start an interactive shell with this environment activated, and wait until it exits.
+    ///
+    /// Returns the exit code of the shell.
+    pub async fn run_interactive_shell(&self, shell: ShellType) -> Result<i32> {
+        if let Ok(active_env) = std::env::var("VIVA_ENV") {
+            warn!("Already in a shell of environment '{}', starting a nested shell.", active_env);
+        }
+
+        let init_dir = std::env::temp_dir().join(format!("viva-shell-{}", std::process::id()));
+        fs::create_dir_all(&init_dir).await?;
+        let init = self.activation().render_interactive_init(shell, &self.id);
+
+        let mut command = Command::new(shell.executable());
+        match shell {
+            ShellType::Bash => {
+                let init_file = init_dir.join("bashrc");
+                fs::write(&init_file, init).await?;
+                command.arg("--rcfile").arg(&init_file).arg("-i");
+            }
+            ShellType::Zsh => {
+                fs::write(init_dir.join(".zshrc"), init).await?;
+                if let Some(zdotdir) = std::env::var_os("ZDOTDIR") {
+                    command.env("VIVA_ORIG_ZDOTDIR", zdotdir);
+                }
+                command.env("ZDOTDIR", &init_dir).arg("-i");
+            }
+            ShellType::Fish => {
+                let init_file = init_dir.join("init.fish");
+                fs::write(&init_file, init).await?;
+                command
+                    .arg("-C")
+                    .arg(format!("source '{}'", init_file.to_string_lossy().replace('\'', "\\'")));
+            }
+            ShellType::PowerShell => {
+                let init_file = init_dir.join("init.ps1");
+                fs::write(&init_file, init).await?;
+                command
+                    .arg("-NoExit")
+                    .arg("-Command")
+                    .arg(format!(". '{}'", init_file.to_string_lossy().replace('\'', "''")));
+            }
+        }
+        command.envs(self.activated_env_vars()?);
+
+        let result = async {
+            let mut child = command
+                .spawn()
+                .with_context(|| format!("Failed to start shell: {}", shell.executable()))?;
+            let _lock = child
+                .id()
+                .map(|pid| crate::locks::RunningLock::acquire(&[self.env_path.as_path()], pid));
+            Ok::<_, anyhow::Error>(child.wait().await?)
+        }
+        .await;
+        let _ = fs::remove_dir_all(&init_dir).await;
+        Ok(result?.code().unwrap_or(1))
+    }
+
+    /// The bin directories of this environment, followed by the one of its base prefix (if any).
+    pub fn get_bin_paths(&self) -> Vec<PathBuf> {
+        let mut bin_paths = vec![self.get_bin_path()];
+        if let Some(base_prefix) = &self.spec.base_prefix {
+            bin_paths.push(base_prefix.join(CONDA_BIN_DIRNAME));
+        }
+        bin_paths
+    }
+}
+
+/// Translate a conda `environment.yml` dependency into a match spec.
+///
+/// Conda uses `name=1.2` for 'any 1.2.x version' and `name=1.2.3=build` for an exact build, all
+/// other forms (e.g. `name>=1.2`, `name 1.2.*`) are match specs already.
+fn conda_dep_to_match_spec(dependency: &str) -> String {
+    let dependency = dependency.trim();
+    if dependency.contains(|c: char| "<>!~ ".contains(c)) || dependency.contains("==") {
+        return String::from(dependency);
+    }
+    let parts: Vec<&str> = dependency.splitn(3, '=').collect();
+    match parts.as_slice() {
+        [name, version] if version.ends_with('*') => format!("{} {}", name, version),
+        [name, version] => format!("{} {}.*", name, version),
+        [name, version, build] => format!("{} {} {}", name, version, build),
+        _ => String::from(dependency),
+    }
+}
+
+/// Ask the user for confirmation if a sync would download more than the configured threshold.
+///
+/// In non-interactive sessions, this fails unless `assume_yes` is set.
+fn confirm_download(env_id: &str, estimate: &SyncEstimate, config: &VivaConfig) -> Result<()> {
+    let threshold = match config.confirm_above_mb {
+        Some(threshold_mb) => threshold_mb * 1000 * 1000,
+        None => return Ok(()),
+    };
+    if estimate.download_bytes <= threshold || config.assume_yes {
+        return Ok(());
+    }
+
+    let msg = format!(
+        "Syncing environment '{}' requires downloading {} packages ({}).",
+        env_id,
+        estimate.download_count,
+        indicatif::HumanBytes(estimate.download_bytes)
+    );
+    if !console::user_attended() {
+        bail!("{} Not running interactively, use '--yes' to confirm.", msg);
+    }
+
+    let confirmed = global_multi_progress().suspend(|| -> Result<bool> {
+        let term = console::Term::stderr();
+        term.write_str(&format!("{} Continue? [y/N] ", msg))?;
+        let answer = term.read_line()?;
+        Ok(matches!(answer.trim().to_lowercase().as_str(), "y" | "yes"))
+    })?;
+    match confirmed {
+        true => Ok(()),
+        false => bail!("Sync of environment '{}' cancelled.", env_id),
+    }
+}
+
+/// Run a command with the stdio of the current process (so output is streamed, not buffered), and
+/// return its exit status.
+///
+/// The provided prefixes are marked as in use while the command runs.
+async fn run_command<S: AsRef<str>, I: AsRef<[S]>>(
+    mut command: Command,
+    cmd: I,
+    prefixes: &[&Path],
+) -> Result<ExitStatus> {
+    let mut child = command.spawn().with_context(|| {
+        format!(
+            "Failed to spawn subprocess: {}",
+            cmd.as_ref()
+                .iter()
+                .map(|s| s.as_ref())
+                .collect::<Vec<&str>>()
+                .join(" ")
+        )
+    })?;
+
+    let _lock = child
+        .id()
+        .map(|pid| crate::locks::RunningLock::acquire(prefixes, pid));
+    let status = child.wait().await?;
+    debug!("Subprocess finished: {}", status);
+    Ok(status)
 }
 
 #[async_trait]
-pub trait EnvironmentCollection: Debug {
+pub trait EnvironmentCollection: Debug + Send + Sync {
     // fn init(context: &VivaContext) -> Self;
     async fn get_env_ids(&self) -> Vec<String>;
     async fn get_env(&self, env_id: &str) -> Result<&VivaEnvSpec>;
     async fn delete_env(&mut self, env_id: &str) -> Result<()>;
     async fn set_env(&mut self, env_id: &str, env: &VivaEnvSpec) -> Result<()>;
+    /// Spec entries that could not be loaded (id or file -> error message).
+    async fn get_invalid_envs(&self) -> BTreeMap<String, String> {
+        BTreeMap::new()
+    }
+    /// Problems that didn't prevent loading (e.g. shadowed ids, or unknown fields).
+    async fn get_warnings(&self) -> Vec<String> {
+        vec![]
+    }
+    /// Re-read the collection from its source.
+    async fn reload(&mut self) -> Result<()> {
+        Ok(())
+    }
+    /// The paths to watch for changes to the collection.
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![]
+    }
+    /// The path of the lockfile of an environment, if this collection supports lockfiles.
+    fn get_lockfile_path(&self, _env_id: &str) -> Option<PathBuf> {
+        None
+    }
+    /// The prefix path of an environment, if this collection keeps prefixes in a location of its
+    /// own (instead of the default environment directory).
+    fn get_env_path(&self, _env_id: &str) -> Option<PathBuf> {
+        None
+    }
+    /// Restore the previous version of an environment spec.
+    ///
+    /// Returns the restored spec, or `None` if the environment did not exist before the last change
+    /// (in which case it is removed).
+    async fn undo_env(&mut self, env_id: &str) -> Result<Option<VivaEnvSpec>> {
+        bail!("Undo is not supported by this collection (env: {})", env_id)
+    }
 }
 
 #[derive(Debug)]
@@ -452,7 +2295,9 @@ pub struct DefaultEnvCollection {
     single_envs: Option<BTreeMap<String, VivaEnvSpec>>,
 
     collected_envs_dirty: bool,
-    single_envs_dirty: Vec<String>
+    single_envs_dirty: Vec<String>,
+    invalid_envs: BTreeMap<String, String>,
+    warnings: Vec<String>,
 }
 
 impl DefaultEnvCollection {
@@ -462,13 +2307,63 @@ impl DefaultEnvCollection {
             collected_envs: None,
             single_envs: None,
             collected_envs_dirty: false,
-            single_envs_dirty: Vec::new()
+            single_envs_dirty: Vec::new(),
+            invalid_envs: BTreeMap::new(),
+            warnings: Vec::new(),
         };
 
         env.load_registered_envs(false).await?;
         Ok(env)
     }
 
+    async fn write_single_env(&mut self, env_id: &str, env_spec: &VivaEnvSpec) -> Result<()> {
+        let spec_config_file = self
+            .base_config_path
+            .join("envs")
+            .join(format!("{}.yaml", env_id));
+        // TODO: check if already exists
+
+        let env_spec = env_spec.normalized();
+        write_model_spec(&spec_config_file, &env_spec).await?;
+        self.single_envs
+            .as_mut()
+            .unwrap()
+            .insert(env_id.to_string(), env_spec);
+
+        self.sync_config().await?;
+        Ok(())
+    }
+
+    /// The file that holds the previous versions of an environment spec.
+    fn history_file(&self, env_id: &str) -> PathBuf {
+        self.base_config_path
+            .join(ENV_HISTORY_DIRNAME)
+            .join(format!("{}.json", env_id))
+    }
+
+    async fn read_history(&self, env_id: &str) -> Result<Vec<Option<VivaEnvSpec>>> {
+        let history_file = self.history_file(env_id);
+        match history_file.exists() {
+            true => read_model_spec(&history_file).await,
+            false => Ok(vec![]),
+        }
+    }
+
+    /// Remember the current version of an environment spec (`None` if it doesn't exist yet),
+    /// before it is changed to `new_spec`.
+    async fn push_history(&self, env_id: &str, new_spec: Option<&VivaEnvSpec>) -> Result<()> {
+        let current = self.get_env(env_id).await.ok().cloned();
+        if current.as_ref() == new_spec {
+            return Ok(());
+        }
+        let mut history = self.read_history(env_id).await?;
+        history.push(current);
+        if history.len() > ENV_HISTORY_LENGTH {
+            history.drain(0..history.len() - ENV_HISTORY_LENGTH);
+        }
+        write_model_spec(&self.history_file(env_id), &history).await
+    }
+
     fn find_collected_envs_file(&self) -> PathBuf {
 
         let mut envs_file = self.base_config_path.join("envs.json");
@@ -481,12 +2376,14 @@ impl DefaultEnvCollection {
 
     fn find_single_env_file(&self, env_id: &str) -> PathBuf {
 
-        let mut env_file = self.base_config_path.join("envs").join(env_id);
-        env_file.set_extension("json");
-        if !env_file.exists() {
-            env_file.set_extension("yaml");
+        let env_base = self.base_config_path.join("envs").join(env_id);
+        for ext in SPEC_FILE_EXTENSIONS {
+            let env_file = PathBuf::from(format!("{}.{}", env_base.display(), ext));
+            if env_file.exists() {
+                return env_file;
+            }
         }
-        env_file
+        PathBuf::from(format!("{}.json", env_base.display()))
 
     }
 
@@ -494,6 +2391,8 @@ impl DefaultEnvCollection {
 
         let mut single_envs: BTreeMap<String, VivaEnvSpec> = BTreeMap::new();
         let mut collected_envs: BTreeMap<String, VivaEnvSpec>;
+        let mut invalid_envs: BTreeMap<String, String> = BTreeMap::new();
+        let mut warnings: Vec<String> = vec![];
 
         let mut collected_envs_dirty: bool = false;
 
@@ -502,37 +2401,70 @@ impl DefaultEnvCollection {
                 true => {
                     let envs_file = self.find_collected_envs_file();
 
+                    collected_envs = BTreeMap::new();
                     if envs_file.exists() {
-                        collected_envs = read_models_spec(&envs_file).await?;
-                    } else {
-                        collected_envs = BTreeMap::new();
+                        match read_models_spec::<VivaEnvSpec>(&envs_file).await {
+                            Ok(mut envs) => {
+                                for env_spec in envs.values_mut() {
+                                    env_spec.record_provenance(&envs_file.display().to_string());
+                                }
+                                if let Ok(raw) = read_raw_spec(&envs_file).await {
+                                    for field in find_unknown_fields::<BTreeMap<String, VivaEnvSpec>>(&raw) {
+                                        warnings.push(format!("{}: unknown field '{}'", envs_file.display(), field));
+                                    }
+                                }
+                                collected_envs = envs
+                            }
+                            Err(e) => {
+                                warn!("Invalid environments file '{}': {:#}", envs_file.display(), e);
+                                invalid_envs.insert(envs_file.display().to_string(), format!("{:#}", e));
+                            }
+                        }
                     }
 
                     let envs_subdir = &self.base_config_path.join("envs");
                     if envs_subdir.is_dir() {
-                        for entry in std::fs::read_dir(envs_subdir).unwrap() {
-                            let entry = entry.unwrap();
-                            let spec_config_file: &PathBuf = &entry.path();
-
-                            if spec_config_file.is_file() {
-                                let env_spec: VivaEnvSpec =
-                                    read_model_spec(&spec_config_file).await?;
-                                let env_id: String = spec_config_file
-                                    .file_stem()
-                                    .unwrap()
-                                    .to_string_lossy()
-                                    .into();
-
-                                if collected_envs.contains_key(&env_id) {
-                                    debug!(
-                                        "Overwriting env {}, as it has it's own spec file.",
-                                        env_id
-                                    );
-                                    collected_envs.remove(&env_id);
-                                    collected_envs_dirty = true;
+                        let ignore = match SpecIgnore::load(&self.base_config_path) {
+                            Ok(ignore) => ignore,
+                            Err(e) => {
+                                warn!("{:#}", e);
+                                invalid_envs.insert(IGNORE_FILENAME.to_string(), format!("{:#}", e));
+                                SpecIgnore::default()
+                            }
+                        };
+                        let spec_files = find_spec_files(envs_subdir, &ignore, &mut warnings).await;
+                        for read_spec in read_spec_files::<VivaEnvSpec>(spec_files).await {
+                            let (env_id, spec_config_file) = (read_spec.id, read_spec.path);
+                            let env_spec: VivaEnvSpec = match read_spec.result {
+                                Ok((mut env_spec, unknown_fields)) => {
+                                    for field in unknown_fields {
+                                        warnings.push(format!("{}: unknown field '{}'", spec_config_file.display(), field));
+                                    }
+                                    env_spec.record_provenance(&spec_config_file.display().to_string());
+                                    env_spec
+                                }
+                                Err(e) => {
+                                    warn!("Invalid environment spec '{}': {:#}", spec_config_file.display(), e);
+                                    invalid_envs.insert(env_id, format!("{:#}", e));
+                                    continue;
                                 }
-                                single_envs.insert(env_id, env_spec);
+                            };
+
+                            if collected_envs.contains_key(&env_id) {
+                                debug!(
+                                    "Overwriting env {}, as it has it's own spec file.",
+                                    env_id
+                                );
+                                warnings.push(format!(
+                                    "Environment '{}' is defined in '{}', and shadowed by: {}",
+                                    env_id,
+                                    envs_file.display(),
+                                    spec_config_file.display()
+                                ));
+                                collected_envs.remove(&env_id);
+                                collected_envs_dirty = true;
                             }
+                            single_envs.insert(env_id, env_spec);
                         }
                     }
                 }
@@ -547,6 +2479,8 @@ impl DefaultEnvCollection {
         self.single_envs = Some(single_envs);
         self.collected_envs = Some(collected_envs);
         self.collected_envs_dirty = collected_envs_dirty;
+        self.invalid_envs = invalid_envs;
+        self.warnings = warnings;
         Ok(())
     }
 
@@ -557,7 +2491,11 @@ impl DefaultEnvCollection {
             let envs_file = self.find_collected_envs_file();
             match &self.collected_envs {
                 Some(map) => {
-                    write_models_spec(&envs_file, map).await?;
+                    let map: BTreeMap<String, VivaEnvSpec> = map
+                        .iter()
+                        .map(|(env_id, env_spec)| (env_id.clone(), env_spec.normalized()))
+                        .collect();
+                    write_models_spec(&envs_file, &map).await?;
                 },
                 None => {
                     if envs_file.exists() {
@@ -572,7 +2510,7 @@ impl DefaultEnvCollection {
 
             match self.single_envs.as_ref().unwrap().get(env_id) {
                 Some(env_spec) => {
-                    write_model_spec(&env_file, env_spec).await?;
+                    write_model_spec(&env_file, &env_spec.normalized()).await?;
                 },
                 None => {
                     if env_file.exists() {
@@ -590,6 +2528,30 @@ impl DefaultEnvCollection {
 #[async_trait]
 impl EnvironmentCollection for DefaultEnvCollection {
 
+    async fn get_invalid_envs(&self) -> BTreeMap<String, String> {
+        self.invalid_envs.clone()
+    }
+
+    async fn get_warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+
+    async fn reload(&mut self) -> Result<()> {
+        self.load_registered_envs(true).await
+    }
+
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![self.base_config_path.clone()]
+    }
+
+    fn get_lockfile_path(&self, env_id: &str) -> Option<PathBuf> {
+        Some(
+            self.base_config_path
+                .join("envs")
+                .join(format!("{}.{}", env_id, LOCKFILE_EXTENSION)),
+        )
+    }
+
     async fn get_env_ids(&self) -> Vec<String> {
         let mut collected: Vec<String> = self.collected_envs
             .as_ref()
@@ -627,6 +2589,7 @@ impl EnvironmentCollection for DefaultEnvCollection {
         match self.single_envs.as_ref() {
             Some(envs) => {
                 if envs.contains_key(env_id) {
+                    self.push_history(env_id, None).await?;
                     self.single_envs_dirty.push(env_id.to_string());
                     self.single_envs.as_mut().unwrap().remove(env_id);
                 }
@@ -634,6 +2597,12 @@ impl EnvironmentCollection for DefaultEnvCollection {
             None => {}
         }
 
+        if let Some(lockfile_path) = self.get_lockfile_path(env_id) {
+            if lockfile_path.exists() {
+                fs::remove_file(&lockfile_path).await?;
+            }
+        }
+
         self.sync_config().await?;
 
         Ok(())
@@ -644,20 +2613,32 @@ impl EnvironmentCollection for DefaultEnvCollection {
         //     return Err(anyhow!("Environment with id '{}' already exists", env_id));
         // }
 
-        let spec_config_file = self
-            .base_config_path
-            .join("envs")
-            .join(format!("{}.yaml", env_id));
-        // TODO: check if already exists
+        self.push_history(env_id, Some(env_spec)).await?;
+        self.write_single_env(env_id, env_spec).await
+    }
 
-        write_model_spec(&spec_config_file, env_spec).await?;
-        self.single_envs
-            .as_mut()
-            .unwrap()
-            .insert(env_id.to_string(), env_spec.clone());
+    async fn undo_env(&mut self, env_id: &str) -> Result<Option<VivaEnvSpec>> {
+        let mut history = self.read_history(env_id).await?;
+        let previous = match history.pop() {
+            Some(previous) => previous,
+            None => bail!("No earlier version of environment '{}' recorded.", env_id),
+        };
+        write_model_spec(&self.history_file(env_id), &history).await?;
 
-        self.sync_config().await?;
-        Ok(())
+        match &previous {
+            Some(env_spec) => {
+                self.write_single_env(env_id, env_spec).await?;
+            }
+            None => {
+                if let Some(envs) = self.single_envs.as_mut() {
+                    if envs.remove(env_id).is_some() {
+                        self.single_envs_dirty.push(env_id.to_string());
+                    }
+                }
+                self.sync_config().await?;
+            }
+        }
+        Ok(previous)
     }
 
 
@@ -665,8 +2646,160 @@ impl EnvironmentCollection for DefaultEnvCollection {
 
 #[cfg(test)]
 mod tests {
-    
-    
+    use super::*;
+
+    #[test]
+    fn test_underlay_spec() {
+        let mut spec = VivaEnvSpec::new();
+        spec.channels = vec![String::from("conda-forge")];
+        spec.pkg_specs = vec![String::from("python=3.11")];
+        spec.env_vars.insert(String::from("MODE"), String::from("dev"));
+
+        let mut lower = VivaEnvSpec::new();
+        lower.channels = vec![String::from("bioconda"), String::from("conda-forge")];
+        lower.pkg_specs = vec![String::from("python=3.10"), String::from("samtools")];
+        lower.env_vars.insert(String::from("MODE"), String::from("prod"));
+        lower.max_size = Some(String::from("2GB"));
+
+        spec.underlay_spec(lower);
+        assert_eq!(spec.channels, vec!["conda-forge", "bioconda"]);
+        assert_eq!(spec.pkg_specs, vec!["python=3.11", "samtools"]);
+        assert_eq!(spec.env_vars["MODE"], "dev");
+        assert_eq!(spec.max_size.as_deref(), Some("2GB"));
+    }
+
+    #[test]
+    fn test_prefix_hash() {
+        let mut spec = VivaEnvSpec::new();
+        spec.channels = vec![String::from("conda-forge")];
+        spec.pkg_specs = vec![String::from("python=3.11"), String::from("numpy")];
+        let content_hash = spec.content_hash();
+        let prefix_hash = spec.prefix_hash();
+
+        let mut reordered = spec.clone();
+        reordered.pkg_specs = vec![String::from("numpy"), String::from("python=3.11"), String::from("numpy")];
+        assert_eq!(reordered.prefix_hash(), prefix_hash);
+
+        let mut with_platform = spec.clone();
+        with_platform.platform = Some(Platform::current().to_string());
+        assert_eq!(with_platform.prefix_hash(), prefix_hash);
+
+        // env vars and the size budget don't change the prefix, but are part of the content hash
+        let mut with_env_vars = spec.clone();
+        with_env_vars.env_vars.insert(String::from("MODE"), String::from("dev"));
+        with_env_vars.max_size = Some(String::from("2GB"));
+        assert_eq!(with_env_vars.prefix_hash(), prefix_hash);
+        assert_ne!(with_env_vars.content_hash(), content_hash);
+
+        let mut with_pip = spec.clone();
+        with_pip.pip_specs = vec![String::from("requests")];
+        assert_ne!(with_pip.prefix_hash(), prefix_hash);
+        assert_eq!(spec.content_addressed_env_id(), format!("spec-{}", prefix_hash));
+    }
+
+    #[test]
+    fn test_is_equivalent_to() {
+        let mut spec = VivaEnvSpec::new();
+        spec.pkg_specs = vec![String::from("python"), String::from("numpy")];
+        let mut reordered = spec.clone();
+        reordered.pkg_specs.reverse();
+        assert!(spec.is_equivalent_to(&reordered));
+
+        // a package dropped from the spec is still installed
+        let mut dropped = spec.clone();
+        dropped.pkg_specs = vec![String::from("python")];
+        assert!(dropped.is_satisfied_by(&spec));
+        assert!(!dropped.is_equivalent_to(&spec));
+        assert!(!spec.is_equivalent_to(&dropped));
+
+        let mut other_channel = spec.clone();
+        other_channel.channels.push(String::from("bioconda"));
+        assert!(!spec.is_equivalent_to(&other_channel));
+    }
+
+    #[test]
+    fn test_remove_pkg_specs_needs_sync() {
+        let mut spec = VivaEnvSpec::new();
+        spec.pkg_specs = vec![String::from("python"), String::from("numpy")];
+        let mut env = VivaEnv::create(
+            String::from("test"),
+            String::from("default"),
+            spec.clone(),
+            PathBuf::from("/tmp/test"),
+            spec.clone(),
+            PathBuf::from("/tmp/test").join(ENV_SPEC_FILENAME),
+            EnvSyncStatus::Synced,
+        );
+
+        env.remove_pkg_specs(&vec![String::from("numpy")]).unwrap();
+        assert_eq!(env.spec.pkg_specs, vec!["python"]);
+        assert_eq!(env.sync_status, EnvSyncStatus::NotSynced);
+        assert_eq!(env.explain_sync().removed_pkg_specs, vec!["numpy"]);
+    }
+
+    #[tokio::test]
+    async fn test_sync_from_lockfile() {
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join("locked");
+        let mut spec = VivaEnvSpec::new();
+        // a channel that can't be fetched from, so any attempt to solve fails
+        spec.channels = vec![format!("file://{}/no-channel", dir.path().display())];
+        let config = VivaConfig {
+            offline: true,
+            ..Default::default()
+        };
+        let mut env = VivaEnv::create(
+            String::from("locked"),
+            String::from("default"),
+            spec.clone(),
+            env_path.clone(),
+            spec.clone(),
+            env_path.join(ENV_SPEC_FILENAME),
+            EnvSyncStatus::NotSynced,
+        );
+        env.config = Arc::new(config);
+        let lockfile_path = dir.path().join(format!("locked.{}", LOCKFILE_EXTENSION));
+        env.lockfile_path = Some(lockfile_path.clone());
+
+        let mut lockfile = EnvLockfile::new(&spec);
+        lockfile.set_packages(&spec, &spec.get_platform_name(), vec![]);
+        lockfile.write(&lockfile_path).await.unwrap();
+
+        assert!(env.plan_sync().await.unwrap().unwrap().from_lockfile);
+        assert!(env.sync(EnvCheckStrategy::Auto).await.unwrap());
+        assert!(env_path.join(ENV_SPEC_FILENAME).is_file());
+        assert!(env.solve_for_sync(false).await.is_err());
+    }
+
+    #[test]
+    fn test_sync_handle_is_send() {
+        fn assert_send<T: Send>(_: &T) {}
+        let dir = tempfile::tempdir().unwrap();
+        let env_path = dir.path().join("test");
+        let mut env = VivaEnv::create(
+            String::from("test"),
+            String::from("default"),
+            VivaEnvSpec::new(),
+            env_path.clone(),
+            VivaEnvSpec::new(),
+            env_path.join(ENV_SPEC_FILENAME),
+            EnvSyncStatus::NotSynced,
+        );
+        // the sync doesn't start before the handle is polled
+        let handle = env.sync_with_handle(EnvCheckStrategy::Skip);
+        assert_send(&handle);
+    }
+
+    #[test]
+    fn test_invalid_env_var_names() {
+        let spec: VivaEnvSpec =
+            serde_yaml::from_str("channels: []\npkg_specs: []\nenv_vars:\n  _MODE_2: dev\n").unwrap();
+        assert_eq!(spec.env_vars["_MODE_2"], "dev");
+        for name in ["2MODE", "MODE-X", "A B", "X=Y", "$(id)", ""] {
+            let spec_data = format!("channels: []\npkg_specs: []\nenv_vars:\n  '{}': dev\n", name);
+            assert!(serde_yaml::from_str::<VivaEnvSpec>(&spec_data).is_err(), "{}", name);
+        }
+    }
 
     #[tokio::test]
     async fn test_viva_env_from_str_with_spec_file() {
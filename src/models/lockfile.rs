@@ -0,0 +1,194 @@
+use crate::config::VivaConfig;
+use crate::models::environment::{fnv1a_hash, SpecProvenance, VivaEnvSpec};
+use crate::rattler::commands::create::solve;
+use anyhow::{Context, Result};
+use rattler_conda_types::{PrefixRecord, RepoDataRecord};
+use rattler_repodata_gateway::fetch::CacheAction;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+/// The fully resolved package set of an environment (with urls and hashes), so it can be
+/// installed again without solving, and with the exact same packages, on other machines.
+///
+/// Packages are recorded per platform, all of them were resolved for the same spec.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct EnvLockfile {
+    /// The spec the packages were resolved for.
+    pub spec: VivaEnvSpec,
+    /// The content hash of the spec (see [`VivaEnvSpec::content_hash`]), so other tools can check
+    /// which spec a lockfile belongs to without parsing specs.
+    #[serde(default)]
+    pub spec_hash: String,
+    /// The resolved packages (platform -> packages).
+    #[serde(default)]
+    pub platforms: BTreeMap<String, Vec<RepoDataRecord>>,
+    /// The mirrors that served packages, if they are not the ones in the locked urls (platform ->
+    /// locked url -> url the package was downloaded from).
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub served_by: BTreeMap<String, BTreeMap<String, String>>,
+}
+
+impl EnvLockfile {
+    pub fn new(spec: &VivaEnvSpec) -> EnvLockfile {
+        let mut spec = spec.normalized();
+        spec.provenance = SpecProvenance::default();
+        EnvLockfile {
+            spec_hash: spec.content_hash(),
+            spec,
+            platforms: BTreeMap::new(),
+            served_by: BTreeMap::new(),
+        }
+    }
+
+    /// Read a lockfile, returns `None` if it doesn't exist.
+    ///
+    /// Lockfiles are always json (see [`EnvLockfile::write`]), whatever their extension.
+    pub async fn read(lockfile_path: &PathBuf) -> Result<Option<EnvLockfile>> {
+        let lockfile_json = match tokio::fs::read_to_string(lockfile_path).await {
+            Ok(lockfile_json) => lockfile_json,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to read lockfile: {}", lockfile_path.display()))
+            }
+        };
+        let lockfile = serde_json::from_str(&lockfile_json)
+            .with_context(|| format!("Invalid lockfile: {}", lockfile_path.display()))?;
+        Ok(Some(lockfile))
+    }
+
+    pub async fn write(&self, lockfile_path: &PathBuf) -> Result<()> {
+        if let Some(parent) = lockfile_path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let lockfile_json = serde_json::to_string_pretty(self)?;
+        tokio::fs::write(lockfile_path, lockfile_json).await?;
+        Ok(())
+    }
+
+    /// The locked packages for the platform of the spec (usually the current one), if the lockfile
+    /// was resolved for the provided spec.
+    pub fn get_packages(&self, spec: &VivaEnvSpec) -> Option<&Vec<RepoDataRecord>> {
+        self.get_packages_for(spec, &spec.get_platform_name())
+    }
+
+    /// The locked packages for a platform, if the lockfile was resolved for the provided spec.
+    pub fn get_packages_for(&self, spec: &VivaEnvSpec, platform: &str) -> Option<&Vec<RepoDataRecord>> {
+        match &self.spec == spec {
+            true => self.platforms.get(platform),
+            false => None,
+        }
+    }
+
+    /// Solve a spec for a platform (the one of the spec, if none is provided), without an
+    /// environment prefix (and without installing anything).
+    pub async fn resolve_packages(
+        spec: &VivaEnvSpec,
+        platform: Option<&str>,
+        config: &VivaConfig,
+    ) -> Result<Vec<RepoDataRecord>> {
+        let mut spec = spec.clone();
+        if let Some(platform) = platform {
+            spec.platform = Some(String::from(platform));
+        }
+        // a prefix that doesn't exist, so no installed packages are taken into account
+        let target_prefix = std::env::temp_dir().join(format!("viva-lock-{}", std::process::id()));
+        let solved = solve(&target_prefix, &spec, CacheAction::CacheOrFetch, false, config)
+            .await
+            .with_context(|| format!("Failed to solve environment spec for platform: {}", spec.get_platform_name()))?;
+        Ok(solved.required_packages)
+    }
+
+    /// A hash of the locked packages for the platform of the spec (of their urls, which contain
+    /// name, version and build), if the lockfile was resolved for the provided spec.
+    pub fn packages_hash(&self, spec: &VivaEnvSpec) -> Option<String> {
+        let packages = self.get_packages(spec)?;
+        let mut urls = packages
+            .iter()
+            .map(|record| record.url.to_string())
+            .collect::<Vec<String>>();
+        urls.sort();
+        Some(fnv1a_hash(urls.join("\n").as_bytes()))
+    }
+
+    /// Record the packages for a platform.
+    ///
+    /// If the lockfile was resolved for a different spec, the packages of all other platforms are
+    /// dropped.
+    pub fn set_packages(&mut self, spec: &VivaEnvSpec, platform: &str, packages: Vec<RepoDataRecord>) {
+        if &self.spec != spec {
+            *self = EnvLockfile::new(spec);
+        }
+        self.platforms.insert(String::from(platform), packages);
+        self.served_by.remove(platform);
+    }
+
+    /// Record which mirrors served the packages of a platform, according to the (installed) prefix
+    /// records of the packages, which contain the url they were downloaded from.
+    pub fn set_served_by(&mut self, platform: &str, installed: &[PrefixRecord]) {
+        let packages = match self.platforms.get(platform) {
+            Some(packages) => packages,
+            None => return,
+        };
+        let served_by = packages
+            .iter()
+            .filter_map(|locked| {
+                installed
+                    .iter()
+                    .map(|record| &record.repodata_record)
+                    .find(|record| record.file_name == locked.file_name && record.url != locked.url)
+                    .map(|record| (locked.url.to_string(), record.url.to_string()))
+            })
+            .collect::<BTreeMap<String, String>>();
+        match served_by.is_empty() {
+            true => self.served_by.remove(platform),
+            false => self.served_by.insert(String::from(platform), served_by),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::defaults::LOCKFILE_EXTENSION;
+
+    fn record(name: &str) -> RepoDataRecord {
+        let file_name = format!("{}-1.0-0.conda", name);
+        serde_json::from_value(serde_json::json!({
+            "name": name,
+            "version": "1.0",
+            "build": "0",
+            "build_number": 0,
+            "subdir": "linux-64",
+            "fn": file_name,
+            "url": format!("https://conda.anaconda.org/conda-forge/linux-64/{}", file_name),
+            "channel": "https://conda.anaconda.org/conda-forge/",
+        }))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_write_read_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let lockfile_path = dir.path().join(format!("env.{}", LOCKFILE_EXTENSION));
+        assert!(EnvLockfile::read(&lockfile_path).await.unwrap().is_none());
+
+        let mut spec = VivaEnvSpec::new();
+        spec.pkg_specs = vec![String::from("python")];
+        let mut lockfile = EnvLockfile::new(&spec);
+        lockfile.set_packages(&spec, "linux-64", vec![record("python"), record("zlib")]);
+        lockfile.write(&lockfile_path).await.unwrap();
+
+        let read = EnvLockfile::read(&lockfile_path).await.unwrap().unwrap();
+        assert_eq!(read.spec_hash, lockfile.spec_hash);
+        let packages = read.get_packages_for(&spec, "linux-64").unwrap();
+        assert_eq!(
+            packages.iter().map(|record| record.file_name.as_str()).collect::<Vec<_>>(),
+            vec!["python-1.0-0.conda", "zlib-1.0-0.conda"]
+        );
+        assert_eq!(read.packages_hash(&spec), lockfile.packages_hash(&spec));
+
+        tokio::fs::write(&lockfile_path, "pkg_specs: [python]\n").await.unwrap();
+        assert!(EnvLockfile::read(&lockfile_path).await.is_err());
+    }
+}
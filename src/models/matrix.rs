@@ -0,0 +1,207 @@
+use crate::defaults::LOCKFILE_EXTENSION;
+use crate::models::environment::{pkg_spec_name, VivaEnvSpec};
+use crate::models::lockfile::EnvLockfile;
+use crate::config::VivaConfig;
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+/// A package whose version varies across a matrix (e.g. `python=3.9,3.10,3.11`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatrixAxis {
+    pub package: String,
+    pub versions: Vec<String>,
+}
+
+impl FromStr for MatrixAxis {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (package, versions) = s
+            .split_once('=')
+            .ok_or_else(|| anyhow!("Invalid matrix axis (must be '<package>=<version>,...'): {}", s))?;
+        let package = package.trim();
+        let versions = versions
+            .split(',')
+            .map(|version| version.trim().to_string())
+            .filter(|version| !version.is_empty())
+            .collect::<Vec<String>>();
+        if package.is_empty() || versions.is_empty() {
+            return Err(anyhow!(
+                "Invalid matrix axis (must be '<package>=<version>,...'): {}",
+                s
+            ));
+        }
+        Ok(MatrixAxis {
+            package: String::from(package),
+            versions,
+        })
+    }
+}
+
+/// One concrete environment of a matrix.
+#[derive(Debug, Deserialize, Serialize, Clone)]
+pub struct MatrixEntry {
+    /// A name for the entry, made of the template name and the selected versions (e.g.
+    /// `tests-python3.10`), usable as CI job or environment id, and as file name.
+    pub name: String,
+    /// The selected version of each varied package.
+    pub variant: BTreeMap<String, String>,
+    pub spec: VivaEnvSpec,
+    /// The content hash of the spec (see [`VivaEnvSpec::content_hash`]).
+    pub spec_hash: String,
+    /// The lockfile written for this entry, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub lockfile: Option<PathBuf>,
+}
+
+/// Expand a template spec into one spec per combination of the versions of the axes.
+///
+/// The package specs of a varied package are replaced by `<package>=<version>` (or it is added,
+/// if the template doesn't contain it), everything else is taken from the template.
+pub fn expand_matrix(name: &str, template: &VivaEnvSpec, axes: &[MatrixAxis]) -> Vec<MatrixEntry> {
+    let mut variants: Vec<BTreeMap<String, String>> = vec![BTreeMap::new()];
+    for axis in axes {
+        variants = variants
+            .iter()
+            .flat_map(|variant| {
+                axis.versions.iter().map(move |version| {
+                    let mut variant = variant.clone();
+                    variant.insert(axis.package.clone(), version.clone());
+                    variant
+                })
+            })
+            .collect();
+    }
+
+    variants
+        .into_iter()
+        .map(|variant| {
+            let mut spec = template.clone();
+            let mut entry_name_parts = vec![String::from(name)];
+            for axis in axes {
+                let version = &variant[&axis.package];
+                let pkg_spec = format!("{}={}", axis.package, version);
+                spec.pkg_specs
+                    .retain(|existing| pkg_spec_name(existing) != axis.package);
+                spec.provenance
+                    .pkg_specs
+                    .retain(|existing, _| pkg_spec_name(existing) != axis.package);
+                spec.provenance
+                    .pkg_specs
+                    .insert(pkg_spec.clone(), String::from("matrix"));
+                spec.pkg_specs.push(pkg_spec);
+                entry_name_parts.push(format!("{}{}", axis.package, version));
+            }
+            let spec = spec.normalized();
+            MatrixEntry {
+                name: entry_name(&entry_name_parts),
+                spec_hash: spec.content_hash(),
+                variant,
+                spec,
+                lockfile: None,
+            }
+        })
+        .collect()
+}
+
+/// Join the parts of the name of a matrix entry with `-`, replacing characters that can't be used in
+/// file names or CI job ids: wildcards become `x` (e.g. `python3.10.x` for `3.10.*`), and
+/// everything except letters, digits, `.`, `-` and `_` becomes `_`.
+fn entry_name(parts: &[String]) -> String {
+    parts
+        .iter()
+        .map(|part| {
+            part.chars()
+                .map(|c| match c {
+                    '*' => 'x',
+                    c if c.is_ascii_alphanumeric() || ".-_".contains(c) => c,
+                    _ => '_',
+                })
+                .collect::<String>()
+        })
+        .collect::<Vec<String>>()
+        .join("-")
+}
+
+/// Solve the spec of a matrix entry for the provided platforms (the one of the spec, if none are
+/// provided), without installing anything, and write the result to a lockfile in the provided
+/// directory.
+pub async fn lock_matrix_entry(
+    entry: &mut MatrixEntry,
+    lockfile_dir: &PathBuf,
+    platforms: &[String],
+    config: &VivaConfig,
+) -> Result<()> {
+    let lockfile_path = lockfile_dir.join(format!("{}.{}", entry.name, LOCKFILE_EXTENSION));
+    let mut lockfile = EnvLockfile::read(&lockfile_path)
+        .await?
+        .unwrap_or_else(|| EnvLockfile::new(&entry.spec));
+    let platforms = match platforms.is_empty() {
+        true => vec![entry.spec.get_platform_name()],
+        false => platforms.to_vec(),
+    };
+    for platform in platforms {
+        let packages = EnvLockfile::resolve_packages(&entry.spec, Some(&platform), config).await?;
+        lockfile.set_packages(&entry.spec, &platform, packages);
+    }
+    lockfile.write(&lockfile_path).await?;
+    entry.lockfile = Some(lockfile_path);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_axis() {
+        let axis = MatrixAxis::from_str("python=3.10, 3.11,").unwrap();
+        assert_eq!(axis.package, "python");
+        assert_eq!(axis.versions, vec!["3.10", "3.11"]);
+        assert!(MatrixAxis::from_str("python").is_err());
+        assert!(MatrixAxis::from_str("python=").is_err());
+    }
+
+    #[test]
+    fn test_expand_matrix() {
+        let mut template = VivaEnvSpec::new();
+        template.channels = vec![String::from("conda-forge")];
+        template.pkg_specs = vec![String::from("python>=3.8"), String::from("pytest")];
+        let axes = vec![
+            MatrixAxis::from_str("python=3.10,3.11").unwrap(),
+            MatrixAxis::from_str("numpy=1.26.*,2.0").unwrap(),
+        ];
+
+        let entries = expand_matrix("unit-tests", &template, &axes);
+        let names = entries.iter().map(|entry| entry.name.as_str()).collect::<Vec<_>>();
+        assert_eq!(
+            names,
+            vec![
+                "unit-tests-python3.10-numpy1.26.x",
+                "unit-tests-python3.10-numpy2.0",
+                "unit-tests-python3.11-numpy1.26.x",
+                "unit-tests-python3.11-numpy2.0",
+            ]
+        );
+
+        let entry = &entries[0];
+        assert_eq!(entry.variant["python"], "3.10");
+        assert_eq!(entry.variant["numpy"], "1.26.*");
+        // the template spec for a varied package is replaced, others are kept
+        let mut pkg_specs = entry.spec.pkg_specs.clone();
+        pkg_specs.sort();
+        assert_eq!(pkg_specs, vec!["numpy=1.26.*", "pytest", "python=3.10"]);
+        assert_eq!(entry.spec.channels, template.channels);
+        assert_eq!(entry.spec_hash, entry.spec.content_hash());
+        assert_ne!(entries[0].spec_hash, entries[1].spec_hash);
+    }
+
+    #[test]
+    fn test_entry_name() {
+        let parts = vec![String::from("tests"), String::from("python>=3.10,<3.12|*/x")];
+        assert_eq!(entry_name(&parts), "tests-python__3.10__3.12_x_x");
+    }
+}
@@ -1,21 +1,365 @@
 pub mod app;
 pub mod cmd;
 pub mod environment;
+pub mod lockfile;
+pub mod matrix;
+pub mod profile;
+pub mod project;
+pub mod remote;
+pub mod requirements;
+pub mod system;
+pub mod workspace;
 
 
 // use directories::ProjectDirs;
+use crate::defaults::IGNORE_FILENAME;
 use anyhow::{anyhow, Context, Result};
+use futures::StreamExt;
+use globset::{GlobBuilder, GlobMatcher};
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
 use serde_json::Result as SerdeJsonResult;
 use serde_yaml::Result as SerdeYamlResult;
-use std::collections::{BTreeMap};
-use std::path::PathBuf;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
 use tokio::fs;
 use tokio::fs::File;
 use tokio::io::AsyncReadExt;
 use tokio::io::AsyncWriteExt;
 
+/// The file extensions of model spec files.
+pub(crate) const SPEC_FILE_EXTENSIONS: [&str; 3] = ["json", "yaml", "yml"];
+
+/// Glob patterns (read from a `.vivaignore` file) for files in a collection config directory that
+/// should not be parsed as specs.
+///
+/// Like with `.gitignore`, patterns without a `/` match the file name anywhere, other patterns
+/// match the path relative to the config directory, a trailing `/` only matches directories, and
+/// a leading `!` re-includes previously ignored paths. The last matching pattern wins. Paths in an
+/// ignored directory are ignored as well, unless they are re-included (unlike with `.gitignore`,
+/// that also works for files in ignored directories).
+#[derive(Debug, Default)]
+pub(crate) struct SpecIgnore {
+    base_dir: PathBuf,
+    patterns: Vec<IgnorePattern>,
+}
+
+#[derive(Debug)]
+struct IgnorePattern {
+    matcher: GlobMatcher,
+    negated: bool,
+    dir_only: bool,
+    match_name: bool,
+}
+
+impl SpecIgnore {
+    /// Read the `.vivaignore` file in the provided directory (if it exists).
+    pub(crate) fn load(base_dir: &Path) -> Result<SpecIgnore> {
+        let ignore_file = base_dir.join(IGNORE_FILENAME);
+        let content = match ignore_file.is_file() {
+            true => std::fs::read_to_string(&ignore_file)?,
+            false => String::new(),
+        };
+        SpecIgnore::parse(base_dir, &content)
+            .with_context(|| format!("Invalid ignore file: {}", ignore_file.display()))
+    }
+
+    fn parse(base_dir: &Path, content: &str) -> Result<SpecIgnore> {
+        let mut patterns = vec![];
+        for line in content.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (negated, pattern) = match line.strip_prefix('!') {
+                Some(pattern) => (true, pattern),
+                None => (false, line),
+            };
+            let (dir_only, pattern) = match pattern.strip_suffix('/') {
+                Some(pattern) => (true, pattern),
+                None => (false, pattern),
+            };
+            let match_name = !pattern.contains('/');
+            let pattern = pattern.trim_start_matches('/');
+            let matcher = GlobBuilder::new(pattern)
+                .literal_separator(true)
+                .build()?
+                .compile_matcher();
+            patterns.push(IgnorePattern {
+                matcher,
+                negated,
+                dir_only,
+                match_name,
+            });
+        }
+        Ok(SpecIgnore {
+            base_dir: base_dir.to_path_buf(),
+            patterns,
+        })
+    }
+
+    /// Whether there are patterns that re-include paths, which might be in ignored directories.
+    fn has_negations(&self) -> bool {
+        self.patterns.iter().any(|pattern| pattern.negated)
+    }
+
+    /// Whether the provided path should be ignored (`in_ignored_dir`: whether one of its parent
+    /// directories is ignored).
+    pub(crate) fn is_ignored(&self, path: &Path, is_dir: bool, in_ignored_dir: bool) -> bool {
+        let rel_path = path.strip_prefix(&self.base_dir).unwrap_or(path);
+        let file_name = match path.file_name() {
+            Some(name) => Path::new(name),
+            None => return in_ignored_dir,
+        };
+        let mut ignored = in_ignored_dir;
+        for pattern in &self.patterns {
+            if pattern.dir_only && !is_dir {
+                continue;
+            }
+            let matched = match pattern.match_name {
+                true => pattern.matcher.is_match(file_name),
+                false => pattern.matcher.is_match(rel_path),
+            };
+            if matched {
+                ignored = !pattern.negated;
+            }
+        }
+        ignored
+    }
+}
+
+/// Find all model spec files in a directory, recursively.
+///
+/// Returns a map of ids to spec files, the id of a spec file is its path relative to the
+/// directory, without extension (e.g. `team-a/foo.yaml` -> `team-a/foo`). Hidden files, files
+/// without a spec file extension and ignored paths are skipped, symlinked directories are
+/// followed (once).
+///
+/// Problems (like duplicate ids) are added to `warnings`.
+pub(crate) async fn find_spec_files(
+    spec_dir: &Path,
+    ignore: &SpecIgnore,
+    warnings: &mut Vec<String>,
+) -> BTreeMap<String, PathBuf> {
+    let mut result: BTreeMap<String, PathBuf> = BTreeMap::new();
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    // directories that still have to be scanned (depth-first, in sorted order), with the id
+    // prefix of the spec files in them, and whether they are ignored
+    let mut pending: Vec<SpecDir> = vec![SpecDir {
+        path: spec_dir.to_path_buf(),
+        id_prefix: String::new(),
+        ignored: false,
+    }];
+    while let Some(dir) = pending.pop() {
+        let subdirs = collect_spec_files(&dir, ignore, &mut visited, &mut result, warnings).await;
+        pending.extend(subdirs.into_iter().rev());
+    }
+    result
+}
+
+/// A directory that is scanned for spec files.
+struct SpecDir {
+    path: PathBuf,
+    id_prefix: String,
+    /// Ignored directories are only scanned for paths that are re-included.
+    ignored: bool,
+}
+
+/// How many spec files are read (and parsed) at the same time when a collection is loaded.
+const SPEC_READ_CONCURRENCY: usize = 32;
+
+/// A spec file that was read with [`read_spec_files`].
+pub(crate) struct ReadSpecFile<T> {
+    pub(crate) id: String,
+    pub(crate) path: PathBuf,
+    /// The parsed model, and the fields in the file that are not part of it (see
+    /// [`find_unknown_fields`]).
+    pub(crate) result: Result<(T, Vec<String>)>,
+}
+
+async fn read_spec_file<T: DeserializeOwned>(id: String, path: PathBuf) -> ReadSpecFile<T> {
+    let result = match fs::read_to_string(&path).await {
+        Ok(spec_data) => parse_spec_with_unknown_fields::<T>(&path, &spec_data),
+        Err(e) => Err(anyhow!("Can't read specification file '{}': {}", path.display(), e)),
+    };
+    ReadSpecFile { id, path, result }
+}
+
+/// Parse spec data into a model, and collect the fields that are not part of it (see
+/// [`find_unknown_fields`]) in the same pass, so the data is only parsed once.
+fn parse_spec_with_unknown_fields<T: DeserializeOwned>(
+    path: &Path,
+    spec_data: &str,
+) -> Result<(T, Vec<String>)> {
+    let mut unknown_fields = vec![];
+    let is_json = path.extension().map(|ext| ext == "json").unwrap_or(false);
+    let model: Result<T> = match is_json {
+        true => {
+            let mut deserializer = serde_json::Deserializer::from_str(spec_data);
+            serde_ignored::deserialize(&mut deserializer, |field| unknown_fields.push(dotted_path(&field)))
+                .and_then(|model| deserializer.end().map(|_| model))
+                .map_err(anyhow::Error::from)
+        }
+        // yaml is a superset of json, so this works for both formats
+        false => {
+            let deserializer = serde_yaml::Deserializer::from_str(spec_data);
+            serde_ignored::deserialize(deserializer, |field| unknown_fields.push(dotted_path(&field)))
+                .map_err(anyhow::Error::from)
+        }
+    };
+    let model = model.with_context(|| format!("Unable to parse specification file: {}", path.display()))?;
+    Ok((model, unknown_fields))
+}
+
+/// Read and parse spec files (e.g. found with [`find_spec_files`]) concurrently, at most
+/// [`SPEC_READ_CONCURRENCY`] at a time. The results are in the order of the ids.
+pub(crate) async fn read_spec_files<T: DeserializeOwned>(
+    spec_files: BTreeMap<String, PathBuf>,
+) -> Vec<ReadSpecFile<T>> {
+    futures::stream::iter(spec_files.into_iter().map(|(id, path)| read_spec_file::<T>(id, path)))
+        .buffered(SPEC_READ_CONCURRENCY)
+        .collect()
+        .await
+}
+
+/// Read the raw data of a spec file, without parsing it into a model.
+pub(crate) async fn read_raw_spec(spec_file: &PathBuf) -> Result<serde_json::Value> {
+    let spec_data = fs::read_to_string(spec_file).await?;
+    // yaml is a superset of json, so this works for both formats
+    let value: serde_json::Value = serde_yaml::from_str(&spec_data)
+        .with_context(|| format!("Unable to parse specification file: {}", spec_file.display()))?;
+    Ok(value)
+}
+
+/// Find fields in raw spec data that are not fields of the model type (e.g. typos).
+///
+/// Returns the (dotted) paths of all unknown fields.
+pub(crate) fn find_unknown_fields<T: DeserializeOwned>(raw: &serde_json::Value) -> Vec<String> {
+    let mut result = vec![];
+    let _ = serde_ignored::deserialize::<_, _, T>(raw, |path| result.push(dotted_path(&path)));
+    result
+}
+
+fn dotted_path(path: &serde_ignored::Path) -> String {
+    let (parent, segment) = match path {
+        serde_ignored::Path::Root => return String::new(),
+        serde_ignored::Path::Seq { parent, index } => (parent, index.to_string()),
+        serde_ignored::Path::Map { parent, key } => (parent, key.clone()),
+        serde_ignored::Path::Some { parent }
+        | serde_ignored::Path::NewtypeStruct { parent }
+        | serde_ignored::Path::NewtypeVariant { parent } => return dotted_path(parent),
+    };
+    match dotted_path(parent) {
+        prefix if prefix.is_empty() => segment,
+        prefix => format!("{}.{}", prefix, segment),
+    }
+}
+
+/// Scan a single directory for spec files (added to `result`), returns the subdirectories that
+/// should be scanned as well.
+async fn collect_spec_files(
+    spec_dir: &SpecDir,
+    ignore: &SpecIgnore,
+    visited: &mut HashSet<PathBuf>,
+    result: &mut BTreeMap<String, PathBuf>,
+    warnings: &mut Vec<String>,
+) -> Vec<SpecDir> {
+    let dir = &spec_dir.path;
+    let id_prefix = &spec_dir.id_prefix;
+    let mut subdirs = vec![];
+    match fs::canonicalize(dir).await {
+        Ok(canonical) => {
+            if !visited.insert(canonical) {
+                warn!("Not following directory twice: {}", dir.display());
+                return subdirs;
+            }
+        }
+        Err(e) => {
+            warn!("Can't resolve directory '{}': {}", dir.display(), e);
+            return subdirs;
+        }
+    }
+
+    let mut entries = match fs::read_dir(dir).await {
+        Ok(entries) => entries,
+        Err(e) => {
+            warn!("Can't read directory '{}': {}", dir.display(), e);
+            return subdirs;
+        }
+    };
+
+    let mut paths: Vec<PathBuf> = vec![];
+    loop {
+        match entries.next_entry().await {
+            Ok(Some(entry)) => paths.push(entry.path()),
+            Ok(None) => break,
+            Err(e) => {
+                let warning = format!("Can't read all entries of directory '{}': {}", dir.display(), e);
+                warn!("{}", warning);
+                warnings.push(warning);
+                break;
+            }
+        }
+    }
+    paths.sort();
+
+    for path in paths {
+        let file_name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_string(),
+            None => continue,
+        };
+        if file_name.starts_with('.') {
+            continue;
+        }
+
+        // follows symlinks, broken ones are skipped
+        let metadata = match fs::metadata(&path).await {
+            Ok(metadata) => metadata,
+            Err(_) => continue,
+        };
+        let is_dir = metadata.is_dir();
+        let ignored = ignore.is_ignored(&path, is_dir, spec_dir.ignored);
+        if is_dir {
+            // ignored directories only need to be scanned if paths in them can be re-included
+            if ignored && !ignore.has_negations() {
+                debug!("Ignoring path: {}", path.display());
+                continue;
+            }
+            subdirs.push(SpecDir {
+                path,
+                id_prefix: format!("{}{}/", id_prefix, file_name),
+                ignored,
+            });
+        } else if ignored {
+            debug!("Ignoring path: {}", path.display());
+            continue;
+        } else if metadata.is_file() {
+            let is_spec_file = match path.extension() {
+                Some(ext) => {
+                    let ext = ext.to_string_lossy().to_lowercase();
+                    SPEC_FILE_EXTENSIONS.contains(&ext.as_str())
+                }
+                None => false,
+            };
+            if !is_spec_file {
+                debug!("Ignoring non-spec file: {}", path.display());
+                continue;
+            }
+            let stem = path.file_stem().unwrap().to_string_lossy();
+            let id = format!("{}{}", id_prefix, stem);
+            if result.contains_key(&id) {
+                let warning = format!("Duplicate spec for '{}', ignoring: {}", id, path.display());
+                warn!("{}", warning);
+                warnings.push(warning);
+                continue;
+            }
+            result.insert(id, path);
+        }
+    }
+    subdirs
+}
+
 pub(crate) async fn read_models_spec<T: DeserializeOwned>(
     specs_file: &PathBuf,
 ) -> Result<BTreeMap<String, T>> {
@@ -57,9 +401,28 @@ pub(crate) async fn write_models_spec<T: Serialize>(
     specs_file: &PathBuf,
     specs: &BTreeMap<String, T>,
 ) -> Result<()> {
-    let mut file = File::create(specs_file).await?;
     let specs_data = serde_json::to_string_pretty(specs)?;
-    file.write_all(specs_data.as_bytes()).await?;
+    write_spec_data(specs_file, &specs_data).await
+}
+
+/// The temporary file a spec file is written to, before it is moved into place.
+pub(crate) fn tmp_spec_path(spec_file: &Path) -> PathBuf {
+    let file_name = spec_file.file_name().unwrap_or_default().to_string_lossy();
+    spec_file.with_file_name(format!(".{}.viva-tmp", file_name))
+}
+
+/// (Over-)write a spec file, so it either has its previous or its new content, also if viva is
+/// interrupted: the data is written to a temporary file, which is then renamed.
+async fn write_spec_data(spec_file: &Path, data: &str) -> Result<()> {
+    let _journal = crate::journal::begin_write_spec(spec_file, data)?;
+    let tmp_path = tmp_spec_path(spec_file);
+    let mut file = File::create(&tmp_path).await?;
+    file.write_all(data.as_bytes()).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&tmp_path, spec_file)
+        .await
+        .with_context(|| format!("Failed to write spec file: {}", spec_file.display()))?;
     Ok(())
 }
 
@@ -215,14 +578,96 @@ pub(crate) async fn write_model_spec<T: Serialize>(
         None => "json"
     };
 
-    if ext == "yaml" {
-        let mut file = File::create(model_spec_file).await?;
-        let model_spec_data = serde_yaml::to_string(model_spec)?;
-        file.write_all(model_spec_data.as_bytes()).await?;
+    let model_spec_data = if ext == "yaml" {
+        serde_yaml::to_string(model_spec)?
     } else {
-        let mut file = File::create(model_spec_file).await?;
-        let model_spec_data = serde_json::to_string(model_spec)?;
-        file.write_all(model_spec_data.as_bytes()).await?;
+        serde_json::to_string(model_spec)?
+    };
+    write_spec_data(model_spec_file, &model_spec_data).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::environment::VivaEnvSpec;
+
+    #[test]
+    fn test_find_unknown_fields() {
+        let raw: serde_json::Value = serde_yaml::from_str(
+            "python:\n  channels: [conda-forge]\n  pkg_specs: [python]\n  pip_specs: []\n  env_vars: {}\n  pkg_spec: [numpy]\n  provenance:\n    chanels: {}\n",
+        )
+        .unwrap();
+        let unknown = find_unknown_fields::<BTreeMap<String, VivaEnvSpec>>(&raw);
+        assert_eq!(unknown, vec!["python.pkg_spec", "python.provenance.chanels"]);
+    }
+
+    #[test]
+    fn test_parse_spec_with_unknown_fields() {
+        let (spec, unknown) = parse_spec_with_unknown_fields::<VivaEnvSpec>(
+            Path::new("python.yaml"),
+            "channels: [conda-forge]\npkg_specs: [python]\nenv_vars:\n  PORT: 8080\npkg_spec: [numpy]\n",
+        )
+        .unwrap();
+        assert_eq!(spec.pkg_specs, vec!["python"]);
+        assert_eq!(spec.env_vars["PORT"], "8080");
+        assert_eq!(unknown, vec!["pkg_spec"]);
+
+        let (_, unknown) = parse_spec_with_unknown_fields::<VivaEnvSpec>(
+            Path::new("python.json"),
+            r#"{"channels": [], "pkg_specs": [], "chanels": []}"#,
+        )
+        .unwrap();
+        assert_eq!(unknown, vec!["chanels"]);
+        assert!(parse_spec_with_unknown_fields::<VivaEnvSpec>(Path::new("python.json"), "{} {}").is_err());
+        assert!(parse_spec_with_unknown_fields::<VivaEnvSpec>(Path::new("python.yaml"), "channels: [").is_err());
+    }
+
+    #[test]
+    fn test_ignore_in_ignored_dir() {
+        let base_dir = PathBuf::from("/config");
+        let ignore = SpecIgnore::parse(&base_dir, "envs/drafts/\n*~\n!envs/drafts/keep.yaml\n").unwrap();
+        assert!(ignore.is_ignored(&base_dir.join("envs/drafts"), true, false));
+        assert!(ignore.is_ignored(&base_dir.join("envs/drafts/other.yaml"), false, true));
+        assert!(!ignore.is_ignored(&base_dir.join("envs/drafts/keep.yaml"), false, true));
+        assert!(ignore.is_ignored(&base_dir.join("envs/foo.yaml~"), false, false));
+        assert!(!ignore.is_ignored(&base_dir.join("envs/foo.yaml"), false, false));
+    }
+
+    #[tokio::test]
+    async fn test_find_spec_files_reincluded() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base_dir = tmp.path();
+        let envs_dir = base_dir.join("envs");
+        std::fs::create_dir_all(envs_dir.join("drafts")).unwrap();
+        for file in ["foo.yaml", "drafts/other.yaml", "drafts/keep.yaml"] {
+            std::fs::write(envs_dir.join(file), "channels: []\npkg_specs: []\n").unwrap();
+        }
+
+        let ignore = SpecIgnore::parse(base_dir, "envs/drafts/\n!envs/drafts/keep.yaml\n").unwrap();
+        let mut warnings = vec![];
+        let spec_files = find_spec_files(&envs_dir, &ignore, &mut warnings).await;
+        assert_eq!(spec_files.keys().collect::<Vec<_>>(), vec!["drafts/keep", "foo"]);
+
+        let ignore = SpecIgnore::parse(base_dir, "envs/drafts/\n").unwrap();
+        let spec_files = find_spec_files(&envs_dir, &ignore, &mut warnings).await;
+        assert_eq!(spec_files.keys().collect::<Vec<_>>(), vec!["foo"]);
+    }
+
+    #[tokio::test]
+    async fn test_find_spec_files_reincluded_nested() {
+        let tmp = tempfile::tempdir().unwrap();
+        let base_dir = tmp.path();
+        let envs_dir = base_dir.join("envs");
+        std::fs::create_dir_all(envs_dir.join("drafts/team")).unwrap();
+        for file in ["drafts/other.yaml", "drafts/team/other.yaml", "drafts/team/keep.yaml"] {
+            std::fs::write(envs_dir.join(file), "channels: []\npkg_specs: []\n").unwrap();
+        }
+
+        // the file is re-included, although its parent's parent is ignored
+        let ignore = SpecIgnore::parse(base_dir, "envs/drafts/\n!envs/drafts/team/keep.yaml\n").unwrap();
+        let mut warnings = vec![];
+        let spec_files = find_spec_files(&envs_dir, &ignore, &mut warnings).await;
+        assert_eq!(spec_files.keys().collect::<Vec<_>>(), vec!["drafts/team/keep"]);
+        assert!(warnings.is_empty());
     }
-    Ok(())
 }
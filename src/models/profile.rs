@@ -0,0 +1,124 @@
+use crate::models::app::{AppCollection, DefaultAppCollection, VivaAppManifestEntry};
+use crate::models::environment::{DefaultEnvCollection, EnvironmentCollection, VivaEnvSpec};
+use crate::models::read_model_spec;
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+
+/// A provisioning profile, describing the desired viva state of a machine in a single file.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ProvisioningProfile {
+    /// Additional collections (id -> config directory) to load envs and apps from.
+    #[serde(default)]
+    pub collections: BTreeMap<String, PathBuf>,
+    /// Environments that should be registered and synced.
+    #[serde(default)]
+    pub envs: BTreeMap<String, VivaEnvSpec>,
+    /// Apps that should be registered.
+    #[serde(default)]
+    pub apps: BTreeMap<String, VivaAppManifestEntry>,
+    /// Ids of apps that should be exposed via shims.
+    #[serde(default)]
+    pub shims: Vec<String>,
+}
+
+impl ProvisioningProfile {
+    pub async fn read(profile_file: &PathBuf) -> Result<ProvisioningProfile> {
+        read_model_spec(profile_file).await
+    }
+
+    /// Create a profile from a collection config directory, containing all of its envs and apps.
+    pub async fn from_collection_path(config_path: &PathBuf) -> Result<ProvisioningProfile> {
+        let mut profile = ProvisioningProfile::default();
+
+        let env_collection = DefaultEnvCollection::create(config_path.clone()).await?;
+        for env_id in env_collection.get_env_ids().await {
+            let env_spec = env_collection.get_env(&env_id).await?;
+            profile.envs.insert(env_id, env_spec.clone());
+        }
+
+        let app_collection = DefaultAppCollection::create(config_path.clone()).await?;
+        for app_id in app_collection.get_app_ids().await {
+            let app_spec = app_collection.get_app(&app_id).await?;
+            profile.apps.insert(
+                app_id,
+                VivaAppManifestEntry {
+                    spec: app_spec.clone(),
+                    placement: None,
+                },
+            );
+        }
+
+        Ok(profile)
+    }
+
+    /// Read a profile file, or create a profile from a collection, if the path is a directory.
+    pub async fn read_or_collect(path: &PathBuf) -> Result<ProvisioningProfile> {
+        match path.is_dir() {
+            true => ProvisioningProfile::from_collection_path(path).await,
+            false => ProvisioningProfile::read(path).await,
+        }
+    }
+}
+
+/// The state of a single environment or app, compared to its declared spec.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DriftStatus {
+    Ok,
+    Missing,
+    SpecMismatch,
+    NotSynced,
+    EnvMissing,
+}
+
+/// A machine-readable report of how the current state deviates from a profile.
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DriftReport {
+    pub in_sync: bool,
+    pub envs: BTreeMap<String, DriftStatus>,
+    pub apps: BTreeMap<String, DriftStatus>,
+    /// The content hashes of the environment specs, as expected by the profile, and as registered.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub env_hashes: BTreeMap<String, SpecHashes>,
+}
+
+/// The content hashes (see [`VivaEnvSpec::content_hash`]) of an environment spec in a drift report.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct SpecHashes {
+    pub expected: String,
+    /// The hash of the registered spec, if the environment is registered.
+    pub actual: Option<String>,
+}
+
+/// A single step needed to bring the current state in line with a provisioning profile.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProvisionAction {
+    AddCollection(String),
+    RegisterEnv(String),
+    UpdateEnv(String),
+    RegisterApp(String),
+    RemoveApp(String),
+    RemoveEnv(String),
+    InstallShim(String),
+    RemoveShim(String),
+    SyncEnv(String),
+}
+
+impl fmt::Display for ProvisionAction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ProvisionAction::AddCollection(id) => write!(f, "+ add collection: {}", id),
+            ProvisionAction::RegisterEnv(id) => write!(f, "+ register environment: {}", id),
+            ProvisionAction::UpdateEnv(id) => write!(f, "~ update environment spec: {}", id),
+            ProvisionAction::RegisterApp(id) => write!(f, "+ register app: {}", id),
+            ProvisionAction::RemoveApp(id) => write!(f, "- remove app: {}", id),
+            ProvisionAction::RemoveEnv(id) => write!(f, "- remove environment: {}", id),
+            ProvisionAction::InstallShim(id) => write!(f, "+ install shim for app: {}", id),
+            ProvisionAction::RemoveShim(id) => write!(f, "- remove shim of app: {}", id),
+            ProvisionAction::SyncEnv(id) => write!(f, "~ resync environment: {}", id),
+        }
+    }
+}
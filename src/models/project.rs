@@ -0,0 +1,223 @@
+use crate::defaults::{LOCKFILE_EXTENSION, PROJECT_DIRNAME, PROJECT_ENVS_FILENAME, PROJECT_SPEC_FILENAME};
+use crate::models::environment::{EnvironmentCollection, VivaEnvSpec};
+use crate::models::{read_model_spec, write_model_spec, SPEC_FILE_EXTENSIONS};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::debug;
+
+/// A project-local spec file, that selects the environment viva uses for commands run in (a
+/// subdirectory of) the project directory, if no environment is specified explicitly.
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+pub struct ProjectSpec {
+    /// The id of the environment (defaults to the name of the project directory).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_id: Option<String>,
+    /// An (optional) spec that is merged into the environment.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub env_spec: Option<VivaEnvSpec>,
+}
+
+/// The environment to use if none is specified explicitly, and where that choice came from.
+#[derive(Debug, Clone)]
+pub struct DefaultEnv {
+    pub env_id: String,
+    /// A human readable description of where the env id came from.
+    pub source: String,
+    /// The spec to merge into the environment (from a project spec), if any.
+    pub env_spec: Option<VivaEnvSpec>,
+}
+
+impl ProjectSpec {
+    /// Find the project spec file in the provided directory or one of its parents, and read it.
+    pub async fn find(start_dir: &Path) -> Result<Option<(PathBuf, ProjectSpec)>> {
+        for dir in start_dir.ancestors() {
+            for extension in SPEC_FILE_EXTENSIONS {
+                let spec_file = dir.join(format!("{}.{}", PROJECT_SPEC_FILENAME, extension));
+                if spec_file.is_file() {
+                    let project_spec: ProjectSpec = read_model_spec(&spec_file).await?;
+                    return Ok(Some((spec_file, project_spec)));
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The id of the project environment, for a project spec found at the provided path.
+    pub fn get_env_id(&self, spec_file: &Path) -> String {
+        match &self.env_id {
+            Some(env_id) => env_id.clone(),
+            None => spec_file
+                .parent()
+                .and_then(|dir| dir.file_name())
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_else(|| String::from("default")),
+        }
+    }
+}
+
+/// The environments a project declares (in a `viva.yaml` in the project directory, or in
+/// `.viva/envs.yaml`).
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct ProjectEnvsSpec {
+    /// The environment to use in the project if none is specified explicitly (defaults to the
+    /// only environment, if there is just one).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default_env: Option<String>,
+    /// The environments of the project (id -> spec).
+    #[serde(default)]
+    pub envs: BTreeMap<String, VivaEnvSpec>,
+}
+
+impl ProjectEnvsSpec {
+    /// The id of the environment to use in the project if none is specified explicitly, if any.
+    pub fn get_default_env_id(&self) -> Option<String> {
+        match &self.default_env {
+            Some(env_id) => Some(env_id.clone()),
+            None if self.envs.len() == 1 => self.envs.keys().next().cloned(),
+            None => None,
+        }
+    }
+}
+
+/// An environment collection backed by the environments file of a project, found in the current
+/// directory or one of its parents (like git finds its repository).
+///
+/// The prefixes of project environments live in the project (`.viva/envs/<id>`), as do their
+/// lockfiles (`.viva/<id>.lock`), which can be committed along with the environments file.
+#[derive(Debug)]
+pub struct ProjectEnvCollection {
+    project_dir: PathBuf,
+    envs_file: PathBuf,
+    project: ProjectEnvsSpec,
+}
+
+impl ProjectEnvCollection {
+    pub async fn create(envs_file: PathBuf) -> Result<Self> {
+        let project_dir = Self::project_dir_of(&envs_file);
+        let mut collection = ProjectEnvCollection {
+            project_dir,
+            envs_file,
+            project: ProjectEnvsSpec::default(),
+        };
+        collection.load().await?;
+        Ok(collection)
+    }
+
+    /// Find the environments file of a project in the provided directory or one of its parents.
+    pub fn find(start_dir: &Path) -> Option<PathBuf> {
+        for dir in start_dir.ancestors() {
+            for extension in SPEC_FILE_EXTENSIONS {
+                let candidates = [
+                    dir.join(format!("{}.{}", PROJECT_ENVS_FILENAME, extension)),
+                    dir.join(PROJECT_DIRNAME).join(format!("envs.{}", extension)),
+                ];
+                if let Some(envs_file) = candidates.into_iter().find(|path| path.is_file()) {
+                    return Some(envs_file);
+                }
+            }
+        }
+        None
+    }
+
+    /// The project directory of an environments file (`.viva/envs.yaml` belongs to the parent of
+    /// the `.viva` directory).
+    fn project_dir_of(envs_file: &Path) -> PathBuf {
+        let dir = envs_file.parent().unwrap_or_else(|| Path::new("."));
+        match dir.file_name() {
+            Some(name) if name == PROJECT_DIRNAME => dir.parent().unwrap_or(dir).to_path_buf(),
+            _ => dir.to_path_buf(),
+        }
+    }
+
+    pub fn get_project_dir(&self) -> &Path {
+        &self.project_dir
+    }
+
+    pub fn get_project(&self) -> &ProjectEnvsSpec {
+        &self.project
+    }
+
+    async fn load(&mut self) -> Result<()> {
+        debug!("Loading project environments: {}", self.envs_file.display());
+        let mut project: ProjectEnvsSpec = read_model_spec(&self.envs_file).await?;
+        let source = self.envs_file.display().to_string();
+        for env_spec in project.envs.values_mut() {
+            env_spec.record_provenance(&source);
+        }
+        self.project = project;
+        Ok(())
+    }
+
+    async fn write(&mut self) -> Result<()> {
+        // the file is committed with the project, entries recorded as coming from itself are noise
+        let source = self.envs_file.display().to_string();
+        let mut project = self.project.clone();
+        for env_spec in project.envs.values_mut() {
+            *env_spec = env_spec.normalized();
+            env_spec.provenance.channels.retain(|_, entry_source| entry_source != &source);
+            env_spec.provenance.pkg_specs.retain(|_, entry_source| entry_source != &source);
+        }
+        write_model_spec(&self.envs_file, &project).await?;
+        self.load().await
+    }
+}
+
+#[async_trait]
+impl EnvironmentCollection for ProjectEnvCollection {
+    async fn get_env_ids(&self) -> Vec<String> {
+        self.project.envs.keys().cloned().collect()
+    }
+
+    async fn get_env(&self, env_id: &str) -> Result<&VivaEnvSpec> {
+        self.project
+            .envs
+            .get(env_id)
+            .ok_or(anyhow!("No env found with name: {}", env_id))
+    }
+
+    async fn delete_env(&mut self, env_id: &str) -> Result<()> {
+        if self.project.envs.remove(env_id).is_some() {
+            if self.project.default_env.as_deref() == Some(env_id) {
+                self.project.default_env = None;
+            }
+            self.write().await?;
+        }
+        if let Some(lockfile_path) = self.get_lockfile_path(env_id) {
+            if lockfile_path.exists() {
+                fs::remove_file(&lockfile_path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_env(&mut self, env_id: &str, env_spec: &VivaEnvSpec) -> Result<()> {
+        self.project
+            .envs
+            .insert(String::from(env_id), env_spec.clone());
+        self.write().await
+    }
+
+    async fn reload(&mut self) -> Result<()> {
+        self.load().await
+    }
+
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![self.envs_file.clone()]
+    }
+
+    fn get_lockfile_path(&self, env_id: &str) -> Option<PathBuf> {
+        Some(
+            self.project_dir
+                .join(PROJECT_DIRNAME)
+                .join(format!("{}.{}", env_id, LOCKFILE_EXTENSION)),
+        )
+    }
+
+    fn get_env_path(&self, env_id: &str) -> Option<PathBuf> {
+        Some(self.project_dir.join(PROJECT_DIRNAME).join("envs").join(env_id))
+    }
+}
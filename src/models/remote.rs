@@ -0,0 +1,708 @@
+// Environment collections a team publishes at a url, so everyone can pull the same catalog of
+// environments: either an index file served over http(s) (or from a `file` url), which lists the
+// spec files of the collection relative to itself, or a git repository that contains spec files
+// (and optionally an index).
+//
+// Remote collections are read-only. They are cached in the viva data dir, so they keep working
+// offline, and the cache is refreshed when it is older than the refresh interval of the collection.
+// Indexes can be signed with minisign (`index.yaml.minisig`, with the public key in `minisign.pub`
+// next to the index), the signing key is pinned on first use (see the `signing` module). A signed
+// index has to list the sha256 checksum of every file of the collection, and each file is checked
+// against it when it's fetched.
+
+use crate::auth::ChannelAuth;
+use crate::config::VivaConfig;
+use crate::defaults::{
+    COLLECTION_INDEX_FILENAME, COLLECTION_KEY_FILENAME, LOCKFILE_EXTENSION, REMOTE_COLLECTIONS_DIRNAME,
+    REMOTE_COLLECTIONS_FILENAME, TRUSTED_KEYS_FILENAME,
+};
+use crate::models::environment::{EnvironmentCollection, VivaEnvSpec};
+use crate::models::{
+    find_spec_files, read_model_spec, read_models_spec, read_spec_files, write_models_spec, SpecIgnore,
+    SPEC_FILE_EXTENSIONS,
+};
+use crate::signing::{sha256_hex, KeyTrust, TrustStore};
+use anyhow::{anyhow, bail, Context, Result};
+use async_trait::async_trait;
+use reqwest::header::AUTHORIZATION;
+use reqwest::{Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Component, Path, PathBuf};
+use std::process::Stdio;
+use std::time::{Duration, SystemTime};
+use tokio::fs;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// How old the cache of a remote collection can get, if the collection doesn't configure it.
+pub const DEFAULT_REFRESH_INTERVAL: &str = "1d";
+
+/// The file (in the cache directory of a remote collection) that is written after every successful
+/// refresh, its modification time is the time of the last refresh.
+const REFRESHED_MARKER: &str = ".refreshed";
+
+/// A remote collection, as registered with 'collection add'.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct RemoteCollectionConfig {
+    /// The url of the index file, or of the git repository (`git+https://...`, `git@host:repo`, or
+    /// any url that ends with `.git`).
+    pub url: String,
+    /// How old the cached specs can get before they are refreshed (e.g. `30m`, `12h`, `7d`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub refresh_interval: Option<String>,
+}
+
+/// The index of a remote collection (environment name -> spec file, relative to the index).
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+struct CollectionIndex {
+    #[serde(default)]
+    envs: BTreeMap<String, String>,
+    /// The sha256 checksums of the files of the collection (path relative to the index -> hex
+    /// digest), required for all spec files and lockfiles if the index is signed.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    sha256: BTreeMap<String, String>,
+}
+
+impl CollectionIndex {
+    /// Check a file of the collection against its checksum in the index.
+    ///
+    /// Files of a signed index have to have a checksum (the signature only covers the index),
+    /// files of an unsigned one are only checked if they have one.
+    fn verify_file(&self, relative: &Path, content: &[u8], signed: bool) -> Result<()> {
+        let key = slash_path(relative);
+        match self.sha256.get(&key) {
+            Some(expected) => {
+                let actual = sha256_hex(content);
+                if !expected.eq_ignore_ascii_case(&actual) {
+                    bail!(
+                        "Checksum of collection file '{}' doesn't match the index (expected sha256 {}, got {}), not trusting it",
+                        key,
+                        expected,
+                        actual
+                    );
+                }
+                Ok(())
+            }
+            None if signed => bail!(
+                "Collection file '{}' has no sha256 checksum in the signed index, not trusting it",
+                key
+            ),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Parse an interval like `90s`, `30m`, `12h` or `7d`.
+pub fn parse_interval(interval: &str) -> Result<Duration> {
+    let interval = interval.trim();
+    let split = interval
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(interval.len());
+    let (value, unit) = interval.split_at(split);
+    let value: u64 = value
+        .parse()
+        .map_err(|_| anyhow!("Invalid interval '{}' (e.g. '30m', '12h', '7d')", interval))?;
+    let seconds = match unit.trim() {
+        "s" => value,
+        "m" => value * 60,
+        "h" => value * 60 * 60,
+        "d" => value * 60 * 60 * 24,
+        _ => bail!("Invalid interval '{}' (e.g. '30m', '12h', '7d')", interval),
+    };
+    Ok(Duration::from_secs(seconds))
+}
+
+/// Whether a collection url points to a git repository (instead of an index file).
+fn is_git_url(url: &str) -> bool {
+    url.starts_with("git+") || url.starts_with("git@") || url.starts_with("ssh://") || url.trim_end_matches('/').ends_with(".git")
+}
+
+/// The relative path of a spec file listed in an index, which has to stay inside the collection.
+fn checked_relative_path(path: &str) -> Result<PathBuf> {
+    let relative = Path::new(path);
+    let inside = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir));
+    if path.is_empty() || path.contains("://") || !inside {
+        bail!("Invalid spec file path in collection index (has to be relative, and inside the collection): {}", path);
+    }
+    Ok(relative.to_path_buf())
+}
+
+/// A relative path with `/` separators, as used in indexes and git revisions.
+fn slash_path(relative: &Path) -> String {
+    relative
+        .components()
+        .map(|component| component.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// The index file in a directory, if there is one.
+fn find_index(dir: &Path) -> Option<PathBuf> {
+    SPEC_FILE_EXTENSIONS
+        .iter()
+        .map(|ext| dir.join(format!("{}.{}", COLLECTION_INDEX_FILENAME, ext)))
+        .find(|path| path.is_file())
+}
+
+/// Download a file of a remote collection, `None` if it doesn't exist.
+async fn download(url: &Url, auth: &ChannelAuth, client: &Client) -> Result<Option<Vec<u8>>> {
+    if url.scheme() == "file" {
+        let path = url
+            .to_file_path()
+            .map_err(|_| anyhow!("Invalid file url: {}", url))?;
+        return match fs::read(&path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e).with_context(|| format!("Failed to read: {}", path.display())),
+        };
+    }
+    // the authenticated url can contain a token, so it's not used in messages
+    let (authenticated_url, authorization) = auth.authenticate(url.clone())?;
+    let mut request = client.get(authenticated_url);
+    if let Some(authorization) = authorization {
+        request = request.header(AUTHORIZATION, authorization);
+    }
+    let response = request
+        .send()
+        .await
+        .with_context(|| format!("Failed to download: {}", url))?;
+    if response.status() == StatusCode::NOT_FOUND {
+        return Ok(None);
+    }
+    let response = response
+        .error_for_status()
+        .with_context(|| format!("Failed to download: {}", url))?;
+    Ok(Some(response.bytes().await?.to_vec()))
+}
+
+/// Run git, and return its output.
+async fn git(dir: Option<&Path>, args: &[&str]) -> Result<Vec<u8>> {
+    let mut command = Command::new("git");
+    if let Some(dir) = dir {
+        command.arg("-C").arg(dir);
+    }
+    debug!("Running: git {}", args.join(" "));
+    let output = command
+        .args(args)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("Failed to run 'git', is it installed?")?;
+    if !output.status.success() {
+        bail!(
+            "'git {}' failed: {}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(output.stdout)
+}
+
+/// A file at a revision of a git checkout, `None` if it doesn't exist.
+async fn git_file(checkout: &Path, revision: &str, path: &str) -> Option<Vec<u8>> {
+    git(Some(checkout), &["show", &format!("{}:{}", revision, path)])
+        .await
+        .ok()
+}
+
+/// A read-only environment collection, fetched from a url and cached locally.
+///
+/// The ids of its environments are prefixed with the collection id (`<collection>/<env>`), like
+/// the ones of workspaces.
+#[derive(Debug)]
+pub struct RemoteEnvCollection {
+    collection_id: String,
+    remote: RemoteCollectionConfig,
+    cache_dir: PathBuf,
+    trust_store_path: PathBuf,
+    config: VivaConfig,
+    envs: BTreeMap<String, VivaEnvSpec>,
+    spec_files: BTreeMap<String, PathBuf>,
+    invalid_envs: BTreeMap<String, String>,
+    warnings: Vec<String>,
+}
+
+impl RemoteEnvCollection {
+    /// Load a remote collection from its cache, which is refreshed first if it is outdated.
+    ///
+    /// Failing to refresh only results in a warning (the cached specs are used, if there are any).
+    pub async fn create(
+        collection_id: &str,
+        remote: RemoteCollectionConfig,
+        data_dir: &Path,
+        config: &VivaConfig,
+    ) -> Result<Self> {
+        let mut collection = RemoteEnvCollection {
+            collection_id: String::from(collection_id),
+            remote,
+            cache_dir: Self::cache_dir(data_dir, collection_id),
+            trust_store_path: data_dir.join(TRUSTED_KEYS_FILENAME),
+            config: config.clone(),
+            envs: BTreeMap::new(),
+            spec_files: BTreeMap::new(),
+            invalid_envs: BTreeMap::new(),
+            warnings: Vec::new(),
+        };
+        collection.load(false).await?;
+        Ok(collection)
+    }
+
+    /// The directory a remote collection is cached in.
+    pub fn cache_dir(data_dir: &Path, collection_id: &str) -> PathBuf {
+        data_dir.join(REMOTE_COLLECTIONS_DIRNAME).join(collection_id)
+    }
+
+    /// The registered remote collections (collection id -> remote).
+    /// The registered remote collections (none, if no remote collection was ever added).
+    pub async fn read_registry(config_dir: &Path) -> Result<BTreeMap<String, RemoteCollectionConfig>> {
+        let registry_file = config_dir.join(REMOTE_COLLECTIONS_FILENAME);
+        match registry_file.exists() {
+            true => read_models_spec(&registry_file).await,
+            false => Ok(BTreeMap::new()),
+        }
+    }
+
+    pub async fn write_registry(
+        config_dir: &Path,
+        collections: &BTreeMap<String, RemoteCollectionConfig>,
+    ) -> Result<()> {
+        write_models_spec(&config_dir.join(REMOTE_COLLECTIONS_FILENAME), collections).await
+    }
+
+    pub fn get_remote(&self) -> &RemoteCollectionConfig {
+        &self.remote
+    }
+
+    /// When the cached specs of a remote collection were fetched the last time, if ever.
+    pub fn refreshed_at(data_dir: &Path, collection_id: &str) -> Option<SystemTime> {
+        std::fs::metadata(Self::cache_dir(data_dir, collection_id).join(REFRESHED_MARKER))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// When the cached specs were fetched the last time, if ever.
+    pub fn last_refreshed(&self) -> Option<SystemTime> {
+        std::fs::metadata(self.cache_dir.join(REFRESHED_MARKER))
+            .and_then(|metadata| metadata.modified())
+            .ok()
+    }
+
+    /// Fetch the specs of the collection (even if the cache is up to date), and re-read them.
+    pub async fn refresh(&mut self) -> Result<()> {
+        self.load(true).await
+    }
+
+    fn content_dir(&self) -> PathBuf {
+        self.cache_dir.join("content")
+    }
+
+    fn env_id(&self, env_name: &str) -> String {
+        format!("{}/{}", self.collection_id, env_name)
+    }
+
+    fn needs_refresh(&self) -> Result<bool> {
+        let interval = parse_interval(
+            self.remote
+                .refresh_interval
+                .as_deref()
+                .unwrap_or(DEFAULT_REFRESH_INTERVAL),
+        )?;
+        Ok(match self.last_refreshed() {
+            Some(refreshed) => refreshed.elapsed().map(|age| age > interval).unwrap_or(true),
+            None => true,
+        })
+    }
+
+    async fn load(&mut self, force_refresh: bool) -> Result<()> {
+        let mut warnings = vec![];
+        if force_refresh || self.needs_refresh()? {
+            let result = match self.config.offline {
+                true => Err(anyhow!("not available in offline mode")),
+                false => self.fetch().await,
+            };
+            if let Err(e) = result {
+                let e = e.context(format!("Failed to fetch remote collection '{}'", self.collection_id));
+                if force_refresh {
+                    return Err(e);
+                }
+                let message = match self.content_dir().exists() {
+                    true => format!("{:#} (using the cached specs)", e),
+                    false => format!("{:#}", e),
+                };
+                // offline, the cache is expected to be used, that's not worth a warning
+                if !(self.config.offline && self.content_dir().exists()) {
+                    warn!("{}", message);
+                    warnings.push(message);
+                }
+            }
+        }
+
+        let content_dir = self.content_dir();
+        let mut spec_files = BTreeMap::new();
+        let mut invalid_envs = BTreeMap::new();
+        if content_dir.is_dir() {
+            match find_index(&content_dir) {
+                Some(index_file) => {
+                    let index: CollectionIndex = read_model_spec(&index_file).await?;
+                    for (env_name, path) in index.envs {
+                        match checked_relative_path(&path) {
+                            Ok(relative) => {
+                                spec_files.insert(env_name, content_dir.join(relative));
+                            }
+                            Err(e) => {
+                                invalid_envs.insert(self.env_id(&env_name), format!("{:#}", e));
+                            }
+                        }
+                    }
+                }
+                None => {
+                    let ignore = SpecIgnore::load(&content_dir).unwrap_or_default();
+                    spec_files = find_spec_files(&content_dir, &ignore, &mut warnings).await;
+                }
+            }
+        }
+
+        let mut envs = BTreeMap::new();
+        self.spec_files = BTreeMap::new();
+        for read_spec in read_spec_files::<VivaEnvSpec>(spec_files).await {
+            let env_id = self.env_id(&read_spec.id);
+            match read_spec.result {
+                Ok((mut env_spec, _unknown_fields)) => {
+                    env_spec.record_provenance(&format!("remote collection: {}", self.remote.url));
+                    envs.insert(env_id.clone(), env_spec);
+                    self.spec_files.insert(env_id, read_spec.path);
+                }
+                Err(e) => {
+                    invalid_envs.insert(env_id, format!("{:#}", e));
+                }
+            }
+        }
+        self.envs = envs;
+        self.invalid_envs = invalid_envs;
+        self.warnings = warnings;
+        Ok(())
+    }
+
+    /// Fetch the collection into its cache directory (replacing the previous content only if
+    /// everything could be fetched, and verified).
+    async fn fetch(&self) -> Result<()> {
+        fs::create_dir_all(&self.cache_dir).await?;
+        match is_git_url(&self.remote.url) {
+            true => self.fetch_git().await?,
+            false => self.fetch_index().await?,
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs())
+            .unwrap_or_default();
+        fs::write(self.cache_dir.join(REFRESHED_MARKER), now.to_string()).await?;
+        Ok(())
+    }
+
+    /// A clean directory to fetch into, before it replaces the content directory.
+    async fn staging_dir(&self) -> Result<PathBuf> {
+        let staging_dir = self
+            .cache_dir
+            .join(format!("content.tmp-{}", std::process::id()));
+        if staging_dir.exists() {
+            fs::remove_dir_all(&staging_dir).await?;
+        }
+        Ok(staging_dir)
+    }
+
+    async fn replace_content(&self, staging_dir: &Path) -> Result<()> {
+        let content_dir = self.content_dir();
+        if content_dir.exists() {
+            fs::remove_dir_all(&content_dir).await?;
+        }
+        fs::rename(staging_dir, &content_dir).await?;
+        Ok(())
+    }
+
+    async fn fetch_index(&self) -> Result<()> {
+        let index_url = Url::parse(&self.remote.url)
+            .with_context(|| format!("Invalid collection url: {}", self.remote.url))?;
+        let auth = ChannelAuth::load(&self.config)?;
+        let client = Client::new();
+
+        let index = download(&index_url, &auth, &client)
+            .await?
+            .ok_or_else(|| anyhow!("Collection index not found: {}", index_url))?;
+        let signature_url = Url::parse(&format!("{}.minisig", index_url))?;
+        let signature = download(&signature_url, &auth, &client)
+            .await?
+            .map(String::from_utf8)
+            .transpose()?;
+        let public_key = match signature {
+            Some(_) => download(&index_url.join(COLLECTION_KEY_FILENAME)?, &auth, &client)
+                .await?
+                .map(String::from_utf8)
+                .transpose()?,
+            None => None,
+        };
+        let signed = self
+            .verify_index(Some(&index), signature.as_deref(), public_key.as_deref())
+            .await?;
+        let parsed: CollectionIndex = serde_yaml::from_slice(&index)
+            .with_context(|| format!("Invalid collection index: {}", index_url))?;
+
+        let staging_dir = self.staging_dir().await?;
+        fs::create_dir_all(&staging_dir).await?;
+        let extension = index_url
+            .path()
+            .rsplit('.')
+            .next()
+            .filter(|extension| SPEC_FILE_EXTENSIONS.contains(extension))
+            .unwrap_or("yaml");
+        fs::write(
+            staging_dir.join(format!("{}.{}", COLLECTION_INDEX_FILENAME, extension)),
+            &index,
+        )
+        .await?;
+        for path in parsed.envs.values() {
+            let relative = checked_relative_path(path)?;
+            let spec_url = index_url.join(path)?;
+            let spec = download(&spec_url, &auth, &client)
+                .await?
+                .ok_or_else(|| anyhow!("Spec file of collection not found: {}", spec_url))?;
+            parsed.verify_file(&relative, &spec, signed)?;
+            let target = staging_dir.join(&relative);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            fs::write(&target, spec).await?;
+
+            // lockfiles are optional, next to the spec files
+            let lockfile = relative.with_extension(LOCKFILE_EXTENSION);
+            let lockfile_url = index_url.join(&lockfile.to_string_lossy())?;
+            if let Some(content) = download(&lockfile_url, &auth, &client).await? {
+                parsed.verify_file(&lockfile, &content, signed)?;
+                fs::write(staging_dir.join(&lockfile), content).await?;
+            }
+        }
+        self.replace_content(&staging_dir).await
+    }
+
+    async fn fetch_git(&self) -> Result<()> {
+        let url = self
+            .remote
+            .url
+            .strip_prefix("git+")
+            .unwrap_or(&self.remote.url);
+        let checkout = self.content_dir();
+        if checkout.join(".git").is_dir() {
+            git(Some(&checkout), &["fetch", "--quiet", "--depth", "1", "origin"]).await?;
+            self.verify_git_index(&checkout, "FETCH_HEAD").await?;
+            git(Some(&checkout), &["reset", "--quiet", "--hard", "FETCH_HEAD"]).await?;
+            return Ok(());
+        }
+        let staging_dir = self.staging_dir().await?;
+        git(
+            None,
+            &["clone", "--quiet", "--depth", "1", url, &staging_dir.to_string_lossy()],
+        )
+        .await?;
+        if let Err(e) = self.verify_git_index(&staging_dir, "HEAD").await {
+            let _ = fs::remove_dir_all(&staging_dir).await;
+            return Err(e);
+        }
+        self.replace_content(&staging_dir).await
+    }
+
+    /// Verify the signature of the index at a revision of a git checkout, and the spec files and
+    /// lockfiles it lists against their checksums.
+    ///
+    /// A checkout without an index can't be verified, so it is only accepted if no key is pinned
+    /// for the collection.
+    async fn verify_git_index(&self, checkout: &Path, revision: &str) -> Result<()> {
+        for extension in SPEC_FILE_EXTENSIONS {
+            let index_path = format!("{}.{}", COLLECTION_INDEX_FILENAME, extension);
+            if let Some(index) = git_file(checkout, revision, &index_path).await {
+                let signature = git_file(checkout, revision, &format!("{}.minisig", index_path))
+                    .await
+                    .map(String::from_utf8)
+                    .transpose()?;
+                let public_key = git_file(checkout, revision, COLLECTION_KEY_FILENAME)
+                    .await
+                    .map(String::from_utf8)
+                    .transpose()?;
+                let signed = self
+                    .verify_index(Some(&index), signature.as_deref(), public_key.as_deref())
+                    .await?;
+                let parsed: CollectionIndex = serde_yaml::from_slice(&index)
+                    .with_context(|| format!("Invalid collection index: {}", index_path))?;
+                for path in parsed.envs.values() {
+                    let relative = checked_relative_path(path)?;
+                    let spec = git_file(checkout, revision, &slash_path(&relative))
+                        .await
+                        .ok_or_else(|| anyhow!("Spec file of collection not found: {}", path))?;
+                    parsed.verify_file(&relative, &spec, signed)?;
+                    let lockfile = relative.with_extension(LOCKFILE_EXTENSION);
+                    if let Some(content) = git_file(checkout, revision, &slash_path(&lockfile)).await {
+                        parsed.verify_file(&lockfile, &content, signed)?;
+                    }
+                }
+                return Ok(());
+            }
+        }
+        self.verify_index(None, None, None).await?;
+        Ok(())
+    }
+
+    /// Verify the signature of an index (if it is signed), with the key pinned for the collection,
+    /// returns whether it is signed.
+    ///
+    /// Once a key is pinned, the collection has to stay signed.
+    async fn verify_index(&self, index: Option<&[u8]>, signature: Option<&str>, public_key: Option<&str>) -> Result<bool> {
+        let mut trust_store = TrustStore::load(self.trust_store_path.clone()).await?;
+        match (index, signature) {
+            (Some(index), Some(signature)) => {
+                let public_key = public_key.ok_or_else(|| {
+                    anyhow!(
+                        "Collection '{}' is signed, but doesn't provide its public key ({})",
+                        self.collection_id,
+                        COLLECTION_KEY_FILENAME
+                    )
+                })?;
+                let trust = trust_store
+                    .verify_index(&self.collection_id, index, signature, public_key)
+                    .await?;
+                if trust == KeyTrust::FirstUse {
+                    warn!(
+                        "Pinned the signing key of collection '{}' on first use (see 'viva trust list')",
+                        self.collection_id
+                    );
+                }
+                Ok(true)
+            }
+            _ => match trust_store.get_pinned_keys().contains_key(&self.collection_id) {
+                true => bail!(
+                    "Collection '{}' is not signed, but a key is pinned for it. If it is not supposed to be signed anymore, use 'viva trust forget {}' and try again.",
+                    self.collection_id,
+                    self.collection_id
+                ),
+                false => Ok(false),
+            },
+        }
+    }
+
+    fn read_only_error(&self, env_id: &str) -> anyhow::Error {
+        anyhow!(
+            "Environment '{}' is provided by the read-only remote collection '{}' ({}), clone it to make changes: viva env clone {} <new-id>",
+            env_id,
+            self.collection_id,
+            self.remote.url,
+            env_id
+        )
+    }
+}
+
+#[async_trait]
+impl EnvironmentCollection for RemoteEnvCollection {
+    async fn get_env_ids(&self) -> Vec<String> {
+        self.envs.keys().cloned().collect()
+    }
+
+    async fn get_env(&self, env_id: &str) -> Result<&VivaEnvSpec> {
+        self.envs
+            .get(env_id)
+            .ok_or(anyhow!("No env found with name: {}", env_id))
+    }
+
+    async fn delete_env(&mut self, env_id: &str) -> Result<()> {
+        Err(self.read_only_error(env_id))
+    }
+
+    async fn set_env(&mut self, env_id: &str, _env: &VivaEnvSpec) -> Result<()> {
+        Err(self.read_only_error(env_id))
+    }
+
+    async fn get_invalid_envs(&self) -> BTreeMap<String, String> {
+        self.invalid_envs.clone()
+    }
+
+    async fn get_warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+
+    async fn reload(&mut self) -> Result<()> {
+        self.load(false).await
+    }
+
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![self.content_dir()]
+    }
+
+    fn get_lockfile_path(&self, env_id: &str) -> Option<PathBuf> {
+        // only lockfiles the collection provides, viva can't write new ones
+        let lockfile_path = self.spec_files.get(env_id)?.with_extension(LOCKFILE_EXTENSION);
+        lockfile_path.is_file().then_some(lockfile_path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("90s").unwrap(), Duration::from_secs(90));
+        assert_eq!(parse_interval("30m").unwrap(), Duration::from_secs(30 * 60));
+        assert_eq!(parse_interval("12h").unwrap(), Duration::from_secs(12 * 60 * 60));
+        assert_eq!(parse_interval("7d").unwrap(), Duration::from_secs(7 * 24 * 60 * 60));
+        assert!(parse_interval("1w").is_err());
+        assert!(parse_interval("d").is_err());
+        assert!(parse_interval("12").is_err());
+    }
+
+    #[test]
+    fn test_is_git_url() {
+        assert!(is_git_url("git+https://example.com/team/envs"));
+        assert!(is_git_url("git@github.com:team/envs.git"));
+        assert!(is_git_url("https://github.com/team/envs.git"));
+        assert!(!is_git_url("https://example.com/envs/index.yaml"));
+    }
+
+    #[test]
+    fn test_verify_collection_file() {
+        let index: CollectionIndex = serde_yaml::from_str(
+            "envs:\n  data: envs/data.yaml\nsha256:\n  envs/data.yaml: ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad\n",
+        )
+        .unwrap();
+        let spec = Path::new("envs/data.yaml");
+        index.verify_file(spec, b"abc", true).unwrap();
+        assert!(index.verify_file(spec, b"abd", false).is_err());
+
+        // files without a checksum are only accepted in unsigned indexes
+        let lockfile = spec.with_extension(LOCKFILE_EXTENSION);
+        index.verify_file(&lockfile, b"abc", false).unwrap();
+        assert!(index.verify_file(&lockfile, b"abc", true).is_err());
+    }
+
+    #[tokio::test]
+    async fn test_registry_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(RemoteEnvCollection::read_registry(dir.path()).await.unwrap().is_empty());
+
+        let remote = RemoteCollectionConfig {
+            url: String::from("https://example.com/envs/index.yaml"),
+            refresh_interval: Some(String::from("12h")),
+        };
+        let registry = BTreeMap::from([(String::from("team"), remote)]);
+        RemoteEnvCollection::write_registry(dir.path(), &registry).await.unwrap();
+        let read = RemoteEnvCollection::read_registry(dir.path()).await.unwrap();
+        assert_eq!(read["team"].url, "https://example.com/envs/index.yaml");
+        assert_eq!(read["team"].refresh_interval.as_deref(), Some("12h"));
+    }
+
+    #[test]
+    fn test_checked_relative_path() {
+        assert_eq!(checked_relative_path("envs/data.yaml").unwrap(), PathBuf::from("envs/data.yaml"));
+        assert!(checked_relative_path("../data.yaml").is_err());
+        assert!(checked_relative_path("/etc/passwd").is_err());
+        assert!(checked_relative_path("https://example.com/data.yaml").is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// Converting pip requirements files (`requirements.txt`) to environment specs.
+//
+// Most packages on PyPI are available from conda-forge under the same name, so requirements are
+// mapped to conda package specs with the same name, except for the packages in a translation table
+// of names that differ (e.g. `torch` -> `pytorch`). Version specifiers are translated to conda's
+// syntax. Requirements conda can't express (direct urls, VCS and local paths, environment markers,
+// local version labels) can only be installed with pip, editable installs are skipped.
+
+use anyhow::{anyhow, Context, Result};
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+
+/// PyPI package names (normalized, see [`normalize_name`]) whose conda-forge package has a
+/// different name.
+const CONDA_NAMES: [(&str, &str); 18] = [
+    ("torch", "pytorch"),
+    ("opencv-python", "opencv"),
+    ("opencv-python-headless", "opencv"),
+    ("opencv-contrib-python", "opencv"),
+    ("opencv-contrib-python-headless", "opencv"),
+    ("tables", "pytables"),
+    ("msgpack", "msgpack-python"),
+    ("psycopg2-binary", "psycopg2"),
+    ("psycopg-binary", "psycopg"),
+    ("tensorflow-cpu", "tensorflow"),
+    ("graphviz", "python-graphviz"),
+    ("docker", "docker-py"),
+    ("kaleido", "python-kaleido"),
+    ("pyqt5", "pyqt"),
+    ("sklearn", "scikit-learn"),
+    ("typing-extensions", "typing_extensions"),
+    ("ruamel-yaml", "ruamel.yaml"),
+    ("zope-interface", "zope.interface"),
+];
+
+static REQUIREMENT: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(r"^([A-Za-z0-9][A-Za-z0-9._-]*)\s*(\[[^\]]*\])?\s*\(?([^)]*)\)?$").expect("Invalid regex")
+});
+static VERSION_CONSTRAINT: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"^(===|==|~=|!=|<=|>=|<|>)\s*([A-Za-z0-9.*!_-]+)$").expect("Invalid regex"));
+
+/// How a single requirement can be installed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Conversion {
+    /// As a conda package, with a note if something was changed (e.g. the package name).
+    Conda { spec: String, note: Option<String> },
+    /// Only with pip, for the provided reason.
+    PipOnly { reason: String },
+}
+
+/// The result of converting a requirements file.
+#[derive(Debug, Default)]
+pub struct RequirementsImport {
+    pub pkg_specs: Vec<String>,
+    /// Requirements that can only be installed with pip (verbatim), and why.
+    pub pip_specs: Vec<(String, String)>,
+    /// Things the user should know about (translated names, skipped lines, ...).
+    pub notes: Vec<String>,
+}
+
+/// The normalized name of a PyPI package (PEP 503).
+fn normalize_name(name: &str) -> String {
+    let mut normalized = String::new();
+    let mut separator = false;
+    for c in name.chars() {
+        match c {
+            '-' | '_' | '.' => separator = true,
+            c => {
+                if separator && !normalized.is_empty() {
+                    normalized.push('-');
+                }
+                separator = false;
+                normalized.push(c.to_ascii_lowercase());
+            }
+        }
+    }
+    normalized
+}
+
+/// The name of the conda package for a PyPI package.
+fn conda_name(name: &str) -> String {
+    let normalized = normalize_name(name);
+    match CONDA_NAMES.iter().find(|(pypi, _)| *pypi == normalized) {
+        Some((_, conda)) => String::from(*conda),
+        None => name.to_lowercase(),
+    }
+}
+
+/// The upper bound of a compatible release clause (`~=1.4.2` -> `1.5`), `None` if the version
+/// can't be incremented.
+fn compatible_upper_bound(version: &str) -> Option<String> {
+    let mut parts = version
+        .split('.')
+        .map(|part| part.parse::<u64>().ok())
+        .collect::<Option<Vec<u64>>>()?;
+    if parts.len() < 2 {
+        return None;
+    }
+    parts.pop();
+    let last = parts.last_mut()?;
+    *last += 1;
+    Some(parts.iter().map(|part| part.to_string()).collect::<Vec<_>>().join("."))
+}
+
+/// Translate PEP 440 version specifiers to a conda version spec.
+fn convert_version_spec(specifiers: &str) -> Result<String> {
+    let mut constraints = vec![];
+    for specifier in specifiers.split(',').map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        let captures = VERSION_CONSTRAINT
+            .captures(specifier)
+            .ok_or_else(|| anyhow!("unsupported version specifier '{}'", specifier))?;
+        let (op, version) = (&captures[1], &captures[2]);
+        if version.contains('+') {
+            return Err(anyhow!("local version labels are not supported by conda"));
+        }
+        match op {
+            "~=" => {
+                let upper = compatible_upper_bound(version)
+                    .ok_or_else(|| anyhow!("unsupported compatible release '{}'", specifier))?;
+                constraints.push(format!(">={}", version));
+                constraints.push(format!("<{}", upper));
+            }
+            "===" => constraints.push(format!("=={}", version)),
+            // conda's fuzzy match ('=1.2' matches '1.2.*')
+            "==" if version.ends_with(".*") => constraints.push(format!("={}", version.trim_end_matches(".*"))),
+            op => constraints.push(format!("{}{}", op, version)),
+        }
+    }
+    Ok(constraints.join(","))
+}
+
+/// Convert a single requirement (without options or comments) to a conda package spec, if that's
+/// possible.
+pub fn convert_requirement(requirement: &str) -> Conversion {
+    if requirement.contains(';') {
+        return Conversion::PipOnly {
+            reason: String::from("environment markers"),
+        };
+    }
+    if requirement.contains('@') || requirement.contains("://") || requirement.starts_with('.') || requirement.starts_with('/') {
+        return Conversion::PipOnly {
+            reason: String::from("direct reference (url, VCS or path)"),
+        };
+    }
+    let captures = match REQUIREMENT.captures(requirement.trim()) {
+        Some(captures) => captures,
+        None => {
+            return Conversion::PipOnly {
+                reason: String::from("unsupported requirement syntax"),
+            }
+        }
+    };
+    let name = &captures[1];
+    let version_spec = match convert_version_spec(captures.get(3).map(|m| m.as_str()).unwrap_or("")) {
+        Ok(version_spec) => version_spec,
+        Err(e) => return Conversion::PipOnly { reason: e.to_string() },
+    };
+
+    let conda_name = conda_name(name);
+    let mut notes = vec![];
+    if normalize_name(&conda_name) != normalize_name(name) {
+        notes.push(format!("'{}' is called '{}' in conda channels", name, conda_name));
+    }
+    if let Some(extras) = captures.get(2) {
+        notes.push(format!("extras {} of '{}' are not installed", extras.as_str(), name));
+    }
+    Conversion::Conda {
+        spec: format!("{}{}", conda_name, version_spec),
+        note: match notes.is_empty() {
+            true => None,
+            false => Some(notes.join(", ")),
+        },
+    }
+}
+
+/// The logical lines of a requirements file: comments removed, continuation lines joined.
+fn logical_lines(content: &str) -> Vec<String> {
+    let mut lines = vec![];
+    let mut current = String::new();
+    for line in content.lines() {
+        let line = match line.find(" #") {
+            Some(index) => &line[..index],
+            None if line.trim_start().starts_with('#') => "",
+            None => line,
+        };
+        match line.trim_end().strip_suffix('\\') {
+            Some(continued) => current.push_str(continued),
+            None => {
+                current.push_str(line);
+                let complete = current.trim().to_string();
+                if !complete.is_empty() {
+                    lines.push(complete);
+                }
+                current.clear();
+            }
+        }
+    }
+    if !current.trim().is_empty() {
+        lines.push(current.trim().to_string());
+    }
+    lines
+}
+
+/// Convert a requirements file (including the files it references with `-r`) to conda package
+/// specs, and requirements that can only be installed with pip.
+pub async fn import_requirements(requirements_file: &Path) -> Result<RequirementsImport> {
+    let mut import = RequirementsImport::default();
+    let mut visited: HashSet<PathBuf> = HashSet::new();
+    let mut pending = vec![requirements_file.to_path_buf()];
+    while let Some(file) = pending.pop() {
+        if !visited.insert(file.clone()) {
+            continue;
+        }
+        let content = fs::read_to_string(&file)
+            .await
+            .with_context(|| format!("Failed to read requirements file: {}", file.display()))?;
+        for line in logical_lines(&content) {
+            if let Some(included) = line
+                .strip_prefix("-r ")
+                .or_else(|| line.strip_prefix("--requirement "))
+            {
+                let base_dir = file.parent().unwrap_or_else(|| Path::new("."));
+                pending.push(base_dir.join(included.trim()));
+                continue;
+            }
+            if line.starts_with("-e ") || line.starts_with("--editable ") {
+                import.notes.push(format!(
+                    "skipped editable install (run 'pip install -e' in the environment instead): {}",
+                    line
+                ));
+                continue;
+            }
+            if line.starts_with('-') {
+                import
+                    .notes
+                    .push(format!("skipped option (not supported for conda packages): {}", line));
+                continue;
+            }
+            // per-requirement options (e.g. '--hash' from pip-compile) don't apply to conda packages
+            let line = match line.split_once(" --") {
+                Some((requirement, options)) => {
+                    if !options.split(" --").all(|option| option.trim().starts_with("hash")) {
+                        import
+                            .notes
+                            .push(format!("skipped options of requirement: {}", line));
+                    }
+                    requirement.trim().to_string()
+                }
+                None => line,
+            };
+            match convert_requirement(&line) {
+                Conversion::Conda { spec, note } => {
+                    if let Some(note) = note {
+                        import.notes.push(note);
+                    }
+                    if !import.pkg_specs.contains(&spec) {
+                        import.pkg_specs.push(spec);
+                    }
+                }
+                Conversion::PipOnly { reason } => import.pip_specs.push((line, reason)),
+            }
+        }
+    }
+    Ok(import)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn conda_spec(requirement: &str) -> String {
+        match convert_requirement(requirement) {
+            Conversion::Conda { spec, .. } => spec,
+            Conversion::PipOnly { reason } => panic!("Not convertible ({}): {}", reason, requirement),
+        }
+    }
+
+    #[test]
+    fn test_convert_requirement() {
+        assert_eq!(conda_spec("numpy"), "numpy");
+        assert_eq!(conda_spec("NumPy>=1.20, <2"), "numpy>=1.20,<2");
+        assert_eq!(conda_spec("torch==2.1.0"), "pytorch==2.1.0");
+        assert_eq!(conda_spec("requests[socks] ~= 2.28.1"), "requests>=2.28.1,<2.29");
+        assert_eq!(conda_spec("pandas==2.1.*"), "pandas=2.1");
+        assert_eq!(conda_spec("typing_extensions"), "typing_extensions");
+        assert!(matches!(
+            convert_requirement("pywin32; sys_platform == 'win32'"),
+            Conversion::PipOnly { .. }
+        ));
+        assert!(matches!(
+            convert_requirement("mylib @ git+https://example.com/mylib.git"),
+            Conversion::PipOnly { .. }
+        ));
+        assert!(matches!(convert_requirement("torch==2.1.0+cpu"), Conversion::PipOnly { .. }));
+    }
+
+    #[test]
+    fn test_logical_lines() {
+        let content = "# comment\nnumpy  # inline\n\npandas \\\n  >=2\n--index-url https://example.com\n";
+        assert_eq!(
+            logical_lines(content),
+            vec!["numpy", "pandas   >=2", "--index-url https://example.com"]
+        );
+    }
+
+    #[test]
+    fn test_normalize_name() {
+        assert_eq!(normalize_name("Ruamel.YAML"), "ruamel-yaml");
+        assert_eq!(normalize_name("typing__extensions"), "typing-extensions");
+    }
+}
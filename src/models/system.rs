@@ -0,0 +1,134 @@
+use crate::config::VivaConfig;
+use crate::defaults::{IGNORE_FILENAME, LOCKFILE_EXTENSION, SYSTEM_ENVS_DIR};
+use crate::models::environment::{EnvironmentCollection, VivaEnvSpec};
+use crate::models::{find_spec_files, read_spec_files, SpecIgnore};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use tracing::{debug, warn};
+
+/// A read-only environment collection with specs an administrator provides for all users of a
+/// machine (one spec file per environment, like the `envs` directory of the default collection).
+///
+/// It is registered after the collections of the user, so users can shadow a system environment
+/// with one of their own (with the same id). The prefixes of system environments are created per
+/// user, like those of every other environment; lockfiles next to the specs are used (but never
+/// written).
+#[derive(Debug)]
+pub struct SystemEnvCollection {
+    envs_dir: PathBuf,
+    envs: BTreeMap<String, VivaEnvSpec>,
+    invalid_envs: BTreeMap<String, String>,
+    warnings: Vec<String>,
+}
+
+impl SystemEnvCollection {
+    pub async fn create(envs_dir: PathBuf) -> Result<Self> {
+        let mut collection = SystemEnvCollection {
+            envs_dir,
+            envs: BTreeMap::new(),
+            invalid_envs: BTreeMap::new(),
+            warnings: Vec::new(),
+        };
+        collection.load().await?;
+        Ok(collection)
+    }
+
+    /// The directory of the system collection (the configured one, or the platform default).
+    pub fn envs_dir(config: &VivaConfig) -> PathBuf {
+        match &config.system_envs_dir {
+            Some(envs_dir) => envs_dir.clone(),
+            None => PathBuf::from(SYSTEM_ENVS_DIR),
+        }
+    }
+
+    async fn load(&mut self) -> Result<()> {
+        debug!("Loading system environments: {}", self.envs_dir.display());
+        let mut envs = BTreeMap::new();
+        let mut invalid_envs = BTreeMap::new();
+        let mut warnings = vec![];
+        if self.envs_dir.is_dir() {
+            let ignore = match SpecIgnore::load(&self.envs_dir) {
+                Ok(ignore) => ignore,
+                Err(e) => {
+                    warn!("{:#}", e);
+                    invalid_envs.insert(IGNORE_FILENAME.to_string(), format!("{:#}", e));
+                    SpecIgnore::default()
+                }
+            };
+            let spec_files = find_spec_files(&self.envs_dir, &ignore, &mut warnings).await;
+            for read_spec in read_spec_files::<VivaEnvSpec>(spec_files).await {
+                match read_spec.result {
+                    Ok((mut env_spec, unknown_fields)) => {
+                        for field in unknown_fields {
+                            warnings.push(format!("{}: unknown field '{}'", read_spec.path.display(), field));
+                        }
+                        env_spec.record_provenance(&read_spec.path.display().to_string());
+                        envs.insert(read_spec.id, env_spec);
+                    }
+                    Err(e) => {
+                        warn!("Invalid environment spec '{}': {:#}", read_spec.path.display(), e);
+                        invalid_envs.insert(read_spec.id, format!("{:#}", e));
+                    }
+                }
+            }
+        }
+        self.envs = envs;
+        self.invalid_envs = invalid_envs;
+        self.warnings = warnings;
+        Ok(())
+    }
+
+    fn read_only_error(&self, env_id: &str) -> anyhow::Error {
+        anyhow!(
+            "Environment '{}' is provided by the read-only system collection ({}), clone it to make changes: viva env clone {} <new-id>",
+            env_id,
+            self.envs_dir.display(),
+            env_id
+        )
+    }
+}
+
+#[async_trait]
+impl EnvironmentCollection for SystemEnvCollection {
+    async fn get_env_ids(&self) -> Vec<String> {
+        self.envs.keys().cloned().collect()
+    }
+
+    async fn get_env(&self, env_id: &str) -> Result<&VivaEnvSpec> {
+        self.envs
+            .get(env_id)
+            .ok_or(anyhow!("No env found with name: {}", env_id))
+    }
+
+    async fn delete_env(&mut self, env_id: &str) -> Result<()> {
+        Err(self.read_only_error(env_id))
+    }
+
+    async fn set_env(&mut self, env_id: &str, _env: &VivaEnvSpec) -> Result<()> {
+        Err(self.read_only_error(env_id))
+    }
+
+    async fn get_invalid_envs(&self) -> BTreeMap<String, String> {
+        self.invalid_envs.clone()
+    }
+
+    async fn get_warnings(&self) -> Vec<String> {
+        self.warnings.clone()
+    }
+
+    async fn reload(&mut self) -> Result<()> {
+        self.load().await
+    }
+
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![self.envs_dir.clone()]
+    }
+
+    fn get_lockfile_path(&self, env_id: &str) -> Option<PathBuf> {
+        // only lockfiles the administrator provided, viva can't write new ones
+        let lockfile_path = self.envs_dir.join(format!("{}.{}", env_id, LOCKFILE_EXTENSION));
+        lockfile_path.is_file().then_some(lockfile_path)
+    }
+}
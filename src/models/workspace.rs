@@ -0,0 +1,341 @@
+use crate::defaults::{LOCKFILE_EXTENSION, WORKSPACES_DIRNAME, WORKSPACE_SPEC_FILENAME};
+use crate::models::environment::{pkg_spec_name, EnvironmentCollection, VivaEnvSpec};
+use crate::models::{read_model_spec, write_model_spec, SPEC_FILE_EXTENSIONS};
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::debug;
+
+/// A group of environments that share channels and package pins (e.g. the standard versions a
+/// lab or team agreed on).
+#[derive(Debug, Deserialize, Serialize, Clone, Default, PartialEq)]
+pub struct WorkspaceSpec {
+    /// Channels all environments of the workspace use (before their own).
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub channels: Vec<String>,
+    /// Package specs with versions (e.g. `python=3.11`), that replace the package specs of the
+    /// environments without a version constraint for the same package.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pins: Vec<String>,
+    /// The environments of the workspace (name -> spec, without the inherited settings).
+    #[serde(default)]
+    pub envs: BTreeMap<String, VivaEnvSpec>,
+}
+
+impl WorkspaceSpec {
+    /// The spec of one of the environments of the workspace, with the workspace channels and pins
+    /// applied.
+    ///
+    /// Inherited entries are recorded in the provenance of the spec, so they can be told apart
+    /// from the entries of the environment itself.
+    pub fn resolve_env(&self, workspace_id: &str, env_spec: &VivaEnvSpec) -> VivaEnvSpec {
+        let source = format!("workspace: {}", workspace_id);
+        let mut resolved = env_spec.clone();
+
+        let mut channels = self.channels.clone();
+        for channel in &channels {
+            if !env_spec.channels.contains(channel) {
+                resolved
+                    .provenance
+                    .channels
+                    .insert(channel.clone(), source.clone());
+            }
+        }
+        for channel in &env_spec.channels {
+            if !channels.contains(channel) {
+                channels.push(channel.clone());
+            }
+        }
+        resolved.channels = channels;
+
+        resolved.pkg_specs = env_spec
+            .pkg_specs
+            .iter()
+            .map(|pkg_spec| {
+                let pin = match pkg_spec_is_unconstrained(pkg_spec) {
+                    true => self
+                        .pins
+                        .iter()
+                        .find(|pin| pkg_spec_name(pin) == pkg_spec_name(pkg_spec)),
+                    false => None,
+                };
+                match pin {
+                    Some(pin) => {
+                        resolved.provenance.pkg_specs.remove(pkg_spec);
+                        resolved
+                            .provenance
+                            .pkg_specs
+                            .insert(pin.clone(), source.clone());
+                        pin.clone()
+                    }
+                    None => pkg_spec.clone(),
+                }
+            })
+            .collect();
+
+        resolved
+    }
+
+    /// The inverse of [`WorkspaceSpec::resolve_env`]: removes the inherited entries of a (resolved)
+    /// environment spec again, so only the settings of the environment itself are stored.
+    pub fn unresolve_env(&self, workspace_id: &str, env_spec: &VivaEnvSpec) -> VivaEnvSpec {
+        let source = format!("workspace: {}", workspace_id);
+        let inherited = |provenance: &BTreeMap<String, String>, entry: &String| {
+            provenance.get(entry) == Some(&source)
+        };
+
+        let mut spec = env_spec.clone();
+        spec.channels
+            .retain(|channel| !inherited(&env_spec.provenance.channels, channel));
+        spec.pkg_specs = env_spec
+            .pkg_specs
+            .iter()
+            .map(|pkg_spec| match inherited(&env_spec.provenance.pkg_specs, pkg_spec) {
+                true => pkg_spec_name(pkg_spec).to_string(),
+                false => pkg_spec.clone(),
+            })
+            .collect();
+        spec.provenance
+            .channels
+            .retain(|_, entry_source| entry_source != &source);
+        spec.provenance
+            .pkg_specs
+            .retain(|_, entry_source| entry_source != &source);
+        spec
+    }
+}
+
+/// Whether a package spec is just a package name, without any version or build constraint.
+fn pkg_spec_is_unconstrained(pkg_spec: &str) -> bool {
+    pkg_spec_name(pkg_spec) == pkg_spec.trim()
+}
+
+/// An environment collection backed by a workspace file (`<config>/workspaces/<id>/workspace.yaml`).
+///
+/// The ids of its environments are prefixed with the workspace id (`<workspace>/<env>`), and
+/// their specs have the workspace channels and pins applied when the file is loaded. Their
+/// prefixes are kept in `<data>/workspaces/<id>/`, so they can't collide with the prefixes of
+/// environments of other collections.
+#[derive(Debug)]
+pub struct WorkspaceEnvCollection {
+    workspace_id: String,
+    workspace_file: PathBuf,
+    envs_dir: PathBuf,
+    workspace: WorkspaceSpec,
+    resolved_envs: BTreeMap<String, VivaEnvSpec>,
+}
+
+impl WorkspaceEnvCollection {
+    pub async fn create(workspace_id: &str, workspace_file: PathBuf, data_dir: &Path) -> Result<Self> {
+        let mut collection = WorkspaceEnvCollection {
+            workspace_id: String::from(workspace_id),
+            workspace_file,
+            envs_dir: data_dir.join(WORKSPACES_DIRNAME).join(workspace_id),
+            workspace: WorkspaceSpec::default(),
+            resolved_envs: BTreeMap::new(),
+        };
+        collection.load().await?;
+        Ok(collection)
+    }
+
+    /// Find all workspaces in a config directory (workspace id -> workspace file).
+    pub fn find_workspaces(base_config_path: &PathBuf) -> BTreeMap<String, PathBuf> {
+        let mut workspaces = BTreeMap::new();
+        let entries = match std::fs::read_dir(base_config_path.join(WORKSPACES_DIRNAME)) {
+            Ok(entries) => entries,
+            Err(_) => return workspaces,
+        };
+        for entry in entries.flatten() {
+            if !entry.path().is_dir() {
+                continue;
+            }
+            let workspace_file = SPEC_FILE_EXTENSIONS
+                .iter()
+                .map(|ext| entry.path().join(format!("{}.{}", WORKSPACE_SPEC_FILENAME, ext)))
+                .find(|path| path.is_file());
+            if let Some(workspace_file) = workspace_file {
+                workspaces.insert(
+                    entry.file_name().to_string_lossy().to_string(),
+                    workspace_file,
+                );
+            }
+        }
+        workspaces
+    }
+
+    pub fn get_workspace_id(&self) -> &str {
+        &self.workspace_id
+    }
+
+    pub fn get_workspace(&self) -> &WorkspaceSpec {
+        &self.workspace
+    }
+
+    async fn load(&mut self) -> Result<()> {
+        debug!("Loading workspace: {}", self.workspace_file.display());
+        self.workspace = read_model_spec(&self.workspace_file).await?;
+        self.resolved_envs = self
+            .workspace
+            .envs
+            .iter()
+            .map(|(name, env_spec)| {
+                (
+                    self.env_id(name),
+                    self.workspace.resolve_env(&self.workspace_id, env_spec),
+                )
+            })
+            .collect();
+        Ok(())
+    }
+
+    fn env_id(&self, env_name: &str) -> String {
+        format!("{}/{}", self.workspace_id, env_name)
+    }
+
+    fn env_name<'a>(&self, env_id: &'a str) -> Result<&'a str> {
+        env_id
+            .strip_prefix(&format!("{}/", self.workspace_id))
+            .filter(|name| !name.is_empty())
+            .ok_or_else(|| {
+                anyhow!(
+                    "Environment '{}' is not part of workspace '{}'",
+                    env_id,
+                    self.workspace_id
+                )
+            })
+    }
+
+    async fn write(&mut self) -> Result<()> {
+        let mut workspace = self.workspace.clone();
+        workspace.envs = workspace
+            .envs
+            .iter()
+            .map(|(name, env_spec)| (name.clone(), env_spec.normalized()))
+            .collect();
+        write_model_spec(&self.workspace_file, &workspace).await?;
+        self.load().await
+    }
+}
+
+#[async_trait]
+impl EnvironmentCollection for WorkspaceEnvCollection {
+    async fn get_env_ids(&self) -> Vec<String> {
+        self.resolved_envs.keys().cloned().collect()
+    }
+
+    async fn get_env(&self, env_id: &str) -> Result<&VivaEnvSpec> {
+        self.resolved_envs
+            .get(env_id)
+            .ok_or(anyhow!("No env found with name: {}", env_id))
+    }
+
+    async fn delete_env(&mut self, env_id: &str) -> Result<()> {
+        let env_name = self.env_name(env_id)?.to_string();
+        if self.workspace.envs.remove(&env_name).is_some() {
+            self.write().await?;
+        }
+        if let Some(lockfile_path) = self.get_lockfile_path(env_id) {
+            if lockfile_path.exists() {
+                fs::remove_file(&lockfile_path).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn set_env(&mut self, env_id: &str, env_spec: &VivaEnvSpec) -> Result<()> {
+        let env_name = self.env_name(env_id)?.to_string();
+        let env_spec = self.workspace.unresolve_env(&self.workspace_id, env_spec);
+        self.workspace.envs.insert(env_name, env_spec);
+        self.write().await
+    }
+
+    async fn reload(&mut self) -> Result<()> {
+        self.load().await
+    }
+
+    async fn watch_paths(&self) -> Vec<PathBuf> {
+        vec![self.workspace_file.clone()]
+    }
+
+    fn get_lockfile_path(&self, env_id: &str) -> Option<PathBuf> {
+        let env_name = self.env_name(env_id).ok()?;
+        let workspace_dir = self.workspace_file.parent()?;
+        Some(workspace_dir.join(format!("{}.{}", env_name, LOCKFILE_EXTENSION)))
+    }
+
+    fn get_env_path(&self, env_id: &str) -> Option<PathBuf> {
+        Some(self.envs_dir.join(self.env_name(env_id).ok()?))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn workspace() -> WorkspaceSpec {
+        WorkspaceSpec {
+            channels: vec![String::from("lab-channel"), String::from("conda-forge")],
+            pins: vec![String::from("python=3.11"), String::from("numpy>=1.26")],
+            envs: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_resolve_env() {
+        let mut env_spec = VivaEnvSpec::new();
+        env_spec.channels = vec![String::from("conda-forge"), String::from("bioconda")];
+        env_spec.pkg_specs = vec![String::from("python"), String::from("numpy<2"), String::from("samtools")];
+
+        let resolved = workspace().resolve_env("lab", &env_spec);
+        assert_eq!(resolved.channels, vec!["lab-channel", "conda-forge", "bioconda"]);
+        // specs with their own constraint win over the pins
+        assert_eq!(resolved.pkg_specs, vec!["python=3.11", "numpy<2", "samtools"]);
+        assert_eq!(resolved.provenance.channels["lab-channel"], "workspace: lab");
+        assert!(!resolved.provenance.channels.contains_key("conda-forge"));
+        assert_eq!(resolved.provenance.pkg_specs["python=3.11"], "workspace: lab");
+    }
+
+    #[test]
+    fn test_unresolve_env_round_trip() {
+        let mut env_spec = VivaEnvSpec::new();
+        env_spec.channels = vec![String::from("conda-forge"), String::from("bioconda")];
+        env_spec.pkg_specs = vec![String::from("python"), String::from("numpy<2"), String::from("samtools")];
+
+        let workspace = workspace();
+        let resolved = workspace.resolve_env("lab", &env_spec);
+        let unresolved = workspace.unresolve_env("lab", &resolved);
+        assert_eq!(unresolved.channels, env_spec.channels);
+        assert_eq!(unresolved.pkg_specs, env_spec.pkg_specs);
+        assert_eq!(unresolved.provenance, env_spec.provenance);
+        assert_eq!(workspace.resolve_env("lab", &unresolved), resolved);
+
+        // entries that are added to the resolved spec are kept
+        let mut changed = resolved.clone();
+        changed.pkg_specs.push(String::from("pandas"));
+        let unresolved = workspace.unresolve_env("lab", &changed);
+        assert_eq!(unresolved.pkg_specs, vec!["python", "numpy<2", "samtools", "pandas"]);
+    }
+
+    #[tokio::test]
+    async fn test_env_paths() {
+        let dir = tempfile::tempdir().unwrap();
+        let workspace_file = dir.path().join("config/workspaces/lab/workspace.yaml");
+        fs::create_dir_all(workspace_file.parent().unwrap()).await.unwrap();
+        fs::write(&workspace_file, "envs:\n  analysis:\n    channels: []\n    pkg_specs: [python]\n")
+            .await
+            .unwrap();
+
+        let data_dir = dir.path().join("data");
+        let collection = WorkspaceEnvCollection::create("lab", workspace_file, &data_dir).await.unwrap();
+        assert_eq!(collection.get_env_ids().await, vec!["lab/analysis"]);
+        // not in the default environment directory, where an environment 'lab' would live
+        assert_eq!(
+            collection.get_env_path("lab/analysis"),
+            Some(data_dir.join("workspaces/lab/analysis"))
+        );
+        assert_eq!(collection.get_env_path("other/analysis"), None);
+    }
+}
@@ -0,0 +1,283 @@
+// Machine-readable progress reporting.
+//
+// If enabled, viva writes one json object per line for every progress update (instead of rendering
+// progress bars), either to stderr or to a file (e.g. a named pipe), so GUI wrappers and CI
+// parsers can follow long-running operations like syncing an environment.
+//
+// Applications that embed viva can also receive the updates in-process, via the handle returned by
+// `VivaEnv::sync_with_handle`. The handle runs the sync in an operation scope (a task-local), which
+// gets the progress events of that sync only, and carries its cancel flag.
+
+use anyhow::{bail, Context, Result};
+use futures::Stream;
+use indicatif::ProgressDrawTarget;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+use tokio::sync::watch;
+
+use crate::rattler::global_multi_progress;
+
+static PROGRESS_SINK: Lazy<Mutex<Option<Box<dyn Write + Send>>>> = Lazy::new(|| Mutex::new(None));
+
+/// The operation (of a [`SyncHandle`]) that the current task works on.
+#[derive(Clone)]
+struct OperationScope {
+    progress: UnboundedSender<ProgressEvent>,
+    cancelled: watch::Receiver<bool>,
+}
+
+tokio::task_local! {
+    static OPERATION_SCOPE: OperationScope;
+}
+
+/// A single progress update.
+#[derive(Debug, Serialize, Clone, Default)]
+pub struct ProgressEvent {
+    /// The phase of the operation (e.g. 'repodata', 'solving', 'download', 'link', 'pip').
+    pub phase: String,
+    /// One of 'started', 'progress', 'finished', or 'failed'.
+    pub status: String,
+    /// The package (or channel subdirectory) the update is about, if any.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub package: Option<String>,
+    /// The number of bytes transferred so far (for downloads).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bytes: Option<u64>,
+    /// The number of items processed so far in this phase.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub done: Option<u64>,
+    /// The total number of items (or bytes) of this phase, if known.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub total: Option<u64>,
+}
+
+impl ProgressEvent {
+    pub(crate) fn new(phase: &str, status: &str) -> ProgressEvent {
+        ProgressEvent {
+            phase: String::from(phase),
+            status: String::from(status),
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn package(mut self, package: impl Into<String>) -> ProgressEvent {
+        self.package = Some(package.into());
+        self
+    }
+
+    pub(crate) fn bytes(mut self, bytes: u64, total: Option<u64>) -> ProgressEvent {
+        self.bytes = Some(bytes);
+        self.total = total;
+        self
+    }
+
+    pub(crate) fn count(mut self, done: u64, total: Option<u64>) -> ProgressEvent {
+        self.done = Some(done);
+        self.total = total;
+        self
+    }
+
+    /// Write the event to the progress stream (if json progress is enabled), and send it to the
+    /// handle of the operation it belongs to (if any).
+    pub(crate) fn emit(self) {
+        let _ = OPERATION_SCOPE.try_with(|scope| scope.progress.send(self.clone()));
+        let mut sink = match PROGRESS_SINK.lock() {
+            Ok(sink) => sink,
+            Err(_) => return,
+        };
+        if let Some(writer) = sink.as_mut() {
+            if let Ok(line) = serde_json::to_string(&self) {
+                let _ = writeln!(writer, "{}", line);
+                let _ = writer.flush();
+            }
+        }
+    }
+}
+
+/// Report progress as newline-delimited json, to the provided file (e.g. a named pipe), or to
+/// stderr. Progress bars are not rendered anymore after this is called.
+pub fn enable_json_progress(target: Option<&Path>) -> Result<()> {
+    let writer: Box<dyn Write + Send> = match target {
+        Some(path) => Box::new(
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .with_context(|| format!("Failed to open progress stream: {}", path.display()))?,
+        ),
+        None => Box::new(std::io::stderr()),
+    };
+    global_multi_progress().set_draw_target(ProgressDrawTarget::hidden());
+    if let Ok(mut sink) = PROGRESS_SINK.lock() {
+        *sink = Some(writer);
+    }
+    Ok(())
+}
+
+/// Whether progress is reported as json (in which case no progress bars should be drawn).
+pub(crate) fn json_progress_enabled() -> bool {
+    PROGRESS_SINK
+        .lock()
+        .map(|sink| sink.is_some())
+        .unwrap_or(false)
+}
+
+/// Whether the operation the current task works on was cancelled (never, outside of a
+/// [`SyncHandle`]).
+pub(crate) fn is_cancelled() -> bool {
+    OPERATION_SCOPE
+        .try_with(|scope| *scope.cancelled.borrow())
+        .unwrap_or(false)
+}
+
+/// Fails if the operation the current task works on was cancelled. Cancellation is cooperative:
+/// operations check at points where they can stop without leaving work (e.g. linking packages on
+/// other threads) behind.
+pub(crate) fn check_cancelled() -> Result<()> {
+    if is_cancelled() {
+        bail!("cancelled");
+    }
+    Ok(())
+}
+
+/// Cancels the operation of a [`SyncHandle`], can be cloned and moved to other tasks (e.g. the
+/// one handling the 'cancel' button of a UI).
+#[derive(Debug, Clone)]
+pub struct SyncCanceller {
+    cancelled: Arc<watch::Sender<bool>>,
+}
+
+impl SyncCanceller {
+    pub fn cancel(&self) {
+        let _ = self.cancelled.send(true);
+    }
+}
+
+/// A running environment sync: a future that resolves to the result of the sync (whether the
+/// environment needed to be synced), with access to its progress and the ability to cancel it.
+///
+/// The progress events are the ones of this sync only, so concurrent syncs can be told apart. The
+/// handle is `Send`, so it can be spawned onto another task.
+pub struct SyncHandle<'a> {
+    future: Pin<Box<dyn Future<Output = Result<bool>> + Send + 'a>>,
+    progress: Option<UnboundedReceiver<ProgressEvent>>,
+    canceller: SyncCanceller,
+}
+
+impl<'a> SyncHandle<'a> {
+    pub(crate) fn new<F>(create_future: impl FnOnce(watch::Receiver<bool>) -> F) -> SyncHandle<'a>
+    where
+        F: Future<Output = Result<bool>> + Send + 'a,
+    {
+        // the progress stream ends once the operation is over (and its scope is dropped)
+        let (progress_sender, progress) = unbounded_channel();
+        let (cancelled, cancelled_receiver) = watch::channel(false);
+        let scope = OperationScope {
+            progress: progress_sender,
+            cancelled: cancelled_receiver.clone(),
+        };
+        let future = create_future(cancelled_receiver);
+        SyncHandle {
+            future: Box::pin(OPERATION_SCOPE.scope(scope, future)),
+            progress: Some(progress),
+            canceller: SyncCanceller {
+                cancelled: Arc::new(cancelled),
+            },
+        }
+    }
+
+    /// The progress events of the sync, as a stream that ends when the sync is over. Can only be
+    /// taken once, later calls return an empty stream.
+    pub fn progress(&mut self) -> impl Stream<Item = ProgressEvent> {
+        let mut progress = self.progress.take();
+        futures::stream::poll_fn(move |cx| match progress.as_mut() {
+            Some(progress) => progress.poll_recv(cx),
+            None => Poll::Ready(None),
+        })
+    }
+
+    /// Cancel the sync. The handle resolves to an error once the sync stopped (packages that are
+    /// being linked are finished first), and the environment is synced again the next time it's
+    /// used.
+    pub fn cancel(&self) {
+        self.canceller.cancel();
+    }
+
+    /// A canceller for the sync, to cancel it from another task.
+    pub fn canceller(&self) -> SyncCanceller {
+        self.canceller.clone()
+    }
+}
+
+impl<'a> Future for SyncHandle<'a> {
+    type Output = Result<bool>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        self.future.as_mut().poll(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::StreamExt;
+
+    fn handle_emitting(phase: &'static str) -> SyncHandle<'static> {
+        SyncHandle::new(move |_| async move {
+            for _ in 0..3 {
+                ProgressEvent::new(phase, "progress").emit();
+                tokio::task::yield_now().await;
+            }
+            Ok(true)
+        })
+    }
+
+    #[tokio::test]
+    async fn test_progress_per_handle() {
+        let mut first = handle_emitting("first");
+        let mut second = handle_emitting("second");
+        let first_progress = first.progress();
+        let second_progress = second.progress();
+
+        // not part of any operation
+        ProgressEvent::new("other", "progress").emit();
+        let (first_result, second_result) = tokio::join!(tokio::spawn(first), tokio::spawn(second));
+        assert!(first_result.unwrap().unwrap());
+        assert!(second_result.unwrap().unwrap());
+
+        let first_events = first_progress.collect::<Vec<_>>().await;
+        let second_events = second_progress.collect::<Vec<_>>().await;
+        assert_eq!(first_events.len(), 3);
+        assert!(first_events.iter().all(|event| event.phase == "first"));
+        assert_eq!(second_events.len(), 3);
+        assert!(second_events.iter().all(|event| event.phase == "second"));
+    }
+
+    #[tokio::test]
+    async fn test_cancel() {
+        let (started, mut started_receiver) = unbounded_channel();
+        let handle = SyncHandle::new(move |_| async move {
+            let _ = started.send(());
+            loop {
+                check_cancelled()?;
+                tokio::time::sleep(std::time::Duration::from_millis(10)).await;
+            }
+        });
+        let canceller = handle.canceller();
+        let task = tokio::spawn(handle);
+        started_receiver.recv().await;
+        canceller.cancel();
+        assert_eq!(task.await.unwrap().unwrap_err().to_string(), "cancelled");
+
+        // outside of a handle, nothing is ever cancelled
+        assert!(!is_cancelled());
+        assert!(check_cancelled().is_ok());
+    }
+}
@@ -1,77 +1,348 @@
-use crate::models::environment::VivaEnvSpec;
+use crate::auth::{display_url, ChannelAuth};
+use crate::cache;
+use crate::config::{parse_size, PathConflictPolicy, SizeBudgetPolicy, VivaConfig};
+use crate::conflicts::{self, OperationStep, PackageFailure, PathConflicts, PathOwners, TransactionFailures};
+use crate::defaults::FETCHER_REPODATA_MAX_AGE;
+use crate::fetch::{self, Fetcher, HttpsFetcher};
+use crate::locks::{CacheLock, PackageCacheLock};
+use crate::models::remote::parse_interval;
+use crate::models::environment::{
+    ExecutableSource, PackageExplanation, PlannedAction, PlannedOperation, RejectedCandidate, SyncEstimate,
+    VivaEnvSpec,
+};
+use crate::progress::{check_cancelled, is_cancelled, json_progress_enabled, ProgressEvent};
 use crate::rattler::global_multi_progress;
+use crate::throttle::RateLimiter;
+use crate::watchdog::{retry_stalled, StallPolicy};
 use anyhow::{Context, Result};
-use futures::{stream, stream::FuturesUnordered, FutureExt, StreamExt, TryFutureExt, TryStreamExt};
-use indicatif::{HumanBytes, ProgressBar, ProgressState, ProgressStyle};
+use futures::{stream, stream::FuturesUnordered, FutureExt, StreamExt, TryFutureExt};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use indicatif::{HumanBytes, ProgressBar, ProgressDrawTarget, ProgressState, ProgressStyle};
 use rattler::{
     install::{link_package, InstallDriver, InstallOptions, Transaction, TransactionOperation},
     package_cache::PackageCache,
 };
 use rattler_conda_types::{
-    Channel, ChannelConfig, GenericVirtualPackage, MatchSpec, Platform, PrefixRecord,
-    RepoDataRecord,
+    Channel, GenericVirtualPackage, MatchSpec, PackageRecord, Platform,
+    PrefixRecord, RepoDataRecord, Version,
 };
 use rattler_repodata_gateway::fetch::{
     CacheAction, CacheResult, DownloadProgress, FetchRepoDataOptions,
 };
 use rattler_repodata_gateway::sparse::SparseRepoData;
 use rattler_solve::{LibsolvRepoData, SolverBackend, SolverTask};
-use reqwest::Client;
+use reqwest::{Client, Url};
 use std::{
     borrow::Cow,
+    collections::{HashMap, HashSet},
     fmt::Write,
     future::ready,
     io::ErrorKind,
     path::{Path, PathBuf},
     str::FromStr,
+    sync::Arc,
     time::Duration,
 };
 use tokio::task::JoinHandle;
 
-pub async fn create(
+/// The result of solving an environment spec, which can be applied to the target prefix.
+pub(crate) struct SolvedEnvironment {
+    /// The packages that are currently installed in the target prefix.
+    pub installed_packages: Vec<PrefixRecord>,
+    /// The packages that should be installed in the target prefix.
+    pub required_packages: Vec<RepoDataRecord>,
+    pub platform: Platform,
+    pkgs_dir: PathBuf,
+    download_client: Client,
+    /// Credentials for private channels.
+    auth: Arc<ChannelAuth>,
+    /// The base urls of all mirrors of a channel, used for failover when downloading packages.
+    mirror_groups: Vec<Vec<Url>>,
+    /// The package specs of the environment spec.
+    specs: Vec<MatchSpec>,
+    /// All package records that were available to the solver.
+    available_packages: Vec<Vec<RepoDataRecord>>,
+}
+
+impl SolvedEnvironment {
+    /// Estimate the work needed to apply this solution: the number of packages that need to be
+    /// installed, and how many of those (and how many bytes) need to be downloaded because they are
+    /// not in the package cache yet.
+    pub fn estimate(&self) -> SyncEstimate {
+        let mut estimate = SyncEstimate::default();
+        for record in &self.required_packages {
+            let installed = self
+                .installed_packages
+                .iter()
+                .any(|installed| is_same_package(&installed.repodata_record, record));
+            if installed {
+                continue;
+            }
+            estimate.install_count += 1;
+
+            if !self.is_cached(record) {
+                estimate.download_count += 1;
+                estimate.download_bytes += record.package_record.size.unwrap_or(0);
+            }
+        }
+        estimate.total_count = self.required_packages.len();
+        estimate
+    }
+
+    /// Whether a package is extracted in the package cache already.
+    fn is_cached(&self, record: &RepoDataRecord) -> bool {
+        let package_record = &record.package_record;
+        self.pkgs_dir.join(cache_entry_name(package_record)).is_dir()
+    }
+
+    /// The operations applying this solution would perform on the target prefix (the same
+    /// transaction [`apply`] executes), sorted by package name.
+    pub fn plan_operations(&self) -> Result<Vec<PlannedOperation>> {
+        let transaction = Transaction::from_current_and_desired(
+            self.installed_packages.iter(),
+            self.required_packages.iter(),
+            self.platform,
+        )?;
+        let version_of = |record: &PackageRecord| format!("{} ({})", record.version, record.build);
+        let mut operations: Vec<PlannedOperation> = transaction
+            .operations
+            .iter()
+            .map(|operation| {
+                let (action, old, new) = match operation {
+                    TransactionOperation::Install(new) => (PlannedAction::Install, None, Some(*new)),
+                    TransactionOperation::Change { old, new } => {
+                        let (old_record, new_record) =
+                            (&old.repodata_record.package_record, &new.package_record);
+                        let action = match new_record.version.cmp(&old_record.version) {
+                            std::cmp::Ordering::Greater => PlannedAction::Upgrade,
+                            std::cmp::Ordering::Less => PlannedAction::Downgrade,
+                            std::cmp::Ordering::Equal => PlannedAction::Change,
+                        };
+                        (action, Some(*old), Some(*new))
+                    }
+                    TransactionOperation::Reinstall(old) => (PlannedAction::Reinstall, Some(*old), None),
+                    TransactionOperation::Remove(old) => (PlannedAction::Remove, Some(*old), None),
+                };
+                let name = new
+                    .map(|new| new.package_record.name.clone())
+                    .or_else(|| old.map(|old| old.repodata_record.package_record.name.clone()))
+                    .unwrap_or_default();
+                PlannedOperation {
+                    action,
+                    name,
+                    installed: old.map(|old| version_of(&old.repodata_record.package_record)),
+                    version: new.map(|new| version_of(&new.package_record)),
+                    channel: new.map(|new| new.channel.clone()),
+                    download_bytes: new
+                        .filter(|new| !self.is_cached(new))
+                        .map(|new| new.package_record.size.unwrap_or(0)),
+                }
+            })
+            .collect();
+        operations.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(operations)
+    }
+
+    /// Find the package that installs one of the provided paths (relative to the prefix), using the
+    /// file lists of the extracted packages in the package cache.
+    pub fn find_executable(&self, paths: &[String]) -> ExecutableSource {
+        let pkgs_dir = &self.pkgs_dir;
+        let mut uncached_packages = 0;
+        for record in &self.required_packages {
+            let package_record = &record.package_record;
+            let cache_entry = pkgs_dir.join(cache_entry_name(package_record));
+            match package_file_list(&cache_entry) {
+                Some(files) => {
+                    if paths.iter().any(|path| files.contains(path)) {
+                        return ExecutableSource::Package {
+                            name: package_record.name.clone(),
+                        };
+                    }
+                }
+                None => uncached_packages += 1,
+            }
+        }
+        match uncached_packages {
+            0 => ExecutableSource::NotFound,
+            uncached_packages => ExecutableSource::Unknown { uncached_packages },
+        }
+    }
+
+    /// Explain why a package was chosen: the requirement chains that lead to it from the spec, and
+    /// which constraints exclude the newer versions of it that were available.
+    pub fn explain_package(&self, name: &str) -> Result<PackageExplanation> {
+        let chosen = self
+            .required_packages
+            .iter()
+            .find(|record| record.package_record.name == name)
+            .ok_or_else(|| anyhow::anyhow!("package is not part of the environment: {}", name))?;
+
+        // all the constraints of the spec and the chosen packages, per package name
+        let mut constraints: Vec<(String, MatchSpec, String)> = vec![];
+        for spec in &self.specs {
+            if let Some(spec_name) = &spec.name {
+                constraints.push((spec_name.clone(), spec.clone(), format!("spec '{}'", spec)));
+            }
+        }
+        for record in &self.required_packages {
+            let package = &record.package_record;
+            let requirements = package
+                .depends
+                .iter()
+                .map(|dep| (dep, "requires"))
+                .chain(package.constrains.iter().map(|dep| (dep, "constrains")));
+            for (dep, kind) in requirements {
+                let Ok(dep_spec) = MatchSpec::from_str(dep) else {
+                    continue;
+                };
+                if let Some(dep_name) = &dep_spec.name {
+                    let source = format!(
+                        "{} {} {} '{}'",
+                        package.name, package.version, kind, dep
+                    );
+                    constraints.push((dep_name.clone(), dep_spec.clone(), source));
+                }
+            }
+        }
+
+        let mut chains: Vec<Vec<String>> = vec![];
+        collect_requirement_chains(name, &constraints, &mut vec![], &mut chains);
+
+        let chosen_package = &chosen.package_record;
+        let mut rejected_newer: Vec<RejectedCandidate> = vec![];
+        for candidate in self.available_packages.iter().flatten() {
+            let package = &candidate.package_record;
+            let is_newer = package.name == name
+                && (package.version > chosen_package.version
+                    || (package.version == chosen_package.version
+                        && package.build_number > chosen_package.build_number));
+            if !is_newer {
+                continue;
+            }
+            let excluded_by = constraints
+                .iter()
+                .filter(|(constraint_name, spec, _)| constraint_name == name && !spec.matches(package))
+                .map(|(_, _, source)| source.clone())
+                .collect::<Vec<_>>();
+            rejected_newer.push(RejectedCandidate {
+                version: package.version.to_string(),
+                build: package.build.clone(),
+                channel: candidate.channel.clone(),
+                excluded_by,
+            });
+        }
+
+        Ok(PackageExplanation {
+            name: String::from(name),
+            version: chosen_package.version.to_string(),
+            build: chosen_package.build.clone(),
+            channel: chosen.channel.clone(),
+            chains,
+            rejected_newer,
+        })
+    }
+}
+
+/// Solve an environment spec, without changing the target prefix.
+///
+/// If `lock_installed` is set, the solver prefers the versions of the packages that are already
+/// installed in the target prefix, otherwise the newest matching versions are selected.
+pub(crate) async fn solve(
     target_prefix: &PathBuf,
     env_spec: &VivaEnvSpec,
     cache_action: CacheAction,
-) -> Result<()> {
-    let channel_config = ChannelConfig::default();
+    lock_installed: bool,
+    config: &VivaConfig,
+) -> Result<SolvedEnvironment> {
+    let channel_config = config.channel_config()?;
+
+    // In offline mode, only cached repodata is used (and it's an error if there is none).
+    let offline = config.offline;
+    let cache_action = match offline {
+        true => CacheAction::UseCacheOnly,
+        false => cache_action,
+    };
 
-    // Determine the platform we're going to install for
-    let install_platform = Platform::current();
+    // Determine the platform we're going to install for (the current one, unless the spec says
+    // otherwise)
+    let install_platform = env_spec.get_platform()?;
 
     // Parse the specs from the command line. We do this explicitly instead of allow clap to deal
     // with this because we need to parse the `channel_config` when parsing matchspecs.
-    let specs = env_spec
+    let mut specs = env_spec
         .pkg_specs
         .iter()
         .map(|spec| MatchSpec::from_str(spec))
         .collect::<Result<Vec<_>, _>>()?;
 
+    // Pip specs are installed with the pip of the environment.
+    if !env_spec.pip_specs.is_empty()
+        && !specs.iter().any(|spec| spec.name.as_deref() == Some("pip"))
+    {
+        specs.push(MatchSpec::from_str("pip")?);
+    }
+
     // Find the default cache directory. Create it if it doesnt exist yet.
-    let cache_dir = dirs::cache_dir()
-        .ok_or_else(|| anyhow::anyhow!("could not determine cache directory for current platform"))?
-        .join("rattler/cache");
+    let cache_dir = default_cache_dir()?;
     std::fs::create_dir_all(&cache_dir)
         .map_err(|e| anyhow::anyhow!("could not create cache directory: {}", e))?;
 
+    let download_client = Client::builder()
+        .no_gzip()
+        .build()
+        .expect("failed to create client");
+    let auth = Arc::new(ChannelAuth::load(config)?);
+
     // Determine the channels to use from the command line or select the default. Like matchspecs
     // this also requires the use of the `channel_config` so we have to do this manually.
-    let channels = env_spec
-        .channels
-        .clone()
-        .into_iter()
-        .map(|channel_str| Channel::from_str(&channel_str, &channel_config))
-        .collect::<Result<Vec<_>, _>>()?;
+    //
+    // If mirrors are configured for a channel, all of them are candidates, ordered by latency, and
+    // used in that order if fetching from one of them fails. The mirrors of all channels are probed
+    // concurrently.
+    let channel_candidates = futures::future::try_join_all(env_spec.channels.iter().map(
+        |channel_str| {
+            let channel_config = &channel_config;
+            let download_client = &download_client;
+            async move {
+                let channel = Channel::from_str(channel_str, channel_config)?;
+                let platforms = match &channel.platforms {
+                    Some(platforms) => platforms.to_vec(),
+                    None if install_platform == Platform::NoArch => vec![Platform::NoArch],
+                    None => vec![install_platform, Platform::NoArch],
+                };
+                let candidates = match config.channel_mirrors_for(channel_str)? {
+                    Some(mirrors) if !mirrors.is_empty() => {
+                        let mirrors = mirrors
+                            .iter()
+                            .map(|mirror| Channel::from_str(mirror, channel_config))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        match offline {
+                            true => mirrors,
+                            false => order_by_latency(download_client, mirrors).await,
+                        }
+                    }
+                    _ => vec![channel],
+                };
+                Ok::<_, anyhow::Error>((candidates, platforms))
+            }
+        },
+    ))
+    .await?;
+    let mirror_groups: Vec<Vec<Url>> = channel_candidates
+        .iter()
+        .filter(|(candidates, _)| candidates.len() > 1)
+        .map(|(candidates, _)| candidates.iter().map(|c| c.base_url.clone()).collect())
+        .collect();
 
     // Each channel contains multiple subdirectories. Users can specify the subdirectories they want
     // to use when specifying their channels. If the user didn't specify the default subdirectories
-    // we use defaults based on the current platform.
-    let channel_urls = channels
+    // we use defaults based on the platform we install for.
+    let channel_urls = channel_candidates
         .iter()
-        .flat_map(|channel| {
-            channel
-                .platforms_or_default()
+        .flat_map(|(candidates, platforms)| {
+            platforms
                 .iter()
-                .map(move |platform| (channel.clone(), *platform))
+                .map(move |platform| (candidates.clone(), *platform))
         })
         .collect::<Vec<_>>();
 
@@ -80,33 +351,72 @@ pub async fn create(
         .await
         .context("failed to determine currently installed packages")?;
 
+    // Determine the packages of the (read-only) base prefix this environment extends, if any. Those
+    // are pinned during the solve, and not installed into the target prefix.
+    let base_packages = match &env_spec.base_prefix {
+        Some(base_prefix) => {
+            if !base_prefix.join("conda-meta").is_dir() {
+                anyhow::bail!("base prefix is not a conda environment: {}", base_prefix.display());
+            }
+            find_installed_packages(base_prefix, 100)
+                .await
+                .context("failed to determine packages of base prefix")?
+        }
+        None => vec![],
+    };
+
     // For each channel/subdirectory combination, download and cache the `repodata.json` that should
     // be available from the corresponding Url. The code below also displays a nice CLI progress-bar
     // to give users some more information about what is going on.
-    let download_client = Client::builder()
-        .no_gzip()
-        .build()
-        .expect("failed to create client");
     let multi_progress = global_multi_progress();
 
     let repodata_cache_path = cache_dir.join("repodata");
     let channel_and_platform_len = channel_urls.len();
     let repodata_download_client = download_client.clone();
+    let repodata_auth = auth.clone();
+    let stall_policy = StallPolicy::from_config(config)?;
     let sparse_repo_datas = futures::stream::iter(channel_urls)
-        .map(move |(channel, platform)| {
+        .map(move |(candidates, platform)| {
             let repodata_cache = repodata_cache_path.clone();
             let download_client = repodata_download_client.clone();
+            let auth = repodata_auth.clone();
             let multi_progress = multi_progress.clone();
             async move {
-                fetch_repo_data_records_with_progress(
-                    channel,
-                    platform,
-                    &repodata_cache,
-                    download_client.clone(),
-                    multi_progress,
-                    cache_action,
-                )
-                .await
+                let mut candidates = candidates.into_iter().peekable();
+                loop {
+                    let channel = candidates.next().expect("at least one channel candidate");
+                    let channel_url = channel.base_url.clone();
+                    let result = fetch_repo_data_records_with_progress(
+                        channel,
+                        platform,
+                        &repodata_cache,
+                        download_client.clone(),
+                        &auth,
+                        multi_progress.clone(),
+                        cache_action,
+                        stall_policy,
+                    )
+                    .await
+                    .map_err(|e| match offline {
+                        true => anyhow::anyhow!(
+                            "No cached repodata for {}/{} in offline mode (sync once while online to fill the cache): {}",
+                            channel_url,
+                            platform,
+                            e
+                        ),
+                        false => e,
+                    });
+                    match result {
+                        Err(e) if candidates.peek().is_some() => {
+                            tracing::warn!(
+                                "failed to fetch repodata from {}, trying next mirror: {}",
+                                channel_url,
+                                e
+                            );
+                        }
+                        result => break result,
+                    }
+                }
             }
         })
         .buffer_unordered(channel_and_platform_len)
@@ -122,56 +432,266 @@ pub async fn create(
         SparseRepoData::load_records_recursive(&sparse_repo_datas, package_names)
     })?;
 
+    // Channels with a (curated) view only provide the packages listed in it.
+    let repodatas = match env_spec.channel_views.is_empty() {
+        true => repodatas,
+        false => apply_channel_views(repodatas, env_spec, &specs, config)?,
+    };
+
     // Determine virtual packages of the system. These packages define the capabilities of the
     // system. Some packages depend on these virtual packages to indiciate compability with the
-    // hardware of the system.
-    let virtual_packages = wrap_in_progress("determining virtual packages", move || {
-        rattler_virtual_packages::VirtualPackage::current().map(|vpkgs| {
-            vpkgs
-                .iter()
-                .map(|vpkg| GenericVirtualPackage::from(vpkg.clone()))
-                .collect::<Vec<_>>()
-        })
-    })?;
+    // hardware of the system. For other platforms, the system can't be inspected, so defaults are
+    // used.
+    let virtual_packages = match install_platform == Platform::current() {
+        true => wrap_in_progress("determining virtual packages", move || {
+            rattler_virtual_packages::VirtualPackage::current().map(|vpkgs| {
+                vpkgs
+                    .iter()
+                    .map(|vpkg| GenericVirtualPackage::from(vpkg.clone()))
+                    .collect::<Vec<_>>()
+            })
+        })?,
+        false => default_virtual_packages(install_platform)?,
+    };
 
     // Now that we parsed and downloaded all information, construct the packaging problem that we
     // need to solve. We do this by constructing a `SolverProblem`. This encapsulates all the
     // information required to be able to solve the problem.
-    let solver_task = SolverTask {
-        available_packages: repodatas
-            .iter()
-            .map(|records| LibsolvRepoData::from_records(records)),
-        locked_packages: installed_packages
+    let root_specs = specs.clone();
+    let locked_packages = match lock_installed {
+        true => installed_packages
             .iter()
             .map(|record| record.repodata_record.clone())
             .collect(),
-        virtual_packages,
-        specs,
-        pinned_packages: Vec::new(),
+        false => Vec::new(),
     };
+    let pinned_packages = base_packages
+        .iter()
+        .map(|record| record.repodata_record.clone())
+        .collect();
+    let solve_timeout = match &config.solve_timeout {
+        Some(solve_timeout) => Some(parse_interval(solve_timeout)?),
+        None => None,
+    };
+    let problem_size = format!(
+        "{} package specs, {} candidate packages",
+        specs.len(),
+        repodatas.iter().map(|records| records.len()).sum::<usize>()
+    );
 
     // Next, use a solver to solve this specific problem. This provides us with all the operations
     // we need to apply to our environment to bring it up to date.
-    let required_packages = wrap_in_progress("solving", move || {
-        rattler_solve::LibsolvBackend.solve(solver_task)
+    let (required_packages, repodatas) = solve_with_timeout(repodatas, solve_timeout, &problem_size, move |repodatas| {
+        let solver_task = SolverTask {
+            available_packages: repodatas
+                .iter()
+                .map(|records| LibsolvRepoData::from_records(records)),
+            locked_packages,
+            virtual_packages,
+            specs,
+            pinned_packages,
+        };
+        Ok(rattler_solve::LibsolvBackend.solve(solver_task)?)
     })?;
 
-    // Construct a transaction to
-    let transaction = Transaction::from_current_and_desired(
+    // Packages that are provided by the base prefix don't need to be installed again.
+    let required_packages = required_packages
+        .into_iter()
+        .filter(|record| !base_packages.iter().any(|base| is_same_package(&base.repodata_record, record)))
+        .collect::<Vec<_>>();
+
+    Ok(SolvedEnvironment {
         installed_packages,
         required_packages,
-        install_platform,
+        platform: install_platform,
+        pkgs_dir: packages_dir(config)?,
+        download_client,
+        auth,
+        mirror_groups,
+        specs: root_specs,
+        available_packages: repodatas,
+    })
+}
+
+/// Prepare the installation of a previously resolved (locked) package set, without fetching
+/// repodata or solving.
+pub(crate) async fn from_locked_packages(
+    target_prefix: &PathBuf,
+    env_spec: &VivaEnvSpec,
+    packages: Vec<RepoDataRecord>,
+    config: &VivaConfig,
+) -> Result<SolvedEnvironment> {
+    let channel_config = config.channel_config()?;
+
+    let specs = env_spec
+        .pkg_specs
+        .iter()
+        .map(|spec| MatchSpec::from_str(spec))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    let cache_dir = default_cache_dir()?;
+    std::fs::create_dir_all(&cache_dir)
+        .map_err(|e| anyhow::anyhow!("could not create cache directory: {}", e))?;
+
+    let download_client = Client::builder()
+        .no_gzip()
+        .build()
+        .expect("failed to create client");
+    let auth = Arc::new(ChannelAuth::load(config)?);
+
+    // The locked urls point to the mirror that was used when resolving, the other mirrors of the
+    // same channel can still be used as fallbacks.
+    let mut mirror_groups: Vec<Vec<Url>> = vec![];
+    for channel_str in &env_spec.channels {
+        if let Some(mirrors) = config.channel_mirrors_for(channel_str)? {
+            if !mirrors.is_empty() {
+                mirror_groups.push(
+                    mirrors
+                        .iter()
+                        .map(|mirror| Channel::from_str(mirror, &channel_config).map(|c| c.base_url))
+                        .collect::<Result<Vec<_>, _>>()?,
+                );
+            }
+        }
+    }
+
+    let installed_packages = find_installed_packages(&target_prefix, 100)
+        .await
+        .context("failed to determine currently installed packages")?;
+
+    Ok(SolvedEnvironment {
+        installed_packages,
+        required_packages: packages,
+        platform: env_spec.get_platform()?,
+        pkgs_dir: packages_dir(config)?,
+        download_client,
+        auth,
+        mirror_groups,
+        specs,
+        available_packages: vec![],
+    })
+}
+
+/// The virtual packages to assume for a platform other than the current one.
+///
+/// Like with conda, the versions can be overridden with the `CONDA_OVERRIDE_GLIBC`,
+/// `CONDA_OVERRIDE_OSX` and `CONDA_OVERRIDE_CUDA` environment variables.
+fn default_virtual_packages(platform: Platform) -> Result<Vec<GenericVirtualPackage>> {
+    let virtual_package = |name: &str, version: &str, build_string: &str| -> Result<GenericVirtualPackage> {
+        Ok(GenericVirtualPackage {
+            name: String::from(name),
+            version: Version::from_str(version)?,
+            build_string: String::from(build_string),
+        })
+    };
+    let override_or = |var: &str, default: &str| std::env::var(var).unwrap_or_else(|_| String::from(default));
+
+    let platform_name = platform.as_str();
+    let mut packages = vec![];
+    if platform_name.starts_with("linux-") {
+        packages.push(virtual_package("__unix", "0", "0")?);
+        packages.push(virtual_package("__linux", "0", "0")?);
+        packages.push(virtual_package("__glibc", &override_or("CONDA_OVERRIDE_GLIBC", "2.17"), "0")?);
+    } else if platform_name.starts_with("osx-") {
+        let default_osx = match platform {
+            Platform::OsxArm64 => "11.0",
+            _ => "10.15",
+        };
+        packages.push(virtual_package("__unix", "0", "0")?);
+        packages.push(virtual_package("__osx", &override_or("CONDA_OVERRIDE_OSX", default_osx), "0")?);
+    } else if platform_name.starts_with("win-") {
+        packages.push(virtual_package("__win", "0", "0")?);
+    }
+    if let Ok(cuda) = std::env::var("CONDA_OVERRIDE_CUDA") {
+        if !cuda.is_empty() {
+            packages.push(virtual_package("__cuda", &cuda, "0")?);
+        }
+    }
+    Ok(packages)
+}
+
+/// Collect the chains of requirements that lead from the spec to a package (root first).
+fn collect_requirement_chains(
+    name: &str,
+    constraints: &[(String, MatchSpec, String)],
+    current: &mut Vec<String>,
+    chains: &mut Vec<Vec<String>>,
+) {
+    const MAX_CHAINS: usize = 10;
+    const MAX_DEPTH: usize = 20;
+
+    for (constraint_name, _, source) in constraints {
+        if chains.len() >= MAX_CHAINS || current.len() >= MAX_DEPTH {
+            return;
+        }
+        if constraint_name != name || source.contains(" constrains ") {
+            continue;
+        }
+        if current.contains(source) {
+            // dependency cycle
+            continue;
+        }
+        current.push(source.clone());
+        match source.starts_with("spec ") {
+            true => chains.push(current.iter().rev().cloned().collect()),
+            false => {
+                let requirer = source.split(' ').next().unwrap_or_default().to_string();
+                collect_requirement_chains(&requirer, constraints, current, chains);
+            }
+        }
+        current.pop();
+    }
+}
+
+/// Apply a solved environment to the target prefix.
+pub(crate) async fn apply(
+    target_prefix: &PathBuf,
+    env_spec: &VivaEnvSpec,
+    solved: SolvedEnvironment,
+    config: &VivaConfig,
+) -> Result<()> {
+    // Make sure the environment stays within its size budget (if it has one).
+    if let Some(max_size) = env_spec.get_max_size_bytes()? {
+        check_size_budget(&solved.required_packages, max_size, config.size_budget_policy)?;
+    }
+
+    // The packages the files of the environment belong to, to detect packages that would overwrite
+    // each other's files
+    let path_owners = PathOwners::new(&solved.installed_packages);
+
+    // Construct a transaction to
+    let transaction = Transaction::from_current_and_desired(
+        solved.installed_packages,
+        solved.required_packages,
+        solved.platform,
     )?;
 
     if !transaction.operations.is_empty() {
         // Execute the operations that are returned by the solver.
-        execute_transaction(transaction, target_prefix, cache_dir, download_client).await?;
-        println!(
+        let rate_limiter = match &config.limit_rate {
+            Some(limit_rate) => Some(Arc::new(RateLimiter::new(parse_size(limit_rate)?))),
+            None => None,
+        };
+        execute_transaction(
+            transaction,
+            target_prefix,
+            solved.pkgs_dir,
+            solved.download_client,
+            solved.auth,
+            rate_limiter,
+            StallPolicy::from_config(config)?,
+            solved.mirror_groups,
+            config.offline,
+            &path_owners,
+            config.path_conflicts,
+        )
+        .await?;
+        eprintln!(
             "{} Successfully updated the environment",
             console::style(console::Emoji("✔", "")).green(),
         );
     } else {
-        println!(
+        eprintln!(
             "{} Already up to date",
             console::style(console::Emoji("✔", "")).green(),
         );
@@ -180,15 +700,143 @@ pub async fn create(
     Ok(())
 }
 
+/// Returns the directory that holds the package and repodata caches.
+pub(crate) fn default_cache_dir() -> Result<PathBuf> {
+    Ok(dirs::cache_dir()
+        .ok_or_else(|| anyhow::anyhow!("could not determine cache directory for current platform"))?
+        .join("rattler/cache"))
+}
+
+/// Returns the directory pip installs packages from in offline mode (wheels or source archives, e.g.
+/// from `pip download -d <dir>`), since pip has no cache it can install from without an index.
+pub(crate) fn pip_wheels_dir() -> Result<PathBuf> {
+    Ok(default_cache_dir()?.join("pip"))
+}
+
+/// Returns the directory of the package cache (`pkgs_dir` in the config, or `pkgs` in the default
+/// cache directory).
+pub(crate) fn packages_dir(config: &VivaConfig) -> Result<PathBuf> {
+    match &config.pkgs_dir {
+        Some(pkgs_dir) => Ok(pkgs_dir.clone()),
+        None => Ok(default_cache_dir()?.join("pkgs")),
+    }
+}
+
+/// The name of the directory of a package in the package cache.
+pub(crate) fn cache_entry_name(package_record: &PackageRecord) -> String {
+    format!("{}-{}-{}", package_record.name, package_record.version, package_record.build)
+}
+
+/// Checks the projected size of the environment against its budget, and warns or fails (depending
+/// on the policy) if it is exceeded.
+///
+/// Since repodata doesn't contain installed sizes, the (compressed) download size of the packages
+/// is used as the projection.
+fn check_size_budget(
+    required_packages: &[RepoDataRecord],
+    max_size: u64,
+    policy: SizeBudgetPolicy,
+) -> Result<()> {
+    let total_size: u64 = required_packages
+        .iter()
+        .map(|record| record.package_record.size.unwrap_or(0))
+        .sum();
+    if total_size <= max_size {
+        return Ok(());
+    }
+
+    let mut largest = required_packages.iter().collect::<Vec<_>>();
+    largest.sort_by_key(|record| std::cmp::Reverse(record.package_record.size.unwrap_or(0)));
+    let largest = largest
+        .iter()
+        .take(5)
+        .map(|record| {
+            format!(
+                "  {} {} ({})",
+                record.package_record.name,
+                record.package_record.version,
+                HumanBytes(record.package_record.size.unwrap_or(0))
+            )
+        })
+        .collect::<Vec<_>>()
+        .join("\n");
+    let msg = format!(
+        "projected environment size ({}) exceeds its budget ({}), largest packages:\n{}",
+        HumanBytes(total_size),
+        HumanBytes(max_size),
+        largest
+    );
+
+    match policy {
+        SizeBudgetPolicy::Fail => Err(anyhow::anyhow!(msg)),
+        SizeBudgetPolicy::Warn => {
+            eprintln!(
+                "{} {}",
+                console::style(console::Emoji("⚠", "!")).yellow(),
+                msg
+            );
+            Ok(())
+        }
+    }
+}
+
+/// Returns true if both records describe the same package build.
+/// The files of an extracted package (from `info/paths.json`, or the older `info/files`).
+fn package_file_list(package_dir: &Path) -> Option<HashSet<String>> {
+    let info_dir = package_dir.join("info");
+    if let Ok(paths_json) = std::fs::read_to_string(info_dir.join("paths.json")) {
+        let paths_json: serde_json::Value = serde_json::from_str(&paths_json).ok()?;
+        let files = paths_json
+            .get("paths")?
+            .as_array()?
+            .iter()
+            .filter_map(|entry| entry.get("_path").and_then(|path| path.as_str()))
+            .map(String::from)
+            .collect();
+        return Some(files);
+    }
+    let files = std::fs::read_to_string(info_dir.join("files")).ok()?;
+    Some(
+        files
+            .lines()
+            .map(|line| line.trim().to_string())
+            .filter(|line| !line.is_empty())
+            .collect(),
+    )
+}
+
+fn is_same_package(a: &RepoDataRecord, b: &RepoDataRecord) -> bool {
+    a.package_record.name == b.package_record.name
+        && a.package_record.version == b.package_record.version
+        && a.package_record.build == b.package_record.build
+}
+
 /// Executes the transaction on the given environment.
+#[allow(clippy::too_many_arguments)]
 async fn execute_transaction(
     transaction: Transaction<PrefixRecord, RepoDataRecord>,
     target_prefix: &PathBuf,
-    cache_dir: PathBuf,
+    pkgs_dir: PathBuf,
     download_client: Client,
+    auth: Arc<ChannelAuth>,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    stall_policy: StallPolicy,
+    mirror_groups: Vec<Vec<Url>>,
+    offline: bool,
+    path_owners: &PathOwners,
+    path_conflicts: PathConflictPolicy,
 ) -> anyhow::Result<()> {
-    // Open the package cache
-    let package_cache = PackageCache::new(cache_dir.join("pkgs"));
+    // The files of removed packages (including the old versions of updated ones) can be claimed by
+    // other packages.
+    for op in &transaction.operations {
+        if let Some(remove_record) = op.record_to_remove() {
+            path_owners.release(&remove_record.repodata_record.package_record.name);
+        }
+    }
+
+    // Open the package cache, its entries are locked while they are used (see `PackageCacheLock`),
+    // since other tools (if it is shared) or `viva cache clean` might change them concurrently
+    let package_cache = PackageCache::new(&pkgs_dir);
 
     // Create an install driver which helps limit the number of concurrent fileystem operations
     let install_driver = InstallDriver::default();
@@ -230,33 +878,65 @@ async fn execute_transaction(
     );
     link_pb.enable_steady_tick(Duration::from_millis(100));
 
-    // Perform all transactions operations in parallel.
-    stream::iter(transaction.operations)
-        .map(Ok)
-        .try_for_each_concurrent(50, |op| {
+    ProgressEvent::new("install", "started")
+        .count(0, Some(total_operations as u64))
+        .emit();
+
+    // Perform all transactions operations in parallel. All of them are attempted, so the failures of
+    // all packages can be reported.
+    let failures = stream::iter(transaction.operations)
+        .map(|op| {
             let target_prefix = target_prefix.clone();
             let download_client = download_client.clone();
+            let auth = &auth;
             let package_cache = &package_cache;
             let install_driver = &install_driver;
             let download_pb = download_pb.as_ref();
             let link_pb = &link_pb;
             let install_options = &install_options;
+            let rate_limiter = rate_limiter.clone();
+            let mirror_groups = &mirror_groups;
+            let pkgs_dir = &pkgs_dir;
             async move {
                 execute_operation(
                     &target_prefix,
                     download_client,
+                    auth,
+                    rate_limiter,
+                    stall_policy,
+                    mirror_groups,
+                    offline,
                     package_cache,
+                    pkgs_dir,
                     install_driver,
                     download_pb,
                     link_pb,
                     op,
                     install_options,
+                    path_owners,
+                    path_conflicts,
                 )
                 .await
             }
         })
-        .await?;
+        .buffer_unordered(50)
+        .filter_map(|result| ready(result.err()))
+        .collect::<Vec<PackageFailure>>()
+        .await;
+    if is_cancelled() {
+        ProgressEvent::new("install", "failed").emit();
+        link_pb.abandon_with_message("Cancelled");
+        anyhow::bail!("cancelled");
+    }
+    if !failures.is_empty() {
+        ProgressEvent::new("install", "failed").emit();
+        link_pb.abandon_with_message("Failed");
+        return Err(TransactionFailures(failures).into());
+    }
 
+    ProgressEvent::new("install", "finished")
+        .count(total_operations as u64, Some(total_operations as u64))
+        .emit();
     Ok(())
 }
 
@@ -266,37 +946,101 @@ async fn execute_transaction(
 async fn execute_operation(
     target_prefix: &Path,
     download_client: Client,
+    auth: &ChannelAuth,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    stall_policy: StallPolicy,
+    mirror_groups: &[Vec<Url>],
+    offline: bool,
     package_cache: &PackageCache,
+    pkgs_dir: &Path,
     install_driver: &InstallDriver,
     download_pb: Option<&ProgressBar>,
     link_pb: &ProgressBar,
     op: TransactionOperation<PrefixRecord, RepoDataRecord>,
     install_options: &InstallOptions,
-) -> anyhow::Result<()> {
+    path_owners: &PathOwners,
+    path_conflicts: PathConflictPolicy,
+) -> Result<(), PackageFailure> {
     // Determine the package to install
     let install_record = op.record_to_install();
     let remove_record = op.record_to_remove();
+    let package_name = install_record
+        .map(|record| record.package_record.name.to_string())
+        .or_else(|| remove_record.map(|record| record.repodata_record.package_record.name.to_string()))
+        .unwrap_or_default();
+    let package_record = install_record
+        .map(|record| &record.package_record)
+        .or_else(|| remove_record.map(|record| &record.repodata_record.package_record));
+    let failure = |step: OperationStep| {
+        let package = match package_record {
+            Some(record) => format!("{} {}", record.name, record.version),
+            None => package_name.clone(),
+        };
+        move |error: anyhow::Error| PackageFailure { package, step, error }
+    };
+
+    // operations that already started are finished, so nothing is left running when the
+    // transaction returns
+    check_cancelled().map_err(failure(OperationStep::Download))?;
 
     // Create a future to remove the existing package
     let remove_future = if let Some(remove_record) = remove_record {
         remove_package_from_environment(target_prefix, remove_record).left_future()
     } else {
         ready(Ok(())).right_future()
-    };
+    }
+    .map_err(failure(OperationStep::Remove));
 
     // Create a future to download the package
     let cached_package_dir_fut = if let Some(install_record) = install_record {
         async {
-            // Make sure the package is available in the package cache.
-            let result = package_cache
-                .get_or_fetch_from_url(
-                    &install_record.package_record,
-                    install_record.url.clone(),
-                    download_client.clone(),
-                )
-                .map_ok(|cache_dir| Some((install_record.clone(), cache_dir)))
-                .map_err(anyhow::Error::from)
-                .await;
+            // Nobody else may extract (or remove) the package while it is validated (and downloaded,
+            // if necessary).
+            let _lock =
+                PackageCacheLock::acquire(&pkgs_dir.join(cache_entry_name(&install_record.package_record)), true)
+                    .await?;
+
+            // Make sure the package is available in the package cache. If the package can't be
+            // downloaded from its url, try the other mirrors of its channel (if there are any).
+            let urls = match offline {
+                true => vec![install_record.url.clone()],
+                false => std::iter::once(install_record.url.clone())
+                    .chain(alternative_mirror_urls(&install_record.url, mirror_groups))
+                    .collect::<Vec<_>>(),
+            };
+            let mut result = Err(anyhow::anyhow!("no url for package"));
+            for (idx, url) in urls.iter().enumerate() {
+                result = match offline {
+                    true => cached_package(package_cache, &install_record.package_record).await,
+                    false => fetch_package_into_cache(
+                        package_cache,
+                        &install_record.package_record,
+                        url.clone(),
+                        download_client.clone(),
+                        auth,
+                        rate_limiter.clone(),
+                        stall_policy,
+                    )
+                    .await,
+                }
+                .map(|cache_dir| {
+                    // the prefix records which mirror the package was downloaded from
+                    let mut record = install_record.clone();
+                    record.url = url.clone();
+                    Some((record, cache_dir))
+                });
+                match &result {
+                    Err(e) if idx + 1 < urls.len() => {
+                        tracing::warn!("failed to download {}, trying next mirror: {}", url, e);
+                    }
+                    _ => break,
+                }
+            }
+
+            // pruning the cache by age is based on when packages were last used
+            if let Ok(Some((_, cache_dir))) = &result {
+                cache::record_package_use(cache_dir);
+            }
 
             // Increment the download progress bar.
             if let Some(pb) = download_pb {
@@ -304,6 +1048,11 @@ async fn execute_operation(
                 if pb.length() == Some(pb.position()) {
                     pb.set_style(finished_progress_style());
                 }
+                let status = if result.is_ok() { "progress" } else { "failed" };
+                ProgressEvent::new("download", status)
+                    .package(&install_record.package_record.name)
+                    .count(pb.position(), pb.length())
+                    .emit();
             }
 
             result
@@ -311,21 +1060,30 @@ async fn execute_operation(
         .left_future()
     } else {
         ready(Ok(None)).right_future()
-    };
+    }
+    .map_err(failure(OperationStep::Download));
 
     // Await removal and downloading concurrently
     let (_, install_package) = tokio::try_join!(remove_future, cached_package_dir_fut)?;
+    check_cancelled().map_err(failure(OperationStep::Link))?;
 
     // If there is a package to install, do that now.
     if let Some((record, package_dir)) = install_package {
-        install_package_to_environment(
-            target_prefix,
-            package_dir,
-            record.clone(),
-            install_driver,
-            install_options,
-        )
-        .await?;
+        async {
+            let _lock = PackageCacheLock::acquire(&package_dir, false).await?;
+            install_package_to_environment(
+                target_prefix,
+                package_dir,
+                record.clone(),
+                install_driver,
+                install_options,
+                path_owners,
+                path_conflicts,
+            )
+            .await
+        }
+        .await
+        .map_err(failure(OperationStep::Link))?;
     }
 
     // Increment the link progress bar since we finished a step!
@@ -333,10 +1091,199 @@ async fn execute_operation(
     if link_pb.length() == Some(link_pb.position()) {
         link_pb.set_style(finished_progress_style());
     }
+    ProgressEvent::new("link", "progress")
+        .package(package_name)
+        .count(link_pb.position(), link_pb.length())
+        .emit();
 
     Ok(())
 }
 
+/// Make sure a package is in the package cache, downloading it from the given url if necessary.
+async fn fetch_package_into_cache(
+    package_cache: &PackageCache,
+    package_record: &PackageRecord,
+    url: Url,
+    download_client: Client,
+    auth: &ChannelAuth,
+    rate_limiter: Option<Arc<RateLimiter>>,
+    stall_policy: StallPolicy,
+) -> anyhow::Result<PathBuf> {
+    // A registered custom fetcher takes precedence, otherwise https downloads are streamed directly
+    // into the cache unless they have to be throttled, watched for stalls, or sent with the
+    // credentials of the channel as header (a conda token is part of the url).
+    let custom_fetcher = fetch::custom_fetcher_for(&url);
+    let (url, authorization) = match custom_fetcher {
+        Some(_) => (url, None),
+        None => auth.authenticate(url)?,
+    };
+    let downloader = match (custom_fetcher, rate_limiter) {
+        (Some(fetcher), _) => PackageDownloader::Custom(fetcher),
+        (None, Some(rate_limiter)) => PackageDownloader::Https(
+            HttpsFetcher::with_rate_limiter(download_client, rate_limiter).with_authorization(authorization),
+            stall_policy,
+        ),
+        (None, None) if stall_policy.timeout.is_some() || authorization.is_some() => PackageDownloader::Https(
+            HttpsFetcher::new(download_client).with_authorization(authorization),
+            stall_policy,
+        ),
+        (None, None) => {
+            return Ok(package_cache
+                .get_or_fetch_from_url(package_record, url, download_client)
+                .await?);
+        }
+    };
+    let cache_dir = package_cache
+        .get_or_fetch(package_record, move |destination| async move {
+            fetch_package_with(downloader, url, destination).await
+        })
+        .await?;
+    Ok(cache_dir)
+}
+
+/// How a package archive is downloaded (by [`fetch_package_with`]).
+enum PackageDownloader {
+    /// With a registered custom fetcher.
+    Custom(Arc<dyn Fetcher>),
+    /// Over https, retrying stalled downloads.
+    Https(HttpsFetcher, StallPolicy),
+}
+
+impl PackageDownloader {
+    async fn download(&self, url: &Url, destination: &Path) -> Result<()> {
+        match self {
+            PackageDownloader::Custom(fetcher) => fetcher.fetch(url, destination).await,
+            PackageDownloader::Https(fetcher, stall_policy) => {
+                // the url can contain a conda token, which must not end up in warnings or logs
+                let what = format!("downloading {}", display_url(url));
+                retry_stalled(&what, *stall_policy, |heartbeat| async move {
+                    fetcher.fetch_with_heartbeat(url, destination, Some(&heartbeat)).await
+                })
+                .await
+            }
+        }
+    }
+}
+
+/// Returns the directory of a package in the package cache, without downloading it if it's not
+/// there (for offline mode).
+async fn cached_package(
+    package_cache: &PackageCache,
+    package_record: &PackageRecord,
+) -> anyhow::Result<PathBuf> {
+    let package = cache_entry_name(package_record);
+    package_cache
+        .get_or_fetch(package_record, move |_destination| async move {
+            Err(std::io::Error::new(
+                ErrorKind::NotFound,
+                format!("{} is not in the package cache", package),
+            ))
+        })
+        .await
+        .map_err(|e| {
+            anyhow::anyhow!(
+                "Package not available in offline mode (sync once while online to fill the cache): {}",
+                e
+            )
+        })
+}
+
+/// Returns the urls of a package on all the other mirrors of its channel.
+fn alternative_mirror_urls(url: &Url, mirror_groups: &[Vec<Url>]) -> Vec<Url> {
+    for group in mirror_groups {
+        let base = group
+            .iter()
+            .find(|base| url.as_str().starts_with(base.as_str()));
+        if let Some(base) = base {
+            let suffix = &url.as_str()[base.as_str().len()..];
+            return group
+                .iter()
+                .filter(|other| *other != base)
+                .filter_map(|other| Url::parse(&format!("{}{}", other.as_str(), suffix)).ok())
+                .collect();
+        }
+    }
+    vec![]
+}
+
+/// Order channel mirrors by the latency of a request to their `noarch` repodata, unreachable
+/// mirrors are moved to the end.
+async fn order_by_latency(client: &Client, mirrors: Vec<Channel>) -> Vec<Channel> {
+    let latencies = futures::future::join_all(mirrors.iter().map(|mirror| {
+        let url = mirror.platform_url(Platform::NoArch).join("repodata.json");
+        let client = client.clone();
+        async move {
+            let url = url.ok()?;
+            let start = std::time::Instant::now();
+            let response = client
+                .head(url)
+                .timeout(Duration::from_secs(5))
+                .send()
+                .await
+                .ok()?;
+            match response.status().is_success() {
+                true => Some(start.elapsed()),
+                false => None,
+            }
+        }
+    }))
+    .await;
+
+    let mut mirrors = mirrors.into_iter().zip(latencies).collect::<Vec<_>>();
+    mirrors.sort_by_key(|(_, latency)| latency.unwrap_or(Duration::MAX));
+    mirrors.into_iter().map(|(mirror, _)| mirror).collect()
+}
+
+/// Download a package archive with the provided downloader, and extract it into the destination.
+async fn fetch_package_with(
+    downloader: PackageDownloader,
+    url: Url,
+    destination: PathBuf,
+) -> Result<(), std::io::Error> {
+    let to_io_error = |e: anyhow::Error| std::io::Error::new(ErrorKind::Other, e);
+
+    let file_name = url
+        .path_segments()
+        .and_then(|segments| segments.last())
+        .unwrap_or("package.tar.bz2")
+        .to_string();
+    let download_dir = DownloadDir::create(&destination)?;
+    let archive_path = download_dir.0.join(&file_name);
+
+    downloader
+        .download(&url, &archive_path)
+        .await
+        .map_err(to_io_error)?;
+
+    // the download directory is deleted (when it's dropped) after the archive was extracted
+    tokio::task::spawn_blocking(move || {
+        rattler_package_streaming::fs::extract(&archive_path, &destination)
+            .map(|_| ())
+            .map_err(|e| std::io::Error::new(ErrorKind::Other, e))
+    })
+    .await
+    .map_err(|e| std::io::Error::new(ErrorKind::Other, e))?
+}
+
+/// A temporary download directory next to a package cache destination, deleted when it's dropped
+/// (so also if the download fails, or is cancelled). Directories of killed processes are removed
+/// when the cache is pruned.
+struct DownloadDir(PathBuf);
+
+impl DownloadDir {
+    fn create(destination: &Path) -> Result<DownloadDir, std::io::Error> {
+        let dir = PathBuf::from(format!("{}.download", destination.display()));
+        std::fs::create_dir_all(&dir)?;
+        Ok(DownloadDir(dir))
+    }
+}
+
+impl Drop for DownloadDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
 /// Install a package into the environment and write a `conda-meta` file that contains information
 /// about how the file was linked.
 async fn install_package_to_environment(
@@ -345,14 +1292,53 @@ async fn install_package_to_environment(
     repodata_record: RepoDataRecord,
     install_driver: &InstallDriver,
     install_options: &InstallOptions,
+    path_owners: &PathOwners,
+    path_conflicts: PathConflictPolicy,
 ) -> anyhow::Result<()> {
+    // Make sure the package doesn't overwrite the files of other packages (unless that's wanted).
+    let package_name = repodata_record.package_record.name.clone();
+    let python_info = install_options.python_info.clone();
+    let paths_package_dir = package_dir.clone();
+    let (mut paths_json, target_paths) = tokio::task::spawn_blocking(move || {
+        conflicts::package_paths(&paths_package_dir, python_info.as_ref())
+    })
+    .await??;
+    let conflicts = path_owners.claim(&package_name, &target_paths, path_conflicts);
+    if !conflicts.is_empty() {
+        let listed = conflicts
+            .iter()
+            .map(|conflict| conflict.to_string())
+            .collect::<Vec<String>>()
+            .join(", ");
+        match path_conflicts {
+            PathConflictPolicy::Error => return Err(PathConflicts(conflicts).into()),
+            PathConflictPolicy::Clobber => {
+                tracing::warn!("{}: overwriting files of other packages: {}", package_name, listed);
+            }
+            PathConflictPolicy::Skip => {
+                tracing::warn!("{}: keeping files of other packages: {}", package_name, listed);
+                let skipped = conflicts.into_iter().map(|conflict| conflict.path).collect::<HashSet<PathBuf>>();
+                paths_json.paths = paths_json
+                    .paths
+                    .into_iter()
+                    .zip(target_paths)
+                    .filter(|(_, target_path)| !skipped.contains(target_path))
+                    .map(|(entry, _)| entry)
+                    .collect();
+            }
+        }
+    }
+
     // Link the contents of the package into our environment. This returns all the paths that were
     // linked.
     let paths = link_package(
         &package_dir,
         target_prefix,
         install_driver,
-        install_options.clone(),
+        InstallOptions {
+            paths_json: Some(paths_json),
+            ..install_options.clone()
+        },
     )
     .await?;
 
@@ -436,15 +1422,162 @@ async fn remove_package_from_environment(
 
 /// Displays a spinner with the given message while running the specified function to completion.
 fn wrap_in_progress<T, F: FnOnce() -> T>(msg: impl Into<Cow<'static, str>>, func: F) -> T {
+    let msg = msg.into();
     let pb = ProgressBar::new_spinner();
+    if json_progress_enabled() {
+        pb.set_draw_target(ProgressDrawTarget::hidden());
+    }
     pb.enable_steady_tick(Duration::from_millis(100));
     pb.set_style(long_running_progress_style());
-    pb.set_message(msg);
+    pb.set_message(msg.clone());
+    ProgressEvent::new(&msg, "started").emit();
     let result = func();
     pb.finish_and_clear();
+    ProgressEvent::new(&msg, "finished").emit();
     result
 }
 
+/// Run the solver on the provided repodata (which is handed back with the solution).
+///
+/// With a time limit, the solver runs on a thread of its own: libsolv can't be interrupted, so a
+/// solve that takes too long is abandoned (its thread ends with the process).
+fn solve_with_timeout<S>(
+    repodatas: Vec<Vec<RepoDataRecord>>,
+    solve_timeout: Option<Duration>,
+    problem_size: &str,
+    solve: S,
+) -> Result<(Vec<RepoDataRecord>, Vec<Vec<RepoDataRecord>>)>
+where
+    S: FnOnce(&[Vec<RepoDataRecord>]) -> Result<Vec<RepoDataRecord>> + Send + 'static,
+{
+    wrap_in_progress("solving", move || {
+        let solve_timeout = match solve_timeout {
+            Some(solve_timeout) => solve_timeout,
+            None => return solve(&repodatas).map(|required| (required, repodatas)),
+        };
+        let (sender, receiver) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            let result = solve(&repodatas).map(|required| (required, repodatas));
+            let _ = sender.send(result);
+        });
+        match receiver.recv_timeout(solve_timeout) {
+            Ok(result) => result,
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => Err(anyhow::anyhow!(
+                "Solving did not finish within {}s ({}), narrow down the package specs (e.g. pin versions), or raise the 'solve_timeout' setting",
+                solve_timeout.as_secs(),
+                problem_size
+            )),
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => {
+                Err(anyhow::anyhow!("The solver stopped unexpectedly ({})", problem_size))
+            }
+        }
+    })
+}
+
+/// Remove the package records that are not part of the view of their channel (see
+/// [`VivaEnvSpec::channel_views`]).
+///
+/// Fails if a package spec of the environment names a package that only the views exclude, so
+/// that is reported as such instead of as an unsolvable spec.
+fn apply_channel_views(
+    repodatas: Vec<Vec<RepoDataRecord>>,
+    env_spec: &VivaEnvSpec,
+    specs: &[MatchSpec],
+    config: &VivaConfig,
+) -> Result<Vec<Vec<RepoDataRecord>>> {
+    let channel_config = config.channel_config()?;
+    // records refer to their channel by its canonical name (the one of the mirror they came from)
+    let mut views: HashMap<String, (&str, GlobSet)> = HashMap::new();
+    for (channel_str, patterns) in &env_spec.channel_views {
+        if !env_spec.channels.contains(channel_str) {
+            anyhow::bail!("Channel view for a channel the environment doesn't use: {}", channel_str);
+        }
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(
+                Glob::new(pattern)
+                    .with_context(|| format!("Invalid pattern in the view of channel '{}': {}", channel_str, pattern))?,
+            );
+        }
+        let view = builder.build()?;
+        let mut channels = vec![channel_str.clone()];
+        if let Some(mirrors) = config.channel_mirrors_for(channel_str)? {
+            channels.extend(mirrors.iter().cloned());
+        }
+        for channel in channels {
+            let canonical_name = Channel::from_str(&channel, &channel_config)?.canonical_name();
+            views.insert(canonical_name, (channel_str.as_str(), view.clone()));
+        }
+    }
+
+    let mut excluded: HashMap<String, &str> = HashMap::new();
+    let mut available: HashSet<String> = HashSet::new();
+    let repodatas = repodatas
+        .into_iter()
+        .map(|records| {
+            records
+                .into_iter()
+                .filter(|record| {
+                    let name = &record.package_record.name;
+                    match views.get(&record.channel) {
+                        Some((channel_str, view)) if !view.is_match(name) => {
+                            excluded.insert(name.clone(), *channel_str);
+                            false
+                        }
+                        _ => {
+                            available.insert(name.clone());
+                            true
+                        }
+                    }
+                })
+                .collect::<Vec<_>>()
+        })
+        .collect::<Vec<_>>();
+
+    for spec in specs {
+        if let Some(name) = &spec.name {
+            if let (Some(channel_str), false) = (excluded.get(name), available.contains(name)) {
+                anyhow::bail!(
+                    "Package '{}' is not part of the view of channel '{}' (see 'channel_views' in the environment spec)",
+                    name,
+                    channel_str
+                );
+            }
+        }
+    }
+    Ok(repodatas)
+}
+
+/// The repodata of a single channel subdirectory (from the repodata cache, if it is up to date),
+/// for checks that don't need a full solve.
+pub(crate) async fn fetch_channel_repodata(
+    channel: &str,
+    platform: Platform,
+    config: &VivaConfig,
+) -> Result<SparseRepoData> {
+    let channel = Channel::from_str(channel, &config.channel_config()?)?;
+    let cache_action = match config.offline {
+        true => CacheAction::UseCacheOnly,
+        false => CacheAction::CacheOrFetch,
+    };
+    let download_client = Client::builder()
+        .no_gzip()
+        .build()
+        .expect("failed to create client");
+    let auth = ChannelAuth::load(config)?;
+    fetch_repo_data_records_with_progress(
+        channel,
+        platform,
+        &default_cache_dir()?.join("repodata"),
+        download_client,
+        &auth,
+        global_multi_progress(),
+        cache_action,
+        StallPolicy::from_config(config)?,
+    )
+    .await
+}
+
 /// Given a channel and platform, download and cache the `repodata.json` for it. This function
 /// reports its progress via a CLI progressbar.
 async fn fetch_repo_data_records_with_progress(
@@ -452,8 +1585,10 @@ async fn fetch_repo_data_records_with_progress(
     platform: Platform,
     repodata_cache: &Path,
     client: Client,
+    auth: &ChannelAuth,
     multi_progress: indicatif::MultiProgress,
     cache_action: CacheAction,
+    stall_policy: StallPolicy,
 ) -> Result<SparseRepoData, anyhow::Error> {
     // Create a progress bar
     let progress_bar = multi_progress.add(
@@ -463,22 +1598,85 @@ async fn fetch_repo_data_records_with_progress(
             .with_style(default_bytes_style()),
     );
     progress_bar.enable_steady_tick(Duration::from_millis(100));
+    let subdir = format!("{}/{platform}", friendly_channel_name(&channel));
+    check_cancelled()?;
+    ProgressEvent::new("repodata", "started").package(&subdir).emit();
 
-    // Download the repodata.json
-    let download_progress_progress_bar = progress_bar.clone();
-    let result = rattler_repodata_gateway::fetch::fetch_repo_data(
-        channel.platform_url(platform),
-        client,
-        repodata_cache,
-        FetchRepoDataOptions {
-            cache_action: cache_action,
-            download_progress: Some(Box::new(move |DownloadProgress { total, bytes }| {
-                download_progress_progress_bar.set_length(total.unwrap_or(bytes));
-                download_progress_progress_bar.set_position(bytes);
-            })),
-            ..Default::default()
-        },
-    )
+    // Channels with a scheme that is handled by a custom fetcher bypass the repodata gateway, and so
+    // do channels with credentials: a conda token is part of the url, which the gateway would use as
+    // cache key (and record in the cache), and other credentials are sent as header, which the
+    // gateway can only do with a client of its own. Their repodata is cached under the bare url.
+    let platform_url = channel.platform_url(platform);
+    let direct_fetch = match fetch::custom_fetcher_for(&platform_url) {
+        Some(fetcher) => Some((fetcher, platform_url.clone())),
+        None => {
+            let (authenticated_url, authorization) = auth.authenticate(platform_url.clone())?;
+            match authenticated_url != platform_url || authorization.is_some() {
+                true => {
+                    let fetcher: Arc<dyn Fetcher> =
+                        Arc::new(HttpsFetcher::new(client.clone()).with_authorization(authorization));
+                    Some((fetcher, authenticated_url))
+                }
+                false => None,
+            }
+        }
+    };
+    if let Some((fetcher, fetch_url)) = direct_fetch {
+        let result = fetch_repo_data_with(
+            fetcher.as_ref(),
+            &platform_url,
+            &fetch_url,
+            repodata_cache,
+            cache_action,
+        )
+        .await;
+        let repo_data_json_path = match result {
+            Err(e) => {
+                progress_bar.set_style(errored_progress_style());
+                progress_bar.finish_with_message("Error");
+                ProgressEvent::new("repodata", "failed").package(&subdir).emit();
+                return Err(e);
+            }
+            Ok(path) => path,
+        };
+        progress_bar.set_style(deserializing_progress_style());
+        progress_bar.set_message("Deserializing..");
+        let repodata = tokio::task::spawn_blocking(move || {
+            SparseRepoData::new(channel, platform.to_string(), repo_data_json_path)
+        })
+        .await??;
+        progress_bar.set_style(finished_progress_style());
+        progress_bar.finish_with_message("Done");
+        ProgressEvent::new("repodata", "finished").package(&subdir).emit();
+        return Ok(repodata);
+    }
+
+    // Download the repodata.json, retrying if the download stalls (the channel url itself can
+    // contain a conda token, which must not end up in warnings or logs)
+    let what = format!("fetching repodata from {}", display_url(&platform_url));
+    let result = retry_stalled(&what, stall_policy, |heartbeat| {
+        let download_progress_progress_bar = progress_bar.clone();
+        let download_progress_subdir = subdir.clone();
+        rattler_repodata_gateway::fetch::fetch_repo_data(
+            platform_url.clone(),
+            client.clone(),
+            repodata_cache,
+            FetchRepoDataOptions {
+                cache_action: cache_action,
+                download_progress: Some(Box::new(move |DownloadProgress { total, bytes }| {
+                    heartbeat.beat_total(bytes);
+                    download_progress_progress_bar.set_length(total.unwrap_or(bytes));
+                    download_progress_progress_bar.set_position(bytes);
+                    ProgressEvent::new("repodata", "progress")
+                        .package(&download_progress_subdir)
+                        .bytes(bytes, total)
+                        .emit();
+                })),
+                ..Default::default()
+            },
+        )
+        .map_err(anyhow::Error::from)
+    })
     .await;
 
     // Error out if an error occurred, but also update the progress bar
@@ -486,7 +1684,8 @@ async fn fetch_repo_data_records_with_progress(
         Err(e) => {
             progress_bar.set_style(errored_progress_style());
             progress_bar.finish_with_message("Error");
-            return Err(e.into());
+            ProgressEvent::new("repodata", "failed").package(&subdir).emit();
+            return Err(e);
         }
         Ok(result) => result,
     };
@@ -510,11 +1709,13 @@ async fn fetch_repo_data_records_with_progress(
                 CacheResult::CacheHit | CacheResult::CacheHitAfterFetch
             );
             progress_bar.finish_with_message(if is_cache_hit { "Using cache" } else { "Done" });
+            ProgressEvent::new("repodata", "finished").package(&subdir).emit();
             Ok(repodata)
         }
         Ok(Err(err)) => {
             progress_bar.set_style(errored_progress_style());
             progress_bar.finish_with_message("Error");
+            ProgressEvent::new("repodata", "failed").package(&subdir).emit();
             Err(err.into())
         }
         Err(err) => match err.try_into_panic() {
@@ -531,6 +1732,61 @@ async fn fetch_repo_data_records_with_progress(
     }
 }
 
+/// Download the `repodata.json` of a channel subdirectory with a custom fetcher (from `fetch_url`,
+/// which might contain credentials), and return its path in the repodata cache (named after
+/// `platform_url`).
+async fn fetch_repo_data_with(
+    fetcher: &dyn Fetcher,
+    platform_url: &Url,
+    fetch_url: &Url,
+    repodata_cache: &Path,
+    cache_action: CacheAction,
+) -> Result<PathBuf> {
+    let cache_name: String = platform_url
+        .as_str()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c } else { '_' })
+        .collect();
+    let cache_path = repodata_cache.join(format!("{}.json", cache_name));
+
+    // Other tasks (or viva processes) might be fetching the same repodata, so only one of them
+    // downloads it, the others use the result.
+    let _lock = CacheLock::acquire(&cache_path.with_extension("json.lock")).await?;
+    let cache_age = std::fs::metadata(&cache_path)
+        .and_then(|m| m.modified())
+        .ok()
+        .map(|modified| modified.elapsed().unwrap_or_default());
+    let use_cache = match (&cache_action, cache_age) {
+        (_, None) | (CacheAction::NoCache, _) => false,
+        (CacheAction::CacheOrFetch, Some(age)) => age < FETCHER_REPODATA_MAX_AGE,
+        (CacheAction::UseCacheOnly | CacheAction::ForceCacheOnly, Some(_)) => true,
+    };
+    if use_cache {
+        return Ok(cache_path);
+    }
+    if matches!(cache_action, CacheAction::ForceCacheOnly | CacheAction::UseCacheOnly) {
+        return Err(anyhow::anyhow!(
+            "No cached repodata available for: {}",
+            platform_url
+        ));
+    }
+
+    tokio::fs::create_dir_all(repodata_cache).await?;
+    let download_path = cache_path.with_extension("json.download");
+    if let Err(e) = fetcher
+        .fetch(&fetch_url.join("repodata.json")?, &download_path)
+        .await
+    {
+        let _ = tokio::fs::remove_file(&download_path).await;
+        return Err(e);
+    }
+    tokio::fs::rename(&download_path, &cache_path).await?;
+    // the channel of the cached file, for 'viva cache info'
+    let info = serde_json::json!({ "url": platform_url.join("repodata.json")?.as_str() });
+    tokio::fs::write(cache_path.with_extension("info.json"), info.to_string()).await?;
+    Ok(cache_path)
+}
+
 /// Returns a friendly name for the specified channel.
 fn friendly_channel_name(channel: &Channel) -> String {
     channel
@@ -600,7 +1856,7 @@ fn long_running_progress_style() -> indicatif::ProgressStyle {
 
 /// Scans the conda-meta directory of an environment and returns all the [`PrefixRecord`]s found in
 /// there.
-async fn find_installed_packages(
+pub(crate) async fn find_installed_packages(
     target_prefix: &Path,
     concurrency_limit: usize,
 ) -> Result<Vec<PrefixRecord>, std::io::Error> {
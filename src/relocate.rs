@@ -0,0 +1,422 @@
+// Moving conda prefixes to a different location.
+//
+// When packages are linked into a prefix, placeholders in their files are replaced with the path of
+// the prefix. So after moving a prefix, all occurrences of the old prefix path need to be replaced
+// with the new one. Text files can simply be rewritten, in binary files the prefix is assumed to be
+// part of a null-terminated string, which is padded with null bytes after the replacement, so
+// offsets within the file don't change. Like conda does, binary files are linked with the (long)
+// placeholder prefix their package was built with replaced, and padded to its length, so the new
+// path can be longer than the old one, as long as it's not longer than that placeholder (which is
+// read from the `info/paths.json` file of the package in the package cache).
+//
+// The rewritten files are prepared before the prefix is moved, so a relocation that fails leaves
+// the prefix as it was.
+
+use anyhow::{anyhow, bail, Context, Result};
+use rattler_conda_types::package::{FileMode, PathsJson};
+use rattler_conda_types::PrefixRecord;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::journal::{self, JournalOperation};
+
+/// Move a prefix to a new location, and rewrite all references to its old location.
+///
+/// Returns the number of files that were rewritten.
+pub(crate) async fn relocate_prefix(old_prefix: &Path, new_prefix: &Path) -> Result<usize> {
+    let old_prefix = old_prefix.to_path_buf();
+    let new_prefix = new_prefix.to_path_buf();
+
+    match tokio::task::spawn_blocking(move || relocate_prefix_blocking(&old_prefix, &new_prefix)).await {
+        Ok(result) => result,
+        Err(err) => {
+            if let Ok(panic) = err.try_into_panic() {
+                std::panic::resume_unwind(panic);
+            }
+            Err(anyhow!("cancelled"))
+        }
+    }
+}
+
+/// The directory the rewritten files of a prefix are prepared in, next to its new location (so they
+/// can be moved into place without copying).
+pub(crate) fn staging_dir(new_prefix: &Path) -> PathBuf {
+    new_prefix.with_file_name(format!(
+        ".{}.viva-relocating",
+        new_prefix.file_name().unwrap_or_default().to_string_lossy()
+    ))
+}
+
+/// Relocate a prefix in two steps: the rewritten files are prepared in a staging directory first,
+/// without touching the prefix (so every problem, like a binary file that can't take a longer
+/// path, is found before anything is changed). Then the prefix is moved, and the prepared files
+/// replace the originals. The second step is journaled, so it's finished if it's interrupted.
+fn relocate_prefix_blocking(old_prefix: &Path, new_prefix: &Path) -> Result<usize> {
+    if !old_prefix.is_dir() {
+        bail!("Prefix does not exist: {}", old_prefix.display());
+    }
+    if new_prefix.exists() {
+        bail!("Target path already exists: {}", new_prefix.display());
+    }
+    if let Some(parent) = new_prefix.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let staging = staging_dir(new_prefix);
+    if staging.exists() {
+        fs::remove_dir_all(&staging)?;
+    }
+    let rewritten = match stage_files(old_prefix, new_prefix, &staging) {
+        Ok(rewritten) => rewritten,
+        Err(e) => {
+            let _ = fs::remove_dir_all(&staging);
+            return Err(e);
+        }
+    };
+
+    let _journal = journal::begin(JournalOperation::RelocatePrefix {
+        old_prefix: old_prefix.to_path_buf(),
+        new_prefix: new_prefix.to_path_buf(),
+        staging: staging.clone(),
+    })?;
+    if let Err(e) = fs::rename(old_prefix, new_prefix) {
+        let _ = fs::remove_dir_all(&staging);
+        return Err(e).with_context(|| {
+            format!(
+                "Could not move prefix (moving across filesystems is not supported): {} -> {}",
+                old_prefix.display(),
+                new_prefix.display()
+            )
+        });
+    }
+    finish_relocation(&staging, new_prefix)?;
+    Ok(rewritten)
+}
+
+/// Write the rewritten version of every file of the prefix that references its old location into
+/// the staging directory (at the same relative path). Returns the number of staged files.
+fn stage_files(old_prefix: &Path, new_prefix: &Path, staging: &Path) -> Result<usize> {
+    let old = old_prefix.to_string_lossy().into_owned();
+    let new = new_prefix.to_string_lossy().into_owned();
+
+    let placeholder_lengths = binary_placeholder_lengths(old_prefix);
+    let mut rewritten = 0;
+    for path in collect_files(old_prefix)? {
+        let relative = path.strip_prefix(old_prefix)?;
+        let placeholder_len = placeholder_lengths.get(relative).copied();
+        let staged = staging.join(relative);
+        if stage_file(&path, &staged, old.as_bytes(), new.as_bytes(), placeholder_len, old_prefix, new_prefix)? {
+            rewritten += 1;
+        }
+    }
+    Ok(rewritten)
+}
+
+/// The lengths of the placeholders the binary files of a prefix were linked with (relative path ->
+/// placeholder length), from the `info/paths.json` files of the extracted packages. Packages that
+/// are not in the package cache anymore are skipped.
+fn binary_placeholder_lengths(prefix: &Path) -> HashMap<PathBuf, usize> {
+    let mut lengths = HashMap::new();
+    let entries = match fs::read_dir(prefix.join("conda-meta")) {
+        Ok(entries) => entries,
+        Err(_) => return lengths,
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|extension| extension.to_str()) != Some("json") {
+            continue;
+        }
+        let package_dir = match PrefixRecord::from_path(&path).map(|record| record.extracted_package_dir) {
+            Ok(Some(package_dir)) => package_dir,
+            _ => continue,
+        };
+        match PathsJson::from_package_directory_with_deprecated_fallback(&package_dir) {
+            Ok(paths) => {
+                for paths_entry in paths.paths {
+                    if let (FileMode::Binary, Some(placeholder)) = (paths_entry.file_mode, &paths_entry.prefix_placeholder) {
+                        lengths.insert(paths_entry.relative_path, placeholder.len());
+                    }
+                }
+            }
+            Err(e) => debug!("Could not read paths of package '{}': {}", package_dir.display(), e),
+        }
+    }
+    lengths
+}
+
+/// Move the staged files of a relocation into the (already moved) prefix, and remove the staging
+/// directory.
+pub(crate) fn finish_relocation(staging: &Path, new_prefix: &Path) -> Result<()> {
+    if staging.exists() {
+        for path in collect_files(staging)? {
+            let target = new_prefix.join(path.strip_prefix(staging)?);
+            fs::rename(&path, &target)
+                .with_context(|| format!("Could not move relocated file into place: {}", target.display()))?;
+            debug!("Relocated file: {}", target.display());
+        }
+        fs::remove_dir_all(staging)?;
+    }
+    Ok(())
+}
+
+/// Recursively collect all files (and symlinks) below a directory, without following symlinks.
+fn collect_files(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut result = vec![];
+    let mut dirs = vec![dir.to_path_buf()];
+    while let Some(current) = dirs.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                dirs.push(entry.path());
+            } else {
+                result.push(entry.path());
+            }
+        }
+    }
+    Ok(result)
+}
+
+/// Write the relocated version of a single file to the staging path, if it references the old
+/// prefix. Returns true if the file needs to be replaced.
+///
+/// `placeholder_len` is the length of the placeholder a binary file was linked with, if known.
+fn stage_file(
+    path: &Path,
+    staged: &Path,
+    old: &[u8],
+    new: &[u8],
+    placeholder_len: Option<usize>,
+    old_prefix: &Path,
+    new_prefix: &Path,
+) -> Result<bool> {
+    let metadata = fs::symlink_metadata(path)?;
+
+    if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path)?;
+        return match target.strip_prefix(old_prefix) {
+            Ok(relative) => {
+                create_parent(staged)?;
+                create_symlink(&new_prefix.join(relative), staged)?;
+                Ok(true)
+            }
+            Err(_) => Ok(false),
+        };
+    }
+
+    let content = fs::read(path)?;
+    if find(&content, old, 0).is_none() {
+        return Ok(false);
+    }
+
+    let new_content = match content.contains(&0u8) {
+        false => replace_text(&content, old, new),
+        true => match replace_binary(&content, old, new, placeholder_len) {
+            Some(new_content) => new_content,
+            None => bail!(
+                "Can't relocate binary file '{}', the new prefix is longer than the old one, and than the placeholder its package was built with (if the package is still in the package cache).",
+                path.display()
+            ),
+        },
+    };
+
+    // a new file, which also breaks hard links into the package cache
+    create_parent(staged)?;
+    fs::write(staged, new_content)?;
+    fs::set_permissions(staged, metadata.permissions())?;
+    Ok(true)
+}
+
+fn create_parent(path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    Ok(())
+}
+
+fn find(haystack: &[u8], needle: &[u8], start: usize) -> Option<usize> {
+    if needle.is_empty() || haystack.len() < needle.len() {
+        return None;
+    }
+    (start..=haystack.len() - needle.len()).find(|&i| &haystack[i..i + needle.len()] == needle)
+}
+
+fn replace_text(content: &[u8], old: &[u8], new: &[u8]) -> Vec<u8> {
+    let mut result = Vec::with_capacity(content.len());
+    let mut pos = 0;
+    while let Some(idx) = find(content, old, pos) {
+        result.extend_from_slice(&content[pos..idx]);
+        result.extend_from_slice(new);
+        pos = idx + old.len();
+    }
+    result.extend_from_slice(&content[pos..]);
+    result
+}
+
+fn count(haystack: &[u8], needle: &[u8]) -> usize {
+    let mut count = 0;
+    let mut pos = 0;
+    while let Some(idx) = find(haystack, needle, pos) {
+        count += 1;
+        pos = idx + needle.len();
+    }
+    count
+}
+
+/// Replace the prefix in the null-terminated strings of a binary file, without changing the
+/// length of the file. Each string keeps the space it had: if the prefix was linked in place of a
+/// placeholder of `placeholder_len`, the string is followed by null bytes for the difference of
+/// their lengths, which the new prefix can use. Returns `None` if a string doesn't fit.
+fn replace_binary(content: &[u8], old: &[u8], new: &[u8], placeholder_len: Option<usize>) -> Option<Vec<u8>> {
+    let reserved = placeholder_len.unwrap_or(0).max(old.len());
+    if new.len() > reserved {
+        return None;
+    }
+    let mut result = content.to_vec();
+    let mut pos = 0;
+    while let Some(idx) = find(&result, old, pos) {
+        // the null-terminated string containing the prefix, and the padding that follows it
+        let end = result[idx..].iter().position(|b| *b == 0).map(|p| idx + p).unwrap_or(result.len());
+        let padded_end = end + count(&result[idx..end], old) * (reserved - old.len());
+        if padded_end > result.len() || result[end..padded_end].iter().any(|b| *b != 0) {
+            return None;
+        }
+        let replaced = replace_text(&result[idx..end], old, new);
+        let padding = (padded_end - idx) - replaced.len();
+        result.splice(idx..padded_end, replaced.into_iter().chain(std::iter::repeat(0u8).take(padding)));
+        pos = padded_end;
+    }
+    Some(result)
+}
+
+#[cfg(unix)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+fn create_symlink(target: &Path, link: &Path) -> std::io::Result<()> {
+    std::os::windows::fs::symlink_file(target, link)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_prefix(prefix: &Path) {
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin/script"), format!("#!{}/bin/python\n", prefix.display())).unwrap();
+        let mut binary = format!("\0{}/lib\0rest", prefix.display()).into_bytes();
+        binary.extend_from_slice(&[0, 1, 2]);
+        fs::write(prefix.join("bin/binary"), binary).unwrap();
+        fs::write(prefix.join("bin/untouched"), "nothing to see here").unwrap();
+        #[cfg(unix)]
+        create_symlink(&prefix.join("bin/script"), &prefix.join("bin/link")).unwrap();
+    }
+
+    #[test]
+    fn test_relocate_round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let old_prefix = dir.join("envs/old");
+        let new_prefix = dir.join("envs/new");
+        create_prefix(&old_prefix);
+        let original = fs::read(old_prefix.join("bin/binary")).unwrap();
+
+        let expected = match cfg!(unix) {
+            true => 3,
+            false => 2,
+        };
+        assert_eq!(relocate_prefix_blocking(&old_prefix, &new_prefix).unwrap(), expected);
+        assert!(!old_prefix.exists());
+        assert!(!staging_dir(&new_prefix).exists());
+        assert_eq!(
+            fs::read_to_string(new_prefix.join("bin/script")).unwrap(),
+            format!("#!{}/bin/python\n", new_prefix.display())
+        );
+        let binary = fs::read(new_prefix.join("bin/binary")).unwrap();
+        assert_eq!(binary.len(), original.len());
+        assert!(find(&binary, format!("{}/lib\0", new_prefix.display()).as_bytes(), 0).is_some());
+        #[cfg(unix)]
+        assert_eq!(fs::read_link(new_prefix.join("bin/link")).unwrap(), new_prefix.join("bin/script"));
+
+        relocate_prefix_blocking(&new_prefix, &old_prefix).unwrap();
+        assert_eq!(fs::read(old_prefix.join("bin/binary")).unwrap(), original);
+        assert_eq!(
+            fs::read_to_string(old_prefix.join("bin/script")).unwrap(),
+            format!("#!{}/bin/python\n", old_prefix.display())
+        );
+        assert_eq!(fs::read_to_string(old_prefix.join("bin/untouched")).unwrap(), "nothing to see here");
+    }
+
+    #[test]
+    fn test_relocate_binary_to_longer_prefix() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let old_prefix = dir.join("envs/old");
+        let new_prefix = dir.join("envs/a-much-longer-name");
+        let placeholder = format!("/opt/{}", "placehold_".repeat(20));
+
+        let package_dir = dir.join("pkgs/tool-1.0-0");
+        fs::create_dir_all(package_dir.join("info")).unwrap();
+        let paths = serde_json::json!({
+            "paths_version": 1,
+            "paths": [{
+                "_path": "bin/tool",
+                "path_type": "hardlink",
+                "file_mode": "binary",
+                "prefix_placeholder": placeholder,
+            }],
+        });
+        fs::write(package_dir.join("info/paths.json"), paths.to_string()).unwrap();
+        let record = serde_json::json!({
+            "name": "tool",
+            "version": "1.0",
+            "build": "0",
+            "build_number": 0,
+            "subdir": "linux-64",
+            "fn": "tool-1.0-0.conda",
+            "url": "https://conda.anaconda.org/conda-forge/linux-64/tool-1.0-0.conda",
+            "channel": "https://conda.anaconda.org/conda-forge/",
+            "extracted_package_dir": package_dir,
+        });
+        fs::create_dir_all(old_prefix.join("conda-meta")).unwrap();
+        fs::write(old_prefix.join("conda-meta/tool-1.0-0.json"), record.to_string()).unwrap();
+
+        // linked like conda does: the placeholder is replaced, and the string padded to its length
+        let mut binary = format!("\0{}/lib", old_prefix.display()).into_bytes();
+        binary.resize(1 + placeholder.len() + "/lib".len(), 0);
+        binary.extend_from_slice(b"\0rest");
+        fs::create_dir_all(old_prefix.join("bin")).unwrap();
+        fs::write(old_prefix.join("bin/tool"), &binary).unwrap();
+
+        assert_eq!(relocate_prefix_blocking(&old_prefix, &new_prefix).unwrap(), 1);
+        let relocated = fs::read(new_prefix.join("bin/tool")).unwrap();
+        assert_eq!(relocated.len(), binary.len());
+        assert!(find(&relocated, format!("\0{}/lib\0", new_prefix.display()).as_bytes(), 0).is_some());
+        assert!(relocated.ends_with(b"\0\0rest"));
+
+        relocate_prefix_blocking(&new_prefix, &old_prefix).unwrap();
+        assert_eq!(fs::read(old_prefix.join("bin/tool")).unwrap(), binary);
+    }
+
+    #[test]
+    fn test_failed_relocation_leaves_prefix_untouched() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let old_prefix = dir.join("envs/old");
+        let new_prefix = dir.join("envs/a-much-longer-name");
+        create_prefix(&old_prefix);
+
+        let e = relocate_prefix_blocking(&old_prefix, &new_prefix).unwrap_err();
+        assert!(e.to_string().contains("new prefix is longer"));
+        assert!(!new_prefix.exists());
+        assert!(!staging_dir(&new_prefix).exists());
+        assert_eq!(
+            fs::read_to_string(old_prefix.join("bin/script")).unwrap(),
+            format!("#!{}/bin/python\n", old_prefix.display())
+        );
+    }
+}
@@ -0,0 +1,228 @@
+// Helpers to run commands in viva environments on remote hosts, via ssh.
+//
+// This shells out to the system `ssh` and `scp` executables, so any configuration (keys, jump
+// hosts, ...) from the users' ssh config is respected.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::process::{ExitStatus, Output, Stdio};
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::models::environment::VivaEnvSpec;
+
+/// The path viva gets installed to on a remote host, if it isn't available there already.
+const REMOTE_VIVA_PATH: &str = "~/.local/bin/viva";
+
+/// Quote a string so it can be safely used as a single argument in a posix shell.
+pub fn shell_quote(arg: &str) -> String {
+    if !arg.is_empty()
+        && arg
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "-_./=:@%+,".contains(c))
+    {
+        return arg.to_string();
+    }
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Quote a string so it reaches a program that is started by `cmd.exe` (e.g. with `cmd /C`) as a
+/// single, unchanged argument.
+pub fn cmd_quote(arg: &str) -> String {
+    escape_cmd_metachars(&windows_quote(arg), "^%")
+}
+
+/// Like [`cmd_quote`], for a command in a batch (`.cmd`) file, where `%` is escaped as `%%`.
+pub fn batch_quote(arg: &str) -> String {
+    escape_cmd_metachars(&windows_quote(arg), "%%")
+}
+
+/// Quote a string the way the C runtime splits a Windows command line into arguments: in double
+/// quotes, with backslashes only escaped where they precede a quote.
+fn windows_quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"') {
+        return arg.to_string();
+    }
+    let mut quoted = String::from("\"");
+    let mut backslashes = 0;
+    for c in arg.chars() {
+        match c {
+            '\\' => backslashes += 1,
+            '"' => {
+                quoted.push_str(&"\\".repeat(backslashes * 2 + 1));
+                quoted.push('"');
+                backslashes = 0;
+            }
+            c => {
+                quoted.push_str(&"\\".repeat(backslashes));
+                quoted.push(c);
+                backslashes = 0;
+            }
+        }
+    }
+    quoted.push_str(&"\\".repeat(backslashes * 2));
+    quoted.push('"');
+    quoted
+}
+
+/// Escape every character `cmd.exe` would interpret with `^` (including the quotes, so `cmd.exe`
+/// never considers anything quoted), and `%` with the provided replacement.
+fn escape_cmd_metachars(arg: &str, percent: &str) -> String {
+    let mut escaped = String::with_capacity(arg.len());
+    for c in arg.chars() {
+        match c {
+            '%' => escaped.push_str(percent),
+            '^' | '&' | '|' | '<' | '>' | '(' | ')' | '!' | '"' => {
+                escaped.push('^');
+                escaped.push(c);
+            }
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Run a shell command on the remote host, streaming its output.
+async fn ssh(host: &str, remote_cmd: &str) -> Result<ExitStatus> {
+    debug!("Running remote command on '{}': {}", host, remote_cmd);
+    let status = Command::new("ssh")
+        .arg(host)
+        .arg(remote_cmd)
+        .stdin(Stdio::inherit())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .status()
+        .await
+        .context("Failed to run 'ssh', is it installed?")?;
+    Ok(status)
+}
+
+/// Run a shell command on the remote host, capturing its output.
+async fn ssh_output(host: &str, remote_cmd: &str) -> Result<Output> {
+    debug!("Running remote command on '{}': {}", host, remote_cmd);
+    let output = Command::new("ssh")
+        .arg(host)
+        .arg(remote_cmd)
+        .stdin(Stdio::null())
+        .output()
+        .await
+        .context("Failed to run 'ssh', is it installed?")?;
+    Ok(output)
+}
+
+/// Returns the `uname -s`/`uname -m` values matching the local platform.
+fn local_uname() -> (&'static str, Vec<&'static str>) {
+    let os = match std::env::consts::OS {
+        "linux" => "Linux",
+        "macos" => "Darwin",
+        other => other,
+    };
+    let arch = match std::env::consts::ARCH {
+        "aarch64" => vec!["aarch64", "arm64"],
+        other => vec![other],
+    };
+    (os, arch)
+}
+
+/// Make sure viva is available on the remote host, and return the command to invoke it.
+///
+/// If viva can't be found, the currently running binary is copied over, as long as the remote
+/// host has the same OS and architecture.
+pub async fn ensure_remote_viva(host: &str) -> Result<String> {
+    for candidate in ["viva", REMOTE_VIVA_PATH] {
+        let output = ssh_output(host, &format!("{} --version", candidate)).await?;
+        if output.status.success() {
+            return Ok(candidate.to_string());
+        }
+    }
+
+    let output = ssh_output(host, "uname -s -m").await?;
+    if !output.status.success() {
+        bail!("Could not determine platform of remote host: {}", host);
+    }
+    let remote_uname = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    let mut parts = remote_uname.split_whitespace();
+    let (remote_os, remote_arch) = (parts.next().unwrap_or(""), parts.next().unwrap_or(""));
+    let (local_os, local_arch) = local_uname();
+    if remote_os != local_os || !local_arch.contains(&remote_arch) {
+        bail!(
+            "viva is not installed on remote host '{}', and its platform ({}) does not match the local one, please install it manually.",
+            host,
+            remote_uname
+        );
+    }
+
+    println!("Installing viva on remote host: {}", host);
+    let current_exe = std::env::current_exe().context("Could not determine path of viva executable")?;
+    let status = ssh(host, "mkdir -p ~/.local/bin").await?;
+    if !status.success() {
+        bail!("Could not create '~/.local/bin' on remote host: {}", host);
+    }
+    let status = Command::new("scp")
+        .arg("-q")
+        .arg(&current_exe)
+        .arg(format!("{}:.local/bin/viva", host))
+        .status()
+        .await
+        .context("Failed to run 'scp', is it installed?")?;
+    if !status.success() {
+        bail!("Could not copy viva to remote host: {}", host);
+    }
+    let status = ssh(host, &format!("chmod +x {}", REMOTE_VIVA_PATH)).await?;
+    if !status.success() {
+        bail!("Could not make viva executable on remote host: {}", host);
+    }
+
+    Ok(REMOTE_VIVA_PATH.to_string())
+}
+
+/// Run a command in an environment on a remote host, creating the environment there if necessary.
+///
+/// Output of the remote command is streamed back, and its exit status returned.
+pub async fn remote_run(
+    host: &str,
+    env_id: &str,
+    env_spec: &VivaEnvSpec,
+    cmd: &[String],
+) -> Result<ExitStatus> {
+    if cmd.is_empty() {
+        return Err(anyhow!("No command provided"));
+    }
+    let viva = ensure_remote_viva(host).await?;
+
+    let mut spec_args: Vec<String> = vec![];
+    for channel in &env_spec.channels {
+        spec_args.push(String::from("-c"));
+        spec_args.push(shell_quote(channel));
+    }
+    for pkg_spec in &env_spec.pkg_specs {
+        spec_args.push(String::from("-s"));
+        spec_args.push(shell_quote(pkg_spec));
+    }
+
+    let remote_cmd = format!(
+        "{viva} run --env {env} {specs} -- {cmd}",
+        viva = viva,
+        env = shell_quote(env_id),
+        specs = spec_args.join(" "),
+        cmd = cmd.iter().map(|c| shell_quote(c)).collect::<Vec<String>>().join(" ")
+    );
+
+    ssh(host, &remote_cmd).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cmd_quote() {
+        assert_eq!(cmd_quote("python"), "python");
+        assert_eq!(cmd_quote(""), "^\"^\"");
+        assert_eq!(cmd_quote("a b"), "^\"a b^\"");
+        assert_eq!(cmd_quote("say \"hi\""), "^\"say \\^\"hi\\^\"^\"");
+        assert_eq!(cmd_quote("C:\\some dir\\"), "^\"C:\\some dir\\\\^\"");
+        assert_eq!(cmd_quote("a&b|c<d>e^f"), "a^&b^|c^<d^>e^^f");
+        assert_eq!(cmd_quote("%PATH%"), "^%PATH^%");
+        assert_eq!(batch_quote("100% (sure)"), "^\"100%% ^(sure^)^\"");
+    }
+}
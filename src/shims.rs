@@ -0,0 +1,195 @@
+// Shims: small launcher scripts in a single directory (that users put on their PATH), that run an
+// app through viva ('viva app run'). They don't contain anything about the environment of the app,
+// so they keep working when the environment is renamed, relocated, or its spec changes.
+//
+// The shim directory is shared with whatever else users put there, so viva records which shims it
+// created (and for which app) in a registry file in the directory. Only shims listed there are ever
+// overwritten or removed.
+
+use anyhow::{bail, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tracing::warn;
+
+use crate::defaults::SHIM_REGISTRY_FILENAME;
+
+#[cfg(windows)]
+const SHIM_EXTENSION: &str = ".cmd";
+
+#[cfg(unix)]
+const SHIM_EXTENSION: &str = "";
+
+/// The shims viva created in a shim directory (shim name -> app id).
+#[derive(Debug, Deserialize, Serialize, Clone, Default)]
+struct ShimRegistry {
+    #[serde(default)]
+    shims: BTreeMap<String, String>,
+}
+
+impl ShimRegistry {
+    async fn load(shim_dir: &Path) -> Result<ShimRegistry> {
+        let registry_file = shim_dir.join(SHIM_REGISTRY_FILENAME);
+        match registry_file.exists() {
+            true => Ok(serde_json::from_str(&fs::read_to_string(&registry_file).await?)?),
+            false => Ok(ShimRegistry::default()),
+        }
+    }
+
+    async fn save(&self, shim_dir: &Path) -> Result<()> {
+        fs::create_dir_all(shim_dir).await?;
+        let registry_json = serde_json::to_string_pretty(self)?;
+        fs::write(shim_dir.join(SHIM_REGISTRY_FILENAME), registry_json).await?;
+        Ok(())
+    }
+}
+
+/// The name of the shim for an app (an optional prefix, followed by the app id).
+pub(crate) fn shim_name(app_id: &str, prefix: Option<&str>) -> String {
+    format!("{}{}", prefix.unwrap_or(""), app_id.replace('/', "-"))
+}
+
+/// Create (or update) the shim for an app, which runs the app with the provided viva executable.
+///
+/// Fails if a file with the same name exists in the shim directory that was not created for this
+/// app, unless `force` is set. Executables with the same name in other directories on the PATH
+/// (e.g. from pixi or conda) are only warned about.
+pub(crate) async fn install_shim(
+    shim_dir: &Path,
+    shim_name: &str,
+    app_id: &str,
+    viva_exe: &Path,
+    force: bool,
+) -> Result<PathBuf> {
+    let mut registry = ShimRegistry::load(shim_dir).await?;
+    let shim_path = shim_dir.join(format!("{}{}", shim_name, SHIM_EXTENSION));
+
+    match registry.shims.get(shim_name) {
+        Some(owner) if owner != app_id && !force => {
+            bail!("Shim '{}' is already used by app '{}'.", shim_name, owner)
+        }
+        None if shim_path.exists() && !force => bail!(
+            "A file that was not created by viva already exists: {} (use '--force' to overwrite it, or a shim prefix)",
+            shim_path.display()
+        ),
+        _ => {}
+    }
+
+    if let Some(other) = find_on_path(&format!("{}{}", shim_name, SHIM_EXTENSION), shim_dir) {
+        warn!(
+            "Shim '{}' has the same name as an executable managed by {}: {} (whichever comes first on the PATH is used)",
+            shim_name,
+            detect_manager(&other),
+            other.display()
+        );
+    }
+
+    fs::create_dir_all(shim_dir).await?;
+    fs::write(&shim_path, render_shim(app_id, viva_exe)).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&shim_path, std::fs::Permissions::from_mode(0o755)).await?;
+    }
+
+    registry
+        .shims
+        .insert(String::from(shim_name), String::from(app_id));
+    registry.save(shim_dir).await?;
+    Ok(shim_path)
+}
+
+/// Remove all shims that were created for an app, returns the paths of the removed files.
+pub(crate) async fn remove_shims(shim_dir: &Path, app_id: &str) -> Result<Vec<PathBuf>> {
+    let mut registry = ShimRegistry::load(shim_dir).await?;
+    let owned: Vec<String> = registry
+        .shims
+        .iter()
+        .filter(|(_, owner)| *owner == app_id)
+        .map(|(shim_name, _)| shim_name.clone())
+        .collect();
+
+    let mut removed = vec![];
+    for shim_name in owned {
+        let shim_path = shim_dir.join(format!("{}{}", shim_name, SHIM_EXTENSION));
+        if shim_path.exists() {
+            fs::remove_file(&shim_path).await?;
+            removed.push(shim_path);
+        }
+        registry.shims.remove(&shim_name);
+    }
+    registry.save(shim_dir).await?;
+    Ok(removed)
+}
+
+/// All shims viva created in a shim directory (shim name -> app id).
+pub(crate) async fn list_shims(shim_dir: &Path) -> Result<BTreeMap<String, String>> {
+    Ok(ShimRegistry::load(shim_dir).await?.shims)
+}
+
+/// Find an executable in the directories of the PATH, ignoring the provided directory.
+fn find_on_path(file_name: &str, ignore_dir: &Path) -> Option<PathBuf> {
+    let path = std::env::var_os("PATH")?;
+    std::env::split_paths(&path)
+        .filter(|dir| dir != ignore_dir)
+        .map(|dir| dir.join(file_name))
+        .find(|candidate| candidate.is_file())
+}
+
+/// Whether a directory is on the PATH (otherwise the shims in it can't be started by name).
+pub(crate) fn is_on_path(dir: &Path) -> bool {
+    match std::env::var_os("PATH") {
+        Some(path) => std::env::split_paths(&path).any(|entry| entry == dir),
+        None => false,
+    }
+}
+
+/// A guess of which tool manages the provided executable, for messages.
+fn detect_manager(executable: &Path) -> &'static str {
+    let path = executable.to_string_lossy();
+    if path.contains(".pixi") {
+        "pixi"
+    } else if path.contains("condabin") || path.contains("conda") || path.contains("mamba") {
+        "conda"
+    } else {
+        "another installation"
+    }
+}
+
+#[cfg(unix)]
+fn render_shim(app_id: &str, viva_exe: &Path) -> String {
+    let quote = |s: &str| format!("'{}'", s.replace('\'', "'\\''"));
+    format!(
+        "#!/bin/sh\n# viva shim for app '{}'\nexec {} app run {} -- \"$@\"\n",
+        app_id,
+        quote(&viva_exe.to_string_lossy()),
+        quote(app_id)
+    )
+}
+
+#[cfg(windows)]
+fn render_shim(app_id: &str, viva_exe: &Path) -> String {
+    use crate::remote::batch_quote;
+    format!(
+        "@echo off\r\nrem viva shim for app '{}'\r\n{} app run {} -- %*\r\n",
+        app_id,
+        batch_quote(&viva_exe.to_string_lossy()),
+        batch_quote(app_id)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_render_shim() {
+        let shim = render_shim("team/it's", Path::new("/opt/viva/bin/viva"));
+        assert_eq!(
+            shim,
+            "#!/bin/sh\n# viva shim for app 'team/it's'\nexec '/opt/viva/bin/viva' app run 'team/it'\\''s' -- \"$@\"\n"
+        );
+    }
+}
@@ -0,0 +1,361 @@
+// Verification of signed collection indexes.
+//
+// Collections that are fetched from elsewhere can ship a minisign (https://jedisct1.github.io/minisign/)
+// signature over their index. The key it is signed with is pinned on first use (per collection), so
+// later changes to the specs are only trusted if they are signed with the same key. Rotating a key
+// requires explicitly forgetting the pinned one (`viva trust forget <collection>`). The files of a
+// signed collection are covered by the sha256 checksums in its index.
+
+use anyhow::{anyhow, bail, Result};
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use blake2::{Blake2b512, Digest};
+use ring::signature::{UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::models::{read_models_spec, write_models_spec};
+
+/// Signatures of the message itself (legacy, only for small files).
+const ALGORITHM_ED25519: [u8; 2] = *b"Ed";
+/// Signatures of the BLAKE2b-512 hash of the message (the minisign default).
+const ALGORITHM_ED25519_PREHASHED: [u8; 2] = *b"ED";
+
+/// Decode the first line of a minisign key or signature that is not a comment (keys can be
+/// provided with, or without their comment line).
+fn decode_minisign_line(value: &str, what: &str) -> Result<Vec<u8>> {
+    let line = value
+        .lines()
+        .map(|line| line.trim())
+        .find(|line| !line.is_empty() && !line.starts_with("untrusted comment:"))
+        .ok_or_else(|| anyhow!("Invalid minisign {}: no data", what))?;
+    BASE64
+        .decode(line)
+        .map_err(|e| anyhow!("Invalid minisign {}: {}", what, e))
+}
+
+/// A minisign public key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MinisignPublicKey {
+    key_id: [u8; 8],
+    key: [u8; 32],
+}
+
+impl MinisignPublicKey {
+    /// The key id, as displayed by minisign.
+    pub fn key_id(&self) -> String {
+        format_key_id(&self.key_id)
+    }
+
+    /// The key, in the format of minisign public key files (without the comment line).
+    pub fn to_base64(&self) -> String {
+        let mut bytes = ALGORITHM_ED25519.to_vec();
+        bytes.extend_from_slice(&self.key_id);
+        bytes.extend_from_slice(&self.key);
+        BASE64.encode(bytes)
+    }
+}
+
+impl FromStr for MinisignPublicKey {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = decode_minisign_line(s, "public key")?;
+        if bytes.len() != 42 || bytes[0..2] != ALGORITHM_ED25519 {
+            bail!("Invalid minisign public key: unsupported format");
+        }
+        let mut key_id = [0u8; 8];
+        key_id.copy_from_slice(&bytes[2..10]);
+        let mut key = [0u8; 32];
+        key.copy_from_slice(&bytes[10..42]);
+        Ok(MinisignPublicKey { key_id, key })
+    }
+}
+
+impl fmt::Display for MinisignPublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.to_base64())
+    }
+}
+
+/// Key ids are little-endian numbers, minisign displays them in hex.
+fn format_key_id(key_id: &[u8; 8]) -> String {
+    key_id.iter().rev().map(|b| format!("{:02X}", b)).collect()
+}
+
+/// A minisign signature (the contents of a `.minisig` file).
+#[derive(Debug, Clone)]
+pub struct MinisignSignature {
+    algorithm: [u8; 2],
+    key_id: [u8; 8],
+    signature: Vec<u8>,
+    trusted_comment: String,
+    global_signature: Vec<u8>,
+}
+
+impl MinisignSignature {
+    /// The comment that is covered by the signature (usually contains a timestamp and file name).
+    pub fn trusted_comment(&self) -> &str {
+        &self.trusted_comment
+    }
+
+    /// Verify the signature of the data, and of the trusted comment.
+    pub fn verify(&self, public_key: &MinisignPublicKey, data: &[u8]) -> Result<()> {
+        if self.key_id != public_key.key_id {
+            bail!(
+                "Signature was created with a different key (key id {}, expected {})",
+                format_key_id(&self.key_id),
+                public_key.key_id()
+            );
+        }
+        let key = UnparsedPublicKey::new(&ED25519, &public_key.key);
+        let message = match self.algorithm {
+            ALGORITHM_ED25519_PREHASHED => Blake2b512::digest(data).to_vec(),
+            _ => data.to_vec(),
+        };
+        key.verify(&message, &self.signature)
+            .map_err(|_| anyhow!("Invalid signature"))?;
+
+        let mut global_message = self.signature.clone();
+        global_message.extend_from_slice(self.trusted_comment.as_bytes());
+        key.verify(&global_message, &self.global_signature)
+            .map_err(|_| anyhow!("Invalid signature of trusted comment"))?;
+        Ok(())
+    }
+}
+
+impl FromStr for MinisignSignature {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let lines = s
+            .lines()
+            .map(|line| line.trim_end_matches('\r'))
+            .filter(|line| !line.trim().is_empty() && !line.starts_with("untrusted comment:"))
+            .collect::<Vec<&str>>();
+        let (signature_line, trusted_comment, global_signature_line) = match lines.as_slice() {
+            [signature, trusted_comment, global_signature] => (
+                *signature,
+                trusted_comment
+                    .strip_prefix("trusted comment: ")
+                    .ok_or_else(|| anyhow!("Invalid minisign signature: no trusted comment"))?,
+                *global_signature,
+            ),
+            _ => bail!("Invalid minisign signature: unexpected number of lines"),
+        };
+
+        let bytes = decode_minisign_line(signature_line, "signature")?;
+        if bytes.len() != 74 {
+            bail!("Invalid minisign signature: unsupported format");
+        }
+        let mut algorithm = [0u8; 2];
+        algorithm.copy_from_slice(&bytes[0..2]);
+        if algorithm != ALGORITHM_ED25519 && algorithm != ALGORITHM_ED25519_PREHASHED {
+            bail!("Invalid minisign signature: unsupported algorithm");
+        }
+        let mut key_id = [0u8; 8];
+        key_id.copy_from_slice(&bytes[2..10]);
+        let global_signature = decode_minisign_line(global_signature_line, "signature")?;
+        if global_signature.len() != 64 {
+            bail!("Invalid minisign signature: invalid signature of trusted comment");
+        }
+        Ok(MinisignSignature {
+            algorithm,
+            key_id,
+            signature: bytes[10..].to_vec(),
+            trusted_comment: String::from(trusted_comment),
+            global_signature,
+        })
+    }
+}
+
+/// The sha256 digest of some data, as lowercase hex (like `sha256sum` prints it).
+pub fn sha256_hex(data: &[u8]) -> String {
+    ring::digest::digest(&ring::digest::SHA256, data)
+        .as_ref()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// A key that was pinned for a collection.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub struct PinnedKey {
+    pub public_key: String,
+    pub key_id: String,
+}
+
+/// How the key of a verified index was trusted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyTrust {
+    /// The key was seen for the first time, and is pinned now.
+    FirstUse,
+    /// The key matches the pinned one.
+    Pinned,
+}
+
+/// The keys that were pinned for collections (collection id -> key), stored in the viva data dir.
+#[derive(Debug)]
+pub struct TrustStore {
+    path: PathBuf,
+    keys: BTreeMap<String, PinnedKey>,
+}
+
+impl TrustStore {
+    pub async fn load(path: PathBuf) -> Result<TrustStore> {
+        let keys = match path.exists() {
+            true => read_models_spec(&path).await?,
+            false => BTreeMap::new(),
+        };
+        Ok(TrustStore { path, keys })
+    }
+
+    pub fn get_pinned_keys(&self) -> &BTreeMap<String, PinnedKey> {
+        &self.keys
+    }
+
+    /// Pin a key for a collection (e.g. one that was obtained out-of-band, before the collection is
+    /// used for the first time), replacing a previously pinned one.
+    pub async fn pin(&mut self, collection_id: &str, public_key: &str) -> Result<PinnedKey> {
+        let public_key = MinisignPublicKey::from_str(public_key)?;
+        let pinned = PinnedKey {
+            public_key: public_key.to_base64(),
+            key_id: public_key.key_id(),
+        };
+        self.keys.insert(String::from(collection_id), pinned.clone());
+        self.write().await?;
+        Ok(pinned)
+    }
+
+    /// Remove the pinned key of a collection (e.g. after the key was rotated), returns whether
+    /// there was one.
+    pub async fn forget(&mut self, collection_id: &str) -> Result<bool> {
+        if self.keys.remove(collection_id).is_none() {
+            return Ok(false);
+        }
+        self.write().await?;
+        Ok(true)
+    }
+
+    async fn write(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        write_models_spec(&self.path, &self.keys).await
+    }
+
+    /// Verify the signature of a collection index.
+    ///
+    /// The index has to be signed with the key that is pinned for the collection. If there is no
+    /// pinned key yet, the key the collection provides is used and pinned (trust on first use).
+    /// A collection that provides a different key than the pinned one is rejected, even if its
+    /// index is validly signed with it.
+    pub async fn verify_index(
+        &mut self,
+        collection_id: &str,
+        index: &[u8],
+        signature: &str,
+        provided_key: &str,
+    ) -> Result<KeyTrust> {
+        let signature = MinisignSignature::from_str(signature)?;
+        let provided_key = MinisignPublicKey::from_str(provided_key)?;
+
+        match self.keys.get(collection_id) {
+            Some(pinned) => {
+                let pinned_key = MinisignPublicKey::from_str(&pinned.public_key)?;
+                if pinned_key != provided_key {
+                    bail!(
+                        "The signing key of collection '{}' changed (pinned key id {}, now {}), not trusting its specs. If the key was rotated on purpose, use 'viva trust forget {}' and try again.",
+                        collection_id,
+                        pinned_key.key_id(),
+                        provided_key.key_id(),
+                        collection_id
+                    );
+                }
+                signature
+                    .verify(&pinned_key, index)
+                    .map_err(|e| anyhow!("Index of collection '{}' can't be verified: {}", collection_id, e))?;
+                Ok(KeyTrust::Pinned)
+            }
+            None => {
+                signature
+                    .verify(&provided_key, index)
+                    .map_err(|e| anyhow!("Index of collection '{}' can't be verified: {}", collection_id, e))?;
+                self.keys.insert(
+                    String::from(collection_id),
+                    PinnedKey {
+                        public_key: provided_key.to_base64(),
+                        key_id: provided_key.key_id(),
+                    },
+                );
+                self.write().await?;
+                Ok(KeyTrust::FirstUse)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PUBLIC_KEY: &str = "untrusted comment: minisign public key 0807060504030201
+RWQBAgMEBQYHCAOhB7/zzhC+HXDdGOdLwJln5NYwm6UNXx3chmQSVTG4
+";
+    const OTHER_PUBLIC_KEY: &str = "RWQJCQkJCQkJCXm1Vi6P5lT5QHixEuipi6eQH4U65pW+1+DjkQutBJZk";
+    const INDEX: &[u8] = b"envs:\n  data: data.yaml\n";
+    const SIGNATURE: &str = "untrusted comment: signature from minisign secret key
+RUQBAgMEBQYHCIa9UP9rCPQy438Et+R0iR533I2nPNAurIWaJ6HLjDurS8oya2xJ/cPUSHfuWoqfRHb++mQTdUIVnUO1UxJdNAg=
+trusted comment: timestamp:1700000000\tfile:index.yaml
+g7c+OXWNKbP1Lwy2nd5tcxhT+59RfpH2rGsQQbGUIzmXwIiloeTMpCgO/kRHKYpPtuSmh1YQ+6AzStKyAiyBDw==
+";
+
+    #[test]
+    fn test_verify_signature() {
+        let key = MinisignPublicKey::from_str(PUBLIC_KEY).unwrap();
+        assert_eq!(key.key_id(), "0807060504030201");
+        let signature = MinisignSignature::from_str(SIGNATURE).unwrap();
+        assert_eq!(signature.trusted_comment(), "timestamp:1700000000\tfile:index.yaml");
+
+        signature.verify(&key, INDEX).unwrap();
+        assert!(signature.verify(&key, b"envs:\n  data: evil.yaml\n").is_err());
+
+        let other_key = MinisignPublicKey::from_str(OTHER_PUBLIC_KEY).unwrap();
+        assert!(signature.verify(&other_key, INDEX).is_err());
+
+        let tampered = SIGNATURE.replace("file:index.yaml", "file:other.yaml");
+        let tampered = MinisignSignature::from_str(&tampered).unwrap();
+        assert!(tampered.verify(&key, INDEX).is_err());
+    }
+
+    #[test]
+    fn test_sha256_hex() {
+        assert_eq!(
+            sha256_hex(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_trust_on_first_use() {
+        let dir = tempfile::tempdir().unwrap();
+        let store_path = dir.path().join("trusted_keys.json");
+
+        let mut store = TrustStore::load(store_path.clone()).await.unwrap();
+        let trust = store.verify_index("team", INDEX, SIGNATURE, PUBLIC_KEY).await.unwrap();
+        assert_eq!(trust, KeyTrust::FirstUse);
+
+        let mut store = TrustStore::load(store_path.clone()).await.unwrap();
+        assert_eq!(store.get_pinned_keys()["team"].key_id, "0807060504030201");
+        let trust = store.verify_index("team", INDEX, SIGNATURE, PUBLIC_KEY).await.unwrap();
+        assert_eq!(trust, KeyTrust::Pinned);
+
+        // a collection that switches to another key is rejected, until the pinned key is forgotten
+        assert!(store.verify_index("team", INDEX, SIGNATURE, OTHER_PUBLIC_KEY).await.is_err());
+        assert!(store.forget("team").await.unwrap());
+        assert!(store.get_pinned_keys().is_empty());
+    }
+}
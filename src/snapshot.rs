@@ -0,0 +1,230 @@
+// Cheap snapshots of environment prefixes.
+//
+// A snapshot is a copy of the directory tree of a prefix, where all files are hard-linked instead
+// of copied, so it takes up (almost) no extra space. This is safe because packages are never
+// modified in place: updates unlink the old files and link new ones. Since files contain the
+// absolute path of the prefix, a snapshot can only be restored to the location it was taken from.
+// Metadata files (viva's own files and `conda-meta`) are updated in place, so they are copied.
+
+use anyhow::{anyhow, bail, Context, Result};
+use std::ffi::OsStr;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+use crate::defaults::RUNNING_PIDS_DIRNAME;
+
+/// Create a snapshot of a prefix in the provided snapshots directory.
+///
+/// Returns the name of the snapshot (the current timestamp, if no name is provided).
+pub(crate) async fn create_snapshot(
+    prefix: &Path,
+    snapshots_dir: &Path,
+    name: Option<&str>,
+) -> Result<String> {
+    let name = match name {
+        Some(name) => {
+            if name.is_empty() || name.starts_with('.') || name.contains(['/', '\\']) {
+                bail!("Invalid snapshot name: {}", name);
+            }
+            String::from(name)
+        }
+        None => SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or_default()
+            .to_string(),
+    };
+    let snapshot_path = snapshots_dir.join(&name);
+    if snapshot_path.exists() {
+        bail!("Snapshot already exists: {}", name);
+    }
+    if !prefix.is_dir() {
+        bail!("Environment prefix does not exist: {}", prefix.display());
+    }
+
+    let prefix = prefix.to_path_buf();
+    run_blocking(move || {
+        fs::create_dir_all(&snapshot_path)?;
+        let result = link_tree(&prefix, &snapshot_path, false);
+        if result.is_err() {
+            let _ = fs::remove_dir_all(&snapshot_path);
+        }
+        result
+    })
+    .await?;
+    Ok(name)
+}
+
+/// Restore a prefix from a snapshot (the latest one, if no name is provided).
+///
+/// The snapshot is kept, so it can be restored again. Returns the name of the restored snapshot.
+pub(crate) async fn restore_snapshot(
+    prefix: &Path,
+    snapshots_dir: &Path,
+    name: Option<&str>,
+) -> Result<String> {
+    let name = match name {
+        Some(name) => String::from(name),
+        None => list_snapshots(snapshots_dir)?
+            .pop()
+            .ok_or_else(|| anyhow!("No snapshots available in: {}", snapshots_dir.display()))?,
+    };
+    let snapshot_path = snapshots_dir.join(&name);
+    if !snapshot_path.is_dir() {
+        bail!("Snapshot does not exist: {}", name);
+    }
+
+    let prefix = prefix.to_path_buf();
+    run_blocking(move || {
+        // move the current prefix out of the way, so we can roll back if anything goes wrong
+        let backup = PathBuf::from(format!("{}.viva-restore", prefix.display()));
+        if backup.exists() {
+            fs::remove_dir_all(&backup)?;
+        }
+        if prefix.exists() {
+            fs::rename(&prefix, &backup)?;
+        }
+
+        fs::create_dir_all(&prefix)?;
+        match link_tree(&snapshot_path, &prefix, false) {
+            Ok(()) => {
+                if backup.exists() {
+                    fs::remove_dir_all(&backup)?;
+                }
+                Ok(())
+            }
+            Err(e) => {
+                let _ = fs::remove_dir_all(&prefix);
+                if backup.exists() {
+                    fs::rename(&backup, &prefix)?;
+                }
+                Err(e)
+            }
+        }
+    })
+    .await?;
+    Ok(name)
+}
+
+/// List the names of all snapshots in a snapshots directory, oldest first.
+pub(crate) fn list_snapshots(snapshots_dir: &Path) -> Result<Vec<String>> {
+    if !snapshots_dir.is_dir() {
+        return Ok(vec![]);
+    }
+    let mut snapshots: Vec<(SystemTime, String)> = vec![];
+    for entry in fs::read_dir(snapshots_dir)? {
+        let entry = entry?;
+        if entry.path().is_dir() {
+            let created = entry.metadata()?.modified().unwrap_or(UNIX_EPOCH);
+            snapshots.push((created, entry.file_name().to_string_lossy().to_string()));
+        }
+    }
+    snapshots.sort();
+    Ok(snapshots.into_iter().map(|(_, name)| name).collect())
+}
+
+/// Delete a snapshot.
+pub(crate) async fn delete_snapshot(snapshots_dir: &Path, name: &str) -> Result<()> {
+    let snapshot_path = snapshots_dir.join(name);
+    if !snapshot_path.is_dir() {
+        bail!("Snapshot does not exist: {}", name);
+    }
+    tokio::fs::remove_dir_all(&snapshot_path).await?;
+    Ok(())
+}
+
+/// Recreate the directory tree of `source` in `target`, hard-linking all files (or copying them,
+/// if `copy` is set).
+///
+/// Metadata files are always copied, as are files that can't be hard-linked (e.g. across
+/// filesystems).
+fn link_tree(source: &Path, target: &Path, copy: bool) -> Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let path = entry.path();
+        let target_path = target.join(entry.file_name());
+        let file_type = entry.file_type()?;
+        if entry.file_name() == RUNNING_PIDS_DIRNAME {
+            continue;
+        }
+
+        if file_type.is_symlink() {
+            let link_target = fs::read_link(&path)?;
+            create_symlink(&link_target, &target_path, &path)?;
+        } else if file_type.is_dir() {
+            fs::create_dir(&target_path)?;
+            link_tree(&path, &target_path, copy || is_metadata(&entry.file_name()))?;
+        } else if copy || is_metadata(&entry.file_name()) || fs::hard_link(&path, &target_path).is_err() {
+            debug!("Copying: {}", path.display());
+            fs::copy(&path, &target_path)
+                .with_context(|| format!("Could not copy file: {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether a file or directory holds metadata that is changed in place (e.g. the spec of the
+/// prefix, which is updated on every sync), as opposed to package files.
+fn is_metadata(file_name: &OsStr) -> bool {
+    let file_name = file_name.to_string_lossy();
+    file_name == "conda-meta" || file_name.starts_with(".viva")
+}
+
+#[cfg(unix)]
+fn create_symlink(link_target: &Path, link: &Path, _original: &Path) -> Result<()> {
+    std::os::unix::fs::symlink(link_target, link)?;
+    Ok(())
+}
+
+#[cfg(windows)]
+fn create_symlink(_link_target: &Path, link: &Path, original: &Path) -> Result<()> {
+    // creating symlinks requires special privileges on Windows, so we copy the link target
+    fs::copy(original, link)?;
+    Ok(())
+}
+
+async fn run_blocking<F: FnOnce() -> Result<()> + Send + 'static>(func: F) -> Result<()> {
+    match tokio::task::spawn_blocking(func).await {
+        Ok(result) => result,
+        Err(err) => {
+            if let Ok(panic) = err.try_into_panic() {
+                std::panic::resume_unwind(panic);
+            }
+            Err(anyhow!("cancelled"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_snapshot_copies_metadata() {
+        let base = tempfile::tempdir().unwrap();
+        let prefix = base.path().join("env");
+        let snapshots_dir = base.path().join("snapshots");
+        fs::create_dir_all(prefix.join("conda-meta")).unwrap();
+        fs::create_dir_all(prefix.join("bin")).unwrap();
+        fs::write(prefix.join("bin/tool"), "tool").unwrap();
+        fs::write(prefix.join("conda-meta/history"), "old").unwrap();
+        fs::write(prefix.join(".viva_env"), "old").unwrap();
+
+        let name = create_snapshot(&prefix, &snapshots_dir, Some("test")).await.unwrap();
+        let snapshot = snapshots_dir.join(&name);
+
+        // metadata is changed in place, that must not change the snapshot
+        fs::write(prefix.join("conda-meta/history"), "new").unwrap();
+        fs::write(prefix.join(".viva_env"), "new").unwrap();
+        assert_eq!(fs::read_to_string(snapshot.join("conda-meta/history")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(snapshot.join(".viva_env")).unwrap(), "old");
+        assert_eq!(fs::read_to_string(snapshot.join("bin/tool")).unwrap(), "tool");
+
+        restore_snapshot(&prefix, &snapshots_dir, Some(&name)).await.unwrap();
+        assert_eq!(fs::read_to_string(prefix.join(".viva_env")).unwrap(), "old");
+        fs::write(prefix.join(".viva_env"), "newer").unwrap();
+        assert_eq!(fs::read_to_string(snapshot.join(".viva_env")).unwrap(), "old");
+    }
+}
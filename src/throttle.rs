@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// Limits the aggregate throughput of all downloads that share it.
+///
+/// The limiter keeps track of the total number of bytes consumed since its creation, and delays
+/// every consumer until that total is in line with the configured rate.
+#[derive(Debug)]
+pub(crate) struct RateLimiter {
+    bytes_per_second: u64,
+    state: Mutex<(Instant, u64)>,
+}
+
+impl RateLimiter {
+    pub(crate) fn new(bytes_per_second: u64) -> RateLimiter {
+        RateLimiter {
+            bytes_per_second: bytes_per_second.max(1),
+            state: Mutex::new((Instant::now(), 0)),
+        }
+    }
+
+    /// Account for `bytes` downloaded bytes, waiting as long as necessary to stay within the rate.
+    pub(crate) async fn consume(&self, bytes: u64) {
+        let wait_until = {
+            let mut state = self.state.lock().await;
+            state.1 += bytes;
+            state.0 + Duration::from_secs_f64(state.1 as f64 / self.bytes_per_second as f64)
+        };
+        tokio::time::sleep_until(wait_until.into()).await;
+    }
+}
+
+/// Spaces out requests that share a key (e.g. a channel), requests with different keys don't wait
+/// for each other.
+#[derive(Debug)]
+pub(crate) struct RequestThrottle {
+    interval: Duration,
+    next_requests: Mutex<HashMap<String, Instant>>,
+}
+
+impl RequestThrottle {
+    pub(crate) fn new(interval: Duration) -> RequestThrottle {
+        RequestThrottle {
+            interval,
+            next_requests: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Wait until a request with the provided key may be made.
+    pub(crate) async fn wait(&self, key: &str) {
+        let wait_until = {
+            let mut next_requests = self.next_requests.lock().await;
+            let now = Instant::now();
+            let at = next_requests.get(key).copied().unwrap_or(now).max(now);
+            next_requests.insert(String::from(key), at + self.interval);
+            at
+        };
+        tokio::time::sleep_until(wait_until.into()).await;
+    }
+}
@@ -0,0 +1,74 @@
+use anyhow::Result;
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+use tracing::{debug, warn};
+
+/// Watches the files of collections for changes.
+///
+/// Created via [`crate::VivaContext::watch_collections`], watching stops when it is dropped.
+pub struct CollectionWatcher {
+    _watcher: RecommendedWatcher,
+    events: UnboundedReceiver<()>,
+}
+
+impl std::fmt::Debug for CollectionWatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CollectionWatcher").finish()
+    }
+}
+
+impl CollectionWatcher {
+    pub(crate) fn create(paths: &[PathBuf]) -> Result<CollectionWatcher> {
+        let (sender, events) = unbounded_channel();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            match event {
+                Ok(event) => {
+                    if event.kind.is_access() {
+                        return;
+                    }
+                    debug!("Collection change detected: {:?}", event.paths);
+                    let _ = sender.send(());
+                }
+                Err(e) => warn!("Error watching collections: {}", e),
+            }
+        })?;
+
+        for path in paths {
+            if path.exists() {
+                watcher.watch(path, RecursiveMode::Recursive)?;
+            } else {
+                debug!("Not watching non-existent path: {}", path.display());
+            }
+        }
+
+        Ok(CollectionWatcher {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Whether any changes happened since the last check (does not wait).
+    pub fn has_changed(&mut self) -> bool {
+        let mut changed = false;
+        while self.events.try_recv().is_ok() {
+            changed = true;
+        }
+        changed
+    }
+
+    /// Wait for the next change.
+    ///
+    /// Editors often write files in several steps, so this waits until no further changes happen
+    /// for a short while before returning.
+    pub async fn changed(&mut self) {
+        if self.events.recv().await.is_none() {
+            // the watcher is gone, nothing will ever change
+            std::future::pending::<()>().await;
+        }
+        while let Ok(Some(_)) =
+            tokio::time::timeout(Duration::from_millis(200), self.events.recv()).await
+        {}
+    }
+}
@@ -0,0 +1,213 @@
+// Detecting stalled operations (e.g. a download from a mirror that stopped sending data), so viva
+// retries them or fails with a diagnosis, instead of hanging with a frozen progress bar.
+//
+// Operations report their progress to a `Heartbeat` (e.g. for every chunk of data they receive),
+// and are cancelled if there was no progress for the stall timeout. Only operations that can
+// report progress are watched: downloads with custom fetchers aren't (they might make progress
+// viva can't see), and neither is the solver (it gets an overall timeout instead).
+
+use anyhow::Result;
+use std::fmt;
+use std::future::Future;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+use crate::config::VivaConfig;
+use crate::models::remote::parse_interval;
+
+/// How long an operation may go without progress, if the config doesn't say otherwise.
+const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often a stalled operation is retried, if the config doesn't say otherwise.
+const DEFAULT_STALL_RETRIES: u32 = 2;
+
+/// How stalled operations are dealt with (see the `stall_timeout` and `stall_retries` settings).
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct StallPolicy {
+    /// `None` if operations are never considered stalled.
+    pub timeout: Option<Duration>,
+    pub retries: u32,
+}
+
+impl StallPolicy {
+    pub(crate) fn from_config(config: &VivaConfig) -> Result<StallPolicy> {
+        let timeout = match &config.stall_timeout {
+            Some(stall_timeout) => Some(parse_interval(stall_timeout)?).filter(|timeout| !timeout.is_zero()),
+            None => Some(DEFAULT_STALL_TIMEOUT),
+        };
+        Ok(StallPolicy {
+            timeout,
+            retries: config.stall_retries.unwrap_or(DEFAULT_STALL_RETRIES),
+        })
+    }
+}
+
+/// The progress of a single operation, shared between the operation and its watchdog.
+#[derive(Debug, Clone)]
+pub(crate) struct Heartbeat {
+    started: Instant,
+    /// Milliseconds since `started`, when there was progress the last time.
+    last_beat: Arc<AtomicU64>,
+    bytes: Arc<AtomicU64>,
+}
+
+impl Heartbeat {
+    pub(crate) fn new() -> Heartbeat {
+        Heartbeat {
+            started: Instant::now(),
+            last_beat: Arc::new(AtomicU64::new(0)),
+            bytes: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Record progress (`bytes` more bytes transferred, can be 0 for other kinds of progress).
+    pub(crate) fn beat(&self, bytes: u64) {
+        self.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.last_beat
+            .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Record the total number of bytes transferred so far.
+    pub(crate) fn beat_total(&self, bytes: u64) {
+        let previous = self.bytes.swap(bytes, Ordering::Relaxed);
+        if bytes != previous {
+            self.last_beat
+                .store(self.started.elapsed().as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+
+    fn since_last_beat(&self) -> Duration {
+        self.started
+            .elapsed()
+            .saturating_sub(Duration::from_millis(self.last_beat.load(Ordering::Relaxed)))
+    }
+}
+
+/// The error of an operation that was cancelled because it stopped making progress.
+#[derive(Debug)]
+pub(crate) struct Stalled {
+    what: String,
+    stall_timeout: Duration,
+    elapsed: Duration,
+    bytes: u64,
+}
+
+impl fmt::Display for Stalled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "no progress for {}s while {} ({} received in {}s), the server might be overloaded or unreachable",
+            self.stall_timeout.as_secs(),
+            self.what,
+            indicatif::HumanBytes(self.bytes),
+            self.elapsed.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for Stalled {}
+
+/// Run an operation, and cancel it if its heartbeat stops for longer than the stall timeout.
+pub(crate) async fn watch<T, F>(what: &str, heartbeat: &Heartbeat, stall_timeout: Option<Duration>, operation: F) -> Result<T>
+where
+    F: Future<Output = Result<T>>,
+{
+    let stall_timeout = match stall_timeout {
+        Some(stall_timeout) => stall_timeout,
+        None => return operation.await,
+    };
+    tokio::pin!(operation);
+    loop {
+        let remaining = stall_timeout.saturating_sub(heartbeat.since_last_beat());
+        if remaining.is_zero() {
+            return Err(Stalled {
+                what: String::from(what),
+                stall_timeout,
+                elapsed: heartbeat.started.elapsed(),
+                bytes: heartbeat.bytes.load(Ordering::Relaxed),
+            }
+            .into());
+        }
+        tokio::select! {
+            result = &mut operation => return result,
+            _ = tokio::time::sleep(remaining) => {}
+        }
+    }
+}
+
+/// Run an operation with a fresh heartbeat, and retry it (as often as the policy allows) if it
+/// stalls. Other errors are returned right away.
+pub(crate) async fn retry_stalled<T, F, Fut>(what: &str, policy: StallPolicy, mut operation: F) -> Result<T>
+where
+    F: FnMut(Heartbeat) -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut attempt = 0;
+    loop {
+        let heartbeat = Heartbeat::new();
+        match watch(what, &heartbeat, policy.timeout, operation(heartbeat.clone())).await {
+            Err(e) if e.is::<Stalled>() && attempt < policy.retries => {
+                attempt += 1;
+                warn!("{}, retrying ({}/{})", e, attempt, policy.retries);
+            }
+            result => return result,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_watch_stalled() {
+        let heartbeat = Heartbeat::new();
+        let result = watch("waiting", &heartbeat, Some(Duration::from_millis(50)), async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        })
+        .await;
+        let e = result.expect_err("operation should have stalled");
+        assert!(e.is::<Stalled>());
+        assert!(e.to_string().contains("while waiting"));
+    }
+
+    #[tokio::test]
+    async fn test_watch_progressing() {
+        let heartbeat = Heartbeat::new();
+        let beating = heartbeat.clone();
+        let result = watch("downloading", &heartbeat, Some(Duration::from_millis(100)), async move {
+            // takes longer than the stall timeout, but makes progress all the time
+            for _ in 0..10 {
+                tokio::time::sleep(Duration::from_millis(20)).await;
+                beating.beat(1024);
+            }
+            Ok(42)
+        })
+        .await;
+        assert_eq!(result.unwrap(), 42);
+    }
+
+    #[tokio::test]
+    async fn test_retry_stalled() {
+        let policy = StallPolicy {
+            timeout: Some(Duration::from_millis(20)),
+            retries: 2,
+        };
+        let mut attempts = 0;
+        let result = retry_stalled("downloading", policy, |_| {
+            attempts += 1;
+            let attempt = attempts;
+            async move {
+                if attempt < 3 {
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                }
+                Ok(attempt)
+            }
+        })
+        .await;
+        assert_eq!(result.unwrap(), 3);
+    }
+}